@@ -0,0 +1,630 @@
+//! Highlights, notes and bookmarks made on a Kindle: the 'My Clippings.txt'
+//! file of the device, and the '.mbp'/'.mbs' sidecar files of older ones.
+//!
+//! Clippings give the Kindle locations of the annotations, which are
+//! narrowed down to the exact span of a highlight by finding its text in
+//! the book. Sidecar files give byte offsets of the text. They are PalmDB
+//! databases of the type "BPARMOBI" whose first record ("BPAR") holds the
+//! last position read, followed by "DATA" blocks (a header naming the
+//! position of an annotation, then its text in UTF-16) and "BKMK" blocks
+//! (the start and end of an annotation, or a bookmark).
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use book::MobiBook;
+use common::*;
+use html;
+use html::{Token, Tokenizer};
+use kf8_reader;
+use mobi::TextEncoding;
+use palmdb::{PalmdbHeader, PalmDbType};
+use position::{LOCATION_SIZE, PositionMap};
+
+/// The line ending every clipping.
+const CLIPPING_SEPARATOR: &'static str = "==========";
+
+/// What an annotation is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AnnotationKind {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+/// A highlight, note or bookmark.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Annotation {
+    pub kind: AnnotationKind,
+    /// The title of the book, and its author when known.
+    pub title: String,
+    pub author: Option<String>,
+    pub asin: Option<String>,
+    /// The first and last Kindle location.
+    pub location: Option<(u32, u32)>,
+    /// The page as shown on the device, such as "12" or "xi".
+    pub page: Option<String>,
+    /// When the annotation was made, as written by the device.
+    pub added: Option<String>,
+    /// The highlighted text, or the text of a note.
+    pub text: String,
+    /// The start and end of the annotation in the text, see `PositionMap`.
+    /// Those of sidecar files are offsets of the KF7 text.
+    pub span: Option<(u32, u32)>,
+}
+
+impl Annotation {
+    fn new(kind: AnnotationKind, title: &str) -> Annotation {
+        Annotation {
+            kind: kind,
+            title: title.to_string(),
+            author: None,
+            asin: None,
+            location: None,
+            page: None,
+            added: None,
+            text: String::new(),
+            span: None,
+        }
+    }
+
+    /// Returns whether the annotation was made in a book: by its ASIN when
+    /// both have one, otherwise by its title. Titles are compared by their
+    /// words, and may be cut short.
+    pub fn matches(&self, book: &MobiBook) -> bool {
+        let metadata = book.metadata();
+        if let (Some(ref asin), Some(ref book_asin)) = (self.asin.as_ref(), metadata.asin.as_ref()) {
+            return asin.eq_ignore_ascii_case(book_asin);
+        }
+        let title = title_words(&self.title);
+        !title.is_empty() && [&metadata.title[..], book.title()].iter().any(|book_title| {
+            let book_title = title_words(book_title);
+            !book_title.is_empty()
+                && (book_title.starts_with(&title) || title.starts_with(&book_title))
+        })
+    }
+}
+
+/// Returns the words of a title, in lowercase and separated by spaces.
+fn title_words(title: &str) -> String {
+    title.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits the title line of a clipping, "Title (Author)", into the title
+/// and the author.
+fn split_title(line: &str) -> (String, Option<String>) {
+    let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+    if line.ends_with(')') {
+        // Find the parenthesis opening the last group
+        let mut depth = 0;
+        for (index, c) in line.char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' => depth -= 1,
+                _ => {},
+            }
+            if depth == 0 {
+                let title = line[..index].trim();
+                if !title.is_empty() {
+                    let author = line[index + 1..line.len() - 1].trim().to_string();
+                    return (title.to_string(), Some(author));
+                }
+                break;
+            }
+        }
+    }
+    (line.to_string(), None)
+}
+
+/// Parses a range of numbers such as "123-125", or "123-25" where the end
+/// only gives the last digits.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let mut numbers = text.trim().splitn(2, '-');
+    let start = numbers.next().unwrap_or("").trim();
+    let start_value = match start.parse::<u32>() {
+        Ok(value) => value,
+        Err(_) => return None,
+    };
+    let end = match numbers.next().map(str::trim) {
+        Some(end) if end.len() < start.len() => {
+            format!("{}{}", &start[..start.len() - end.len()], end).parse::<u32>().ok()
+        },
+        Some(end) => end.parse::<u32>().ok(),
+        None => Some(start_value),
+    };
+    end.map(|end| (start_value, ::std::cmp::max(start_value, end)))
+}
+
+/// Parses the line describing a clipping, such as "- Your Highlight on page
+/// 12 | Location 123-125 | Added on Monday, March 3, 2014 10:00:00 PM", or
+/// "- Highlight Loc. 123-25 | Added on ..." from older devices.
+fn parse_description(line: &str, annotation: &mut Annotation) -> bool {
+    let line = line.trim().trim_left_matches('-').trim();
+    for (index, field) in line.split('|').enumerate() {
+        let field = field.trim();
+        let lower = field.to_lowercase();
+        if index == 0 {
+            annotation.kind = if lower.contains("highlight") || lower.contains("clip") {
+                AnnotationKind::Highlight
+            } else if lower.contains("note") {
+                AnnotationKind::Note
+            } else if lower.contains("bookmark") {
+                AnnotationKind::Bookmark
+            } else {
+                return false;
+            };
+        }
+        if lower.starts_with("added on") {
+            annotation.added = Some(field["added on".len()..].trim().to_string());
+            continue;
+        }
+        // Both may be in the first field, as in "on page 5 - location 80"
+        let mut rest = &lower[..];
+        loop {
+            let start = match (rest.find("page"), rest.find("loc")) {
+                (Some(page), Some(location)) => ::std::cmp::min(page, location),
+                (Some(start), None) | (None, Some(start)) => start,
+                (None, None) => break,
+            };
+            let is_page = rest[start..].starts_with("page");
+            let after = rest[start..].trim_left_matches(|c: char| c.is_alphabetic() || c == '.');
+            let value = after.trim_left();
+            let length = value.find(|c: char| !(c.is_alphanumeric() || c == '-'))
+                .unwrap_or(value.len());
+            if is_page {
+                if length > 0 {
+                    annotation.page = Some(value[..length].to_string());
+                }
+            } else if let Some(range) = parse_range(&value[..length]) {
+                annotation.location = Some(range);
+            }
+            rest = &value[length..];
+        }
+    }
+    true
+}
+
+/// Parses the text of a 'My Clippings.txt' file. Clippings whose
+/// description cannot be read, such as from devices set to other
+/// languages, are skipped.
+pub fn parse_clippings(text: &str) -> Vec<Annotation> {
+    let mut annotations = Vec::new();
+    for clipping in text.split(CLIPPING_SEPARATOR) {
+        let mut lines = clipping.lines().skip_while(|line| line.trim().is_empty());
+        let (title, author) = match lines.next() {
+            Some(line) => split_title(line),
+            None => continue,
+        };
+        let mut annotation = Annotation::new(AnnotationKind::Highlight, &title);
+        annotation.author = author;
+        match lines.next() {
+            Some(line) if parse_description(line, &mut annotation) => {},
+            _ => continue,
+        }
+        annotation.text = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+        annotations.push(annotation);
+    }
+    annotations
+}
+
+/// Reads the annotations of a '.mbp' or '.mbs' file, whose positions are
+/// byte offsets of the text of the book.
+pub fn read_mbp(data: &[u8]) -> Result<Vec<Annotation>, io::Error> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let header = try!(PalmdbHeader::read_database(&mut &data[..]));
+    if header.content_type != PalmDbType::Annotations {
+        return Err(invalid("The file is not an annotation file"));
+    }
+    let title = String::from_utf8_lossy(read_until_zero(&header.name)).replace('_', " ");
+    let u32_at = |position: usize| data.get(position..position + 4)
+        .map(|bytes| bytes.iter().fold(0, |value, &byte| value << 8 | byte as u32));
+
+    let start = header.records.get(0).map_or(0, |record| record.data_offset as usize);
+    if data.get(start..start + 4) != Some(&b"BPAR"[..]) {
+        return Err(invalid("The BPAR record is missing"));
+    }
+    let last_read = u32_at(start + 12);
+    let mut position = start + 8 + u32_at(start + 4).unwrap_or(0) as usize;
+
+    // The text of an annotation follows the header with its position for a
+    // highlight, or an empty block for a note
+    let mut annotations: Vec<(u32, Annotation)> = Vec::new();
+    let mut previous = None;
+    let mut location = 0;
+    while data.get(position..position + 4) == Some(&b"DATA"[..]) {
+        let length = match u32_at(position + 4) {
+            Some(length) => length as usize,
+            None => break,
+        };
+        let block = data.get(position + 8..position + 8 + length).unwrap_or(&[]);
+        let kind = if length == 0 {
+            "empty"
+        } else if block.starts_with(b"EBAR") {
+            location = u32_at(position + 0x34).unwrap_or(0);
+            "header"
+        } else {
+            let kind = match previous {
+                Some("empty") => Some(AnnotationKind::Note),
+                Some("header") => Some(AnnotationKind::Highlight),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                let units = block.chunks(2)
+                    .filter(|unit| unit.len() == 2)
+                    .map(|unit| (unit[0] as u16) << 8 | unit[1] as u16)
+                    .collect::<Vec<_>>();
+                let mut annotation = Annotation::new(kind, &title);
+                annotation.text = String::from_utf16_lossy(&units).trim().to_string();
+                annotation.span = Some((location, location));
+                annotations.push((location, annotation));
+            }
+            "text"
+        };
+        previous = Some(kind);
+        position += 8 + length;
+    }
+
+    // Bookmark blocks give the start of the annotation ending at their end,
+    // or are bookmarks
+    while data.get(position..position + 4) == Some(&b"BKMK"[..]) {
+        let (start, end) = match (u32_at(position + 8), u32_at(position + 0x10)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => break,
+        };
+        match annotations.iter_mut().find(|&&mut (location, _)| location == end) {
+            Some(&mut (_, ref mut annotation)) => annotation.span = Some((start, end)),
+            None if Some(end) != last_read => {
+                let mut annotation = Annotation::new(AnnotationKind::Bookmark, &title);
+                annotation.span = Some((end, end));
+                annotations.push((end, annotation));
+            },
+            None => {},
+        }
+        position += 8 + u32_at(position + 4).unwrap_or(0) as usize;
+    }
+
+    let mut annotations = annotations.into_iter().map(|(_, mut annotation)| {
+        annotation.location = annotation.span
+            .map(|(start, end)| (start / LOCATION_SIZE + 1, end / LOCATION_SIZE + 1));
+        annotation
+    }).collect::<Vec<_>>();
+    annotations.sort_by_key(|annotation| annotation.span);
+    Ok(annotations)
+}
+
+/// Reads the annotations of a file: a '.mbp' or '.mbs' file, or else a
+/// 'My Clippings.txt' file.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Vec<Annotation>, io::Error> {
+    let path = path.as_ref();
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    let extension = path.extension()
+        .map_or(String::new(), |extension| extension.to_string_lossy().to_lowercase());
+    match &extension[..] {
+        "mbp" | "mbs" => read_mbp(&data),
+        _ => Ok(parse_clippings(&String::from_utf8_lossy(&data))),
+    }
+}
+
+/// Returns the text that positions point into: the KF8 documents, one
+/// after the other, or the KF7 text.
+fn position_text(book: &MobiBook) -> Result<(Vec<u8>, TextEncoding), io::Error> {
+    match book.kf8() {
+        Some(section) => {
+            let text = try!(kf8_reader::read_text(book, section));
+            let data = text.parts.into_iter().flat_map(|part| part.data).collect();
+            Ok((data, TextEncoding::UTF8))
+        },
+        None => Ok((try!(book.text(&book.main)), book.main.header.text_encoding)),
+    }
+}
+
+/// Returns the characters shown by some markup, with their byte ranges,
+/// leaving out whitespace.
+fn visible_characters(text: &[u8], encoding: TextEncoding, start: usize, end: usize)
+        -> Vec<(char, usize, usize)> {
+    let mut characters = Vec::new();
+    for (range, token) in Tokenizer::new(text) {
+        let data = match token {
+            Token::Text(data) if range.end > start && range.start < end => data,
+            _ => continue,
+        };
+        let mut i = 0;
+        while i < data.len() {
+            let mut length = match encoding {
+                TextEncoding::Latin1 => 1,
+                _ => match data[i] {
+                    0xF0...0xFF => 4,
+                    0xE0...0xEF => 3,
+                    0xC0...0xDF => 2,
+                    _ => 1,
+                },
+            };
+            let entity = if data[i] == b'&' {
+                data[i..].iter().take(12).position(|&byte| byte == b';').and_then(|semicolon| {
+                    let text = html::unescape(&data[i..i + semicolon + 1]);
+                    if text.chars().count() == 1 { Some((text, semicolon + 1)) } else { None }
+                })
+            } else {
+                None
+            };
+            let decoded = if let Some((entity, entity_length)) = entity {
+                length = entity_length;
+                entity
+            } else {
+                let bytes = &data[i..::std::cmp::min(i + length, data.len())];
+                match encoding {
+                    TextEncoding::Latin1 => decode_cp1252(bytes),
+                    _ => String::from_utf8_lossy(bytes).into_owned(),
+                }
+            };
+            for c in decoded.chars().filter(|c| !c.is_whitespace()) {
+                characters.push((c, range.start + i, range.start + i + length));
+            }
+            i += length;
+        }
+    }
+    characters
+}
+
+/// Finds the span of an annotation in the text. Highlights are looked for
+/// around their locations, and the locations are used when they are not
+/// found.
+fn locate(annotation: &Annotation, text: &[u8], encoding: TextEncoding, map: &PositionMap)
+        -> Option<(u32, u32)> {
+    let (first, last) = match annotation.location {
+        Some(location) => location,
+        None => return None,
+    };
+    let start = map.location_position(first);
+    let end = map.location_position(last.saturating_add(1));
+    let needle = annotation.text.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    if annotation.kind != AnnotationKind::Highlight || needle.is_empty() {
+        return Some((start, end));
+    }
+
+    let window_start = map.location_position(first.saturating_sub(1)) as usize;
+    let window_end = ::std::cmp::min(map.location_position(last.saturating_add(2)) as usize,
+        text.len());
+    let characters = visible_characters(text, encoding, window_start, window_end);
+    let found = (0..characters.len().saturating_sub(needle.len() - 1)).find(|&i| {
+        needle.iter().zip(characters[i..].iter()).all(|(&c, &(d, _, _))| c == d)
+    });
+    match found {
+        Some(i) => Some((characters[i].1 as u32, characters[i + needle.len() - 1].2 as u32)),
+        None => Some((start, end)),
+    }
+}
+
+/// Returns the annotations made in a book, with their spans in its text,
+/// in the order of the text.
+pub fn for_book(book: &MobiBook, annotations: &[Annotation]) -> Result<Vec<Annotation>, io::Error> {
+    let map = try!(book.positions());
+    let (text, encoding) = try!(position_text(book));
+    let mut found = annotations.iter()
+        .filter(|annotation| annotation.matches(book))
+        .cloned()
+        .map(|mut annotation| {
+            if annotation.span.is_none() {
+                annotation.span = locate(&annotation, &text, encoding, &map);
+            }
+            annotation
+        })
+        .collect::<Vec<_>>();
+    found.sort_by_key(|annotation| annotation.span.map(|(start, end)| (start, end)));
+    Ok(found)
+}
+
+/// Writes annotations as Markdown: highlights as quotes, followed by their
+/// notes, and bookmarks as list items.
+pub fn to_markdown(title: &str, author: Option<&str>, annotations: &[Annotation]) -> String {
+    let mut output = format!("# {}\n\n", title);
+    if let Some(author) = author {
+        output.push_str(&format!("*{}*\n\n", author));
+    }
+    for annotation in annotations.iter() {
+        let mut place = Vec::new();
+        if let Some((first, last)) = annotation.location {
+            place.push(if first == last {
+                format!("location {}", first)
+            } else {
+                format!("locations {}-{}", first, last)
+            });
+        }
+        if let Some(ref page) = annotation.page {
+            place.push(format!("page {}", page));
+        }
+        if let Some(ref added) = annotation.added {
+            place.push(format!("added on {}", added));
+        }
+        let place = place.join(", ");
+        match annotation.kind {
+            AnnotationKind::Highlight => {
+                for line in annotation.text.lines() {
+                    output.push_str(&format!("> {}\n", line).replace("> \n", ">\n"));
+                }
+                output.push_str(&format!("\n— {}\n\n", place));
+            },
+            AnnotationKind::Note => {
+                output.push_str(&format!("**Note** ({}): {}\n\n", place, annotation.text));
+            },
+            AnnotationKind::Bookmark => {
+                output.push_str(&format!("- Bookmark ({})\n\n", place));
+            },
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    /// Two highlights, a note, a bookmark and the last read position, laid
+    /// out like the '.mbp' files of the Kindle Keyboard.
+    const MBP: &'static [u8] = include_bytes!("../tests/fixtures/annotations.mbp");
+
+    #[test]
+    fn mbp_files() {
+        let annotations = read_mbp(MBP).unwrap();
+        let summary = annotations.iter()
+            .map(|annotation| (annotation.kind, annotation.span, annotation.location))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (AnnotationKind::Highlight, Some((1772, 1830)), Some((12, 13))),
+            (AnnotationKind::Note, Some((2500, 2500)), Some((17, 17))),
+            (AnnotationKind::Bookmark, Some((3000, 3000)), Some((21, 21))),
+            (AnnotationKind::Highlight, Some((4194, 4210)), Some((28, 29))),
+        ]);
+        assert_eq!(annotations[0].title, "The Great Book");
+        assert_eq!(annotations[0].text, "It was the best of times, it was the worst of times");
+        assert_eq!(annotations[1].text, "A note on the second chapter");
+        assert_eq!(annotations[2].text, "");
+
+        let mut book = MBP.to_vec();
+        book[60..68].copy_from_slice(b"BOOKMOBI");
+        assert!(read_mbp(&book).is_err());
+    }
+
+    /// Clippings of several devices, with a byte order mark and CRLF line
+    /// endings like a 'My Clippings.txt' file.
+    const CLIPPINGS: &'static str = include_str!("../tests/fixtures/clippings.txt");
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("123-125"), Some((123, 125)));
+        assert_eq!(parse_range("123-25"), Some((123, 125)));
+        assert_eq!(parse_range("1098-99"), Some((1098, 1099)));
+        assert_eq!(parse_range("99-101"), Some((99, 101)));
+        assert_eq!(parse_range(" 80 "), Some((80, 80)));
+        assert_eq!(parse_range("130-5"), Some((130, 135)));
+        assert_eq!(parse_range("125-1"), Some((125, 125)));
+        assert_eq!(parse_range("x-5"), None);
+        assert_eq!(parse_range("5-x"), None);
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(split_title("\u{feff}The Book (Doe, Jane (Ed.)) "),
+            ("The Book".to_string(), Some("Doe, Jane (Ed.)".to_string())));
+        assert_eq!(split_title("A (Second) Book (Smith)"),
+            ("A (Second) Book".to_string(), Some("Smith".to_string())));
+        assert_eq!(split_title("(Only a group)"), ("(Only a group)".to_string(), None));
+        assert_eq!(split_title("Unbalanced)"), ("Unbalanced)".to_string(), None));
+        assert_eq!(split_title("No author"), ("No author".to_string(), None));
+    }
+
+    #[test]
+    fn descriptions() {
+        let describe = |line: &str| {
+            let mut annotation = Annotation::new(AnnotationKind::Highlight, "");
+            if parse_description(line, &mut annotation) {
+                Some((annotation.kind, annotation.page, annotation.location, annotation.added))
+            } else {
+                None
+            }
+        };
+        assert_eq!(describe("- Your Note on page 12 | Location 125 | Added on Monday"),
+            Some((AnnotationKind::Note, Some("12".to_string()), Some((125, 125)),
+                Some("Monday".to_string()))));
+        assert_eq!(describe("- Highlight Loc. 1098-99  | Added on Tuesday"),
+            Some((AnnotationKind::Highlight, None, Some((1098, 1099)),
+                Some("Tuesday".to_string()))));
+        assert_eq!(describe("- Your Bookmark on Page xi - Location 80"),
+            Some((AnnotationKind::Bookmark, Some("xi".to_string()), Some((80, 80)), None)));
+        assert_eq!(describe("- Clip This Article | Location 3"),
+            Some((AnnotationKind::Highlight, None, Some((3, 3)), None)));
+        assert_eq!(describe("- Votre surlignement sur la page 3"), None);
+    }
+
+    #[test]
+    fn clippings_files() {
+        let annotations = parse_clippings(CLIPPINGS);
+        let summary = annotations.iter()
+            .map(|annotation| (&annotation.title[..], annotation.kind, annotation.location))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            ("The Great Book: A Subtitle", AnnotationKind::Highlight, Some((123, 125))),
+            ("The Great Book: A Subtitle", AnnotationKind::Note, Some((125, 125))),
+            ("Other Book", AnnotationKind::Highlight, Some((1098, 1099))),
+            ("Other Book", AnnotationKind::Bookmark, None),
+            ("Book Without Author", AnnotationKind::Highlight, Some((80, 81))),
+        ]);
+        assert_eq!(annotations[0].author, Some("Doe, Jane (Ed.)".to_string()));
+        assert_eq!(annotations[0].page, Some("12".to_string()));
+        assert_eq!(annotations[0].added, Some("Monday, March 3, 2014 10:00:00 PM".to_string()));
+        assert_eq!(annotations[0].text, "The quick brown fox\njumps over the lazy dog.");
+        assert_eq!(annotations[1].text, "Why a fox?");
+        assert_eq!(annotations[3].page, Some("xi".to_string()));
+        assert_eq!(annotations[3].text, "");
+        assert_eq!(annotations[4].author, None);
+    }
+
+    #[test]
+    fn annotations_of_books() {
+        let mut body = (0..100)
+            .map(|i| format!("<p>Paragraph {} is about &amp; things.</p>", i))
+            .collect::<String>();
+        body.push_str("<p>The <i>quick</i> brown</p><p>fox jumps.</p>");
+        let mut book = testing::kf7_book(&format!("<html><body>{}</body></html>", body));
+        let mut metadata = book.metadata();
+        metadata.title = "The Great Book: A Subtitle".into();
+        book.edit_metadata(&metadata).unwrap();
+        let text = book.text(&book.main).unwrap();
+        let quick = html::find_bytes(&text, b"The <i>quick").unwrap() as u32;
+        let fox = html::find_bytes(&text, b"fox jumps").unwrap() as u32 + 3;
+        let fifty = html::find_bytes(&text, b"Paragraph 50 ").unwrap() as u32;
+        let location = |position: u32| position / LOCATION_SIZE + 1;
+
+        let mut highlight = Annotation::new(AnnotationKind::Highlight, "The Great Book");
+        highlight.location = Some((location(quick), location(fox)));
+        highlight.text = "The quick brown fox".into();
+        let mut entity = highlight.clone();
+        entity.location = Some((location(fifty), location(fifty) + 1));
+        entity.text = "Paragraph 50 is about & things.".into();
+        let mut bookmark = Annotation::new(AnnotationKind::Bookmark, "the great book");
+        bookmark.location = Some((2, 2));
+        let mut far = Annotation::new(AnnotationKind::Note, "The Great Book");
+        far.location = Some((::std::u32::MAX, ::std::u32::MAX));
+        let mut other = highlight.clone();
+        other.title = "Another Book".into();
+        let mut by_asin = highlight.clone();
+        by_asin.asin = Some("B00OTHER".into());
+
+        assert!(highlight.matches(&book) && bookmark.matches(&book));
+        assert!(!other.matches(&book));
+        assert!(by_asin.matches(&book));
+        metadata.asin = Some("b00test".into());
+        book.edit_metadata(&metadata).unwrap();
+        assert!(!by_asin.matches(&book));
+
+        let annotations = vec![far.clone(), highlight, entity, bookmark, other];
+        let found = for_book(&book, &annotations).unwrap();
+        let spans = found.iter().map(|annotation| annotation.span.unwrap()).collect::<Vec<_>>();
+        let length = text.len() as u32;
+        assert_eq!(spans, vec![(150, 300), (fifty, fifty + 35), (quick, fox), (length, length)]);
+        let paragraph = &text[fifty as usize..fifty as usize + 35];
+        assert_eq!(paragraph, &b"Paragraph 50 is about &amp; things."[..]);
+        assert_eq!(found[3].kind, AnnotationKind::Note);
+    }
+
+    #[test]
+    fn markdown() {
+        let annotations = parse_clippings(CLIPPINGS);
+        let markdown = to_markdown("The Great Book", Some("Jane Doe"), &annotations[..4]);
+        assert_eq!(markdown, "# The Great Book\n\n*Jane Doe*\n\n\
+            > The quick brown fox\n> jumps over the lazy dog.\n\n\
+            — locations 123-125, page 12, added on Monday, March 3, 2014 10:00:00 PM\n\n\
+            **Note** (location 125, page 12, added on Monday, March 3, 2014 10:01:12 PM): \
+            Why a fox?\n\n\
+            > An old device\n\n\
+            — locations 1098-1099, added on Tuesday, March 4, 2014, 08:15 AM\n\n\
+            - Bookmark (page xi, added on Tuesday, March 4, 2014 8:16:00 AM)\n\n");
+    }
+}
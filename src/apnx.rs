@@ -0,0 +1,399 @@
+//! APNX files: the real page numbers of a book, stored next to it as
+//! 'book.apnx', or as 'book.sdr/book.apnx' on the device.
+//!
+//! The file starts with a version (0x00010001), the offset of the page
+//! header, the length of the JSON content header naming the book and the
+//! header itself. The page header holds a version, the length of its JSON
+//! part (with the page map), the number of pages and the size of their
+//! offsets in bits, followed by the JSON and the offset of the start of
+//! every page in the stored text.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use serde_json;
+use serde_json::Value;
+use book::MobiBook;
+use common::*;
+use drm;
+use exth_tags::ExthTag;
+use position::PositionMap;
+use writer;
+
+const VERSION: u32 = 0x00010001;
+
+/// The number of bytes of text per page of the fast pagination.
+const FAST_PAGE_SIZE: u32 = 2300;
+/// The characters per line, and the lines per page, of the accurate
+/// pagination.
+const LINE_LENGTH: u32 = 70;
+const PAGE_LINES: usize = 32;
+
+/// How the pages of a book are found, as calibre does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pagination {
+    /// A page every 2300 bytes of text.
+    Fast,
+    /// A page every 32 lines, where every paragraph starts a line and every
+    /// 70 characters of a paragraph make one. Encrypted books fall back to
+    /// the fast pagination.
+    Accurate,
+}
+
+impl Pagination {
+    /// Returns the pagination of a name, "fast" or "accurate".
+    pub fn from_name(name: &str) -> Option<Pagination> {
+        match &name.to_lowercase()[..] {
+            "fast" => Some(Pagination::Fast),
+            "accurate" => Some(Pagination::Accurate),
+            _ => None,
+        }
+    }
+}
+
+/// The page numbers of a book.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Apnx {
+    pub content_guid: String,
+    pub asin: String,
+    pub cde_type: String,
+    /// "MOBI_7" or "MOBI_8", the section the offsets are in.
+    pub format: String,
+    pub acr: String,
+    /// The labels of the pages, such as "(1,r,1),(7,a,1)" for roman numbers
+    /// until page 7, and arabic numbers from 1 after.
+    pub page_map: String,
+    /// The offset of the start of every page in the stored text.
+    pub pages: Vec<u32>,
+}
+
+/// The JSON content header.
+#[derive(Serialize)]
+struct ContentHeader<'a> {
+    #[serde(rename = "contentGuid")]
+    content_guid: &'a str,
+    asin: &'a str,
+    #[serde(rename = "cdeType")]
+    cde_type: &'a str,
+    format: &'a str,
+    #[serde(rename = "fileRevisionId")]
+    file_revision_id: &'a str,
+    acr: &'a str,
+}
+
+/// The JSON part of the page header.
+#[derive(Serialize)]
+struct PageHeader<'a> {
+    asin: &'a str,
+    #[serde(rename = "pageMap")]
+    page_map: &'a str,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Returns a string field of a JSON header.
+fn json_field(header: &Value, name: &str) -> String {
+    header.get(name).and_then(|value| value.as_str()).unwrap_or("").to_string()
+}
+
+/// Returns the path of the APNX file of a book: next to it, with the
+/// extension "apnx".
+pub fn sidecar_path<P: AsRef<Path>>(book_path: P) -> PathBuf {
+    book_path.as_ref().with_extension("apnx")
+}
+
+/// Returns a number in lowercase roman numerals.
+fn roman(mut number: u32) -> String {
+    const NUMERALS: [(u32, &'static str); 13] = [(1000, "m"), (900, "cm"), (500, "d"),
+        (400, "cd"), (100, "c"), (90, "xc"), (50, "l"), (40, "xl"), (10, "x"), (9, "ix"),
+        (5, "v"), (4, "iv"), (1, "i")];
+    let mut text = String::new();
+    for &(value, numeral) in NUMERALS.iter() {
+        while number >= value {
+            text.push_str(numeral);
+            number -= value;
+        }
+    }
+    text
+}
+
+/// Returns the offsets of the pages, by the fast pagination.
+fn fast_pages(text_length: u32) -> Vec<u32> {
+    (0..(text_length + FAST_PAGE_SIZE - 1) / FAST_PAGE_SIZE)
+        .map(|page| page * FAST_PAGE_SIZE)
+        .collect()
+}
+
+/// Returns the offsets of the pages, by the accurate pagination. Like
+/// calibre, this scans the text once, and only looks at the 'p' elements.
+fn accurate_pages(text: &[u8]) -> Vec<u32> {
+    let mut lines = Vec::new();
+    let mut in_tag = false;
+    let mut in_paragraph = false;
+    let mut check_paragraph = false;
+    let mut closing = false;
+    let mut characters = 0;
+    for (position, &byte) in text.iter().enumerate() {
+        let byte = byte.to_ascii_lowercase();
+        if check_paragraph {
+            if byte == b'/' {
+                closing = true;
+                continue;
+            }
+            if byte == b'p' {
+                in_paragraph = !closing;
+                if !closing {
+                    lines.push(position.saturating_sub(2) as u32);
+                }
+            }
+            check_paragraph = false;
+            closing = false;
+            continue;
+        }
+        match byte {
+            b'<' => {
+                in_tag = true;
+                check_paragraph = true;
+            },
+            b'>' => in_tag = false,
+            _ if in_paragraph && !in_tag => {
+                characters += 1;
+                if characters == LINE_LENGTH {
+                    lines.push(position as u32);
+                    characters = 0;
+                }
+            },
+            _ => {},
+        }
+    }
+    lines.into_iter().enumerate()
+        .filter(|&(line, _)| line % PAGE_LINES == 0)
+        .map(|(_, position)| position)
+        .collect()
+}
+
+impl Apnx {
+    /// Reads an APNX file.
+    pub fn read_from(source: &mut Read) -> Result<Apnx, io::Error> {
+        let mut data = Vec::new();
+        try!(source.read_to_end(&mut data));
+        if data.len() < 12 {
+            return Err(invalid("The APNX file is too short"));
+        }
+        let mut header = &data[..];
+        let version = try!(read_u32_be(&mut header));
+        if version >> 16 != 1 {
+            return Err(invalid(&format!("Unknown APNX version 0x{:08X}", version)));
+        }
+        let page_header_offset = try!(read_u32_be(&mut header)) as usize;
+        let content_length = try!(read_u32_be(&mut header)) as usize;
+        let content = match data.get(12..12 + content_length) {
+            Some(content) => try!(serde_json::from_slice::<Value>(content)
+                .map_err(|_| invalid("The content header is not valid JSON"))),
+            None => return Err(invalid("The content header is truncated")),
+        };
+
+        let mut page_header = match data.get(page_header_offset..) {
+            Some(page_header) if page_header.len() >= 8 => page_header,
+            _ => return Err(invalid("The page header is missing")),
+        };
+        try!(read_u16_be(&mut page_header));
+        let map_length = try!(read_u16_be(&mut page_header)) as usize;
+        let page_count = try!(read_u16_be(&mut page_header)) as usize;
+        let bits = try!(read_u16_be(&mut page_header));
+        if page_header.len() < map_length {
+            return Err(invalid("The page header is truncated"));
+        }
+        let page_map = try!(serde_json::from_slice::<Value>(&page_header[..map_length])
+            .map_err(|_| invalid("The page header is not valid JSON")));
+        let mut offsets = &page_header[map_length..];
+        let mut pages = Vec::with_capacity(page_count);
+        for _ in 0..page_count {
+            let offset = match bits {
+                16 => read_u16_be(&mut offsets).map(|offset| offset as u32),
+                32 => read_u32_be(&mut offsets),
+                _ => return Err(invalid(&format!("Unknown page offset size {}", bits))),
+            };
+            pages.push(try!(offset.map_err(|_| invalid("The page offsets are truncated"))));
+        }
+
+        Ok(Apnx {
+            content_guid: json_field(&content, "contentGuid"),
+            asin: json_field(&content, "asin"),
+            cde_type: json_field(&content, "cdeType"),
+            // calibre leaves the format out of the files of MOBI_7 books
+            format: match json_field(&content, "format") {
+                ref format if format.is_empty() => String::from("MOBI_7"),
+                format => format,
+            },
+            acr: json_field(&content, "acr"),
+            page_map: json_field(&page_map, "pageMap"),
+            pages: pages,
+        })
+    }
+
+    /// Opens the APNX file at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Apnx, io::Error> {
+        let mut file = try!(File::open(path));
+        Apnx::read_from(&mut file)
+    }
+
+    /// Generates the page numbers of a book, for its KF8 section if it has
+    /// one.
+    pub fn generate(book: &MobiBook, pagination: Pagination) -> Result<Apnx, io::Error> {
+        try!(book.check_html());
+        let section = book.kf8().unwrap_or(&book.main);
+        let pages = match pagination {
+            Pagination::Accurate if drm::check(&section.header).is_ok() => {
+                accurate_pages(&try!(book.text(section)))
+            },
+            _ => fast_pages(section.header.uncompressed_text_length),
+        };
+
+        let metadata = book.metadata();
+        let cde_type = section.exth.iter().filter_map(|tag| match *tag {
+            ExthTag::CDEType(ref kind) => Some(kind.clone()),
+            _ => None,
+        }).next();
+        Ok(Apnx {
+            content_guid: format!("{:08x}", writer::unique_id(&metadata.title)),
+            asin: metadata.asin.unwrap_or_default(),
+            cde_type: cde_type.unwrap_or_else(|| String::from("EBOK")),
+            format: String::from(if section.is_kf8() { "MOBI_8" } else { "MOBI_7" }),
+            acr: String::from_utf8_lossy(read_until_zero(&book.palmdb.name)).into_owned(),
+            page_map: String::from("(1,a,1)"),
+            pages: pages,
+        })
+    }
+
+    /// Writes the APNX file, with 32-bit page offsets.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        let content = serde_json::to_string(&ContentHeader {
+            content_guid: &self.content_guid,
+            asin: &self.asin,
+            cde_type: &self.cde_type,
+            format: &self.format,
+            file_revision_id: "1",
+            acr: &self.acr,
+        }).unwrap();
+        let page_map = serde_json::to_string(&PageHeader {
+            asin: &self.asin,
+            page_map: &self.page_map,
+        }).unwrap();
+
+        let mut data = Vec::new();
+        try!(write_u32_be(&mut data, VERSION));
+        try!(write_u32_be(&mut data, 12 + content.len() as u32));
+        try!(write_u32_be(&mut data, content.len() as u32));
+        data.extend_from_slice(content.as_bytes());
+        try!(write_u16_be(&mut data, 1));
+        try!(write_u16_be(&mut data, page_map.len() as u16));
+        try!(write_u16_be(&mut data, self.pages.len() as u16));
+        try!(write_u16_be(&mut data, 32));
+        data.extend_from_slice(page_map.as_bytes());
+        for &page in self.pages.iter() {
+            try!(write_u32_be(&mut data, page));
+        }
+        output.write_all(&data)
+    }
+
+    /// Returns the label of every page, from the page map. Pages before the
+    /// first range are numbered from 1.
+    pub fn labels(&self) -> Vec<String> {
+        // The ranges look like "(1,a,1),(7,r,1),(12,c,A|B|C)"
+        let ranges = self.page_map.split(')').filter_map(|range| {
+            let range = range.trim_matches(|c| c == ',' || c == '(' || c == ' ');
+            let mut fields = range.splitn(3, ',');
+            let start = fields.next().and_then(|start| start.trim().parse::<usize>().ok());
+            match (start, fields.next(), fields.next()) {
+                (Some(start), Some(kind), Some(value)) => {
+                    Some((start, kind.trim().to_string(), value.trim().to_string()))
+                },
+                _ => None,
+            }
+        }).collect::<Vec<_>>();
+
+        (1..self.pages.len() + 1).map(|page| {
+            let range = ranges.iter().filter(|&&(start, _, _)| start <= page).last();
+            match range {
+                Some(&(start, ref kind, ref value)) => {
+                    let index = (page - start) as u32;
+                    let first = value.parse::<u32>().unwrap_or(1);
+                    match &kind[..] {
+                        "r" => roman(first + index),
+                        "c" => value.split('|').nth(index as usize).unwrap_or("").to_string(),
+                        _ => (first + index).to_string(),
+                    }
+                },
+                None => page.to_string(),
+            }
+        }).collect()
+    }
+
+    /// Returns the page (from 0) holding an offset of the stored text.
+    pub fn page_at(&self, offset: u32) -> Option<usize> {
+        match self.pages.binary_search(&offset) {
+            Ok(page) => Some(page),
+            Err(0) => None,
+            Err(page) => Some(page - 1),
+        }
+    }
+
+    /// Returns the text position of the start of every page, see
+    /// `PositionMap`.
+    pub fn positions(&self, map: &PositionMap) -> Vec<Option<u32>> {
+        self.pages.iter().map(|&offset| map.stored_position(offset)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    // Written like calibre's APNXBuilder, for a KF8 book and a KF7 one
+    const CALIBRE_MOBI8: &'static [u8] = include_bytes!("../tests/fixtures/calibre_mobi8.apnx");
+    const CALIBRE_MOBI7: &'static [u8] = include_bytes!("../tests/fixtures/calibre_mobi7.apnx");
+
+    #[test]
+    fn reads_calibre_files() {
+        let apnx = Apnx::read_from(&mut &CALIBRE_MOBI8[..]).unwrap();
+        assert_eq!(apnx.content_guid, "6b7f0c2e");
+        assert_eq!(apnx.asin, "B00TEST123");
+        assert_eq!(apnx.format, "MOBI_8");
+        assert_eq!(apnx.acr, "CR!TESTBOOK0000000000000000000");
+        assert_eq!(apnx.pages, vec![0, 2300, 4600, 6900, 9200]);
+        assert_eq!(apnx.labels(), vec!["i", "ii", "1", "2", "3"]);
+
+        let apnx = Apnx::read_from(&mut &CALIBRE_MOBI7[..]).unwrap();
+        assert_eq!(apnx.format, "MOBI_7");
+        assert_eq!(apnx.pages, vec![0, 1822, 3960]);
+        assert_eq!(apnx.page_at(2000), Some(1));
+    }
+
+    #[test]
+    fn writes_calibre_layout() {
+        let apnx = Apnx::read_from(&mut &CALIBRE_MOBI8[..]).unwrap();
+        let mut data = Vec::new();
+        apnx.write_to(&mut data).unwrap();
+        assert_eq!(&data[..], CALIBRE_MOBI8);
+    }
+
+    #[test]
+    fn generated_pages_round_trip() {
+        let paragraph = format!("<p>{}</p>", "word ".repeat(200));
+        let book = testing::kf7_book(&format!("<html><body>{}</body></html>",
+            paragraph.repeat(20)));
+        for &pagination in [Pagination::Fast, Pagination::Accurate].iter() {
+            let apnx = Apnx::generate(&book, pagination).unwrap();
+            assert!(apnx.pages.len() > 1);
+            assert_eq!(apnx.pages[0], apnx.pages.iter().cloned().min().unwrap());
+            let mut data = Vec::new();
+            apnx.write_to(&mut data).unwrap();
+            assert_eq!(Apnx::read_from(&mut &data[..]).unwrap(), apnx);
+        }
+    }
+}
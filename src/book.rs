@@ -0,0 +1,937 @@
+//! Whole MOBI books: the PalmDB records, and the headers of the KF7 and KF8
+//! parts of the book.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use chapters;
+use chapters::Chapter;
+use common::*;
+use container;
+use container::Container;
+use drm;
+use exth_tags;
+use exth_tags::ExthTag;
+use indx;
+use indx::Index;
+use kf8;
+use kf8_reader;
+use kf8_reader::{Flow, FlowKind};
+use metadata;
+use metadata::Metadata;
+use mobi::{CompressionType, MobiHeader};
+use palmdb::PalmdbHeader;
+use palmdoc;
+use position::PositionMap;
+use render;
+use render::TextFormat;
+use writer;
+
+/// One part of a book: a record 0 with its headers, and the records that
+/// follow it. Joint files have a KF7 section and a KF8 section.
+#[derive(Debug, Serialize)]
+pub struct Section {
+    /// The number of the record 0 of the section.
+    pub start: usize,
+    pub header: MobiHeader,
+    pub exth: Vec<ExthTag>,
+    pub full_name: String,
+}
+
+impl Section {
+    fn read_from(records: &[Vec<u8>], start: usize) -> Result<Section, io::Error> {
+        let record0 = &records[start][..];
+        let header = try!(MobiHeader::read_from(&mut &record0[..]));
+        let exth_start = 16 + header.header_length as usize;
+        let exth = if header.has_exth() && exth_start < record0.len() {
+            try!(exth_tags::read_from(&mut &record0[exth_start..]))
+        } else {
+            Vec::new()
+        };
+        let name_start = header.full_name_offset as usize;
+        let name_end = name_start + header.full_name_length as usize;
+        let full_name = match record0.get(name_start..name_end) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => String::new(),
+        };
+        Ok(Section {
+            start: start,
+            header: header,
+            exth: exth,
+            full_name: full_name,
+        })
+    }
+
+    /// Returns whether this is a KF8 section.
+    pub fn is_kf8(&self) -> bool {
+        self.header.kf8.is_some()
+    }
+
+    /// Converts a record number relative to this section to an absolute one.
+    pub fn record(&self, number: u32) -> usize {
+        self.start + number as usize
+    }
+
+    /// Builds record 0 of the section from its header, EXTH tags and full
+    /// name. Record 0 keeps at least the given size, as some creators leave
+    /// room after the full name for later edits.
+    fn to_record0(&mut self, size: usize) -> Vec<u8> {
+        let mut record = writer::record0(&mut self.header, &self.exth, &self.full_name);
+        if record.len() < size {
+            record.resize(size, 0);
+        }
+        record
+    }
+}
+
+/// A MOBI, AZW or AZW3 book held in memory.
+#[derive(Debug)]
+pub struct MobiBook {
+    pub palmdb: PalmdbHeader,
+    pub records: Vec<Vec<u8>>,
+    /// The section starting at record 0.
+    pub main: Section,
+    /// The KF8 section of a joint file, which follows the BOUNDARY record.
+    pub kf8_section: Option<Section>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl MobiBook {
+    /// Opens the book at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MobiBook, io::Error> {
+        let mut file = try!(File::open(path));
+        MobiBook::read_from(&mut file)
+    }
+
+    /// Reads a whole book from the given source.
+    pub fn read_from(source: &mut Read) -> Result<MobiBook, io::Error> {
+        let mut data = Vec::new();
+        try!(source.read_to_end(&mut data));
+        MobiBook::from_bytes(&data)
+    }
+
+    /// Parses a book from the bytes of the file.
+    pub fn from_bytes(data: &[u8]) -> Result<MobiBook, io::Error> {
+        let palmdb = try!(PalmdbHeader::read_from(&mut &data[..]));
+        if palmdb.records.is_empty() {
+            return Err(invalid("The book has no records"));
+        }
+
+        let mut records = Vec::with_capacity(palmdb.records.len());
+        for (i, record) in palmdb.records.iter().enumerate() {
+            let start = record.data_offset as usize;
+            let end = match palmdb.records.get(i + 1) {
+                Some(next) => next.data_offset as usize,
+                None => data.len(),
+            };
+            if start > end || end > data.len() {
+                return Err(invalid("The record offsets are out of order"));
+            }
+            records.push(data[start..end].to_vec());
+        }
+
+        let main = try!(Section::read_from(&records, 0));
+        let boundary = main.exth.iter().filter_map(|tag| match *tag {
+            ExthTag::KF8BoundaryOffset(offset) => Some(offset as usize),
+            _ => None,
+        }).next();
+        let kf8_section = match boundary {
+            Some(start) if start < records.len() && !main.is_kf8() => {
+                Some(try!(Section::read_from(&records, start)))
+            },
+            _ => None,
+        };
+
+        Ok(MobiBook {
+            palmdb: palmdb,
+            records: records,
+            main: main,
+            kf8_section: kf8_section,
+        })
+    }
+
+    /// Returns the KF8 section of the book, if it has one.
+    pub fn kf8(&self) -> Option<&Section> {
+        if self.main.is_kf8() {
+            Some(&self.main)
+        } else {
+            self.kf8_section.as_ref()
+        }
+    }
+
+    /// Returns the KF7 section of the book, if it has one.
+    pub fn kf7(&self) -> Option<&Section> {
+        if self.main.is_kf8() {
+            None
+        } else {
+            Some(&self.main)
+        }
+    }
+
+    /// Returns the section to read the content from: KF8 when available.
+    pub fn preferred_section(&self) -> &Section {
+        self.kf8().unwrap_or(&self.main)
+    }
+
+    /// Returns the title, as stored after the EXTH header.
+    pub fn title(&self) -> &str {
+        &self.main.full_name
+    }
+
+    /// Returns the metadata of the book, from the EXTH tags.
+    pub fn metadata(&self) -> Metadata {
+        let section = self.preferred_section();
+        let mut metadata = Metadata::from_exth_tags(&section.full_name, &section.exth);
+        if metadata.language.is_none() {
+            metadata.language = code_from_locale(section.header.locale.value())
+                .map(String::from);
+        }
+        metadata
+    }
+
+    /// Replaces the metadata of the book. The EXTH header and full name of
+    /// every section are rewritten, and the later records are shifted.
+    /// Encrypted books are refused, as record 0 may hold their voucher.
+    pub fn edit_metadata(&mut self, metadata: &Metadata) -> Result<(), io::Error> {
+        for section in self.sections() {
+            try!(drm::check(&section.header));
+        }
+        let locale = metadata.language.as_ref().map_or(0, |code| locale_from_code(code));
+        self.palmdb.set_name(&metadata.title);
+        self.edit_sections(|section| {
+            let mut tags = metadata.to_exth_tags();
+            tags.extend(section.exth.iter().filter(|tag| !metadata::is_metadata_tag(tag)).cloned());
+            section.exth = tags;
+            section.full_name = metadata.title.clone();
+            if locale != 0 {
+                section.header.locale = Language::from(locale);
+            }
+        });
+        Ok(())
+    }
+
+    /// Lets 'edit' change the headers of every section, then rebuilds their
+    /// records 0 and the record list.
+    pub fn edit_sections<F>(&mut self, mut edit: F) where F: FnMut(&mut Section) {
+        let mut sections = vec![&mut self.main];
+        if let Some(ref mut section) = self.kf8_section {
+            sections.push(section);
+        }
+        for section in sections {
+            edit(section);
+            let size = self.records[section.start].len();
+            self.records[section.start] = section.to_record0(size);
+        }
+        self.palmdb.set_records(&self.records);
+    }
+
+    /// Replaces the data of a record, and shifts the offsets of the records
+    /// after it. The headers are read again when it is a record 0.
+    pub fn replace_record(&mut self, index: usize, data: Vec<u8>) -> Result<(), io::Error> {
+        if index >= self.records.len() {
+            return Err(invalid(&format!("The book has no record {}", index)));
+        }
+        self.records[index] = data;
+        self.palmdb.set_records(&self.records);
+        if index == self.main.start {
+            self.main = try!(Section::read_from(&self.records, index));
+        } else if self.kf8_section.as_ref().map_or(false, |section| section.start == index) {
+            self.kf8_section = Some(try!(Section::read_from(&self.records, index)));
+        }
+        Ok(())
+    }
+
+    /// Returns the section and number of a text record, by its index.
+    pub fn find_text_record(&self, index: usize) -> Option<(&Section, u32)> {
+        self.sections().into_iter()
+            .find(|section| {
+                index > section.start
+                    && index <= section.record(section.header.text_record_count as u32)
+            })
+            .map(|section| (section, (index - section.start) as u32))
+    }
+
+    /// Inserts a record, and shifts the record numbers in the headers that
+    /// point at or after it.
+    pub fn insert_record(&mut self, position: usize, data: Vec<u8>) {
+        self.records.insert(position, data);
+        if let Some(ref mut section) = self.kf8_section {
+            if section.start >= position {
+                section.start += 1;
+            }
+        }
+        self.edit_sections(|section| {
+            // Sections after the new record move along with their records
+            if section.start < position {
+                section.header.shift_records((position - section.start) as u32);
+            }
+            for tag in section.exth.iter_mut() {
+                if let ExthTag::KF8BoundaryOffset(ref mut offset) = *tag {
+                    if *offset as usize >= position {
+                        *offset += 1;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Adds a resource record after the last resource, and returns its
+    /// resource index.
+    pub fn add_resource(&mut self, data: Vec<u8>) -> usize {
+        let (position, index) = match self.first_resource() {
+            Some(first) => {
+                let count = self.resource_count();
+                (first + count, count)
+            },
+            None => {
+                // Resources go after the text and the indices
+                let text_end = self.main.record(self.main.header.text_record_count as u32 + 1);
+                let position = (text_end..self.records.len())
+                    .find(|&i| {
+                        ResourceKind::of(&self.records[i]) == ResourceKind::End
+                            && !self.records[i].starts_with(b"INDX")
+                    })
+                    .unwrap_or(self.records.len());
+                (position, 0)
+            },
+        };
+        self.insert_record(position, data);
+
+        let main_start = self.main.start;
+        self.edit_sections(|section| {
+            if section.start == main_start {
+                let relative = (position - section.start) as u32;
+                if section.header.first_image_record == 0xFFFFFFFF {
+                    section.header.first_image_record = relative;
+                }
+                if !section.is_kf8() && (section.header.last_record as u32) < relative {
+                    section.header.last_record = relative as u16;
+                }
+            }
+            for tag in section.exth.iter_mut() {
+                if let ExthTag::ResourceCount(ref mut count) = *tag {
+                    *count += 1;
+                }
+            }
+        });
+        index
+    }
+
+    /// Returns the resource index of the cover image, if the book has one.
+    pub fn cover(&self) -> Option<usize> {
+        self.main.exth.iter()
+            .chain(self.kf8().map_or(&[][..], |section| &section.exth[..]).iter())
+            .filter_map(|tag| match *tag {
+                ExthTag::CoverOffset(offset) if offset != 0xFFFFFFFF => Some(offset as usize),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Sets the cover image. The image replaces the record of the current
+    /// cover, or is added as a new resource.
+    pub fn set_cover(&mut self, image: Vec<u8>) -> Result<(), io::Error> {
+        try!(drm::check(&self.main.header));
+        match ResourceKind::of(&image) {
+            ResourceKind::Image(_) => {},
+            _ => return Err(invalid("The cover is not a JPEG, PNG, GIF or BMP image")),
+        }
+        let current = self.cover().and_then(|index| {
+            match self.resource(index).map(ResourceKind::of) {
+                Some(ResourceKind::Image(_)) => Some(index),
+                _ => None,
+            }
+        });
+        let index = match current {
+            Some(index) => {
+                let record = self.first_resource().unwrap() + index;
+                self.records[record] = image;
+                index
+            },
+            None => self.add_resource(image),
+        };
+
+        self.edit_sections(|section| {
+            exth_tags::set_tag(&mut section.exth, ExthTag::CoverOffset(index as u32));
+            exth_tags::set_tag(&mut section.exth, ExthTag::ThumbnailOffset(index as u32));
+            exth_tags::set_tag(&mut section.exth, ExthTag::HasFakeCover(false));
+            let has_uri = section.exth.iter().any(|tag| match *tag {
+                ExthTag::KF8CoverURI(_) => true,
+                _ => false,
+            });
+            if section.is_kf8() || has_uri {
+                exth_tags::set_tag(&mut section.exth, ExthTag::KF8CoverURI(kf8::embed_uri(index, None)));
+            }
+        });
+        Ok(())
+    }
+
+    /// Writes the book, with the record offsets matching the records.
+    pub fn write_to(&mut self, output: &mut Write) -> Result<(), io::Error> {
+        self.palmdb.write_with_records(&self.records, output)
+    }
+
+    /// Writes the book to the given path.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<(), io::Error> {
+        let mut file = try!(File::create(path));
+        self.write_to(&mut file)
+    }
+
+    /// Returns the record with the given number relative to a section.
+    pub fn section_record(&self, section: &Section, number: u32) -> Option<&[u8]> {
+        self.records.get(section.record(number)).map(|record| &record[..])
+    }
+
+    /// Returns whether the first image record of a section numbers the
+    /// resources. Joint files keep the resources in the KF7 section, so the
+    /// field means nothing in their KF8 header.
+    pub fn has_resources(&self, section: &Section) -> bool {
+        !(section.is_kf8() && self.kf8_section.is_some())
+    }
+
+    /// Returns the decompressed text of a section.
+    pub fn text(&self, section: &Section) -> Result<Vec<u8>, io::Error> {
+        let header = &section.header;
+        let mut text = Vec::with_capacity(header.uncompressed_text_length as usize);
+        for number in 1..header.text_record_count as u32 + 1 {
+            text.extend(try!(self.text_record(section, number)));
+        }
+        Ok(text)
+    }
+
+    /// Returns the decompressed text of a text record of a section, without
+    /// its trailing entries. Encrypted text gives an `Encrypted` error.
+    pub fn text_record(&self, section: &Section, number: u32) -> Result<Vec<u8>, io::Error> {
+        let header = &section.header;
+        try!(drm::check(header));
+        let record = match self.section_record(section, number) {
+            Some(record) => record,
+            None => return Err(invalid("A text record is missing")),
+        };
+        let record = strip_trailing_entries(record, header.extra_record_data_flags);
+        match header.compression {
+            CompressionType::None => Ok(record.to_vec()),
+            CompressionType::PalmDOC => Ok(palmdoc::decompress(record)),
+            CompressionType::HUFFCDIC => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                    "HUFF/CDIC compressed text is not supported"))
+            },
+            CompressionType::Unknown(value) => {
+                Err(invalid(&format!("Unknown compression type {}", value)))
+            },
+        }
+    }
+
+    /// Returns whether this is a Print Replica book, whose text holds PDF
+    /// files instead of HTML.
+    pub fn is_print_replica(&self) -> bool {
+        self.text_record(&self.main, 1).map_or(false, |text| text.starts_with(b"%MOP"))
+    }
+
+    /// Fails with the error naming the container of Print Replica books,
+    /// whose text holds PDF files, for the uses of the text as HTML.
+    pub fn check_html(&self) -> Result<(), io::Error> {
+        if self.is_print_replica() {
+            Err(Container::PrintReplica.unsupported())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the PDF files of a Print Replica book.
+    pub fn print_replica_pdfs(&self) -> Result<Vec<Vec<u8>>, io::Error> {
+        if !self.is_print_replica() {
+            return Err(invalid("The book is not a Print Replica book"));
+        }
+        let tables = try!(container::print_replica_files(&try!(self.text(&self.main))));
+        Ok(tables.into_iter().filter_map(|files| files.into_iter().next()).collect())
+    }
+
+    /// Returns the text of the book without its markup, see `render`.
+    pub fn to_plain_text(&self) -> Result<String, io::Error> {
+        render::render_book(self, TextFormat::PlainText)
+    }
+
+    /// Returns the text of the book as Markdown, see `render`.
+    pub fn to_markdown(&self) -> Result<String, io::Error> {
+        render::render_book(self, TextFormat::Markdown)
+    }
+
+    /// Returns the chapters of the book, see `chapters`.
+    pub fn chapters(&self) -> Result<Vec<Chapter>, io::Error> {
+        chapters::chapters(self)
+    }
+
+    /// Returns the map of the positions of the text, of the KF8 section if
+    /// there is one.
+    pub fn positions(&self) -> Result<PositionMap, io::Error> {
+        PositionMap::new(self, self.kf8().unwrap_or(&self.main))
+    }
+
+    /// Returns the sections of the book: the main one, then the KF8 section
+    /// of a joint file.
+    pub fn sections(&self) -> Vec<&Section> {
+        Some(&self.main).into_iter().chain(self.kf8_section.iter()).collect()
+    }
+
+    /// Describes every record of the book: its offset, size and role, and
+    /// the decompressed size of text records.
+    pub fn record_table(&self) -> Vec<RecordInfo> {
+        let mut table = self.records.iter().enumerate().map(|(i, record)| {
+            RecordInfo {
+                offset: self.palmdb.records[i].data_offset,
+                size: record.len(),
+                role: RecordRole::of(record),
+                text_length: None,
+            }
+        }).collect::<Vec<_>>();
+        // Every index starts with a header record, which tells how many
+        // INDX and CNCX records follow it
+        let mut i = 0;
+        while i < table.len() {
+            if table[i].role != RecordRole::Index {
+                i += 1;
+                continue;
+            }
+            let header = &self.records[i];
+            let count = |offset: usize| header.get(offset..)
+                .and_then(|mut bytes| read_u32_be(&mut bytes).ok())
+                .unwrap_or(0) as usize;
+            let cncx_start = ::std::cmp::min(i + 1 + count(24), table.len());
+            let cncx_end = ::std::cmp::min(cncx_start + count(52), table.len());
+            for info in table[cncx_start..cncx_end].iter_mut() {
+                if info.role == RecordRole::Unknown {
+                    info.role = RecordRole::Cncx;
+                }
+            }
+            i = ::std::cmp::max(cncx_end, i + 1);
+        }
+        for section in self.sections() {
+            table[section.start].role = RecordRole::Header;
+            for number in 1..section.header.text_record_count as u32 + 1 {
+                let index = section.record(number);
+                if index < table.len() {
+                    table[index].role = RecordRole::Text;
+                    table[index].text_length = self.text_record(section, number).ok()
+                        .map(|text| text.len());
+                }
+            }
+        }
+        table
+    }
+
+    /// Returns the number of the first resource record, if there are any.
+    /// Resources are images, fonts and the like, numbered from this record.
+    pub fn first_resource(&self) -> Option<usize> {
+        let first = self.main.header.first_image_record;
+        if first == 0xFFFFFFFF || first as usize >= self.records.len() {
+            None
+        } else {
+            Some(self.main.record(first))
+        }
+    }
+
+    /// Returns the resource with the given index (from 0), as referenced by
+    /// `recindex` attributes (from 1) and `kindle:embed` URIs (from 1). The
+    /// record is returned even for encrypted books, to check the structure.
+    pub fn resource(&self, index: usize) -> Option<&[u8]> {
+        self.first_resource()
+            .and_then(|first| self.records.get(first + index))
+            .map(|record| &record[..])
+    }
+
+    /// Returns the number of resources, up to the first record that belongs
+    /// to the structure of the book.
+    pub fn resource_count(&self) -> usize {
+        match self.first_resource() {
+            Some(first) => self.records[first..].iter()
+                .take_while(|record| ResourceKind::of(record) != ResourceKind::End)
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Returns the resource index of a record, if it is a resource.
+    pub fn resource_index(&self, record: usize) -> Option<usize> {
+        self.first_resource().and_then(|first| {
+            if record >= first && record < first + self.resource_count() {
+                Some(record - first)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the resources of the book with their kinds. Resources of
+    /// encrypted books give an `Encrypted` error.
+    pub fn resources(&self) -> Result<Vec<(usize, ResourceKind, &[u8])>, io::Error> {
+        try!(drm::check(&self.main.header));
+        let first = self.first_resource().unwrap_or(0);
+        Ok(self.records[first..first + self.resource_count()].iter().enumerate()
+            .map(|(index, record)| (index, ResourceKind::of(record), &record[..]))
+            .collect())
+    }
+
+    /// Returns the fonts of the book by resource index, decoded from their
+    /// FONT records, see `kf8::decode_font_record`.
+    pub fn fonts(&self) -> Result<Vec<(usize, Result<Vec<u8>, io::Error>)>, io::Error> {
+        Ok(try!(self.resources()).into_iter()
+            .filter(|&(_, kind, _)| kind == ResourceKind::Font)
+            .map(|(index, _, record)| (index, kf8::decode_font_record(record)))
+            .collect())
+    }
+
+    /// Returns the flows of the text of the KF8 section, as listed by its
+    /// FDST record. KF7 books have one flow, their HTML.
+    pub fn flows(&self) -> Result<Vec<Flow>, io::Error> {
+        try!(self.check_html());
+        match self.kf8() {
+            Some(section) => Ok(try!(kf8_reader::read_text(self, section)).flow_list()),
+            None => Ok(vec![Flow {
+                number: 0,
+                kind: FlowKind::Html,
+                data: try!(self.text(&self.main)),
+            }]),
+        }
+    }
+
+    /// Reads the index starting at the given record of a section.
+    pub fn index(&self, section: &Section, record: u32) -> Result<Index, io::Error> {
+        indx::read_index(&self.records, section.record(record))
+    }
+
+    /// Returns the NCX (table of contents) entries of a section, in the order
+    /// of the index: by depth, then by position.
+    pub fn ncx(&self, section: &Section) -> Result<Vec<NcxEntry>, io::Error> {
+        let record = match section.header.indx_record_offset {
+            Some(record) => record,
+            None => return Ok(Vec::new()),
+        };
+        let index = try!(self.index(section, record));
+        Ok(index.entries.iter().map(|entry| {
+            let pos_fid = entry.values(6).and_then(|values| {
+                if values.len() >= 2 { Some((values[0], values[1])) } else { None }
+            });
+            NcxEntry {
+                title: index.cncx_string(entry, 3).unwrap_or("").to_string(),
+                offset: entry.value(1).unwrap_or(0),
+                length: entry.value(2).unwrap_or(0),
+                depth: entry.value(4).unwrap_or(0),
+                parent: entry.value(21).map(|parent| parent as usize),
+                children: match (entry.value(22), entry.value(23)) {
+                    (Some(first), Some(last)) => (first as usize..last as usize + 1).collect(),
+                    _ => Vec::new(),
+                },
+                pos_fid: pos_fid,
+            }
+        }).collect())
+    }
+}
+
+/// An entry of the NCX index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NcxEntry {
+    pub title: String,
+    /// The position in the text.
+    pub offset: u32,
+    pub length: u32,
+    pub depth: u32,
+    /// The indices of the parent and children among the entries.
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// The fragment and offset of the position, in KF8 books.
+    pub pos_fid: Option<(u32, u32)>,
+}
+
+/// The description of a record, as listed by 'record_table'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordInfo {
+    pub offset: u32,
+    pub size: usize,
+    pub role: RecordRole,
+    /// The decompressed size of text records.
+    pub text_length: Option<usize>,
+}
+
+/// What a record is used for, as told by its magic or by the headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordRole {
+    /// The record 0 of a section, with the MOBI and EXTH headers.
+    Header,
+    Text,
+    Image,
+    Index,
+    /// The strings of an index, which follow its INDX records.
+    Cncx,
+    Huff,
+    Cdic,
+    Flis,
+    Fcis,
+    Fdst,
+    Datp,
+    Srcs,
+    Resc,
+    Font,
+    Boundary,
+    Eof,
+    Unknown,
+}
+
+impl RecordRole {
+    /// Identifies a record by its magic. Text records have no magic.
+    pub fn of(record: &[u8]) -> RecordRole {
+        const MAGICS: &'static [(&'static [u8], RecordRole)] = &[
+            (b"INDX", RecordRole::Index), (b"HUFF", RecordRole::Huff),
+            (b"CDIC", RecordRole::Cdic), (b"FLIS", RecordRole::Flis),
+            (b"FCIS", RecordRole::Fcis), (b"FDST", RecordRole::Fdst),
+            (b"DATP", RecordRole::Datp), (b"SRCS", RecordRole::Srcs),
+            (b"RESC", RecordRole::Resc), (b"FONT", RecordRole::Font),
+            (b"BOUNDARY", RecordRole::Boundary), (b"\xE9\x8E\r\n", RecordRole::Eof),
+        ];
+        match MAGICS.iter().find(|&&(magic, _)| record.starts_with(magic)) {
+            Some(&(_, role)) => role,
+            None => match ResourceKind::of(record) {
+                ResourceKind::Image(_) => RecordRole::Image,
+                _ => RecordRole::Unknown,
+            },
+        }
+    }
+
+    /// Returns the name of the role, as shown by 'mobi records'.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RecordRole::Header => "header",
+            RecordRole::Text => "text",
+            RecordRole::Image => "image",
+            RecordRole::Index => "INDX",
+            RecordRole::Cncx => "CNCX",
+            RecordRole::Huff => "HUFF",
+            RecordRole::Cdic => "CDIC",
+            RecordRole::Flis => "FLIS",
+            RecordRole::Fcis => "FCIS",
+            RecordRole::Fdst => "FDST",
+            RecordRole::Datp => "DATP",
+            RecordRole::Srcs => "SRCS",
+            RecordRole::Resc => "RESC",
+            RecordRole::Font => "FONT",
+            RecordRole::Boundary => "BOUNDARY",
+            RecordRole::Eof => "EOF",
+            RecordRole::Unknown => "unknown",
+        }
+    }
+}
+
+/// What a record after the first resource record holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResourceKind {
+    Image(&'static str),
+    Font,
+    /// Resources that are not images or fonts (RESC, audio, video...).
+    Other,
+    /// The first record that is not a resource.
+    End,
+}
+
+impl ResourceKind {
+    /// Identifies a record by its leading bytes. Images have the MIME type
+    /// of their format.
+    pub fn of(record: &[u8]) -> ResourceKind {
+        const END_MAGICS: &'static [&'static [u8]] = &[b"FLIS", b"FCIS", b"FDST",
+            b"DATP", b"SRCS", b"CMET", b"BOUNDARY", b"INDX", b"\xE9\x8E\r\n"];
+        if END_MAGICS.iter().any(|magic| record.starts_with(magic)) {
+            ResourceKind::End
+        } else if record.starts_with(b"\xFF\xD8") {
+            ResourceKind::Image("image/jpeg")
+        } else if record.starts_with(b"\x89PNG") {
+            ResourceKind::Image("image/png")
+        } else if record.starts_with(b"GIF8") {
+            ResourceKind::Image("image/gif")
+        } else if record.starts_with(b"BM") {
+            ResourceKind::Image("image/bmp")
+        } else if record.starts_with(b"FONT") {
+            ResourceKind::Font
+        } else {
+            ResourceKind::Other
+        }
+    }
+}
+
+/// Returns the record without the trailing entries that the extra record
+/// data flags announce.
+pub fn strip_trailing_entries(record: &[u8], flags: u32) -> &[u8] {
+    let mut end = record.len();
+    let mut rest = flags >> 1;
+    while rest != 0 {
+        if rest & 1 != 0 {
+            let size = decode_vwi_backward(&record[..end]) as usize;
+            end = end.saturating_sub(size);
+        }
+        rest >>= 1;
+    }
+    if flags & 1 != 0 && end > 0 {
+        // Multibyte characters: the size is in the low bits of the last byte
+        let size = (record[end - 1] & 0x03) as usize + 1;
+        end = end.saturating_sub(size);
+    }
+    &record[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn edited_metadata() {
+        let mut writer = testing::linked_kf8_writer();
+        writer.joint = true;
+        let mut book = testing::kf8_book(&writer);
+        let records = book.records.clone();
+
+        let mut metadata = book.metadata();
+        metadata.title = "A Much Longer Title, With Ünïcode".into();
+        metadata.authors = vec!["B. Author".into()];
+        metadata.language = Some("de".into());
+        book.edit_metadata(&metadata).unwrap();
+
+        let mut data = Vec::new();
+        book.write_to(&mut data).unwrap();
+        let book = MobiBook::from_bytes(&data).unwrap();
+        let kf8_start = book.kf8().unwrap().start;
+        for section in book.sections() {
+            let edited = Metadata::from_exth_tags(&section.full_name, &section.exth);
+            assert_eq!(section.full_name, metadata.title);
+            assert_eq!(edited.title, metadata.title);
+            assert_eq!(edited.authors, metadata.authors);
+            assert_eq!(edited.language, metadata.language);
+            assert_eq!(code_from_locale(section.header.locale.value()), Some("de"));
+        }
+        assert_eq!(book.records.len(), records.len());
+        for (i, record) in records.iter().enumerate() {
+            if i != 0 && i != kf8_start {
+                assert_eq!(&book.records[i], record, "record {}", i);
+            }
+        }
+    }
+
+    /// Returns the cover offset, thumbnail offset, cover URI and resource
+    /// count of a section.
+    fn tags(section: &Section) -> (Option<u32>, Option<u32>, Option<String>, Option<u32>) {
+        let mut tags = (None, None, None, None);
+        for tag in section.exth.iter() {
+            match *tag {
+                ExthTag::CoverOffset(offset) => tags.0 = Some(offset),
+                ExthTag::ThumbnailOffset(offset) => tags.1 = Some(offset),
+                ExthTag::KF8CoverURI(ref uri) => tags.2 = Some(uri.clone()),
+                ExthTag::ResourceCount(count) => tags.3 = Some(count),
+                _ => {},
+            }
+        }
+        tags
+    }
+
+    #[test]
+    fn replaced_covers() {
+        let mut writer = testing::linked_kf8_writer();
+        writer.joint = true;
+        let mut book = testing::kf8_book(&writer);
+        let records = book.records.clone();
+        let record = book.first_resource().unwrap();
+        assert_eq!(book.cover(), Some(0));
+
+        book.set_cover(b"\xFF\xD8\xFF\xE0cover".to_vec()).unwrap();
+        assert_eq!(book.records.len(), records.len());
+        assert_eq!(book.records[record], b"\xFF\xD8\xFF\xE0cover");
+        assert_eq!(book.resource(0), Some(&b"\xFF\xD8\xFF\xE0cover"[..]));
+        for i in 1..records.len() {
+            if i != record && i != book.kf8().unwrap().start {
+                assert_eq!(book.records[i], records[i], "record {}", i);
+            }
+        }
+        assert_eq!(tags(&book.main).0, Some(0));
+        assert!(book.set_cover(b"<html>".to_vec()).is_err());
+    }
+
+    #[test]
+    fn added_covers() {
+        let mut writer = testing::kf8_writer(&["<html><body><p>Text</p></body></html>"]);
+        writer.joint = true;
+        let mut book = testing::kf8_book(&writer);
+        assert_eq!(book.cover(), None);
+        assert_eq!(book.first_resource(), None);
+
+        book.set_cover(b"\xFF\xD8\xFF\xE0cover".to_vec()).unwrap();
+        let mut data = Vec::new();
+        book.write_to(&mut data).unwrap();
+        assert_eq!(::validate::validate(&data), Vec::new());
+        let book = MobiBook::from_bytes(&data).unwrap();
+        assert_eq!(book.cover(), Some(0));
+        assert_eq!(book.resource(0), Some(&b"\xFF\xD8\xFF\xE0cover"[..]));
+        assert_eq!(book.resource_count(), 1);
+        assert!(book.main.header.first_image_record < book.kf8().unwrap().start as u32);
+        assert_eq!(tags(&book.main), (Some(0), Some(0), None, None));
+        let uri = Some("kindle:embed:0001".to_string());
+        assert_eq!(tags(book.kf8().unwrap()), (Some(0), Some(0), uri, Some(1)));
+
+
+        // Later resources follow the cover
+        let mut book = book;
+        assert_eq!(book.add_resource(b"GIF89a".to_vec()), 1);
+        let mut data = Vec::new();
+        book.write_to(&mut data).unwrap();
+        assert_eq!(::validate::validate(&data), Vec::new());
+        let book = MobiBook::from_bytes(&data).unwrap();
+        assert_eq!(book.resource(1), Some(&b"GIF89a"[..]));
+        assert_eq!(tags(book.kf8().unwrap()).3, Some(2));
+    }
+
+    #[test]
+    fn replaced_records() {
+        let mut writer = testing::linked_kf8_writer();
+        writer.joint = true;
+        let mut book = testing::kf8_book(&writer);
+        let records = book.records.clone();
+        let offsets = book.palmdb.records.iter()
+            .map(|record| record.data_offset)
+            .collect::<Vec<_>>();
+
+        let longer = vec![b'x'; records[2].len() + 100];
+        book.replace_record(2, longer.clone()).unwrap();
+        for (i, record) in book.palmdb.records.iter().enumerate() {
+            let shift = if i > 2 { 100 } else { 0 };
+            assert_eq!(record.data_offset, offsets[i] + shift, "record {}", i);
+        }
+        assert!(book.replace_record(records.len(), Vec::new()).is_err());
+
+        let mut data = Vec::new();
+        book.write_to(&mut data).unwrap();
+        let book = MobiBook::from_bytes(&data).unwrap();
+        assert_eq!(book.records.len(), records.len());
+        for (i, record) in records.iter().enumerate() {
+            let expected = if i == 2 { &longer } else { record };
+            assert_eq!(&book.records[i], expected, "record {}", i);
+        }
+    }
+
+    #[test]
+    fn replaced_headers() {
+        let html = "<html><body><p>Text</p></body></html>";
+        let mut book = testing::kf7_book(html);
+        let mut other = testing::kf7_book(html);
+        other.edit_sections(|section| {
+            section.full_name = "Other".into();
+            section.header.last_record = 0;
+        });
+
+        book.replace_record(0, other.records[0].clone()).unwrap();
+        assert_eq!(book.title(), "Other");
+        assert_eq!(book.main.header.last_record, 0);
+        assert_eq!(book.palmdb.records[1].data_offset as usize,
+            book.palmdb.records[0].data_offset as usize + other.records[0].len());
+        assert!(book.replace_record(0, b"not a header".to_vec()).is_err());
+    }
+}
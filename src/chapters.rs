@@ -0,0 +1,334 @@
+//! Splitting of the text of KF7 books into chapters.
+//!
+//! The chapters start at the top-level entries of the NCX. Books without an
+//! NCX are split at their page breaks, and books without those before their
+//! top-level headings. KF8 books are already split into documents, which
+//! become the chapters.
+
+use std::collections::HashMap;
+use std::io;
+use book::MobiBook;
+use common::*;
+use html;
+use html::{Token, Tokenizer};
+use kf8_reader;
+use mobi::TextEncoding;
+use render;
+use render::TextFormat;
+
+/// A chapter of a book.
+#[derive(Debug, Clone, Serialize)]
+pub struct Chapter {
+    pub title: String,
+    /// The byte range of the chapter in the text of the book.
+    pub start: usize,
+    pub end: usize,
+    /// The markup of the chapter, in UTF-8. It may leave elements open that
+    /// started in an earlier chapter, and close elements that end in a
+    /// later one.
+    pub html: String,
+}
+
+impl Chapter {
+    /// Renders the chapter as plain text or Markdown.
+    pub fn text(&self, format: TextFormat) -> String {
+        render::render(&[self.html.as_bytes()], format, &HashMap::new())
+    }
+}
+
+/// Returns the byte range of the body of a document.
+fn body_range(text: &[u8]) -> (usize, usize) {
+    let mut start = None;
+    let mut end = text.len();
+    for (range, token) in Tokenizer::new(text) {
+        match token {
+            Token::StartTag(ref tag) if tag.name == "body" && start.is_none() => {
+                start = Some(range.end);
+            },
+            Token::EndTag(ref name) if name == "body" => end = range.start,
+            _ => {},
+        }
+    }
+    let start = start.unwrap_or(0);
+    (start, ::std::cmp::max(start, end))
+}
+
+/// Moves positions inside tags to the start of the tag.
+fn snap_to_tags(text: &[u8], positions: &mut [usize]) {
+    for (range, token) in Tokenizer::new(text) {
+        if let Token::Text(_) = token {
+            continue;
+        }
+        for position in positions.iter_mut() {
+            if *position > range.start && *position < range.end {
+                *position = range.start;
+            }
+        }
+    }
+}
+
+/// Returns the positions of the page breaks.
+fn page_breaks(text: &[u8], start: usize, end: usize) -> Vec<usize> {
+    Tokenizer::new(text)
+        .filter_map(|(range, token)| match token {
+            Token::StartTag(ref tag) if tag.name == "mbp:pagebreak" => Some(range.start),
+            _ => None,
+        })
+        .filter(|&position| position > start && position < end)
+        .collect()
+}
+
+/// Returns the positions of the headings of the highest level that occurs
+/// more than once, such as the 'h1' elements of the chapter titles.
+fn top_headings(text: &[u8], start: usize, end: usize) -> Vec<usize> {
+    let mut headings: Vec<Vec<usize>> = vec![Vec::new(); 6];
+    for (range, token) in Tokenizer::new(text) {
+        if let Token::StartTag(ref tag) = token {
+            let name = tag.name.as_bytes();
+            if name.len() == 2 && name[0] == b'h' && name[1] >= b'1' && name[1] <= b'6'
+                    && range.start >= start && range.start < end {
+                headings[(name[1] - b'1') as usize].push(range.start);
+            }
+        }
+    }
+    headings.into_iter().find(|positions| positions.len() > 1).unwrap_or_else(Vec::new)
+}
+
+/// Returns the text of the first heading of some markup.
+fn first_heading(html: &[u8]) -> Option<String> {
+    let mut heading: Option<(String, String)> = None;
+    for (_, token) in Tokenizer::new(html) {
+        match token {
+            Token::StartTag(ref tag) if heading.is_none() => {
+                let name = &tag.name[..];
+                if name.len() == 2 && name.starts_with('h') && name[1..].parse::<u8>().is_ok() {
+                    heading = Some((name.to_string(), String::new()));
+                }
+            },
+            Token::Text(text) => {
+                if let Some((_, ref mut title)) = heading {
+                    title.push_str(&html::unescape(text));
+                }
+            },
+            Token::EndTag(ref name) => {
+                if let Some((ref open, ref title)) = heading {
+                    if open == name {
+                        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                        if !title.is_empty() {
+                            return Some(title);
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Returns whether markup shows anything: text or images.
+fn is_blank(html: &[u8]) -> bool {
+    Tokenizer::new(html).all(|(_, token)| match token {
+        Token::Text(text) => html::unescape(text).trim().is_empty(),
+        Token::StartTag(ref tag) => tag.name != "img",
+        _ => true,
+    })
+}
+
+/// Splits text at the given positions, which are sorted. The titles of the
+/// chapters starting at the positions are given, or found in their first
+/// heading. Content before the first position is a chapter of its own,
+/// unless it is blank.
+fn split(text: &[u8], encoding: TextEncoding, start: usize, end: usize,
+        positions: &[(usize, Option<String>)]) -> Vec<Chapter> {
+    let mut bounds = vec![(start, None)];
+    for &(position, ref title) in positions.iter() {
+        if position <= start || position >= end {
+            if position <= start && title.is_some() {
+                bounds[0].1 = title.clone();
+            }
+            continue;
+        }
+        bounds.push((position, title.clone()));
+    }
+
+    let decode = |data: &[u8]| match encoding {
+        TextEncoding::Latin1 => decode_cp1252(data),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+    let mut chapters = Vec::new();
+    for (i, &(chapter_start, ref title)) in bounds.iter().enumerate() {
+        let chapter_end = bounds.get(i + 1).map_or(end, |&(position, _)| position);
+        let data = &text[chapter_start..chapter_end];
+        if i == 0 && bounds.len() > 1 && title.is_none() && is_blank(data) {
+            continue;
+        }
+        let html = decode(data);
+        let title = title.clone()
+            .or_else(|| first_heading(html.as_bytes()))
+            .unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+        chapters.push(Chapter { title: title, start: chapter_start, end: chapter_end, html: html });
+    }
+    chapters
+}
+
+/// Splits the text of a book into chapters.
+pub fn chapters(book: &MobiBook) -> Result<Vec<Chapter>, io::Error> {
+    try!(book.check_html());
+    if let Some(section) = book.kf8() {
+        let text = try!(kf8_reader::read_text(book, section));
+        let mut titles: HashMap<usize, String> = HashMap::new();
+        for entry in try!(book.ncx(section)).iter().filter(|entry| entry.depth == 0) {
+            let position = entry.pos_fid
+                .and_then(|(fid, offset)| text.position(fid, offset))
+                .unwrap_or(entry.offset);
+            if let Some((part, _)) = text.locate(position) {
+                titles.entry(part).or_insert_with(|| entry.title.clone());
+            }
+        }
+        return Ok(text.parts.iter().enumerate().map(|(i, part)| {
+            let html = String::from_utf8_lossy(&part.data).into_owned();
+            let title = titles.get(&i).cloned()
+                .or_else(|| first_heading(&part.data))
+                .unwrap_or_else(|| format!("Chapter {}", i + 1));
+            let start = part.start as usize;
+            Chapter { title: title, start: start, end: start + part.length as usize, html: html }
+        }).collect());
+    }
+
+    let section = &book.main;
+    let text = try!(book.text(section));
+    let encoding = section.header.text_encoding;
+    let (start, end) = body_range(&text);
+
+    let ncx = try!(book.ncx(section));
+    let top = ncx.iter().map(|entry| entry.depth).min().unwrap_or(0);
+    let mut entries = ncx.iter()
+        .filter(|entry| entry.depth == top)
+        .map(|entry| (entry.offset as usize, entry.title.clone()))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|&(offset, _)| offset);
+
+    if !entries.is_empty() {
+        // Entries moved to the same tag make one chapter, with the first title
+        let mut positions = entries.iter().map(|&(offset, _)| offset).collect::<Vec<_>>();
+        snap_to_tags(&text, &mut positions);
+        let mut titled: Vec<(usize, Option<String>)> = Vec::new();
+        for (&position, &(_, ref title)) in positions.iter().zip(entries.iter()) {
+            if titled.last().map_or(true, |&(last, _)| last != position) {
+                titled.push((position, Some(title.clone())));
+            }
+        }
+        return Ok(split(&text, encoding, start, end, &titled));
+    }
+
+    let mut positions = page_breaks(&text, start, end);
+    if positions.is_empty() {
+        positions = top_headings(&text, start, end);
+    }
+    let titled = positions.into_iter().map(|position| (position, None)).collect::<Vec<_>>();
+    Ok(split(&text, encoding, start, end, &titled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indx;
+    use indx::{Cncx, IndexEntry, TagDefinition};
+    use testing;
+
+    /// Returns the titles and byte ranges of the chapters of a book,
+    /// checking that their markup is the text in their range.
+    fn split_book(book: &MobiBook, text: &[u8]) -> Vec<(String, usize, usize)> {
+        chapters(book).unwrap().into_iter().map(|chapter| {
+            assert_eq!(chapter.html.as_bytes(), &text[chapter.start..chapter.end]);
+            (chapter.title, chapter.start, chapter.end)
+        }).collect()
+    }
+
+    /// Adds an NCX of entries at the given offsets and depths to a KF7 book.
+    fn add_ncx(book: &mut MobiBook, entries: &[(usize, &str, u32)]) {
+        let definitions = [
+            TagDefinition::new(1, 1, 0x01),
+            TagDefinition::new(3, 1, 0x02),
+            TagDefinition::new(4, 1, 0x04),
+            TagDefinition::end(),
+        ];
+        let mut cncx = Cncx::new();
+        let entries = entries.iter().enumerate().map(|(i, &(offset, title, depth))| {
+            IndexEntry::new(&format!("{:02X}", i))
+                .tag(1, vec![offset as u32])
+                .tag(3, vec![cncx.add(title)])
+                .tag(4, vec![depth])
+        }).collect::<Vec<_>>();
+        let relative = book.main.header.text_record_count as u32 + 1;
+        let records = indx::write_index(&definitions, &entries, &cncx, 0);
+        for (i, record) in records.into_iter().enumerate() {
+            let position = book.main.record(relative + i as u32);
+            book.insert_record(position, record);
+        }
+        book.edit_sections(|section| section.header.indx_record_offset = Some(relative));
+    }
+
+    #[test]
+    fn ncx_chapters() {
+        let mut book = testing::kf7_book("<html><body><p>Preface</p><mbp:pagebreak/>\
+            <h1>One</h1><p>Text</p><h2 id=\"sub\">Sub</h2><h1>Two</h1><p>End</p></body></html>");
+        let text = book.text(&book.main).unwrap();
+        let one = html::find_bytes(&text, b"<h1>One").unwrap();
+        let sub = html::find_bytes(&text, b"<h2").unwrap();
+        let two = html::find_bytes(&text, b"<h1>Two").unwrap();
+        let end = html::find_bytes(&text, b"</body>").unwrap();
+        // Entries inside a tag move to its start, where the first one wins
+        add_ncx(&mut book, &[(one, "First", 0), (sub, "Sub", 1), (two, "Second", 0),
+            (two + 2, "Inside", 0)]);
+        assert_eq!(split_book(&book, &text), vec![
+            ("Chapter 1".to_string(), body_range(&text).0, one),
+            ("First".to_string(), one, two),
+            ("Second".to_string(), two, end),
+        ]);
+    }
+
+    #[test]
+    fn page_break_chapters() {
+        let book = testing::kf7_book("<html><body><p>Preface</p><mbp:pagebreak/>\
+            <h2>Alpha</h2><h1>x</h1><h1>y</h1><mbp:pagebreak/><p>No heading</p></body></html>");
+        let text = book.text(&book.main).unwrap();
+        let breaks = page_breaks(&text, 0, text.len());
+        let (start, end) = body_range(&text);
+        assert_eq!(split_book(&book, &text), vec![
+            ("Chapter 1".to_string(), start, breaks[0]),
+            ("Alpha".to_string(), breaks[0], breaks[1]),
+            ("Chapter 3".to_string(), breaks[1], end),
+        ]);
+    }
+
+    #[test]
+    fn heading_chapters() {
+        let book = testing::kf7_book("<html><body><p> </p><h2>Part</h2><h3>One</h3>\
+            <p>a</p><h3>Two &amp; more</h3><p>b</p></body></html>");
+        let text = book.text(&book.main).unwrap();
+        let one = html::find_bytes(&text, b"<h3>One").unwrap();
+        let two = html::find_bytes(&text, b"<h3>Two").unwrap();
+        let (start, end) = body_range(&text);
+        // The blank start stays with the heading before the first chapter
+        assert_eq!(split_book(&book, &text), vec![
+            ("Part".to_string(), start, one),
+            ("One".to_string(), one, two),
+            ("Two & more".to_string(), two, end),
+        ]);
+    }
+
+    #[test]
+    fn kf8_chapters() {
+        let book = testing::kf8_book(&testing::linked_kf8_writer());
+        let text = kf8_reader::read_text(&book, book.kf8().unwrap()).unwrap();
+        let rebuilt = text.parts.iter().flat_map(|part| part.data.clone()).collect::<Vec<_>>();
+        let (first, second) = (&text.parts[0], &text.parts[1]);
+        // Only the top-level entries of the NCX name documents
+        assert_eq!(split_book(&book, &rebuilt), vec![
+            ("First".to_string(), 0, first.length as usize),
+            ("Second".to_string(), second.start as usize, (second.start + second.length) as usize),
+        ]);
+    }
+}
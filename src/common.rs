@@ -60,9 +60,136 @@ pub fn write_u24_be(source: &mut Write, val: u32) -> Result<(), byteorder::Error
     source.write_uint::<BigEndian>(val as u64, 3)
 }
 
+/// Writes 'None' as 0xFFFFFFFF, the inverse of 'read_unmaxed_u32'.
+pub fn write_unmaxed_u32(source: &mut Write, val: Option<u32>)
+        -> Result<(), byteorder::Error> {
+    source.write_u32::<BigEndian>(val.unwrap_or(0xFFFFFFFF))
+}
+
+/// Encodes a forward variable-width integer: 7 bits per byte, big-endian,
+/// with the high bit set on the last byte.
+pub fn encode_vwi(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8 | 0x80];
+    let mut rest = value >> 7;
+    while rest != 0 {
+        bytes.insert(0, (rest & 0x7F) as u8);
+        rest >>= 7;
+    }
+    bytes
+}
+
+/// Encodes a backward variable-width integer, where the high bit is set on
+/// the first byte instead, so that it can be read from the end.
+pub fn encode_vwi_backward(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest != 0 {
+        bytes.insert(0, (rest & 0x7F) as u8);
+        rest >>= 7;
+    }
+    bytes[0] |= 0x80;
+    bytes
+}
+
+/// Decodes a forward variable-width integer, returning the value and the
+/// number of bytes it took up.
+pub fn decode_vwi(data: &[u8]) -> (u32, usize) {
+    let mut value = 0u32;
+    for (i, &byte) in data.iter().enumerate() {
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 != 0 || i >= 4 {
+            return (value, i + 1);
+        }
+    }
+    (value, data.len())
+}
+
+/// Decodes a backward variable-width integer ending at the end of 'data'.
+pub fn decode_vwi_backward(data: &[u8]) -> u32 {
+    let mut value = 0u32;
+    let mut shift = 0;
+    for &byte in data.iter().rev() {
+        value |= ((byte & 0x7F) as u32) << shift;
+        shift += 7;
+        if byte & 0x80 != 0 || shift >= 28 {
+            break;
+        }
+    }
+    value
+}
+
+/// Pads the buffer with null bytes until its length is a multiple of 'align'.
+pub fn pad_to_multiple(buf: &mut Vec<u8>, align: usize) {
+    while buf.len() % align != 0 {
+        buf.push(0);
+    }
+}
+
+// The characters of Windows-1252 that differ from Latin-1 (0x80 to 0x9F).
+const CP1252_HIGH: [u16; 32] = [
+    0x20AC, 0x81, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x8D, 0x017D, 0x8F,
+    0x90, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x9D, 0x017E, 0x0178
+];
+
+/// Decodes Windows-1252 text, the encoding of older MOBI books.
+pub fn decode_cp1252(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| match byte {
+        0x80...0x9F => ::std::char::from_u32(CP1252_HIGH[(byte - 0x80) as usize] as u32).unwrap(),
+        _ => byte as char,
+    }).collect()
+}
+
+// Mobipocket stores the locale as a Windows LCID (language | sublanguage).
+const LOCALES: &'static [(&'static str, u32)] = &[
+    ("en", 0x0009), ("en-us", 0x0409), ("en-gb", 0x0809), ("en-au", 0x0C09),
+    ("en-ca", 0x1009), ("de", 0x0007), ("de-de", 0x0407), ("de-at", 0x0C07),
+    ("de-ch", 0x0807), ("fr", 0x000C), ("fr-fr", 0x040C), ("fr-ca", 0x0C0C),
+    ("es", 0x000A), ("es-es", 0x0C0A), ("es-mx", 0x080A), ("it", 0x0010),
+    ("nl", 0x0013), ("pt", 0x0016), ("pt-br", 0x0416), ("pt-pt", 0x0816),
+    ("da", 0x0006), ("sv", 0x001D), ("nb", 0x0014), ("no", 0x0014),
+    ("fi", 0x000B), ("pl", 0x0015), ("cs", 0x0005), ("hu", 0x000E),
+    ("ru", 0x0019), ("uk", 0x0022), ("el", 0x0008), ("tr", 0x001F),
+    ("ar", 0x0001), ("he", 0x000D), ("hi", 0x0039), ("ja", 0x0011),
+    ("ko", 0x0012), ("zh", 0x0004), ("zh-cn", 0x0804), ("zh-tw", 0x0404)
+];
+
+/// Returns the MOBI locale for an IANA language code such as "en-US".
+/// Unknown regions fall back to the plain language.
+pub fn locale_from_code(code: &str) -> u32 {
+    let code = code.trim().to_lowercase().replace('_', "-");
+    let language = code.split('-').next().unwrap_or("").to_string();
+    for candidate in &[code, language] {
+        for &(name, locale) in LOCALES {
+            if name == candidate {
+                return locale;
+            }
+        }
+    }
+    0
+}
+
+/// Returns the IANA language code of a MOBI locale, if it is known.
+pub fn code_from_locale(locale: u32) -> Option<&'static str> {
+    for &(name, value) in LOCALES {
+        if value == locale {
+            return Some(name);
+        }
+    }
+    // Fall back to the language without the region
+    for &(name, value) in LOCALES {
+        if value == locale & 0xFF {
+            return Some(name);
+        }
+    }
+    None
+}
+
 /// Creates an enum with the given variants, where each variant can be
 /// converted to/from associated values of the specified type.
-/// The struct has a 'from(value)' member and a 'value()' member.
+/// The struct has a 'from(value)' member, a 'value()' member and a 'name()'
+/// member, and serializes as its name and value.
 macro_rules! valued_enum {
     (
         $name:ident : $value_type:ty {
@@ -71,7 +198,7 @@ macro_rules! valued_enum {
             ),*
         }
     ) => {
-        #[derive(Debug, PartialEq, Hash)]
+        #[derive(Debug, Clone, Copy, PartialEq, Hash)]
         pub enum $name {
             $(
                 $variant,
@@ -97,6 +224,28 @@ macro_rules! valued_enum {
                     $name::Unknown(value) => value,
                 }
             }
+            
+            /// Returns the name of the variant.
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $(
+                        $name::$variant => stringify!($variant),
+                    )*
+                    $name::Unknown(_) => "Unknown",
+                }
+            }
+        }
+        
+        // Serialized as the name of the variant along with the raw value
+        impl ::serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                    where S: ::serde::Serializer {
+                use ::serde::ser::SerializeStruct;
+                let mut state = try!(serializer.serialize_struct(stringify!($name), 2));
+                try!(state.serialize_field("name", self.name()));
+                try!(state.serialize_field("value", &self.value()));
+                state.end()
+            }
         }
     }
 }
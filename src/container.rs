@@ -0,0 +1,135 @@
+//! Identification of the containers that Kindle books come in, so that the
+//! formats this crate cannot read are reported by name.
+
+use std::io;
+
+/// The format of a book file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Container {
+    /// A PalmDB "BOOKMOBI" database: MOBI, AZW and AZW3 books.
+    Mobi,
+    /// A Print Replica book (.azw4): a MOBI book whose text holds PDF files.
+    PrintReplica,
+    /// A Topaz book (.azw1, .tpz).
+    Topaz,
+    /// A KFX container (.kfx).
+    Kfx,
+    /// A KFX container encrypted with DRM.
+    KfxDrm,
+    /// A PalmDB database of another kind, with its type and creator.
+    PalmDb(String),
+    Unknown,
+}
+
+impl Container {
+    /// Returns the name of the format.
+    pub fn name(&self) -> String {
+        match *self {
+            Container::Mobi => "MOBI".to_string(),
+            Container::PrintReplica => "Print Replica (.azw4)".to_string(),
+            Container::Topaz => "Topaz (.azw1)".to_string(),
+            Container::Kfx => "KFX".to_string(),
+            Container::KfxDrm => "DRM-protected KFX".to_string(),
+            Container::PalmDb(ref kind) => format!("PalmDB '{}'", kind),
+            Container::Unknown => "unknown".to_string(),
+        }
+    }
+
+    /// Returns the error to report when a book in this format is read.
+    pub fn unsupported(&self) -> io::Error {
+        let message = match *self {
+            Container::Unknown => "The file is not a MOBI book".to_string(),
+            Container::PrintReplica => format!("The book is a {} book, which only holds PDF \
+                files; extract them with 'mobi extract-pdf'", self.name()),
+            _ => format!("The book is a {} book, which is not supported", self.name()),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+/// Identifies the format of a book from the start of its file. Print
+/// Replica books are only told apart from MOBI books when the data reaches
+/// past their first text record.
+pub fn sniff(data: &[u8]) -> Container {
+    if data.starts_with(b"TPZ") {
+        return Container::Topaz;
+    } else if data.starts_with(b"CONT") {
+        return Container::Kfx;
+    } else if data.starts_with(b"\xEADRMION\xEE") {
+        return Container::KfxDrm;
+    } else if data.len() < 78 {
+        return Container::Unknown;
+    }
+
+    let kind = &data[60..68];
+    if kind != b"BOOKMOBI" {
+        return if kind.iter().all(|&byte| byte >= 0x20 && byte < 0x7F) {
+            Container::PalmDb(String::from_utf8_lossy(kind).into_owned())
+        } else {
+            Container::Unknown
+        };
+    }
+
+    // The text of Print Replica books starts with '%MOP', which PalmDOC
+    // compression keeps as it is
+    let record_count = (data[76] as usize) << 8 | data[77] as usize;
+    let second_offset = data.get(86..90)
+        .map(|bytes| bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize));
+    if let (true, Some(start)) = (record_count > 1, second_offset) {
+        if data.get(start..start + 4) == Some(&b"%MOP"[..]) {
+            return Container::PrintReplica;
+        }
+    }
+    Container::Mobi
+}
+
+/// Reads the files of the text of a Print Replica book. The text holds
+/// tables of files; the first file of every table is a PDF.
+pub fn print_replica_files(text: &[u8]) -> Result<Vec<Vec<Vec<u8>>>, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+        "The Print Replica table of files is invalid");
+    if !text.starts_with(b"%MOP") {
+        return Err(invalid());
+    }
+    let u32_at = |position: usize| text.get(position..position + 4)
+        .map(|bytes| bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+        .ok_or_else(invalid);
+
+    let table_count = try!(u32_at(4));
+    let mut entry = 8 + 4 * table_count;
+    let mut tables = Vec::new();
+    for i in 0..table_count {
+        let file_count = try!(u32_at(8 + 4 * i));
+        let mut files = Vec::new();
+        for _ in 0..file_count {
+            let (offset, length) = (try!(u32_at(entry)), try!(u32_at(entry + 4)));
+            entry += 8;
+            match text.get(offset..offset + length) {
+                Some(file) => files.push(file.to_vec()),
+                None => return Err(invalid()),
+            }
+        }
+        tables.push(files);
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use render::TextFormat;
+    use testing;
+
+    #[test]
+    fn print_replica_books() {
+        // One table with one file, at offset 20
+        let book = testing::kf7_book("%MOP\0\0\0\x01\0\0\0\x01\0\0\0\x14\0\0\0\x08%PDF-1.4");
+        assert!(book.is_print_replica());
+        assert_eq!(book.print_replica_pdfs().unwrap(), vec![b"%PDF-1.4".to_vec()]);
+        let expected = Container::PrintReplica.unsupported().to_string();
+        for error in vec![book.to_plain_text().err(), book.chapters().err(),
+                book.positions().err(), book.flows().err()] {
+            assert_eq!(error.unwrap().to_string(), expected);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! Kindle dictionaries: the headwords of the orthographic index and the
+//! definitions they point to in the text, with the inflected forms of the
+//! inflection index.
+//!
+//! Inflected forms are stored as rules that transform the headword. A rule
+//! is a string of bytes: 0x01 inserts at the start of the word, 0x02 inserts
+//! at the end, 0x03 deletes at the end and 0x04 deletes at the start. Bytes
+//! from 0x0A to 0x13 move the cursor back from the end by 0 to 9 bytes, and
+//! the bytes above 0x13 are the characters to insert or delete. Characters
+//! inserted at or deleted from the end come last first.
+
+use std::collections::HashMap;
+use std::io;
+use common::*;
+use book::{MobiBook, Section};
+use indx::{Index, IndexEntry};
+use mobi::TextEncoding;
+
+/// Inserts at the start of the word.
+pub const INSERT_START: u8 = 0x01;
+/// Inserts at the end of the word, or before the cursor.
+pub const INSERT_END: u8 = 0x02;
+/// Deletes at the end of the word, or before the cursor.
+pub const DELETE_END: u8 = 0x03;
+/// Deletes at the start of the word.
+pub const DELETE_START: u8 = 0x04;
+/// Moves the cursor back from the end by 0 bytes; up to 0x13 for 9 bytes.
+pub const MOVE_BACK: u8 = 0x0A;
+
+/// An inflected form of a headword, such as the plural of a noun.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Inflection {
+    /// The name of the inflection rule, when the dictionary names it.
+    pub name: String,
+    pub form: String,
+}
+
+/// A headword of a dictionary, with the position of its definition in the
+/// text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Headword {
+    pub word: String,
+    pub start: u32,
+    pub end: u32,
+    pub inflections: Vec<Inflection>,
+}
+
+/// A dictionary read from a book with an orthographic index.
+#[derive(Debug)]
+pub struct Dictionary {
+    /// The language of the headwords.
+    pub input: Language,
+    /// The language of the definitions.
+    pub output: Language,
+    /// The headwords, in the order of the index.
+    pub headwords: Vec<Headword>,
+    text: Vec<u8>,
+    encoding: TextEncoding,
+    /// The headwords by their lowercased words.
+    lookup: HashMap<String, Vec<usize>>,
+    /// The headwords by their lowercased inflected forms.
+    inflected: HashMap<String, Vec<usize>>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Returns the section of a book that holds the orthographic index.
+fn dictionary_section(book: &MobiBook) -> Option<&Section> {
+    book.sections().into_iter().find(|section| section.header.indices.orthographic.is_some())
+}
+
+impl Dictionary {
+    /// Reads the dictionary of a book. Books without an orthographic index
+    /// are not dictionaries.
+    pub fn from_book(book: &MobiBook) -> Result<Dictionary, io::Error> {
+        try!(book.check_html());
+        let section = match dictionary_section(book) {
+            Some(section) => section,
+            None => return Err(invalid("The book is not a dictionary")),
+        };
+        let index = try!(book.index(section, section.header.indices.orthographic.unwrap()));
+        let inflections = match section.header.indices.inflection {
+            Some(record) => Some(try!(book.index(section, record))),
+            None => None,
+        };
+        let text = try!(book.text(section));
+        let mut dictionary = Dictionary {
+            input: section.header.dictionary.input,
+            output: section.header.dictionary.output,
+            headwords: read_headwords(&index, inflections.as_ref()),
+            text: text,
+            encoding: section.header.text_encoding,
+            lookup: HashMap::new(),
+            inflected: HashMap::new(),
+        };
+        for (i, headword) in dictionary.headwords.iter().enumerate() {
+            dictionary.lookup.entry(headword.word.to_lowercase()).or_insert_with(Vec::new).push(i);
+            for inflection in headword.inflections.iter() {
+                let forms = dictionary.inflected.entry(inflection.form.to_lowercase())
+                    .or_insert_with(Vec::new);
+                if !forms.contains(&i) {
+                    forms.push(i);
+                }
+            }
+        }
+        Ok(dictionary)
+    }
+
+    /// Returns the headwords matching a word, ignoring case. Exact matches
+    /// come first, and headwords that the word is an inflected form of come
+    /// last.
+    pub fn find(&self, word: &str) -> Vec<&Headword> {
+        let key = word.to_lowercase();
+        let mut found = self.lookup.get(&key)
+            .map_or(Vec::new(), |indices| indices.iter().map(|&i| &self.headwords[i]).collect());
+        found.sort_by_key(|headword| headword.word != word);
+        if let Some(indices) = self.inflected.get(&key) {
+            for &i in indices.iter() {
+                if !found.contains(&&self.headwords[i]) {
+                    found.push(&self.headwords[i]);
+                }
+            }
+        }
+        found
+    }
+
+    /// Returns the inflected forms of the headwords matching a word.
+    pub fn inflections(&self, headword: &str) -> Vec<&Inflection> {
+        let mut inflections: Vec<&Inflection> = Vec::new();
+        let indices = self.lookup.get(&headword.to_lowercase()).map_or(&[][..], |i| &i[..]);
+        for &i in indices.iter() {
+            for inflection in self.headwords[i].inflections.iter() {
+                if !inflections.contains(&inflection) {
+                    inflections.push(inflection);
+                }
+            }
+        }
+        inflections
+    }
+
+    /// Returns the HTML of the definition of a headword.
+    pub fn definition(&self, headword: &Headword) -> String {
+        let end = ::std::cmp::min(headword.end as usize, self.text.len());
+        let start = ::std::cmp::min(headword.start as usize, end);
+        let html = &self.text[start..end];
+        match self.encoding {
+            TextEncoding::Latin1 => decode_cp1252(html),
+            _ => String::from_utf8_lossy(html).into_owned(),
+        }
+    }
+
+    /// Returns the HTML of the definitions of a word, which may be an
+    /// inflected form. Words with several entries get their definitions one
+    /// after the other.
+    pub fn lookup(&self, word: &str) -> Option<String> {
+        let found = self.find(word);
+        if found.is_empty() {
+            return None;
+        }
+        Some(found.iter().map(|headword| self.definition(headword)).collect::<Vec<_>>().join("\n"))
+    }
+}
+
+/// Applies an inflection rule to the bytes of a headword. Returns None if
+/// the rule does not fit the word.
+pub fn apply_rule(word: &[u8], rule: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = word.to_vec();
+    let mut mode = 0;
+    let mut position = bytes.len();
+    for &byte in rule.iter() {
+        match byte {
+            INSERT_START | DELETE_START => {
+                if mode != INSERT_START && mode != DELETE_START {
+                    position = 0;
+                }
+                mode = byte;
+            },
+            INSERT_END | DELETE_END => {
+                if mode != INSERT_END && mode != DELETE_END {
+                    position = bytes.len();
+                }
+                mode = byte;
+            },
+            0x0A...0x13 => {
+                if mode != INSERT_END && mode != DELETE_END {
+                    mode = INSERT_END;
+                    position = bytes.len();
+                }
+                let offset = (byte - MOVE_BACK) as usize;
+                if offset > position {
+                    return None;
+                }
+                position -= offset;
+            },
+            0x14...0xFF => {
+                match mode {
+                    INSERT_START if position <= bytes.len() => {
+                        bytes.insert(position, byte);
+                        position += 1;
+                    },
+                    INSERT_END if position <= bytes.len() => bytes.insert(position, byte),
+                    DELETE_END if position > 0 && position <= bytes.len() => {
+                        position -= 1;
+                        if bytes.remove(position) != byte {
+                            return None;
+                        }
+                    },
+                    DELETE_START if position < bytes.len() => {
+                        if bytes.remove(position) != byte {
+                            return None;
+                        }
+                    },
+                    _ => return None,
+                }
+            },
+            _ => return None,
+        }
+    }
+    Some(bytes)
+}
+
+/// Returns the inflection rule turning a headword into one of its forms.
+/// Changes at the start of the word are written from the start, and the
+/// other changes from the end. Returns None if the changed bytes include
+/// rule bytes.
+pub fn encode_rule(word: &[u8], form: &[u8]) -> Option<Vec<u8>> {
+    let prefix = word.iter().zip(form.iter()).take_while(|&(a, b)| a == b).count();
+    let suffix = word[prefix..].iter().rev().zip(form[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let deleted = &word[prefix..word.len() - suffix];
+    let inserted = &form[prefix..form.len() - suffix];
+    if deleted.iter().chain(inserted.iter()).any(|&byte| byte <= 0x13) {
+        return None;
+    }
+
+    let mut rule = Vec::new();
+    if prefix == 0 && suffix > 0 {
+        if !deleted.is_empty() {
+            rule.push(DELETE_START);
+            rule.extend_from_slice(deleted);
+        }
+        if !inserted.is_empty() {
+            rule.push(INSERT_START);
+            rule.extend_from_slice(inserted);
+        }
+        return Some(rule);
+    }
+
+    // Moving the cursor switches to inserting at the end
+    let mut back = suffix;
+    while back > 0 {
+        let step = ::std::cmp::min(back, 9);
+        rule.push(MOVE_BACK + step as u8);
+        back -= step;
+    }
+    if !deleted.is_empty() {
+        rule.push(DELETE_END);
+        rule.extend(deleted.iter().rev());
+    }
+    if !inserted.is_empty() {
+        if suffix == 0 || !deleted.is_empty() {
+            rule.push(INSERT_END);
+        }
+        rule.extend(inserted.iter().rev());
+    }
+    Some(rule)
+}
+
+/// Reads the inflected forms of a headword from the inflection groups that
+/// tag 0x2A of its entry lists. Every group lists the names of its rules in
+/// tag 0x05, and the entries holding the rules in tag 0x1A.
+fn read_inflections(inflections: &Index, orthographic: &Index, entry: &IndexEntry)
+        -> Vec<Inflection> {
+    let mut result = Vec::new();
+    for &group in entry.values(0x2A).unwrap_or(&[]).iter() {
+        let group = match inflections.entries.get(group as usize) {
+            Some(group) => group,
+            None => continue,
+        };
+        let names = group.values(0x05).unwrap_or(&[]);
+        for (i, &rule) in group.values(0x1A).unwrap_or(&[]).iter().enumerate() {
+            let rule = match inflections.entries.get(rule as usize) {
+                Some(rule) => &rule.label,
+                None => continue,
+            };
+            if let Some(form) = apply_rule(&entry.label, rule) {
+                let name = names.get(i).and_then(|&offset| inflections.cncx.get(&offset));
+                result.push(Inflection {
+                    name: name.cloned().unwrap_or_else(String::new),
+                    form: orthographic.decode(&form),
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Reads the headwords of an orthographic index. Tag 1 holds the start of
+/// the definition, and tag 2 its length.
+fn read_headwords(index: &Index, inflections: Option<&Index>) -> Vec<Headword> {
+    index.entries.iter().filter_map(|entry| {
+        let start = match entry.value(1) {
+            Some(start) => start,
+            None => return None,
+        };
+        Some(Headword {
+            word: index.label(entry),
+            start: start,
+            end: start.saturating_add(entry.value(2).unwrap_or(0)),
+            inflections: inflections.map_or(Vec::new(), |inflections| {
+                read_inflections(inflections, index, entry)
+            }),
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dictionary_writer::{DictionaryEntry, DictionaryWriter};
+    use metadata::Metadata;
+    use mobi::DictionaryInfo;
+
+    #[test]
+    fn found_headwords() {
+        let languages = DictionaryInfo {
+            input: Language::from(0x09),
+            output: Language::from(0x09),
+        };
+        let mut writer = DictionaryWriter::new(Metadata::new("Gloss"), languages);
+        writer.add_entry(DictionaryEntry::new("banc", "a bench").inflection("", "bank"));
+        writer.add_entry(DictionaryEntry::new("bank", "a river side"));
+        writer.add_entry(DictionaryEntry::new("Bank", "a lender"));
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        let dictionary = Dictionary::from_book(&MobiBook::from_bytes(&data).unwrap()).unwrap();
+
+        let words = |word| dictionary.find(word).iter()
+            .map(|headword| headword.word.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(words("Bank"), vec!["Bank", "bank", "banc"]);
+        assert_eq!(words("bank"), vec!["bank", "Bank", "banc"]);
+        // Without an exact match, the headwords keep the order of the index
+        assert_eq!(words("BANK"), vec!["bank", "Bank", "banc"]);
+        assert_eq!(words("banc"), vec!["banc"]);
+        assert_eq!(words("bench"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,309 @@
+//! Creation of Kindle dictionaries: KF7 books whose entries are found
+//! through an orthographic index of their headwords, and through an
+//! inflection index of the inflected forms of the headwords.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::Write;
+use common::*;
+use dictionary;
+use dictionary::Inflection;
+use exth_tags::ExthTag;
+use html;
+use indx;
+use indx::{Cncx, IndexEntry, TagDefinition};
+use metadata::Metadata;
+use mobi::{DictionaryInfo, MobiHeader};
+use palmdb::PalmdbHeader;
+use writer;
+use writer::MobiWriter;
+
+/// An entry of a dictionary.
+#[derive(Debug, Clone)]
+pub struct DictionaryEntry {
+    pub headword: String,
+    pub inflections: Vec<Inflection>,
+    /// The definition, shown after the headword.
+    pub definition_html: String,
+}
+
+impl DictionaryEntry {
+    pub fn new(headword: &str, definition_html: &str) -> DictionaryEntry {
+        DictionaryEntry {
+            headword: headword.to_string(),
+            inflections: Vec::new(),
+            definition_html: definition_html.to_string(),
+        }
+    }
+
+    /// Adds an inflected form, with the name of its inflection (which may be
+    /// empty).
+    pub fn inflection(mut self, name: &str, form: &str) -> DictionaryEntry {
+        self.inflections.push(Inflection { name: name.to_string(), form: form.to_string() });
+        self
+    }
+}
+
+/// Builds a KF7 dictionary.
+#[derive(Debug, Clone)]
+pub struct DictionaryWriter {
+    pub metadata: Metadata,
+    /// The languages of the headwords and of the definitions.
+    pub languages: DictionaryInfo,
+    /// The name shown when choosing the dictionary; the title by default.
+    pub short_name: Option<String>,
+    /// The entries, in the order of the text.
+    pub entries: Vec<DictionaryEntry>,
+}
+
+impl DictionaryWriter {
+    pub fn new(metadata: Metadata, languages: DictionaryInfo) -> DictionaryWriter {
+        DictionaryWriter {
+            metadata: metadata,
+            languages: languages,
+            short_name: None,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add_entry(&mut self, entry: DictionaryEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Returns the markup of the book: one 'idx:entry' element per entry,
+    /// holding the headword and its inflected forms, followed by the
+    /// definition.
+    pub fn html(&self) -> String {
+        let mut html = String::from("<html><head><guide></guide></head><body>");
+        for entry in self.entries.iter() {
+            let headword = html::escape(&entry.headword);
+            html.push_str("<idx:entry scriptable=\"yes\" spell=\"yes\">");
+            html.push_str(&format!("<idx:orth value=\"{}\"><b>{}</b>", headword, headword));
+            if !entry.inflections.is_empty() {
+                html.push_str("<idx:infl>");
+                for inflection in entry.inflections.iter() {
+                    html.push_str(&format!("<idx:iform name=\"{}\" value=\"{}\"/>",
+                        html::escape(&inflection.name), html::escape(&inflection.form)));
+                }
+                html.push_str("</idx:infl>");
+            }
+            html.push_str("</idx:orth> ");
+            html.push_str(&entry.definition_html);
+            html.push_str("</idx:entry><hr/>");
+        }
+        html.push_str("</body></html>");
+        html
+    }
+
+    /// Returns the start and end of every entry in the text.
+    fn entry_positions(text: &[u8]) -> Vec<(u32, u32)> {
+        let mut positions = Vec::new();
+        let mut start = None;
+        for (range, token) in html::Tokenizer::new(text) {
+            match token {
+                html::Token::StartTag(ref tag) if tag.name == "idx:entry" => {
+                    start = Some(range.start as u32);
+                },
+                html::Token::EndTag(ref name) if name == "idx:entry" => {
+                    if let Some(start) = start.take() {
+                        positions.push((start, range.end as u32));
+                    }
+                },
+                _ => {},
+            }
+        }
+        positions
+    }
+
+    /// Builds the inflection index, and returns it with the inflection group
+    /// of every entry. The groups come first, with empty labels, followed by
+    /// the rules, whose labels are the rule bytes.
+    fn inflection_index(&self) -> (Vec<Vec<u8>>, Vec<Option<u32>>) {
+        let mut groups: Vec<Vec<(String, Vec<u8>)>> = Vec::new();
+        let mut group_numbers: HashMap<Vec<(String, Vec<u8>)>, u32> = HashMap::new();
+        let mut rules: BTreeMap<Vec<u8>, u32> = BTreeMap::new();
+        let entry_groups = self.entries.iter().map(|entry| {
+            let word = entry.headword.as_bytes();
+            let group = entry.inflections.iter()
+                .filter(|inflection| inflection.form != entry.headword)
+                .filter_map(|inflection| {
+                    dictionary::encode_rule(word, inflection.form.as_bytes())
+                        .map(|rule| (inflection.name.clone(), rule))
+                })
+                .collect::<Vec<_>>();
+            if group.is_empty() {
+                return None;
+            }
+            for &(_, ref rule) in group.iter() {
+                rules.insert(rule.clone(), 0);
+            }
+            let number = group_numbers.entry(group.clone()).or_insert_with(|| {
+                groups.push(group);
+                groups.len() as u32 - 1
+            });
+            Some(*number)
+        }).collect::<Vec<_>>();
+
+        if groups.is_empty() {
+            return (Vec::new(), entry_groups);
+        }
+        for (i, number) in rules.values_mut().enumerate() {
+            *number = (groups.len() + i) as u32;
+        }
+
+        let definitions = [
+            TagDefinition::new(5, 1, 0x0F), // Rule names (CNCX offsets)
+            TagDefinition::new(26, 1, 0xF0), // Rule entries
+            TagDefinition::end(),
+        ];
+        let mut cncx = Cncx::new();
+        let mut entries = groups.iter().map(|group| {
+            IndexEntry::new("")
+                .tag(5, group.iter().map(|&(ref name, _)| cncx.add(name)).collect())
+                .tag(26, group.iter().map(|&(_, ref rule)| rules[rule]).collect())
+        }).collect::<Vec<_>>();
+        entries.extend(rules.keys().map(|rule| IndexEntry { label: rule.clone(), tags: Vec::new() }));
+        (indx::write_index(&definitions, &entries, &cncx, 2), entry_groups)
+    }
+
+    /// Builds the orthographic index, with the entries sorted by headword.
+    fn orthographic_index(&self, positions: &[(u32, u32)], groups: &[Option<u32>])
+            -> Vec<Vec<u8>> {
+        let definitions = [
+            TagDefinition::new(1, 1, 0x01), // Start of the entry
+            TagDefinition::new(2, 1, 0x02), // Length of the entry
+            TagDefinition::new(42, 1, 0x04), // Inflection group
+            TagDefinition::end(),
+        ];
+        let mut order = (0..self.entries.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| (self.entries[i].headword.to_lowercase(), i));
+        let entries = order.iter().filter_map(|&i| {
+            positions.get(i).map(|&(start, end)| {
+                let entry = IndexEntry::new(&self.entries[i].headword)
+                    .tag(1, vec![start])
+                    .tag(2, vec![end - start]);
+                match groups[i] {
+                    Some(group) => entry.tag(42, vec![group]),
+                    None => entry,
+                }
+            })
+        }).collect::<Vec<_>>();
+        indx::write_index(&definitions, &entries, &Cncx::new(), 0)
+    }
+
+    /// Returns the EXTH tags of the book, including the short name.
+    fn exth_tags(&self) -> Vec<ExthTag> {
+        let mut tags = self.metadata.to_exth_tags();
+        tags.push(ExthTag::CDEType(String::from("EBOK")));
+        tags.extend(writer::creator_tags());
+        let short_name = self.short_name.as_ref().unwrap_or(&self.metadata.title);
+        tags.push(ExthTag::DictionaryShortName(short_name.clone()));
+        tags
+    }
+
+    /// Builds every record of the book: record 0, the text, the orthographic
+    /// and inflection indices, FLIS, FCIS and the end of the file.
+    pub fn to_records(&self) -> Vec<Vec<u8>> {
+        let text = MobiWriter::new(self.metadata.clone(), &self.html()).kf7_html();
+        let text_records = writer::text_records(&text);
+
+        let mut header = MobiHeader::new();
+        header.mobi_id = writer::unique_id(&self.metadata.title);
+        header.uncompressed_text_length = text.len() as u32;
+        header.text_record_count = text_records.len() as u16;
+        header.extra_record_data_flags = 0x01; // Multibyte trailing entries
+        header.locale = Language::from(
+            self.metadata.language.as_ref().map_or(0, |code| locale_from_code(code)));
+        header.dictionary = self.languages;
+
+        let mut records = vec![Vec::new()];
+        records.extend(text_records);
+        header.first_non_book_record = records.len() as u32;
+        header.last_record = (records.len() - 1) as u16;
+
+        let (inflection_index, groups) = self.inflection_index();
+        header.indices.orthographic = Some(records.len() as u32);
+        records.extend(self.orthographic_index(&DictionaryWriter::entry_positions(&text), &groups));
+        if !inflection_index.is_empty() {
+            header.indices.inflection = Some(records.len() as u32);
+            records.extend(inflection_index);
+        }
+
+        header.fcis_flis.flis_record_number = records.len() as u32;
+        records.push(writer::flis_record());
+        header.fcis_flis.fcis_record_number = records.len() as u32;
+        records.push(writer::fcis_record(text.len() as u32));
+        records[0] = writer::record0(&mut header, &self.exth_tags(), &self.metadata.title);
+        records.push(writer::EOF_RECORD.to_vec());
+        records
+    }
+
+    /// Writes the dictionary as a MOBI file.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        let records = self.to_records();
+        let mut palmdb = PalmdbHeader::new(&self.metadata.title);
+        palmdb.write_with_records(&records, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use book::MobiBook;
+    use dictionary::{apply_rule, encode_rule, Dictionary};
+    use validate;
+
+    fn dictionary(writer: &DictionaryWriter) -> Dictionary {
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        assert_eq!(validate::validate(&data), Vec::new());
+        Dictionary::from_book(&MobiBook::from_bytes(&data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn inflection_rules() {
+        let pairs = [("go", "went"), ("apple", "apples"), ("happy", "unhappy"),
+            ("mouse", "mice"), ("Haus", "Häuser"), ("abcdefghijklmnopq", "abXdefghijklmnopq"),
+            ("ab", "ba"), ("abc", "")];
+        for &(word, form) in pairs.iter() {
+            let rule = encode_rule(word.as_bytes(), form.as_bytes()).unwrap();
+            assert_eq!(apply_rule(word.as_bytes(), &rule).unwrap(), form.as_bytes());
+        }
+    }
+
+    #[test]
+    fn written_dictionaries() {
+        let languages = DictionaryInfo {
+            input: Language::from(0x09),
+            output: Language::from(0x07),
+        };
+        let mut writer = DictionaryWriter::new(Metadata::new("Gloss"), languages);
+        writer.add_entry(DictionaryEntry::new("go", "to move")
+            .inflection("past", "went")
+            .inflection("", "goes"));
+        writer.add_entry(DictionaryEntry::new("apple", "a <i>fruit</i>")
+            .inflection("plural", "apples"));
+        writer.add_entry(DictionaryEntry::new("Bank", "river &amp; side"));
+        // Enough entries to need several index records
+        for i in 0..2000 {
+            let headword = format!("word{:04}", i);
+            writer.add_entry(DictionaryEntry::new(&headword, &format!("number {}", i))
+                .inflection("plural", &format!("word{:04}s", i)));
+        }
+
+        let dictionary = dictionary(&writer);
+        assert_eq!(dictionary.input.value(), 0x09);
+        assert_eq!(dictionary.output.value(), 0x07);
+        assert_eq!(dictionary.headwords.len(), writer.entries.len());
+        assert!(dictionary.lookup("WENT").unwrap().contains("to move"));
+        assert!(dictionary.lookup("apples").unwrap().contains("a <i>fruit</i>"));
+        assert!(dictionary.lookup("bank").unwrap().contains("river &amp; side"));
+        assert!(dictionary.lookup("word1999s").unwrap().contains("number 1999"));
+        assert_eq!(dictionary.lookup("gone"), None);
+
+        let inflections = dictionary.inflections("go");
+        assert_eq!(inflections.len(), 2);
+        assert!(inflections.iter().any(|i| i.name == "past" && i.form == "went"));
+        assert!(inflections.iter().any(|i| i.form == "goes"));
+    }
+}
@@ -0,0 +1,713 @@
+//! Structural comparison of two MOBI books: their headers, EXTH tags,
+//! records and text.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use serde::Serialize;
+use serde_json::{self, Value};
+use book::{MobiBook, RecordRole, Section};
+use exth_tags::ExthTag;
+use html::{Token, Tokenizer};
+
+/// The lines of context around the changes of the text diff.
+const CONTEXT: usize = 3;
+
+/// The most edits that the diffs look for before they give up and report
+/// the rest as replaced.
+const MAX_EDITS: usize = 1024;
+
+/// A header field that differs between the books.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    /// The path of the field, such as "fcis_flis.flis_record_number".
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// An EXTH tag that was added, removed or changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExthChange {
+    Added(String, String),
+    Removed(String, String),
+    Changed(String, String, String),
+}
+
+/// A summary of a record, to compare records by their content.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordSummary {
+    pub role: RecordRole,
+    pub size: usize,
+    pub hash: u64,
+}
+
+/// A record that differs between the books. Records that are only in the
+/// old or the new book have no index in the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordChange {
+    pub old_index: Option<usize>,
+    pub new_index: Option<usize>,
+    pub old: Option<RecordSummary>,
+    pub new: Option<RecordSummary>,
+}
+
+/// The differences between two books.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookDiff {
+    pub palmdb: Vec<FieldChange>,
+    /// The changed MOBI header fields, by section name.
+    pub headers: Vec<(String, Vec<FieldChange>)>,
+    pub exth: Vec<(String, Vec<ExthChange>)>,
+    pub records: Vec<RecordChange>,
+    /// The number of records that are the same in both books.
+    pub same_records: usize,
+    /// The unified diff of the text of the books.
+    pub text: Vec<String>,
+}
+
+impl BookDiff {
+    /// Returns whether the books are the same.
+    pub fn is_empty(&self) -> bool {
+        self.palmdb.is_empty()
+            && self.headers.iter().all(|&(_, ref changes)| changes.is_empty())
+            && self.exth.iter().all(|&(_, ref changes)| changes.is_empty())
+            && self.records.is_empty()
+            && self.text.is_empty()
+    }
+
+    /// Prints the differences.
+    pub fn print(&self) {
+        print_fields("PalmDB header", &self.palmdb);
+        for &(ref name, ref changes) in self.headers.iter() {
+            print_fields(name, changes);
+        }
+        for &(ref name, ref changes) in self.exth.iter() {
+            if changes.is_empty() {
+                continue;
+            }
+            println!("{}:", name);
+            for change in changes.iter() {
+                match *change {
+                    ExthChange::Added(ref tag, ref value) => println!("  + {}: {}", tag, value),
+                    ExthChange::Removed(ref tag, ref value) => println!("  - {}: {}", tag, value),
+                    ExthChange::Changed(ref tag, ref old, ref new) => {
+                        println!("  ~ {}: {} -> {}", tag, old, new);
+                    },
+                }
+            }
+        }
+        if !self.records.is_empty() {
+            println!("Records ({} the same):", self.same_records);
+            for change in self.records.iter() {
+                let index = |index: Option<usize>| index.map_or(String::new(), |i| i.to_string());
+                match (&change.old, &change.new) {
+                    (&Some(ref old), &Some(ref new)) => println!("  ~ {:>5} {:>5} {} -> {}",
+                        index(change.old_index), index(change.new_index), summary(old),
+                        summary(new)),
+                    (&Some(ref old), &None) => println!("  - {:>5} {:>5} {}",
+                        index(change.old_index), "", summary(old)),
+                    (&None, &Some(ref new)) => println!("  + {:>5} {:>5} {}", "",
+                        index(change.new_index), summary(new)),
+                    (&None, &None) => {},
+                }
+            }
+        }
+        for line in self.text.iter() {
+            println!("{}", line);
+        }
+    }
+}
+
+fn print_fields(name: &str, changes: &[FieldChange]) {
+    if changes.is_empty() {
+        return;
+    }
+    println!("{}:", name);
+    for change in changes {
+        println!("  {}: {} -> {}", change.field,
+            change.old.as_ref().map_or("(none)", |value| &value[..]),
+            change.new.as_ref().map_or("(none)", |value| &value[..]));
+    }
+}
+
+fn summary(record: &RecordSummary) -> String {
+    format!("{:<8} {:>8} bytes {:016x}", record.role.name(), record.size, record.hash)
+}
+
+/// Formats a leaf value of a serialized header.
+fn value_string(value: &Value) -> String {
+    match *value {
+        Value::String(ref text) => text.clone(),
+        Value::Null => "none".to_string(),
+        ref value => value.to_string(),
+    }
+}
+
+/// Lists the fields of a serialized header with their paths. Enums with a
+/// name and value, and arrays of numbers are single fields.
+fn flatten(path: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    let join = |key: &str| {
+        if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+    };
+    match *value {
+        Value::Object(ref map) => {
+            if map.len() == 2 && map.contains_key("name") && map.contains_key("value") {
+                fields.push((path.to_string(), format!("{} ({})", value_string(&map["name"]),
+                    value_string(&map["value"]))));
+            } else {
+                for (key, value) in map.iter() {
+                    flatten(&join(key), value, fields);
+                }
+            }
+        },
+        Value::Array(ref values) if !values.iter().all(Value::is_number) => {
+            for (i, value) in values.iter().enumerate() {
+                flatten(&format!("{}[{}]", path, i), value, fields);
+            }
+        },
+        ref value => fields.push((path.to_string(), value_string(value))),
+    }
+}
+
+/// Compares two serializable headers field by field. 'skip' lists the top
+/// level fields to leave out.
+fn compare_fields<T: Serialize>(old: &T, new: &T, skip: &[&str]) -> Vec<FieldChange> {
+    let mut old_fields = Vec::new();
+    let mut new_fields = Vec::new();
+    flatten("", &serde_json::to_value(old).unwrap_or(Value::Null), &mut old_fields);
+    flatten("", &serde_json::to_value(new).unwrap_or(Value::Null), &mut new_fields);
+    let skipped = |field: &str| skip.iter().any(|&name| {
+        field == name || field.starts_with(&format!("{}.", name))
+            || field.starts_with(&format!("{}[", name))
+    });
+
+    let new_map: HashMap<&str, &str> = new_fields.iter()
+        .map(|&(ref field, ref value)| (&field[..], &value[..])).collect();
+    let mut changes = Vec::new();
+    for &(ref field, ref value) in old_fields.iter().filter(|&&(ref field, _)| !skipped(field)) {
+        match new_map.get(&field[..]) {
+            Some(new) if new == value => {},
+            new => changes.push(FieldChange {
+                field: field.clone(),
+                old: Some(value.clone()),
+                new: new.map(|new| new.to_string()),
+            }),
+        }
+    }
+    for &(ref field, ref value) in new_fields.iter().filter(|&&(ref field, _)| !skipped(field)) {
+        if !old_fields.iter().any(|&(ref old, _)| old == field) {
+            changes.push(FieldChange { field: field.clone(), old: None, new: Some(value.clone()) });
+        }
+    }
+    changes
+}
+
+/// Returns the name and value of an EXTH tag.
+fn tag_entry(tag: &ExthTag) -> (String, String) {
+    if let ExthTag::Unhandled { ref tag_type, ref data } = *tag {
+        return (format!("{} ({})", tag_type.name(), tag_type.value()),
+            String::from_utf8_lossy(data).into_owned());
+    }
+    match serde_json::to_value(tag) {
+        Ok(Value::Object(map)) => match map.into_iter().next() {
+            Some((name, value)) => {
+                let mut fields = Vec::new();
+                flatten("", &value, &mut fields);
+                let value = fields.into_iter().map(|(_, value)| value).collect::<Vec<_>>();
+                (name, value.join(", "))
+            },
+            None => (String::new(), String::new()),
+        },
+        Ok(value) => (value_string(&value), String::new()),
+        Err(_) => (format!("{:?}", tag), String::new()),
+    }
+}
+
+/// Compares the EXTH tags of two sections. Tags that appear several times,
+/// like authors, are compared as lists.
+fn compare_exth(old: &[ExthTag], new: &[ExthTag]) -> Vec<ExthChange> {
+    let mut names = Vec::new();
+    let mut old_values: HashMap<String, Vec<String>> = HashMap::new();
+    let mut new_values: HashMap<String, Vec<String>> = HashMap::new();
+    for (tags, values) in vec![(old, &mut old_values), (new, &mut new_values)] {
+        for tag in tags {
+            let (name, value) = tag_entry(tag);
+            if !names.contains(&name) {
+                names.push(name.clone());
+            }
+            values.entry(name).or_insert_with(Vec::new).push(value);
+        }
+    }
+
+    let mut changes = Vec::new();
+    for name in names {
+        let old = old_values.remove(&name).unwrap_or_else(Vec::new);
+        let new = new_values.remove(&name).unwrap_or_else(Vec::new);
+        let removed = old.iter().filter(|value| !new.contains(value)).collect::<Vec<_>>();
+        let added = new.iter().filter(|value| !old.contains(value)).collect::<Vec<_>>();
+        if removed.len() == 1 && added.len() == 1 {
+            changes.push(ExthChange::Changed(name, removed[0].clone(), added[0].clone()));
+            continue;
+        }
+        for value in removed {
+            changes.push(ExthChange::Removed(name.clone(), value.clone()));
+        }
+        for value in added {
+            changes.push(ExthChange::Added(name.clone(), value.clone()));
+        }
+    }
+    changes
+}
+
+fn summarize(record: &[u8]) -> RecordSummary {
+    let mut hasher = DefaultHasher::new();
+    record.hash(&mut hasher);
+    RecordSummary {
+        role: RecordRole::of(record),
+        size: record.len(),
+        hash: hasher.finish(),
+    }
+}
+
+/// Compares the records of two books, matching them up by their content.
+/// Runs of records that differ are paired up as changed records.
+fn compare_records(old: &MobiBook, new: &MobiBook) -> (Vec<RecordChange>, usize) {
+    let summaries = |book: &MobiBook| {
+        book.records.iter().zip(book.record_table().iter()).map(|(record, info)| {
+            // The headers know better what a record holds than its magic
+            RecordSummary { role: info.role, ..summarize(record) }
+        }).collect::<Vec<_>>()
+    };
+    let old_summaries = summaries(old);
+    let new_summaries = summaries(new);
+    let old_hashes = old_summaries.iter().map(|summary| summary.hash).collect::<Vec<_>>();
+    let new_hashes = new_summaries.iter().map(|summary| summary.hash).collect::<Vec<_>>();
+    let edits = edit_script(&old_hashes, &new_hashes, MAX_EDITS).unwrap_or_else(|| {
+        // Too many differences to match up: compare the records by index
+        let common = ::std::cmp::min(old_hashes.len(), new_hashes.len());
+        let mut edits = Vec::new();
+        for i in 0..common {
+            if old_hashes[i] == new_hashes[i] {
+                edits.push(Edit::Same);
+            } else {
+                edits.push(Edit::Delete);
+                edits.push(Edit::Insert);
+            }
+        }
+        edits.extend(vec![Edit::Delete; old_hashes.len() - common]);
+        edits.extend(vec![Edit::Insert; new_hashes.len() - common]);
+        edits
+    });
+
+    let mut changes = Vec::new();
+    let mut same = 0;
+    let (mut x, mut y) = (0, 0);
+    let mut i = 0;
+    while i < edits.len() {
+        if edits[i] == Edit::Same {
+            same += 1;
+            x += 1;
+            y += 1;
+            i += 1;
+            continue;
+        }
+        let run = edits[i..].iter().take_while(|&&edit| edit != Edit::Same).count();
+        let deleted = edits[i..i + run].iter().filter(|&&edit| edit == Edit::Delete).count();
+        let inserted = run - deleted;
+        for j in 0..::std::cmp::max(deleted, inserted) {
+            let old_index = if j < deleted { Some(x + j) } else { None };
+            let new_index = if j < inserted { Some(y + j) } else { None };
+            changes.push(RecordChange {
+                old_index: old_index,
+                new_index: new_index,
+                old: old_index.map(|index| old_summaries[index].clone()),
+                new: new_index.map(|index| new_summaries[index].clone()),
+            });
+        }
+        x += deleted;
+        y += inserted;
+        i += run;
+    }
+    (changes, same)
+}
+
+/// Splits HTML text into lines at line breaks and after block elements, as
+/// book text often has few line breaks.
+pub fn text_lines(text: &[u8]) -> Vec<String> {
+    const BLOCKS: &'static [&'static str] = &["p", "div", "h1", "h2", "h3", "h4", "h5", "h6",
+        "li", "tr", "blockquote", "table", "ul", "ol", "body", "head", "html"];
+    let mut breaks = Vec::new();
+    for (range, token) in Tokenizer::new(text) {
+        let is_break = match token {
+            Token::EndTag(ref name) => BLOCKS.contains(&&name.to_lowercase()[..]),
+            Token::StartTag(ref tag) => {
+                let name = tag.name.to_lowercase();
+                name == "br" || name == "mbp:pagebreak" || name == "hr"
+            },
+            Token::Text(text) => {
+                for (i, _) in text.iter().enumerate().filter(|&(_, &byte)| byte == b'\n') {
+                    // A line break after a block ends the same line
+                    let position = range.start + i;
+                    if breaks.last() == Some(&position) {
+                        breaks.pop();
+                    }
+                    breaks.push(position + 1);
+                }
+                false
+            },
+            _ => false,
+        };
+        if is_break {
+            breaks.push(range.end);
+        }
+    }
+    breaks.push(text.len());
+
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for end in breaks {
+        if end <= start {
+            continue;
+        }
+        let line = String::from_utf8_lossy(&text[start..end]);
+        let line = line.trim_right_matches(|c| c == '\n' || c == '\r');
+        lines.push(line.to_string());
+        start = end;
+    }
+    lines
+}
+
+/// An operation of a line diff.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Same,
+    Insert,
+    Delete,
+}
+
+/// Finds a shortest edit script between two lists, with the
+/// algorithm of Myers. Returns None when there are more than 'max_edits'
+/// edits.
+fn edit_script<T: PartialEq>(old: &[T], new: &[T], max_edits: usize) -> Option<Vec<Edit>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = ::std::cmp::min((n + m) as usize, max_edits) as isize;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+    let mut found = false;
+    'search: for d in 0..max + 1 {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+    if !found {
+        return None;
+    }
+
+    // Walk back through the saved frontiers
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let k = x - y;
+        let index = (k + offset) as usize;
+        let previous_k = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = if d == 0 { 0 } else { v[(previous_k + offset) as usize] };
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            edits.push(Edit::Same);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            edits.push(if x == previous_x { Edit::Insert } else { Edit::Delete });
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// Builds a unified diff of two lists of lines.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &[String], new: &[String])
+        -> Vec<String> {
+    // Only the middle of the texts, between their common start and end,
+    // needs to be compared
+    let prefix = old.iter().zip(new.iter()).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b).count();
+    let middle_old = &old[prefix..old.len() - suffix];
+    let middle_new = &new[prefix..new.len() - suffix];
+    if middle_old.is_empty() && middle_new.is_empty() {
+        return Vec::new();
+    }
+    let middle = edit_script(middle_old, middle_new, MAX_EDITS).unwrap_or_else(|| {
+        let mut edits = vec![Edit::Delete; middle_old.len()];
+        edits.extend(vec![Edit::Insert; middle_new.len()]);
+        edits
+    });
+    let mut edits = vec![Edit::Same; prefix];
+    edits.extend(middle);
+    edits.extend(vec![Edit::Same; suffix]);
+
+    // Give every edit its line numbers, then group the changes into hunks
+    let mut positions = Vec::with_capacity(edits.len());
+    let (mut x, mut y) = (0, 0);
+    for &edit in edits.iter() {
+        positions.push((x, y));
+        match edit {
+            Edit::Same => { x += 1; y += 1; },
+            Edit::Delete => x += 1,
+            Edit::Insert => y += 1,
+        }
+    }
+    let mut lines = vec![format!("--- {}", old_name), format!("+++ {}", new_name)];
+    let mut i = 0;
+    while i < edits.len() {
+        if edits[i] == Edit::Same {
+            i += 1;
+            continue;
+        }
+        let start = i.saturating_sub(CONTEXT);
+        let mut end = i;
+        // Hunks take in changes that are closer than twice the context
+        loop {
+            while end < edits.len() && edits[end] != Edit::Same {
+                end += 1;
+            }
+            let next = (end..edits.len()).find(|&j| edits[j] != Edit::Same);
+            match next {
+                Some(next) if next - end <= 2 * CONTEXT => end = next,
+                _ => break,
+            }
+        }
+        let end = ::std::cmp::min(end + CONTEXT, edits.len());
+        let (old_start, new_start) = positions[start];
+        let old_count = edits[start..end].iter().filter(|&&edit| edit != Edit::Insert).count();
+        let new_count = edits[start..end].iter().filter(|&&edit| edit != Edit::Delete).count();
+        lines.push(format!("@@ -{},{} +{},{} @@", old_start + 1, old_count, new_start + 1,
+            new_count));
+        for j in start..end {
+            let (x, y) = positions[j];
+            lines.push(match edits[j] {
+                Edit::Same => format!(" {}", old[x]),
+                Edit::Delete => format!("-{}", old[x]),
+                Edit::Insert => format!("+{}", new[y]),
+            });
+        }
+        i = end;
+    }
+    lines
+}
+
+/// Returns the name of a section, as shown in the diff.
+fn section_name(section: &Section) -> &'static str {
+    if section.is_kf8() { "KF8" } else { "KF7" }
+}
+
+/// Compares two books.
+pub fn diff(old: &MobiBook, new: &MobiBook) -> BookDiff {
+    let palmdb = compare_fields(&old.palmdb, &new.palmdb, &["records"]);
+
+    let mut headers = Vec::new();
+    let mut exth = Vec::new();
+    let pairs = vec![(Some(&old.main), Some(&new.main)),
+        (old.kf8_section.as_ref(), new.kf8_section.as_ref())];
+    for pair in pairs {
+        let (header_name, exth_name, fields, tags) = match pair {
+            (Some(old), Some(new)) => {
+                let name = section_name(if old.is_kf8() { old } else { new });
+                (format!("{} MOBI header", name), format!("{} EXTH tags", name),
+                    compare_fields(&old.header, &new.header, &[]),
+                    compare_exth(&old.exth, &new.exth))
+            },
+            (Some(old), None) => {
+                (format!("{} MOBI header", section_name(old)),
+                    format!("{} EXTH tags", section_name(old)),
+                    vec![FieldChange { field: "section".to_string(),
+                        old: Some(format!("record {}", old.start)), new: None }],
+                    compare_exth(&old.exth, &[]))
+            },
+            (None, Some(new)) => {
+                (format!("{} MOBI header", section_name(new)),
+                    format!("{} EXTH tags", section_name(new)),
+                    vec![FieldChange { field: "section".to_string(),
+                        old: None, new: Some(format!("record {}", new.start)) }],
+                    compare_exth(&[], &new.exth))
+            },
+            (None, None) => continue,
+        };
+        headers.push((header_name, fields));
+        exth.push((exth_name, tags));
+    }
+
+    let (records, same_records) = compare_records(old, new);
+
+    let text = |book: &MobiBook| text_lines(&book.text(book.preferred_section())
+        .unwrap_or_else(|_| Vec::new()));
+    let text = unified_diff("a", "b", &text(old), &text(new));
+
+    BookDiff {
+        palmdb: palmdb,
+        headers: headers,
+        exth: exth,
+        records: records,
+        same_records: same_records,
+        text: text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exth_tags;
+    use testing;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(' ').map(String::from).collect()
+    }
+
+    /// Applies an edit script to the old list, taking insertions from the
+    /// new list.
+    fn apply(edits: &[Edit], old: &[String], new: &[String]) -> Vec<String> {
+        let (mut x, mut y) = (0, 0);
+        let mut result = Vec::new();
+        for &edit in edits {
+            match edit {
+                Edit::Same => {
+                    assert_eq!(old[x], new[y]);
+                    result.push(old[x].clone());
+                    x += 1;
+                    y += 1;
+                },
+                Edit::Delete => x += 1,
+                Edit::Insert => {
+                    result.push(new[y].clone());
+                    y += 1;
+                },
+            }
+        }
+        assert_eq!((x, y), (old.len(), new.len()));
+        result
+    }
+
+    #[test]
+    fn edit_scripts() {
+        let cases = [("a b c a b b a", "c b a b a c", 5), ("a b c", "a b c", 0), ("", "a b", 3),
+            ("a b", "", 3), ("x a b", "a b y", 2), ("a b c d", "d c b a", 6)];
+        for &(old, new, edit_count) in cases.iter() {
+            let (old, new) = (lines(old), lines(new));
+            let edits = edit_script(&old, &new, MAX_EDITS).unwrap();
+            assert_eq!(apply(&edits, &old, &new), new);
+            let count = edits.iter().filter(|&&edit| edit != Edit::Same).count();
+            assert_eq!(count, edit_count, "{:?} {:?}", old, new);
+        }
+        assert!(edit_script(&lines("a b c"), &lines("d e f"), 5).is_none());
+    }
+
+    #[test]
+    fn unified_diffs() {
+        let old = (1..31).map(|i| format!("line {}", i)).collect::<Vec<_>>();
+        let mut new = old.clone();
+        new[9] = "changed".to_string();
+        assert_eq!(unified_diff("a", "b", &old, &new), vec!["--- a", "+++ b",
+            "@@ -7,7 +7,7 @@", " line 7", " line 8", " line 9", "-line 10", "+changed",
+            " line 11", " line 12", " line 13"]);
+        assert!(unified_diff("a", "b", &old, &old).is_empty());
+
+        // Changes six lines apart share a hunk, and seven lines apart do not
+        new[16].push('!');
+        let diff = unified_diff("a", "b", &old, &new);
+        assert_eq!(diff.iter().filter(|line| line.starts_with("@@")).collect::<Vec<_>>(),
+            vec!["@@ -7,14 +7,14 @@"]);
+        new[16] = old[16].clone();
+        new[17].push('!');
+        let diff = unified_diff("a", "b", &old, &new);
+        assert_eq!(diff.iter().filter(|line| line.starts_with("@@")).collect::<Vec<_>>(),
+            vec!["@@ -7,7 +7,7 @@", "@@ -15,7 +15,7 @@"]);
+
+        let mut new = old.clone();
+        new.insert(0, "first".to_string());
+        new.push("last".to_string());
+        assert_eq!(unified_diff("a", "b", &old, &new), vec!["--- a", "+++ b",
+            "@@ -1,3 +1,4 @@", "+first", " line 1", " line 2", " line 3",
+            "@@ -28,3 +29,4 @@", " line 28", " line 29", " line 30", "+last"]);
+    }
+
+    #[test]
+    fn identical_books() {
+        let mut book = testing::kf8_book(&testing::linked_kf8_writer());
+        let mut data = Vec::new();
+        book.palmdb.write_with_records(&book.records, &mut data).unwrap();
+        let copy = MobiBook::from_bytes(&data).unwrap();
+        let diff = diff(&book, &copy);
+        assert!(diff.is_empty(), "{:?}", diff);
+        assert_eq!(diff.same_records, book.records.len());
+    }
+
+    #[test]
+    fn changed_books() {
+        let old = testing::kf7_book("<html><body><p>One</p><p>Two</p><p>Three</p></body></html>");
+        let mut new = testing::kf7_book("<html><body><p>One</p><p>2</p><p>Three</p></body></html>");
+        new.edit_sections(|section| {
+            exth_tags::set_tag(&mut section.exth, ExthTag::Publisher("Press".into()))
+        });
+        let diff = diff(&old, &new);
+        assert_eq!(diff.exth[0].1, vec![ExthChange::Added("Publisher".into(), "Press".into())]);
+        assert_eq!(diff.text, vec!["--- a", "+++ b", "@@ -1,5 +1,5 @@",
+            " <html><body><p>One</p>", "-<p>Two</p>", "+<p>2</p>", " <p>Three</p>",
+            " </body>", " </html>"]);
+        // Record 0, the text record and the FCIS record with the text length
+        assert_eq!(diff.records.iter().map(|change| (change.old_index, change.new_index))
+            .collect::<Vec<_>>(), vec![(Some(0), Some(0)), (Some(1), Some(1)), (Some(3), Some(3))]);
+        assert_eq!(diff.records[2].new.as_ref().unwrap().role, RecordRole::Fcis);
+
+        let mut changed = testing::kf7_book("<html><body><p>One</p></body></html>");
+        changed.edit_sections(|section| {
+            exth_tags::set_tag(&mut section.exth, ExthTag::Publisher("Other".into()))
+        });
+        let diff = super::diff(&new, &changed);
+        assert_eq!(diff.exth[0].1, vec![
+            ExthChange::Changed("Publisher".into(), "Press".into(), "Other".into())]);
+    }
+
+    #[test]
+    fn inserted_records() {
+        let old = testing::kf7_book("<html><body><p>Text</p></body></html>");
+        let mut new = testing::kf7_book("<html><body><p>Text</p></body></html>");
+        let position = new.main.record(new.main.header.text_record_count as u32 + 1);
+        new.insert_record(position, b"\x89PNGimage".to_vec());
+        let diff = diff(&old, &new);
+        let changes = diff.records.iter()
+            .map(|change| (change.old_index, change.new_index))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec![(Some(0), Some(0)), (None, Some(position))]);
+        assert_eq!(diff.records[1].new.as_ref().unwrap().size, 9);
+        assert_eq!(diff.same_records, old.records.len() - 1);
+        assert!(diff.text.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+//! Detection of DRM-protected books. Nothing here decrypts anything: the
+//! protection is only reported, and reading encrypted text is refused.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use exth_tags::{ExthTag, ExthType};
+use mobi::{EncryptionType, MobiHeader};
+
+/// The EXTH tags that belong to the DRM of a book.
+const DRM_TAGS: &'static [ExthType] = &[ExthType::DRMServerId, ExthType::DRMCommerveId,
+    ExthType::DRMEbookbaseBookId, ExthType::Watermark, ExthType::TamperProofKeys,
+    ExthType::ClippingLimit, ExthType::PublisherLimit];
+
+/// The error for reading the text or resources of an encrypted book. It is
+/// returned inside an `io::Error` of kind `PermissionDenied`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Encrypted {
+    pub scheme: EncryptionType,
+}
+
+impl fmt::Display for Encrypted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The book is encrypted with {} DRM", scheme_name(self.scheme))
+    }
+}
+
+impl Error for Encrypted {
+    fn description(&self) -> &str {
+        "The book is encrypted"
+    }
+}
+
+impl Encrypted {
+    /// Returns the `Encrypted` error inside an I/O error, if it is one.
+    pub fn from_io_error(error: &io::Error) -> Option<&Encrypted> {
+        error.get_ref().and_then(|error| error.downcast_ref::<Encrypted>())
+    }
+}
+
+/// Returns the name of an encryption scheme.
+pub fn scheme_name(scheme: EncryptionType) -> String {
+    match scheme {
+        EncryptionType::None => "no".to_string(),
+        EncryptionType::OldMobiPocket => "old MobiPocket".to_string(),
+        EncryptionType::MobiPocket => "MobiPocket".to_string(),
+        EncryptionType::Unknown(value) => format!("unknown ({})", value),
+    }
+}
+
+/// Returns whether the text of a section is encrypted.
+pub fn is_encrypted(header: &MobiHeader) -> bool {
+    header.encryption != EncryptionType::None
+}
+
+/// Returns an `Encrypted` error if the text of a section is encrypted.
+pub fn check(header: &MobiHeader) -> Result<(), io::Error> {
+    if is_encrypted(header) {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            Encrypted { scheme: header.encryption }))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the DRM tags of an EXTH header, with their names and values.
+/// Binary values are shown in hexadecimal.
+pub fn drm_tags(tags: &[ExthTag]) -> Vec<(&'static str, String)> {
+    tags.iter().filter_map(|tag| {
+        let tag_type = tag.tag_type();
+        if !DRM_TAGS.contains(&tag_type) {
+            return None;
+        }
+        let data = tag.data();
+        let printable = data.iter().all(|&byte| byte >= 0x20 && byte < 0x7F);
+        let value = if printable {
+            String::from_utf8_lossy(&data).into_owned()
+        } else {
+            data.iter().map(|byte| format!("{:02x}", byte)).collect()
+        };
+        Some((tag_type.name(), value))
+    }).collect()
+}
+
+/// The DRM status of a section, as shown by 'info' and 'validate'.
+#[derive(Debug, Clone, Serialize)]
+pub struct DrmStatus {
+    pub scheme: EncryptionType,
+    pub encrypted: bool,
+    /// The DRM tags of the EXTH header, with their names and values.
+    pub tags: Vec<(&'static str, String)>,
+}
+
+impl DrmStatus {
+    pub fn new(header: &MobiHeader, tags: &[ExthTag]) -> DrmStatus {
+        DrmStatus {
+            scheme: header.encryption,
+            encrypted: is_encrypted(header),
+            tags: drm_tags(tags),
+        }
+    }
+
+    pub fn print_info(&self) {
+        println!("===== DRM =====");
+        if self.encrypted {
+            println!("Encrypted with {} DRM", scheme_name(self.scheme));
+        } else {
+            println!("Not encrypted");
+        }
+        for &(name, ref value) in self.tags.iter() {
+            println!("- {}: {}", name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metadata::Metadata;
+    use testing;
+    use writer::Resource;
+
+    #[test]
+    fn encrypted_books_are_refused() {
+        let mut writer = testing::kf8_writer(&["<html><body><p>Text</p></body></html>"]);
+        writer.fonts.push(Resource { name: "f.ttf".into(), data: b"\0\x01\0\0font".to_vec() });
+        writer.joint = true;
+        let mut book = testing::kf8_book(&writer);
+        assert_eq!(book.fonts().unwrap().len(), 1);
+
+        book.edit_sections(|section| section.header.encryption = EncryptionType::MobiPocket);
+        let cover = book.set_cover(b"\xFF\xD8\xFF\xE0".to_vec()).err();
+        let metadata = book.edit_metadata(&Metadata::new("Edited")).err();
+        let errors = vec![book.text(&book.main).err(), book.resources().err(),
+            book.fonts().err(), book.to_plain_text().err(), cover, metadata];
+        for error in errors {
+            let scheme = Encrypted::from_io_error(&error.unwrap()).map(|error| error.scheme);
+            assert_eq!(scheme, Some(EncryptionType::MobiPocket));
+        }
+        assert_eq!(book.resource_count(), 1);
+    }
+}
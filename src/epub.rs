@@ -0,0 +1,1196 @@
+//! EPUB books: conversion of MOBI books to EPUB 3, with an NCX table of
+//! contents for EPUB 2 readers, and reading of EPUB 2 and 3 books to convert
+//! them with the KF8 writer.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::Path;
+use chrono::UTC;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use book::{MobiBook, NcxEntry, ResourceKind, Section};
+use common::*;
+use drm;
+use exth_tags::ExthTag;
+use html;
+use html::{Tag, Token, Tokenizer};
+use kf7;
+use kf8;
+use kf8_reader;
+use kf8_writer::{GuideEntry, Kf8Writer, TocEntry};
+use metadata::Metadata;
+use writer::Resource;
+
+/// The directory holding the package document and the content.
+const ROOT: &'static str = "OEBPS";
+
+/// An EPUB book. Files are named by their path relative to the package
+/// document, and so are the references of the table of contents and guide.
+#[derive(Debug, Clone)]
+pub struct Epub {
+    pub metadata: Metadata,
+    /// The unique identifier of the book.
+    pub identifier: String,
+    /// The XHTML documents, in reading order.
+    pub documents: Vec<Resource>,
+    pub stylesheets: Vec<Resource>,
+    pub images: Vec<Resource>,
+    pub fonts: Vec<Resource>,
+    /// The index of the cover in 'images'.
+    pub cover: Option<usize>,
+    pub toc: Vec<TocEntry>,
+    pub guide: Vec<GuideEntry>,
+}
+
+impl Epub {
+    pub fn new(metadata: Metadata) -> Epub {
+        let identifier = book_uuid(&metadata);
+        Epub {
+            metadata: metadata,
+            identifier: identifier,
+            documents: Vec::new(),
+            stylesheets: Vec::new(),
+            images: Vec::new(),
+            fonts: Vec::new(),
+            cover: None,
+            toc: Vec::new(),
+            guide: Vec::new(),
+        }
+    }
+
+    /// Converts a MOBI book, using its KF8 version when it has one.
+    pub fn from_book(book: &MobiBook) -> Result<Epub, io::Error> {
+        try!(book.check_html());
+        for section in book.sections() {
+            try!(drm::check(&section.header));
+        }
+        let mut epub = Epub::new(book.metadata());
+        let resources = try!(epub.add_resources(book));
+        match book.kf8() {
+            Some(section) => try!(epub.add_kf8_text(book, section, &resources)),
+            None => try!(epub.add_kf7_text(book, &book.main, &resources)),
+        }
+        Ok(epub)
+    }
+
+    /// Opens the EPUB book at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Epub, io::Error> {
+        let file = try!(File::open(path));
+        Epub::read_from(BufReader::new(file))
+    }
+
+    /// Reads an EPUB book: the package document named by the container, the
+    /// files of its manifest and spine, and the table of contents of its
+    /// navigation document or NCX.
+    pub fn read_from<R: Read + Seek>(source: R) -> Result<Epub, io::Error> {
+        let mut zip = try!(ZipArchive::new(source));
+        let container = try!(read_zip_file(&mut zip, "META-INF/container.xml"));
+        let container = read_elements(&container);
+        let opf_path = match container.iter().find(|element| element.name == "rootfile")
+                .and_then(|element| element.attribute("full-path")) {
+            Some(path) => path,
+            None => return Err(invalid("The container does not name a package document")),
+        };
+        let opf = read_elements(&try!(read_zip_file(&mut zip, &opf_path)));
+        let opf_name = opf_path.rsplit('/').next().unwrap_or("").to_string();
+
+        let (metadata, identifier) = read_package_metadata(&opf);
+        let mut epub = Epub::new(metadata);
+        if let Some(identifier) = identifier {
+            epub.identifier = identifier;
+        }
+
+        // The manifest, by id: name, media type and properties
+        let mut manifest = HashMap::new();
+        let mut cover_id = opf.iter()
+            .find(|element| element.name == "meta"
+                && element.attribute("name").map_or(false, |name| name == "cover"))
+            .and_then(|element| element.attribute("content"));
+        let mut nav = None;
+        let mut ncx = None;
+        for item in opf.iter().filter(|element| element.name == "item") {
+            let (id, href) = match (item.attribute("id"), item.attribute("href")) {
+                (Some(id), Some(href)) => (id, href),
+                _ => continue,
+            };
+            let name = html::resolve_href(&opf_name, &href);
+            let media_type = item.attribute("media-type").unwrap_or_default();
+            let properties = item.attribute("properties").unwrap_or_default();
+            let properties = properties.split_whitespace().collect::<Vec<_>>();
+            if properties.contains(&"cover-image") {
+                cover_id = Some(id.clone());
+            }
+            if properties.contains(&"nav") {
+                nav = Some(name.clone());
+            }
+            if media_type == "application/x-dtbncx+xml" {
+                ncx = Some(name.clone());
+            }
+            manifest.insert(id, (name, media_type));
+        }
+        let mut read = |name: &str| {
+            read_zip_file(&mut zip, &percent_decode(&html::resolve_href(&opf_path, name)))
+        };
+
+        let spine = opf.iter()
+            .filter(|element| element.name == "itemref")
+            .filter_map(|element| element.attribute("idref"))
+            .filter_map(|idref| manifest.get(&idref))
+            .filter(|&&(_, ref media_type)| {
+                media_type == "application/xhtml+xml" || media_type == "text/html"
+            })
+            .map(|&(ref name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in spine {
+            let data = try!(read(&name));
+            epub.documents.push(Resource { name: name, data: data });
+        }
+
+        let mut ids = manifest.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            let (ref name, ref media_type) = manifest[&id];
+            let is_font = media_type.contains("font") || media_type == "application/vnd.ms-opentype"
+                || name.ends_with(".ttf") || name.ends_with(".otf");
+            if media_type == "text/css" {
+                let data = try!(read(name));
+                epub.stylesheets.push(Resource { name: name.clone(), data: data });
+            } else if media_type.starts_with("image/") {
+                if cover_id.as_ref() == Some(&id) {
+                    epub.cover = Some(epub.images.len());
+                }
+                let data = try!(read(name));
+                epub.images.push(Resource { name: name.clone(), data: data });
+            } else if is_font {
+                let data = try!(read(name));
+                epub.fonts.push(Resource { name: name.clone(), data: data });
+            }
+        }
+
+        // The EPUB 3 navigation document comes first, the NCX is a fallback
+        let mut landmarks = Vec::new();
+        if let Some(ref nav) = nav {
+            let elements = read_elements(&try!(read(nav)));
+            epub.toc = read_nav_list(&elements, nav, "toc");
+            landmarks = read_landmarks(&elements, nav);
+        }
+        if epub.toc.is_empty() {
+            if let Some(ref ncx) = ncx {
+                epub.toc = read_ncx(&read_elements(&try!(read(ncx))), ncx);
+            }
+        }
+        epub.guide = opf.iter().filter(|element| element.name == "reference").filter_map(|element| {
+            match (element.attribute("type"), element.attribute("href")) {
+                (Some(kind), Some(href)) => Some(GuideEntry {
+                    title: element.attribute("title").unwrap_or_else(|| kind.clone()),
+                    kind: kind,
+                    href: resolve_link(&opf_name, &href),
+                }),
+                _ => None,
+            }
+        }).collect();
+        if epub.guide.is_empty() {
+            epub.guide = landmarks;
+        }
+        Ok(epub)
+    }
+
+    /// Prepares the book for the KF8 writer. Images that cannot be stored as
+    /// resources (SVG files) are left out.
+    pub fn to_kf8(&self) -> Kf8Writer {
+        let mut writer = Kf8Writer::new(self.metadata.clone());
+        writer.parts = self.documents.clone();
+        writer.stylesheets = self.stylesheets.clone();
+        for (index, image) in self.images.iter().enumerate() {
+            let mime = kf8::mime_type(&image.name);
+            if !mime.starts_with("image/") || mime == "image/svg+xml" {
+                continue;
+            }
+            if Some(index) == self.cover {
+                writer.cover = Some(writer.images.len());
+            }
+            writer.images.push(image.clone());
+        }
+        writer.fonts = self.fonts.clone();
+        writer.toc = self.toc.clone();
+        writer.guide = self.guide.clone();
+        writer
+    }
+
+    /// Adds the images and fonts of a book, and returns their names by
+    /// resource index.
+    fn add_resources(&mut self, book: &MobiBook) -> Result<HashMap<usize, String>, io::Error> {
+        let mut names = HashMap::new();
+        for (index, kind, data) in try!(book.resources()) {
+            match kind {
+                ResourceKind::Image(mime) => {
+                    let name = kf7::image_name(index, mime);
+                    names.insert(index, name.clone());
+                    self.images.push(Resource { name: name, data: data.to_vec() });
+                },
+                ResourceKind::Font => {
+                    if let Some(font) = kf8::read_font_record(data) {
+                        let name = format!("fonts/font{:05}.{}", index + 1,
+                            kf8::font_extension(&font));
+                        names.insert(index, name.clone());
+                        self.fonts.push(Resource { name: name, data: font });
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        let cover = book.main.exth.iter().filter_map(|tag| match *tag {
+            ExthTag::CoverOffset(offset) => Some(offset as usize),
+            _ => None,
+        }).next();
+        self.cover = cover
+            .and_then(|cover| names.get(&cover))
+            .and_then(|name| self.images.iter().position(|image| image.name == *name));
+        Ok(names)
+    }
+
+    /// Adds the text of a KF7 section as a single document. Anchors are
+    /// inserted where filepos links and the NCX point to.
+    fn add_kf7_text(&mut self, book: &MobiBook, section: &Section,
+            resources: &HashMap<usize, String>) -> Result<(), io::Error> {
+        const NAME: &'static str = "text/part0000.xhtml";
+        let text = try!(book.text(section));
+        let ncx = try!(book.ncx(section));
+        let targets = ncx.iter().map(|entry| entry.offset as usize).collect::<Vec<_>>();
+        let resolved = kf7::resolve(&text, section.header.text_encoding, &targets, resources, NAME);
+
+        for &(ref kind, ref title, position) in resolved.guide.iter() {
+            self.guide.push(GuideEntry {
+                kind: kind.clone(),
+                title: title.clone(),
+                href: format!("{}#{}", NAME, kf7::anchor_id(position)),
+            });
+        }
+        let document = xhtml_document(&self.metadata.title, &html::to_xhtml(&resolved.body));
+        self.documents.push(Resource { name: NAME.to_string(), data: document });
+
+        let hrefs = ncx.iter()
+            .map(|entry| format!("{}#{}", NAME, kf7::anchor_id(entry.offset as usize)))
+            .collect::<Vec<_>>();
+        self.toc = toc_from_ncx(&ncx, &hrefs);
+        Ok(())
+    }
+
+    /// Adds the documents and flows of a KF8 section, with the `kindle:`
+    /// references replaced by relative links.
+    fn add_kf8_text(&mut self, book: &MobiBook, section: &Section,
+            resources: &HashMap<usize, String>) -> Result<(), io::Error> {
+        let text = try!(kf8_reader::read_text(book, section));
+        let ncx = try!(book.ncx(section));
+        let guide = try!(read_guide(book, section));
+        let part_names = text.parts.iter()
+            .map(|part| format!("text/part{:04}.xhtml", part.file_number))
+            .collect::<Vec<_>>();
+
+        let flow_names = text.flow_names();
+
+        // Every position that is linked to gets an id
+        let mut positions = Vec::new();
+        for part in text.parts.iter() {
+            let mut pos = 0;
+            while let Some(start) = html::find_bytes(&part.data[pos..], b"kindle:pos:fid:") {
+                let uri = String::from_utf8_lossy(&part.data[pos + start..]
+                    [..::std::cmp::min(40, part.data.len() - pos - start)]).into_owned();
+                let position = kf8::parse_pos_uri(&uri)
+                    .and_then(|(fid, offset)| text.position(fid, offset));
+                if let Some(position) = position {
+                    positions.push(position);
+                }
+                pos += start + 15;
+            }
+        }
+        let ncx_positions = ncx.iter().map(|entry| {
+            entry.pos_fid
+                .and_then(|(fid, offset)| text.position(fid, offset))
+                .unwrap_or(entry.offset)
+        }).collect::<Vec<_>>();
+        let guide_positions = guide.iter()
+            .map(|&(_, _, fid, offset)| text.position(fid, offset).unwrap_or(0))
+            .collect::<Vec<_>>();
+        positions.extend(ncx_positions.iter().cloned());
+        positions.extend(guide_positions.iter().cloned());
+
+        let mut targets = vec![Vec::new(); text.parts.len()];
+        for &position in positions.iter() {
+            if let Some((part, offset)) = text.locate(position) {
+                targets[part].push(offset);
+            }
+        }
+        let ids = text.parts.iter().zip(targets.iter_mut())
+            .map(|(part, offsets)| assign_ids(&part.data, offsets))
+            .collect::<Vec<_>>();
+
+        let target_href = |base: &str, position: u32| -> Option<String> {
+            text.locate(position).map(|(part, offset)| {
+                let path = if base.is_empty() {
+                    part_names[part].clone()
+                } else {
+                    html::relative_href(base, &part_names[part])
+                };
+                match ids[part].0.get(&offset) {
+                    Some(&Some(ref id)) => format!("{}#{}", path, id),
+                    _ => path,
+                }
+            })
+        };
+        let resolve = |base: &str, uri: &str| -> Option<String> {
+            if uri.starts_with("kindle:pos:") {
+                kf8::parse_pos_uri(uri)
+                    .and_then(|(fid, offset)| text.position(fid, offset))
+                    .and_then(|position| target_href(base, position))
+            } else if uri.starts_with("kindle:embed:") {
+                kf8::parse_uri_number(uri, "embed")
+                    .and_then(|number| resources.get(&(number as usize).saturating_sub(1)))
+                    .map(|name| html::relative_href(base, name))
+            } else if uri.starts_with("kindle:flow:") {
+                kf8_reader::resolve_flow_uri(uri, base, &flow_names)
+            } else {
+                None
+            }
+        };
+
+        for (index, part) in text.parts.iter().enumerate() {
+            let name = &part_names[index];
+            let tagged = set_ids(&part.data, &ids[index].1);
+            let data = kf8::replace_uris(&tagged, &mut |uri| resolve(name, uri));
+            self.documents.push(Resource { name: name.clone(), data: data });
+        }
+        for (number, flow) in text.flows.iter().enumerate().skip(1) {
+            let name = &flow_names[&number];
+            let data = kf8::replace_uris(flow, &mut |uri| resolve(name, uri));
+            let resource = Resource { name: name.clone(), data: data };
+            if name.ends_with(".css") {
+                self.stylesheets.push(resource);
+            } else {
+                self.images.push(resource);
+            }
+        }
+
+        let hrefs = ncx_positions.iter()
+            .map(|&position| target_href("", position).unwrap_or_else(|| part_names[0].clone()))
+            .collect::<Vec<_>>();
+        self.toc = toc_from_ncx(&ncx, &hrefs);
+        for (&(ref kind, ref title, _, _), &position) in guide.iter().zip(guide_positions.iter()) {
+            if let Some(href) = target_href("", position) {
+                self.guide.push(GuideEntry { kind: kind.clone(), title: title.clone(), href: href });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the book as an EPUB (zip) file.
+    pub fn write_to<W: Write + Seek>(&self, output: W) -> Result<(), io::Error> {
+        let mut zip = ZipWriter::new(output);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        // The mimetype comes first, uncompressed
+        try!(zip.start_file("mimetype", stored));
+        try!(zip.write_all(b"application/epub+zip"));
+
+        try!(zip.start_file("META-INF/container.xml", deflated));
+        try!(zip.write_all(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+            <rootfiles>\n\
+            <rootfile full-path=\"{}/content.opf\" media-type=\"application/oebps-package+xml\"/>\n\
+            </rootfiles>\n\
+            </container>\n", ROOT).as_bytes()));
+
+        try!(zip.start_file(format!("{}/content.opf", ROOT), deflated));
+        try!(zip.write_all(self.package_document().as_bytes()));
+        try!(zip.start_file(format!("{}/nav.xhtml", ROOT), deflated));
+        try!(zip.write_all(self.navigation_document().as_bytes()));
+        try!(zip.start_file(format!("{}/toc.ncx", ROOT), deflated));
+        try!(zip.write_all(self.ncx_document().as_bytes()));
+
+        let files = self.documents.iter()
+            .chain(self.stylesheets.iter())
+            .chain(self.images.iter())
+            .chain(self.fonts.iter());
+        for file in files {
+            // Names are hrefs, which may be percent-encoded
+            try!(zip.start_file(format!("{}/{}", ROOT, percent_decode(&file.name)), deflated));
+            try!(zip.write_all(&file.data));
+        }
+        try!(zip.finish());
+        Ok(())
+    }
+
+    /// Returns the language of the book, which EPUB requires.
+    fn language(&self) -> &str {
+        self.metadata.language.as_ref().map_or("und", |language| &language[..])
+    }
+
+    /// Returns the table of contents, or a single entry for the start of the
+    /// book if it has none.
+    fn toc_or_start(&self) -> Vec<TocEntry> {
+        if !self.toc.is_empty() {
+            return self.toc.clone();
+        }
+        self.documents.first().map(|document| TocEntry {
+            title: self.metadata.title.clone(),
+            href: document.name.clone(),
+            children: Vec::new(),
+        }).into_iter().collect()
+    }
+
+    fn package_document(&self) -> String {
+        let metadata = &self.metadata;
+        let mut opf = String::new();
+        opf.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        opf.push_str("<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" \
+            unique-identifier=\"book-id\">\n");
+        opf.push_str("<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+        opf.push_str(&format!("<dc:identifier id=\"book-id\">{}</dc:identifier>\n",
+            html::escape(&self.identifier)));
+        if let Some(ref isbn) = metadata.isbn {
+            opf.push_str(&format!("<dc:identifier>urn:isbn:{}</dc:identifier>\n", html::escape(isbn)));
+        }
+        opf.push_str(&format!("<dc:title>{}</dc:title>\n", html::escape(&metadata.title)));
+        opf.push_str(&format!("<dc:language>{}</dc:language>\n", html::escape(self.language())));
+        for author in metadata.authors.iter() {
+            opf.push_str(&format!("<dc:creator>{}</dc:creator>\n", html::escape(author)));
+        }
+        for contributor in metadata.contributors.iter() {
+            opf.push_str(&format!("<dc:contributor>{}</dc:contributor>\n", html::escape(contributor)));
+        }
+        let optional = [("publisher", &metadata.publisher), ("description", &metadata.description),
+            ("date", &metadata.published), ("rights", &metadata.rights)];
+        for &(element, value) in optional.iter() {
+            if let Some(ref value) = *value {
+                opf.push_str(&format!("<dc:{0}>{1}</dc:{0}>\n", element, html::escape(value)));
+            }
+        }
+        for subject in metadata.subjects.iter() {
+            opf.push_str(&format!("<dc:subject>{}</dc:subject>\n", html::escape(subject)));
+        }
+        opf.push_str(&format!("<meta property=\"dcterms:modified\">{}</meta>\n",
+            UTC::now().format("%Y-%m-%dT%H:%M:%SZ")));
+        if self.cover.is_some() {
+            opf.push_str("<meta name=\"cover\" content=\"cover-image\"/>\n");
+        }
+        opf.push_str("</metadata>\n<manifest>\n");
+
+        opf.push_str("<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" \
+            properties=\"nav\"/>\n");
+        opf.push_str("<item id=\"ncx\" href=\"toc.ncx\" media-type=\"application/x-dtbncx+xml\"/>\n");
+        let item = |id: &str, resource: &Resource, properties: &str| {
+            format!("<item id=\"{}\" href=\"{}\" media-type=\"{}\"{}/>\n", id,
+                html::escape(&resource.name), kf8::mime_type(&resource.name), properties)
+        };
+        for (i, document) in self.documents.iter().enumerate() {
+            let svg = html::find_bytes(&document.data, b"<svg").is_some();
+            opf.push_str(&item(&format!("text{:04}", i), document,
+                if svg { " properties=\"svg\"" } else { "" }));
+        }
+        for (i, stylesheet) in self.stylesheets.iter().enumerate() {
+            opf.push_str(&item(&format!("style{:04}", i), stylesheet, ""));
+        }
+        for (i, image) in self.images.iter().enumerate() {
+            if Some(i) == self.cover {
+                opf.push_str(&item("cover-image", image, " properties=\"cover-image\""));
+            } else {
+                opf.push_str(&item(&format!("image{:04}", i), image, ""));
+            }
+        }
+        for (i, font) in self.fonts.iter().enumerate() {
+            opf.push_str(&item(&format!("font{:04}", i), font, ""));
+        }
+        opf.push_str("</manifest>\n<spine toc=\"ncx\">\n");
+        for i in 0..self.documents.len() {
+            opf.push_str(&format!("<itemref idref=\"text{:04}\"/>\n", i));
+        }
+        opf.push_str("</spine>\n");
+
+        if !self.guide.is_empty() {
+            opf.push_str("<guide>\n");
+            for reference in self.guide.iter() {
+                opf.push_str(&format!("<reference type=\"{}\" title=\"{}\" href=\"{}\"/>\n",
+                    html::escape(&reference.kind), html::escape(&reference.title),
+                    html::escape(&reference.href)));
+            }
+            opf.push_str("</guide>\n");
+        }
+        opf.push_str("</package>\n");
+        opf
+    }
+
+    fn navigation_document(&self) -> String {
+        fn write_entries(entries: &[TocEntry], nav: &mut String) {
+            nav.push_str("<ol>\n");
+            for entry in entries.iter() {
+                nav.push_str(&format!("<li><a href=\"{}\">{}</a>", html::escape(&entry.href),
+                    html::escape(&entry.title)));
+                if !entry.children.is_empty() {
+                    nav.push('\n');
+                    write_entries(&entry.children, nav);
+                }
+                nav.push_str("</li>\n");
+            }
+            nav.push_str("</ol>\n");
+        }
+
+        let mut nav = String::new();
+        nav.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n");
+        nav.push_str(&format!("<html xmlns=\"http://www.w3.org/1999/xhtml\" \
+            xmlns:epub=\"http://www.idpf.org/2007/ops\" lang=\"{0}\" xml:lang=\"{0}\">\n",
+            html::escape(self.language())));
+        nav.push_str(&format!("<head>\n<title>{}</title>\n</head>\n<body>\n",
+            html::escape(&self.metadata.title)));
+        nav.push_str("<nav epub:type=\"toc\" id=\"toc\">\n");
+        nav.push_str(&format!("<h1>{}</h1>\n", html::escape(&self.metadata.title)));
+        write_entries(&self.toc_or_start(), &mut nav);
+        nav.push_str("</nav>\n");
+
+        let landmarks = self.guide.iter().filter_map(|reference| {
+            let kind = match &reference.kind[..] {
+                "cover" => "cover",
+                "toc" => "toc",
+                "text" | "start" => "bodymatter",
+                _ => return None,
+            };
+            Some(format!("<li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n", kind,
+                html::escape(&reference.href), html::escape(&reference.title)))
+        }).collect::<Vec<_>>();
+        if !landmarks.is_empty() {
+            nav.push_str("<nav epub:type=\"landmarks\" hidden=\"hidden\">\n<ol>\n");
+            for landmark in landmarks {
+                nav.push_str(&landmark);
+            }
+            nav.push_str("</ol>\n</nav>\n");
+        }
+        nav.push_str("</body>\n</html>\n");
+        nav
+    }
+
+    fn ncx_document(&self) -> String {
+        fn write_points(entries: &[TocEntry], play_order: &mut usize, ncx: &mut String) -> usize {
+            let mut depth = 0;
+            for entry in entries.iter() {
+                *play_order += 1;
+                ncx.push_str(&format!("<navPoint id=\"navpoint-{0}\" playOrder=\"{0}\">\n\
+                    <navLabel><text>{1}</text></navLabel>\n<content src=\"{2}\"/>\n",
+                    play_order, html::escape(&entry.title), html::escape(&entry.href)));
+                depth = ::std::cmp::max(depth, 1 + write_points(&entry.children, play_order, ncx));
+                ncx.push_str("</navPoint>\n");
+            }
+            depth
+        }
+
+        let mut points = String::new();
+        let depth = write_points(&self.toc_or_start(), &mut 0, &mut points);
+        let mut ncx = String::new();
+        ncx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        ncx.push_str("<ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n");
+        ncx.push_str(&format!("<head>\n<meta name=\"dtb:uid\" content=\"{}\"/>\n\
+            <meta name=\"dtb:depth\" content=\"{}\"/>\n\
+            <meta name=\"dtb:totalPageCount\" content=\"0\"/>\n\
+            <meta name=\"dtb:maxPageNumber\" content=\"0\"/>\n</head>\n",
+            html::escape(&self.identifier), depth));
+        ncx.push_str(&format!("<docTitle><text>{}</text></docTitle>\n",
+            html::escape(&self.metadata.title)));
+        ncx.push_str("<navMap>\n");
+        ncx.push_str(&points);
+        ncx.push_str("</navMap>\n</ncx>\n");
+        ncx
+    }
+}
+
+/// Creates a UUID URN for a book from its title and authors.
+fn book_uuid(metadata: &Metadata) -> String {
+    let mut hashes = [0u64; 2];
+    for (salt, hash) in hashes.iter_mut().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        salt.hash(&mut hasher);
+        metadata.title.hash(&mut hasher);
+        metadata.authors.hash(&mut hasher);
+        *hash = hasher.finish();
+    }
+    let hex = format!("{:016x}{:016x}", hashes[0], hashes[1]);
+    // A version 4 (random) UUID, as far as readers can tell
+    format!("urn:uuid:{}-{}-4{}-8{}-{}", &hex[0..8], &hex[8..12], &hex[13..16],
+        &hex[17..20], &hex[20..32])
+}
+
+/// Wraps the body content of a document in an XHTML document.
+fn xhtml_document(title: &str, body: &[u8]) -> Vec<u8> {
+    let mut document = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n\
+        <html xmlns=\"http://www.w3.org/1999/xhtml\">\n<head>\n<title>{}</title>\n</head>\n\
+        <body>\n", html::escape(title)).into_bytes();
+    document.extend_from_slice(body);
+    document.extend_from_slice(b"\n</body>\n</html>\n");
+    document
+}
+
+/// Builds the tree of the table of contents from the NCX entries, with the
+/// given link of every entry.
+fn toc_from_ncx(entries: &[NcxEntry], hrefs: &[String]) -> Vec<TocEntry> {
+    // Children always come after their parents, which rules out cycles
+    fn children(entries: &[NcxEntry], hrefs: &[String], indices: &[usize], after: usize)
+            -> Vec<TocEntry> {
+        indices.iter()
+            .filter(|&&i| i >= after && i < entries.len())
+            .map(|&i| TocEntry {
+                title: entries[i].title.clone(),
+                href: hrefs[i].clone(),
+                children: children(entries, hrefs, &entries[i].children, i + 1),
+            })
+            .collect()
+    }
+    let roots = (0..entries.len())
+        .filter(|&i| entries[i].parent.is_none())
+        .collect::<Vec<_>>();
+    children(entries, hrefs, &roots, 0)
+}
+
+/// Reads the guide index of a KF8 section: the type, title, fragment and
+/// offset of every reference.
+fn read_guide(book: &MobiBook, section: &Section)
+        -> Result<Vec<(String, String, u32, u32)>, io::Error> {
+    let record = match section.header.kf8.as_ref().and_then(|kf8| kf8.guide_index) {
+        Some(record) => record,
+        None => return Ok(Vec::new()),
+    };
+    let index = try!(book.index(section, record));
+    Ok(index.entries.iter().filter_map(|entry| {
+        let kind = String::from_utf8_lossy(&entry.label).into_owned();
+        let title = index.cncx_string(entry, 1).unwrap_or(&kind).to_string();
+        entry.values(6)
+            .and_then(|values| if values.len() >= 2 { Some((values[0], values[1])) } else { None })
+            .map(|(fid, offset)| (kind.clone(), title, fid, offset))
+    }).collect())
+}
+
+/// Finds the tag that each target offset of a document points to, which is
+/// the first start tag at or after it. Returns the id of every target
+/// (None for the document itself) and the ids to add, by tag offset.
+fn assign_ids(data: &[u8], targets: &mut Vec<usize>)
+        -> (HashMap<usize, Option<String>>, HashMap<usize, String>) {
+    targets.sort();
+    targets.dedup();
+    let mut ids = HashMap::new();
+    let mut new_ids = HashMap::new();
+    let mut next = 0;
+    for (range, token) in Tokenizer::new(data) {
+        if next >= targets.len() {
+            break;
+        }
+        let tag = match token {
+            Token::StartTag(tag) => tag,
+            _ => continue,
+        };
+        if targets[next] > range.start {
+            continue;
+        }
+        let id = match &tag.name[..] {
+            "html" | "head" | "body" => None,
+            _ => Some(tag.attribute("id").map(String::from).unwrap_or_else(|| {
+                let id = format!("pos{}", range.start);
+                new_ids.insert(range.start, id.clone());
+                id
+            })),
+        };
+        while next < targets.len() && targets[next] <= range.start {
+            ids.insert(targets[next], id.clone());
+            next += 1;
+        }
+    }
+    for &target in targets[next..].iter() {
+        ids.insert(target, None);
+    }
+    (ids, new_ids)
+}
+
+/// Adds the given ids to the tags at their offsets, and removes the KF8
+/// 'aid' attributes.
+fn set_ids(data: &[u8], ids: &HashMap<usize, String>) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    for (range, token) in Tokenizer::new(data) {
+        match token {
+            Token::StartTag(mut tag) => {
+                let removed = tag.remove_attribute("aid").is_some();
+                let id = ids.get(&range.start);
+                if let Some(id) = id {
+                    tag.set_attribute("id", id);
+                }
+                if removed || id.is_some() {
+                    output.extend_from_slice(tag.to_html().as_bytes());
+                } else {
+                    output.extend_from_slice(&data[range]);
+                }
+            },
+            _ => output.extend_from_slice(&data[range]),
+        }
+    }
+    output
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads a file of an EPUB archive.
+fn read_zip_file<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str)
+        -> Result<Vec<u8>, io::Error> {
+    let mut file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => {
+            return Err(invalid(&format!("The book has no file '{}'", name)));
+        },
+        Err(error) => return Err(error.into()),
+    };
+    let mut data = Vec::new();
+    try!(file.read_to_end(&mut data));
+    Ok(data)
+}
+
+/// Decodes the %XX escapes of a link.
+fn percent_decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                output.push(byte);
+                i += 3;
+            },
+            None => {
+                output.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Resolves a link of a document against the name of the document, keeping
+/// its fragment.
+fn resolve_link(base: &str, href: &str) -> String {
+    let (path, fragment) = html::split_fragment(href);
+    let path = if path.is_empty() { base.to_string() } else { html::resolve_href(base, path) };
+    match fragment {
+        Some(fragment) => format!("{}#{}", path, fragment),
+        None => path,
+    }
+}
+
+/// An element of an XML document, with its text content.
+struct Element {
+    /// The name without its namespace prefix.
+    name: String,
+    tag: Tag,
+    text: String,
+    parent: Option<usize>,
+}
+
+impl Element {
+    /// Returns the decoded value of an attribute.
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.tag.attribute(name).map(|value| html::unescape(value.as_bytes()))
+    }
+}
+
+/// Reads the elements of an XML document, in document order.
+fn read_elements(data: &[u8]) -> Vec<Element> {
+    let mut elements: Vec<Element> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    for (_, token) in Tokenizer::new(data) {
+        match token {
+            Token::StartTag(tag) => {
+                let name = tag.name.rsplit(':').next().unwrap_or("").to_string();
+                let self_closing = tag.self_closing;
+                elements.push(Element {
+                    name: name,
+                    tag: tag,
+                    text: String::new(),
+                    parent: open.last().cloned(),
+                });
+                if !self_closing {
+                    open.push(elements.len() - 1);
+                }
+            },
+            Token::EndTag(name) => {
+                let position = open.iter().rposition(|&i| elements[i].tag.name == name);
+                if let Some(position) = position {
+                    open.truncate(position);
+                }
+            },
+            Token::Text(text) => {
+                let text = html::unescape(text);
+                for &i in open.iter() {
+                    elements[i].text.push_str(&text);
+                }
+            },
+            Token::Declaration(declaration) if declaration.starts_with(b"<![CDATA[") => {
+                // Unterminated sections run to the end of the data
+                let content = &declaration[9..];
+                let content = if content.ends_with(b"]]>") {
+                    &content[..content.len() - 3]
+                } else {
+                    content
+                };
+                let text = String::from_utf8_lossy(content);
+                for &i in open.iter() {
+                    elements[i].text.push_str(&text);
+                }
+            },
+            _ => {},
+        }
+    }
+    elements
+}
+
+/// Returns the nearest ancestor of an element with the given name.
+fn ancestor(elements: &[Element], index: usize, name: &str) -> Option<usize> {
+    let mut current = elements[index].parent;
+    while let Some(i) = current {
+        if elements[i].name == name {
+            return Some(i);
+        }
+        current = elements[i].parent;
+    }
+    None
+}
+
+/// Returns whether an element is inside another one.
+fn is_inside(elements: &[Element], index: usize, outer: usize) -> bool {
+    let mut current = elements[index].parent;
+    while let Some(i) = current {
+        if i == outer {
+            return true;
+        }
+        current = elements[i].parent;
+    }
+    false
+}
+
+/// Returns the first element inside another one with the given name.
+fn descendant(elements: &[Element], outer: usize, name: &str) -> Option<usize> {
+    (outer + 1..elements.len())
+        .take_while(|&i| is_inside(elements, i, outer))
+        .find(|&i| elements[i].name == name)
+}
+
+/// Collects the metadata of a package document, and its unique identifier.
+fn read_package_metadata(opf: &[Element]) -> (Metadata, Option<String>) {
+    let mut metadata = Metadata::new("");
+    let unique_id = opf.iter()
+        .find(|element| element.name == "package")
+        .and_then(|element| element.attribute("unique-identifier"));
+    let mut identifier = None;
+
+    // EPUB 3 gives the roles of the creators in separate elements
+    let mut roles = HashMap::new();
+    for element in opf.iter().filter(|element| element.name == "meta") {
+        let refines = element.attribute("refines");
+        if let (Some(refines), Some("role")) = (refines, element.attribute("property").as_ref()
+                .map(|property| &property[..])) {
+            roles.insert(refines.trim_left_matches('#').to_string(), element.text.trim().to_string());
+        }
+    }
+
+    for (index, element) in opf.iter().enumerate() {
+        if !element.tag.name.starts_with("dc:") || ancestor(opf, index, "metadata").is_none() {
+            continue;
+        }
+        let text = element.text.trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        let id = element.attribute("id");
+        match &element.name[..] {
+            "title" if metadata.title.is_empty() => metadata.title = text,
+            "creator" => {
+                let role = element.attribute("opf:role")
+                    .or_else(|| id.as_ref().and_then(|id| roles.get(id).cloned()));
+                match role {
+                    Some(ref role) if role != "aut" => metadata.contributors.push(text),
+                    _ => metadata.authors.push(text),
+                }
+            },
+            "contributor" => metadata.contributors.push(text),
+            "publisher" => metadata.publisher = Some(text),
+            "description" => metadata.description = Some(text),
+            "subject" => metadata.subjects.push(text),
+            "date" if metadata.published.is_none() => metadata.published = Some(text),
+            "rights" => metadata.rights = Some(text),
+            "language" if metadata.language.is_none() => metadata.language = Some(text),
+            "identifier" => {
+                let scheme = element.attribute("opf:scheme").unwrap_or_default().to_lowercase();
+                let lowercase = text.to_lowercase();
+                if scheme == "isbn" {
+                    metadata.isbn = Some(text.clone());
+                } else if lowercase.starts_with("urn:isbn:") {
+                    metadata.isbn = Some(text[9..].to_string());
+                } else if scheme == "mobi-asin" || scheme == "amazon" || scheme == "asin" {
+                    metadata.asin = Some(text.clone());
+                }
+                if id.is_some() && id == unique_id {
+                    identifier = Some(text);
+                }
+            },
+            _ => {},
+        }
+    }
+    if metadata.title.is_empty() {
+        metadata.title = "Unknown".to_string();
+    }
+    (metadata, identifier)
+}
+
+/// Builds the tree of a list of links from its items, given the parent of
+/// every item.
+fn link_tree(items: &[(Option<usize>, TocEntry)], parent: Option<usize>) -> Vec<TocEntry> {
+    items.iter().enumerate()
+        .filter(|&(_, &(item_parent, _))| item_parent == parent)
+        .map(|(i, &(_, ref entry))| TocEntry {
+            title: entry.title.clone(),
+            href: entry.href.clone(),
+            children: link_tree(items, Some(i)),
+        })
+        .collect()
+}
+
+/// Returns the 'nav' element of a navigation document with the given type.
+fn find_nav(elements: &[Element], kind: &str) -> Option<usize> {
+    elements.iter().position(|element| {
+        element.name == "nav" && element.attribute("epub:type")
+            .map_or(false, |types| types.split_whitespace().any(|t| t == kind))
+    })
+}
+
+/// Reads the table of contents of an EPUB 3 navigation document. Links are
+/// resolved against the name of the document.
+fn read_nav_list(elements: &[Element], name: &str, kind: &str) -> Vec<TocEntry> {
+    let nav = match find_nav(elements, kind) {
+        Some(nav) => nav,
+        None => return Vec::new(),
+    };
+    let mut items: Vec<(Option<usize>, TocEntry)> = Vec::new();
+    let mut item_indices = HashMap::new();
+    for i in (nav + 1..elements.len()).take_while(|&i| is_inside(elements, i, nav)) {
+        if elements[i].name != "li" {
+            continue;
+        }
+        let link = descendant(elements, i, "a").or_else(|| descendant(elements, i, "span"));
+        let (title, href) = match link {
+            Some(link) => (elements[link].text.trim().to_string(),
+                elements[link].attribute("href").map_or(String::new(), |href| resolve_link(name, &href))),
+            None => continue,
+        };
+        let parent = ancestor(elements, i, "li").and_then(|li| item_indices.get(&li).cloned());
+        item_indices.insert(i, items.len());
+        items.push((parent, TocEntry { title: title, href: href, children: Vec::new() }));
+    }
+    link_tree(&items, None)
+}
+
+/// Reads the landmarks of an EPUB 3 navigation document as guide entries.
+fn read_landmarks(elements: &[Element], name: &str) -> Vec<GuideEntry> {
+    let nav = match find_nav(elements, "landmarks") {
+        Some(nav) => nav,
+        None => return Vec::new(),
+    };
+    (nav + 1..elements.len())
+        .take_while(|&i| is_inside(elements, i, nav))
+        .filter(|&i| elements[i].name == "a")
+        .filter_map(|i| {
+            let link = &elements[i];
+            let kind = match link.attribute("epub:type").as_ref().map(|kind| &kind[..]) {
+                Some("cover") => "cover",
+                Some("toc") => "toc",
+                Some("bodymatter") => "text",
+                _ => return None,
+            };
+            link.attribute("href").map(|href| GuideEntry {
+                kind: kind.to_string(),
+                title: link.text.trim().to_string(),
+                href: resolve_link(name, &href),
+            })
+        })
+        .collect()
+}
+
+/// Reads the table of contents of an NCX document.
+fn read_ncx(elements: &[Element], name: &str) -> Vec<TocEntry> {
+    let mut items: Vec<(Option<usize>, TocEntry)> = Vec::new();
+    let mut item_indices = HashMap::new();
+    for i in 0..elements.len() {
+        if elements[i].name != "navpoint" {
+            continue;
+        }
+        let title = descendant(elements, i, "navlabel")
+            .map_or(String::new(), |label| elements[label].text.trim().to_string());
+        let href = descendant(elements, i, "content")
+            .and_then(|content| elements[content].attribute("src"))
+            .map_or(String::new(), |src| resolve_link(name, &src));
+        let parent = ancestor(elements, i, "navpoint").and_then(|point| item_indices.get(&point).cloned());
+        item_indices.insert(i, items.len());
+        items.push((parent, TocEntry { title: title, href: href, children: Vec::new() }));
+    }
+    link_tree(&items, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use testing;
+
+    /// Returns the names and the markup of the documents of a book.
+    fn documents(epub: &Epub) -> Vec<(String, String)> {
+        epub.documents.iter()
+            .map(|document| {
+                (document.name.clone(), String::from_utf8_lossy(&document.data).into_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn kf8_books() {
+        for &joint in [false, true].iter() {
+            let mut writer = testing::linked_kf8_writer();
+            writer.joint = joint;
+            let epub = Epub::from_book(&testing::kf8_book(&writer)).unwrap();
+            let documents = documents(&epub);
+            assert_eq!(documents.len(), 2);
+            assert!(documents[0].1.contains(&format!("href=\"{}#x\"",
+                documents[1].0.rsplit('/').next().unwrap())));
+            assert!(documents[1].1.contains(&format!("href=\"{}#p300\"",
+                documents[0].0.rsplit('/').next().unwrap())));
+            assert!(documents[1].1.contains("src=\"../images/image00001.png\""));
+
+            assert_eq!(epub.stylesheets.len(), 1);
+            let css = String::from_utf8_lossy(&epub.stylesheets[0].data).into_owned();
+            assert!(css.contains("url(../images/image00001.png)"));
+            assert_eq!(epub.images[0].data, b"\x89PNGimage");
+            assert_eq!(epub.cover, Some(0));
+            assert_eq!(epub.toc[0].title, "First");
+            assert_eq!(epub.toc[0].children[0].href, format!("{}#x", documents[1].0));
+        }
+    }
+
+    #[test]
+    fn kf7_books() {
+        let book = testing::kf7_book("<html><body><p><a href=\"#end\">End</a></p>\
+            <mbp:pagebreak/><p id=\"end\">The end</p></body></html>");
+        let epub = Epub::from_book(&book).unwrap();
+        let documents = documents(&epub);
+        assert_eq!(documents.len(), 1);
+        let (name, html) = (&documents[0].0, &documents[0].1);
+        let anchor = html.find("id=\"filepos").unwrap() + 4;
+        let id = &html[anchor..anchor + html[anchor..].find('"').unwrap()];
+        assert!(html.contains(&format!("href=\"#{}\"", id)), "{}: {}", name, html);
+    }
+
+    #[test]
+    fn written_files() {
+        let epub = Epub::from_book(&testing::kf8_book(&testing::linked_kf8_writer())).unwrap();
+        let mut data = Cursor::new(Vec::new());
+        epub.write_to(&mut data).unwrap();
+        let mut zip = ZipArchive::new(Cursor::new(data.into_inner())).unwrap();
+        assert_eq!(zip.by_index(0).unwrap().name(), "mimetype");
+        assert_eq!(read_zip_file(&mut zip, "mimetype").unwrap(), b"application/epub+zip");
+        let package = read_zip_file(&mut zip, "OEBPS/content.opf").unwrap();
+        let package = String::from_utf8(package).unwrap();
+        assert!(package.contains("<dc:title>Test</dc:title>"));
+        for document in epub.documents.iter().chain(epub.images.iter()) {
+            let name = format!("OEBPS/{}", document.name);
+            assert_eq!(read_zip_file(&mut zip, &name).unwrap(), document.data);
+        }
+    }
+
+    /// An EPUB 2 book laid out like Sigil's, with an NCX table of contents.
+    const EPUB2: &'static [u8] = include_bytes!("../tests/fixtures/epub2.epub");
+
+    #[test]
+    fn epub2_books() {
+        let epub = Epub::read_from(Cursor::new(EPUB2)).unwrap();
+        assert_eq!(epub.metadata.title, "Café & Co");
+        assert_eq!(epub.metadata.authors, vec!["A. Author".to_string()]);
+        assert_eq!(epub.metadata.contributors, vec!["I. Llustrator".to_string()]);
+        assert_eq!(epub.metadata.isbn, Some("978-0-00-000000-0".to_string()));
+        assert_eq!(epub.identifier, "978-0-00-000000-0");
+        assert_eq!(epub.documents[1].name, "Text/Chapter%202.xhtml");
+        assert_eq!(epub.stylesheets[0].name, "Styles/style.css");
+        assert_eq!(epub.cover, Some(0));
+        assert_eq!(epub.toc[0].children[0].href, "Text/Chapter%201.xhtml#s");
+        assert_eq!(epub.toc[1].title, "Chapter 2");
+        assert_eq!(epub.guide[0].href, "Text/Chapter%201.xhtml#s");
+
+        let mut data = Vec::new();
+        epub.to_kf8().write_to(&mut data).unwrap();
+        let back = Epub::from_book(&MobiBook::from_bytes(&data).unwrap()).unwrap();
+        assert_eq!(back.metadata, epub.metadata);
+        assert_eq!(back.images[0].data, epub.images[0].data);
+        assert_eq!(back.cover, Some(0));
+        assert_eq!(back.toc[0].children[0].href, format!("{}#s", back.documents[0].name));
+        assert_eq!(back.guide[0].href, back.toc[0].children[0].href);
+        let chapter = String::from_utf8_lossy(&back.documents[1].data).into_owned();
+        assert!(chapter.contains("href=\"part0000.xhtml#s\""), "{}", chapter);
+    }
+
+    #[test]
+    fn written_books_read_back() {
+        let epub = Epub::read_from(Cursor::new(EPUB2)).unwrap();
+        let mut data = Cursor::new(Vec::new());
+        epub.write_to(&mut data).unwrap();
+        let back = Epub::read_from(Cursor::new(data.into_inner())).unwrap();
+        assert_eq!(back.metadata, epub.metadata);
+        assert_eq!(back.identifier, epub.identifier);
+        assert_eq!(documents(&back), documents(&epub));
+        assert_eq!(back.cover, epub.cover);
+        assert_eq!(back.toc.len(), 2);
+        assert_eq!(back.toc[0].children[0].title, "Section");
+    }
+
+    #[test]
+    fn truncated_packages() {
+        let mut zip = ZipArchive::new(Cursor::new(EPUB2)).unwrap();
+        let opf = read_zip_file(&mut zip, "OPS/book.opf").unwrap();
+        let end = html::find_bytes(&opf, b"Caf").unwrap();
+        let tails = [(&b"<![CDATA[ab"[..], "ab"), (b"<![CDATA[", "Unknown"),
+            (b"<![CDATA[]]>", "Unknown")];
+        for &(tail, title) in tails.iter() {
+            let mut data = Cursor::new(Vec::new());
+            {
+                let mut copy = ZipWriter::new(&mut data);
+                for i in 0..zip.len() {
+                    let mut file = zip.by_index(i).unwrap();
+                    let mut content = Vec::new();
+                    file.read_to_end(&mut content).unwrap();
+                    if file.name() == "OPS/book.opf" {
+                        content = opf[..end].to_vec();
+                        content.extend_from_slice(tail);
+                    }
+                    copy.start_file(file.name(), FileOptions::default()).unwrap();
+                    copy.write_all(&content).unwrap();
+                }
+                copy.finish().unwrap();
+            }
+            let epub = Epub::read_from(Cursor::new(data.into_inner())).unwrap();
+            assert_eq!(epub.metadata.title, title);
+            assert!(epub.documents.is_empty());
+        }
+        assert_eq!(read_elements(b"<title><![CDATA[ab")[0].text, "ab");
+        assert_eq!(read_elements(b"<title><![CDATA[a]]>b</title>")[0].text, "ab");
+    }
+}
@@ -1,6 +1,6 @@
 
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 use common;
 use common::*;
 
@@ -9,9 +9,14 @@ use common::*;
 pub fn read_from(source: &mut Read) -> Result<Vec<ExthTag>, io::Error> {
     let magic_exth = try!(read_string(source, 4));
     //println!("EXTH magic: {}", magic_exth);
-    assert_eq!(magic_exth, String::from("EXTH"));
+    if magic_exth != "EXTH" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing EXTH header"));
+    }
     
     let header_len = try!(read_u32_be(source));
+    if header_len < 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid EXTH header length"));
+    }
     let exth_record_count = try!(read_u32_be(source));
     //println!("EXTH Header length: {}", header_len);
     //println!("EXTH records: {}", exth_record_count);
@@ -32,6 +37,43 @@ pub fn read_from(source: &mut Read) -> Result<Vec<ExthTag>, io::Error> {
     Ok(exth_tags)
 }
 
+/// Writes the given tags as an EXTH header, including the trailing padding.
+pub fn write_to(tags: &[ExthTag], output: &mut Write) -> Result<(), io::Error> {
+    let mut records = Vec::new();
+    for tag in tags.iter() {
+        try!(tag.write_to(&mut records));
+    }
+    
+    try!(output.write_all(b"EXTH"));
+    try!(write_u32_be(output, 12 + records.len() as u32));
+    try!(write_u32_be(output, tags.len() as u32));
+    try!(output.write_all(&records));
+    
+    // Pad the header to a multiple of four bytes
+    let padding = (4 - records.len() % 4) % 4;
+    try!(output.write_all(&[0; 3][..padding]));
+    Ok(())
+}
+
+/// Sets a tag, replacing the tags of the same type, or adds it.
+pub fn set_tag(tags: &mut Vec<ExthTag>, tag: ExthTag) {
+    let tag_type = tag.tag_type();
+    match tags.iter().position(|other| other.tag_type() == tag_type) {
+        Some(index) => {
+            tags[index] = tag;
+            let mut i = index + 1;
+            while i < tags.len() {
+                if tags[i].tag_type() == tag_type {
+                    tags.remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+        },
+        None => tags.push(tag),
+    }
+}
+
 // Taken from the mobileread wiki
 valued_enum! {
     ExthType : u32 {
@@ -125,17 +167,22 @@ valued_enum! {
     }
 }
 
-#[derive(Debug, PartialEq, Hash)]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize)]
 pub enum ExthTag {
     Contributor(String),
-    Language(Language),
+    Language(String),
     UpdatedTitle(String),
     Author(String),
     Publisher(String),
+    Description(String),
+    ISBN(String),
+    Subject(String),
+    Rights(String),
     ASIN(String),
     Source(String),
     CDEType(String),
     PublishingDate(String), // TODO: Change/parse to a chrono::DateTime
+    DictionaryShortName(String),
     CreatorSoftware(CreatorSoftware),
     CreatorMajorVersion(u32), 
     CreatorMinorVersion(u32),
@@ -144,6 +191,8 @@ pub enum ExthTag {
     HasFakeCover(bool),
     ThumbnailOffset(u32),
     KF8CoverURI(String),
+    KF8BoundaryOffset(u32),
+    ResourceCount(u32),
     StartReadingAtOffset(u32),
     UsedButUnknown(u32),
     Unhandled { tag_type: ExthType, data: Vec<u8> },
@@ -154,6 +203,10 @@ impl ExthTag {
         let record_type = ExthType::from(try!(read_u32_be(source)));
         // including type and length fields
         let record_len = try!(read_u32_be(source));
+        if record_len < 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "Invalid EXTH record length"));
+        }
         let data_len = record_len - 8;
         Ok(match record_type {
             Contributor => {
@@ -163,7 +216,7 @@ impl ExthTag {
             },
             Language => {
                 ExthTag::Language(
-                    common::Language::from(try!(read_u16_be(source)) as u32)
+                    try!(read_string(source, data_len as u64))
                 )
             },
             UpdatedTitle => {
@@ -181,6 +234,26 @@ impl ExthTag {
                     try!(read_string(source, data_len as u64))
                 )
             },
+            Description => {
+                ExthTag::Description(
+                    try!(read_string(source, data_len as u64))
+                )
+            },
+            ISBN => {
+                ExthTag::ISBN(
+                    try!(read_string(source, data_len as u64))
+                )
+            },
+            Subject => {
+                ExthTag::Subject(
+                    try!(read_string(source, data_len as u64))
+                )
+            },
+            Rights => {
+                ExthTag::Rights(
+                    try!(read_string(source, data_len as u64))
+                )
+            },
             ASIN => {
                 ExthTag::ASIN(
                     try!(read_string(source, data_len as u64))
@@ -201,6 +274,11 @@ impl ExthTag {
                     try!(read_string(source, data_len as u64))
                 )
             },
+            DictionaryShortName => {
+                ExthTag::DictionaryShortName(
+                    try!(read_string(source, data_len as u64))
+                )
+            },
             CreatorSoftware => {
                 ExthTag::CreatorSoftware(
                     self::CreatorSoftware::from(
@@ -243,6 +321,16 @@ impl ExthTag {
                     try!(read_string(source, data_len as u64))
                 )
             },
+            KF8BoundaryOffset => {
+                ExthTag::KF8BoundaryOffset(
+                    try!(read_u32_be(source))
+                )
+            },
+            ResourceCount => {
+                ExthTag::ResourceCount(
+                    try!(read_u32_be(source))
+                )
+            },
             StartReadingAtOffset => {
                 ExthTag::StartReadingAtOffset(
                     try!(read_u32_be(source))
@@ -260,4 +348,91 @@ impl ExthTag {
             }
         })
     }
-}
\ No newline at end of file
+    
+    /// Returns the EXTH record type of this tag.
+    pub fn tag_type(&self) -> ExthType {
+        match *self {
+            ExthTag::Contributor(_) => ExthType::Contributor,
+            ExthTag::Language(_) => ExthType::Language,
+            ExthTag::UpdatedTitle(_) => ExthType::UpdatedTitle,
+            ExthTag::Author(_) => ExthType::Author,
+            ExthTag::Publisher(_) => ExthType::Publisher,
+            ExthTag::Description(_) => ExthType::Description,
+            ExthTag::ISBN(_) => ExthType::ISBN,
+            ExthTag::Subject(_) => ExthType::Subject,
+            ExthTag::Rights(_) => ExthType::Rights,
+            ExthTag::ASIN(_) => ExthType::ASIN,
+            ExthTag::Source(_) => ExthType::Source,
+            ExthTag::CDEType(_) => ExthType::CDEType,
+            ExthTag::PublishingDate(_) => ExthType::PublishingDate,
+            ExthTag::DictionaryShortName(_) => ExthType::DictionaryShortName,
+            ExthTag::CreatorSoftware(_) => ExthType::CreatorSoftware,
+            ExthTag::CreatorMajorVersion(_) => ExthType::CreatorMajorVersion,
+            ExthTag::CreatorMinorVersion(_) => ExthType::CreatorMinorVersion,
+            ExthTag::CreatorBuildNumber(_) => ExthType::CreatorBuildNumber,
+            ExthTag::CoverOffset(_) => ExthType::CoverOffset,
+            ExthTag::HasFakeCover(_) => ExthType::HasFakeCover,
+            ExthTag::ThumbnailOffset(_) => ExthType::ThumbnailOffset,
+            ExthTag::KF8CoverURI(_) => ExthType::KF8CoverURI,
+            ExthTag::KF8BoundaryOffset(_) => ExthType::KF8BoundaryOffset,
+            ExthTag::ResourceCount(_) => ExthType::ResourceCount,
+            ExthTag::StartReadingAtOffset(_) => ExthType::StartReadingAtOffset,
+            ExthTag::UsedButUnknown(_) => ExthType::UsedButUnknown,
+            ExthTag::Unhandled { tag_type, .. } => tag_type,
+        }
+    }
+    
+    /// Returns the raw data of this tag, as stored in the EXTH record.
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match *self {
+            ExthTag::Contributor(ref text) |
+            ExthTag::Language(ref text) |
+            ExthTag::UpdatedTitle(ref text) |
+            ExthTag::Author(ref text) |
+            ExthTag::Publisher(ref text) |
+            ExthTag::Description(ref text) |
+            ExthTag::ISBN(ref text) |
+            ExthTag::Subject(ref text) |
+            ExthTag::Rights(ref text) |
+            ExthTag::ASIN(ref text) |
+            ExthTag::Source(ref text) |
+            ExthTag::CDEType(ref text) |
+            ExthTag::PublishingDate(ref text) |
+            ExthTag::DictionaryShortName(ref text) |
+            ExthTag::KF8CoverURI(ref text) => {
+                data.extend_from_slice(text.as_bytes());
+            },
+            ExthTag::CreatorSoftware(ref software) => {
+                write_u32_be(&mut data, software.value()).unwrap();
+            },
+            ExthTag::CreatorMajorVersion(value) |
+            ExthTag::CreatorMinorVersion(value) |
+            ExthTag::CreatorBuildNumber(value) |
+            ExthTag::CoverOffset(value) |
+            ExthTag::ThumbnailOffset(value) |
+            ExthTag::KF8BoundaryOffset(value) |
+            ExthTag::ResourceCount(value) |
+            ExthTag::StartReadingAtOffset(value) |
+            ExthTag::UsedButUnknown(value) => {
+                write_u32_be(&mut data, value).unwrap();
+            },
+            ExthTag::HasFakeCover(fake) => {
+                write_u32_be(&mut data, if fake { 1 } else { 0 }).unwrap();
+            },
+            ExthTag::Unhandled { data: ref raw, .. } => {
+                data.extend_from_slice(raw);
+            },
+        }
+        data
+    }
+    
+    /// Writes this tag as an EXTH record.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        let data = self.data();
+        try!(write_u32_be(output, self.tag_type().value()));
+        try!(write_u32_be(output, 8 + data.len() as u32));
+        try!(output.write_all(&data));
+        Ok(())
+    }
+}
@@ -0,0 +1,599 @@
+//! A small, forgiving HTML/XML tokenizer.
+//! It works on raw bytes, so that byte offsets into the book text (as used by
+//! filepos links and KF8 positions) stay valid.
+
+use std::ops::Range;
+
+/// A start tag, end tag or empty-element tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    /// The lowercased tag name.
+    pub name: String,
+    /// The attributes in document order, with lowercased names and raw values.
+    pub attributes: Vec<(String, String)>,
+    /// Whether the tag ends with '/>'.
+    pub self_closing: bool,
+}
+
+impl Tag {
+    pub fn new(name: &str) -> Tag {
+        Tag {
+            name: name.to_lowercase(),
+            attributes: Vec::new(),
+            self_closing: false,
+        }
+    }
+
+    /// Returns the raw value of the given attribute.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|&&(ref key, _)| key == name)
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    /// Sets the given attribute, replacing any existing value.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        for &mut (ref key, ref mut old) in self.attributes.iter_mut() {
+            if key == name {
+                *old = value.to_string();
+                return;
+            }
+        }
+        self.attributes.push((name.to_string(), value.to_string()));
+    }
+
+    /// Removes the given attribute and returns its value.
+    pub fn remove_attribute(&mut self, name: &str) -> Option<String> {
+        let index = self.attributes.iter().position(|&(ref key, _)| key == name);
+        index.map(|i| self.attributes.remove(i).1)
+    }
+
+    /// Serializes this as a start tag.
+    pub fn to_html(&self) -> String {
+        let mut html = format!("<{}", self.name);
+        for &(ref key, ref value) in self.attributes.iter() {
+            html.push_str(&format!(" {}=\"{}\"", key, value.replace('"', "&quot;")));
+        }
+        if self.self_closing {
+            html.push_str(" />");
+        } else {
+            html.push('>');
+        }
+        html
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token<'a> {
+    Text(&'a [u8]),
+    StartTag(Tag),
+    EndTag(String),
+    Comment(&'a [u8]),
+    /// Doctypes, processing instructions and CDATA sections
+    Declaration(&'a [u8]),
+}
+
+/// Iterates over the tokens of a document, along with their byte ranges.
+pub struct Tokenizer<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// The end tag that ends the current raw text element (script, style).
+    raw_text_end: Option<String>,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(data: &'a [u8]) -> Tokenizer<'a> {
+        Tokenizer { data: data, pos: 0, raw_text_end: None }
+    }
+
+    /// Returns the offset of the next token.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn find_from(&self, pattern: &[u8], from: usize) -> Option<usize> {
+        find_bytes(&self.data[from..], pattern).map(|i| i + from)
+    }
+
+    fn read_raw_text(&mut self, end_tag: &str) -> Token<'a> {
+        let start = self.pos;
+        let closing = format!("</{}", end_tag);
+        let mut end = self.data.len();
+        let mut from = start;
+        while let Some(i) = self.find_from(b"</", from) {
+            let candidate = &self.data[i..];
+            if candidate.len() >= closing.len()
+                    && candidate[..closing.len()].eq_ignore_ascii_case(closing.as_bytes()) {
+                end = i;
+                break;
+            }
+            from = i + 2;
+        }
+        self.pos = end;
+        Token::Text(&self.data[start..end])
+    }
+
+    fn read_tag(&mut self) -> Option<Token<'a>> {
+        let data = self.data;
+        let start = self.pos;
+        let next = match data.get(start + 1) {
+            Some(&byte) => byte,
+            None => return None,
+        };
+
+        if data[start..].starts_with(b"<!--") {
+            let end = self.find_from(b"-->", start + 4).unwrap_or(data.len());
+            self.pos = ::std::cmp::min(end + 3, data.len());
+            return Some(Token::Comment(&data[start + 4..end]));
+        } else if data[start..].starts_with(b"<![CDATA[") {
+            let end = self.find_from(b"]]>", start).map(|i| i + 3).unwrap_or(data.len());
+            self.pos = end;
+            return Some(Token::Declaration(&data[start..end]));
+        } else if next == b'!' || next == b'?' {
+            let end = self.find_from(b">", start).map(|i| i + 1).unwrap_or(data.len());
+            self.pos = end;
+            return Some(Token::Declaration(&data[start..end]));
+        } else if next == b'/' {
+            let close = self.find_from(b">", start).unwrap_or(data.len());
+            let name = String::from_utf8_lossy(&data[start + 2..close]);
+            self.pos = ::std::cmp::min(close + 1, data.len());
+            return Some(Token::EndTag(name.trim().to_lowercase()));
+        } else if !(next as char).is_alphabetic() {
+            return None;
+        }
+
+        // Start tag: name, then attributes until '>'
+        let mut i = start + 1;
+        while i < data.len() && !is_space(data[i]) && data[i] != b'>'
+                && data[i] != b'/' {
+            i += 1;
+        }
+        let mut tag = Tag::new(&String::from_utf8_lossy(&data[start + 1..i]));
+
+        loop {
+            while i < data.len() && (is_space(data[i]) || data[i] == b'/') {
+                if data[i] == b'/' && data.get(i + 1) == Some(&b'>') {
+                    tag.self_closing = true;
+                }
+                i += 1;
+            }
+            if i >= data.len() {
+                break;
+            }
+            if data[i] == b'>' {
+                i += 1;
+                break;
+            }
+
+            let name_start = i;
+            while i < data.len() && !is_space(data[i]) && data[i] != b'='
+                    && data[i] != b'>' && data[i] != b'/' {
+                i += 1;
+            }
+            let name = String::from_utf8_lossy(&data[name_start..i]).to_lowercase();
+            while i < data.len() && is_space(data[i]) {
+                i += 1;
+            }
+
+            let mut value = String::new();
+            if i < data.len() && data[i] == b'=' {
+                i += 1;
+                while i < data.len() && is_space(data[i]) {
+                    i += 1;
+                }
+                if i < data.len() && (data[i] == b'"' || data[i] == b'\'') {
+                    let quote = data[i];
+                    let value_start = i + 1;
+                    i = value_start;
+                    while i < data.len() && data[i] != quote {
+                        i += 1;
+                    }
+                    value = String::from_utf8_lossy(&data[value_start..i]).into_owned();
+                    i += 1;
+                } else {
+                    let value_start = i;
+                    while i < data.len() && !is_space(data[i]) && data[i] != b'>' {
+                        i += 1;
+                    }
+                    value = String::from_utf8_lossy(&data[value_start..i]).into_owned();
+                }
+            }
+            if !name.is_empty() {
+                tag.attributes.push((name, value));
+            }
+        }
+
+        self.pos = ::std::cmp::min(i, data.len());
+        if !tag.self_closing && (tag.name == "script" || tag.name == "style") {
+            self.raw_text_end = Some(tag.name.clone());
+        }
+        Some(Token::StartTag(tag))
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (Range<usize>, Token<'a>);
+
+    fn next(&mut self) -> Option<(Range<usize>, Token<'a>)> {
+        let start = self.pos;
+        if start >= self.data.len() {
+            return None;
+        }
+
+        if let Some(end_tag) = self.raw_text_end.take() {
+            let token = self.read_raw_text(&end_tag);
+            if self.pos > start {
+                return Some((start..self.pos, token));
+            }
+        }
+
+        if self.data[start] == b'<' {
+            if let Some(token) = self.read_tag() {
+                return Some((start..self.pos, token));
+            }
+        }
+
+        // Text until the next tag (a stray '<' is part of the text)
+        let end = self.find_from(b"<", start + 1).unwrap_or(self.data.len());
+        self.pos = end;
+        Some((start..end, Token::Text(&self.data[start..end])))
+    }
+}
+
+fn is_space(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r' || byte == 0x0C
+}
+
+/// Returns the offset of the first occurrence of 'pattern' in 'data'.
+pub fn find_bytes(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    if pattern.is_empty() || pattern.len() > data.len() {
+        return None;
+    }
+    data.windows(pattern.len()).position(|window| window == pattern)
+}
+
+/// Rebuilds the document, letting 'rewrite' change each start tag.
+/// Tags for which it returns false are copied as they were.
+pub fn rewrite_tags<F>(data: &[u8], mut rewrite: F) -> Vec<u8>
+        where F: FnMut(&mut Tag) -> bool {
+    let mut output = Vec::with_capacity(data.len());
+    for (range, token) in Tokenizer::new(data) {
+        match token {
+            Token::StartTag(mut tag) => {
+                if rewrite(&mut tag) {
+                    output.extend_from_slice(tag.to_html().as_bytes());
+                } else {
+                    output.extend_from_slice(&data[range]);
+                }
+            },
+            _ => output.extend_from_slice(&data[range]),
+        }
+    }
+    output
+}
+
+/// Escapes text for use in HTML content or attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            other => escaped.push(other),
+        }
+    }
+    escaped
+}
+
+/// Splits a link into its path and its fragment (without the '#').
+pub fn split_fragment(href: &str) -> (&str, Option<&str>) {
+    match href.find('#') {
+        Some(i) => (&href[..i], Some(&href[i + 1..])),
+        None => (href, None),
+    }
+}
+
+/// Returns whether the link points outside of the book.
+pub fn is_external(href: &str) -> bool {
+    let scheme_end = href.find(':').unwrap_or(0);
+    scheme_end > 0 && href[..scheme_end].chars().all(|c| c.is_ascii_alphanumeric()
+        || c == '+' || c == '-' || c == '.')
+}
+
+/// Resolves a relative path against the path of the document containing it.
+/// Both are relative to the root of the book, and the result is normalized.
+pub fn resolve_href(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = if href.starts_with('/') {
+        Vec::new()
+    } else {
+        let mut parts = base.split('/').collect::<Vec<_>>();
+        parts.pop(); // The file name of the base
+        parts
+    };
+    for part in href.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop();
+            },
+            other => parts.push(other),
+        }
+    }
+    parts.join("/")
+}
+
+/// Returns the path of 'target' relative to the directory of 'base'.
+pub fn relative_href(base: &str, target: &str) -> String {
+    let base_dirs = base.split('/').collect::<Vec<_>>();
+    let base_dirs = &base_dirs[..base_dirs.len() - 1];
+    let target_parts = target.split('/').collect::<Vec<_>>();
+    let common = base_dirs.iter().zip(target_parts.iter())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let common = ::std::cmp::min(common, target_parts.len() - 1);
+    let mut parts = vec![".."; base_dirs.len() - common];
+    parts.extend_from_slice(&target_parts[common..]);
+    parts.join("/")
+}
+
+/// The elements that never have content or an end tag.
+pub const VOID_ELEMENTS: &'static [&'static str] = &["area", "base", "br", "col",
+    "embed", "hr", "img", "input", "link", "meta", "param", "source", "track",
+    "wbr"];
+
+/// Elements that end an open paragraph when they start.
+const BLOCK_ELEMENTS: &'static [&'static str] = &["address", "blockquote",
+    "center", "div", "dl", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "ol", "p",
+    "pre", "table", "ul"];
+
+/// The named entities of HTML that XML does not predefine.
+const ENTITIES: &'static [(&'static str, u32)] = &[
+    ("nbsp", 0x00A0), ("iexcl", 0x00A1), ("cent", 0x00A2), ("pound", 0x00A3),
+    ("curren", 0x00A4), ("yen", 0x00A5), ("brvbar", 0x00A6), ("sect", 0x00A7),
+    ("uml", 0x00A8), ("copy", 0x00A9), ("ordf", 0x00AA), ("laquo", 0x00AB),
+    ("not", 0x00AC), ("shy", 0x00AD), ("reg", 0x00AE), ("macr", 0x00AF),
+    ("deg", 0x00B0), ("plusmn", 0x00B1), ("sup2", 0x00B2), ("sup3", 0x00B3),
+    ("acute", 0x00B4), ("micro", 0x00B5), ("para", 0x00B6), ("middot", 0x00B7),
+    ("cedil", 0x00B8), ("sup1", 0x00B9), ("ordm", 0x00BA), ("raquo", 0x00BB),
+    ("frac14", 0x00BC), ("frac12", 0x00BD), ("frac34", 0x00BE),
+    ("iquest", 0x00BF), ("Agrave", 0x00C0), ("Aacute", 0x00C1),
+    ("Acirc", 0x00C2), ("Atilde", 0x00C3), ("Auml", 0x00C4), ("Aring", 0x00C5),
+    ("AElig", 0x00C6), ("Ccedil", 0x00C7), ("Egrave", 0x00C8),
+    ("Eacute", 0x00C9), ("Ecirc", 0x00CA), ("Euml", 0x00CB), ("Igrave", 0x00CC),
+    ("Iacute", 0x00CD), ("Icirc", 0x00CE), ("Iuml", 0x00CF), ("ETH", 0x00D0),
+    ("Ntilde", 0x00D1), ("Ograve", 0x00D2), ("Oacute", 0x00D3),
+    ("Ocirc", 0x00D4), ("Otilde", 0x00D5), ("Ouml", 0x00D6), ("times", 0x00D7),
+    ("Oslash", 0x00D8), ("Ugrave", 0x00D9), ("Uacute", 0x00DA),
+    ("Ucirc", 0x00DB), ("Uuml", 0x00DC), ("Yacute", 0x00DD), ("THORN", 0x00DE),
+    ("szlig", 0x00DF), ("agrave", 0x00E0), ("aacute", 0x00E1),
+    ("acirc", 0x00E2), ("atilde", 0x00E3), ("auml", 0x00E4), ("aring", 0x00E5),
+    ("aelig", 0x00E6), ("ccedil", 0x00E7), ("egrave", 0x00E8),
+    ("eacute", 0x00E9), ("ecirc", 0x00EA), ("euml", 0x00EB), ("igrave", 0x00EC),
+    ("iacute", 0x00ED), ("icirc", 0x00EE), ("iuml", 0x00EF), ("eth", 0x00F0),
+    ("ntilde", 0x00F1), ("ograve", 0x00F2), ("oacute", 0x00F3),
+    ("ocirc", 0x00F4), ("otilde", 0x00F5), ("ouml", 0x00F6), ("divide", 0x00F7),
+    ("oslash", 0x00F8), ("ugrave", 0x00F9), ("uacute", 0x00FA),
+    ("ucirc", 0x00FB), ("uuml", 0x00FC), ("yacute", 0x00FD), ("thorn", 0x00FE),
+    ("yuml", 0x00FF), ("OElig", 0x0152), ("oelig", 0x0153), ("Scaron", 0x0160),
+    ("scaron", 0x0161), ("Yuml", 0x0178), ("fnof", 0x0192), ("circ", 0x02C6),
+    ("tilde", 0x02DC), ("ensp", 0x2002), ("emsp", 0x2003), ("thinsp", 0x2009),
+    ("zwnj", 0x200C), ("zwj", 0x200D), ("lrm", 0x200E), ("rlm", 0x200F),
+    ("ndash", 0x2013), ("mdash", 0x2014), ("lsquo", 0x2018), ("rsquo", 0x2019),
+    ("sbquo", 0x201A), ("ldquo", 0x201C), ("rdquo", 0x201D), ("bdquo", 0x201E),
+    ("dagger", 0x2020), ("Dagger", 0x2021), ("bull", 0x2022),
+    ("hellip", 0x2026), ("permil", 0x2030), ("prime", 0x2032),
+    ("Prime", 0x2033), ("lsaquo", 0x2039), ("rsaquo", 0x203A),
+    ("oline", 0x203E), ("euro", 0x20AC), ("trade", 0x2122), ("larr", 0x2190),
+    ("uarr", 0x2191), ("rarr", 0x2192), ("darr", 0x2193), ("harr", 0x2194),
+    ("minus", 0x2212), ("lowast", 0x2217), ("le", 0x2264), ("ge", 0x2265),
+    ("ne", 0x2260), ("infin", 0x221E), ("asymp", 0x2248), ("equiv", 0x2261),
+    ("sum", 0x2211), ("prod", 0x220F), ("radic", 0x221A), ("part", 0x2202),
+    ("nabla", 0x2207), ("isin", 0x2208), ("Alpha", 0x0391), ("Beta", 0x0392),
+    ("Gamma", 0x0393), ("Delta", 0x0394), ("Epsilon", 0x0395),
+    ("Theta", 0x0398), ("Lambda", 0x039B), ("Pi", 0x03A0), ("Sigma", 0x03A3),
+    ("Phi", 0x03A6), ("Psi", 0x03A8), ("Omega", 0x03A9), ("alpha", 0x03B1),
+    ("beta", 0x03B2), ("gamma", 0x03B3), ("delta", 0x03B4), ("epsilon", 0x03B5),
+    ("theta", 0x03B8), ("lambda", 0x03BB), ("mu", 0x03BC), ("pi", 0x03C0),
+    ("sigma", 0x03C3), ("phi", 0x03C6), ("psi", 0x03C8), ("omega", 0x03C9)
+];
+
+/// Returns the character of an entity, given its name or number without the
+/// '&' and ';', such as "amp", "#38" or "#x26".
+pub fn entity_char(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        _ if name.starts_with("#x") || name.starts_with("#X") => {
+            u32::from_str_radix(&name[2..], 16).ok().and_then(::std::char::from_u32)
+        },
+        _ if name.starts_with('#') => {
+            name[1..].parse().ok().and_then(::std::char::from_u32)
+        },
+        _ => ENTITIES.iter()
+            .find(|&&(entity, _)| entity == name)
+            .and_then(|&(_, code)| ::std::char::from_u32(code)),
+    }
+}
+
+/// Returns the name of the entity starting at 'data' (at a '&'), if it is
+/// terminated by a ';'.
+fn entity_at(data: &[u8]) -> Option<&str> {
+    let end = data.iter().take(12).position(|&byte| byte == b';');
+    end.and_then(|end| {
+        let name = &data[1..end];
+        let valid = !name.is_empty() && name.iter()
+            .all(|&byte| (byte as char).is_ascii_alphanumeric() || byte == b'#');
+        if valid { ::std::str::from_utf8(name).ok() } else { None }
+    })
+}
+
+/// Decodes the entities of text or of an attribute value. Unknown entities
+/// are kept as they are.
+pub fn unescape(text: &[u8]) -> String {
+    let mut output = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == b'&' {
+            if let Some(name) = entity_at(&text[i..]) {
+                if let Some(c) = entity_char(name) {
+                    output.extend_from_slice(c.to_string().as_bytes());
+                    i += name.len() + 2;
+                    continue;
+                }
+            }
+        }
+        output.push(text[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Escapes text for XML, keeping its entities. Named entities that XML does
+/// not know become character references, and control characters are dropped.
+pub fn escape_xml(text: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        let byte = text[i];
+        match byte {
+            b'&' => {
+                match entity_at(&text[i..]) {
+                    Some(name) => {
+                        match name {
+                            "amp" | "lt" | "gt" | "quot" | "apos" => {
+                                output.push(b'&');
+                                output.extend_from_slice(name.as_bytes());
+                                output.push(b';');
+                            },
+                            _ => match entity_char(name) {
+                                Some(c) => {
+                                    output.extend_from_slice(format!("&#{};", c as u32).as_bytes());
+                                },
+                                None => {
+                                    output.extend_from_slice(b"&amp;");
+                                    output.extend_from_slice(name.as_bytes());
+                                    output.push(b';');
+                                },
+                            },
+                        }
+                        i += name.len() + 2;
+                        continue;
+                    },
+                    None => output.extend_from_slice(b"&amp;"),
+                }
+            },
+            b'<' => output.extend_from_slice(b"&lt;"),
+            b'\t' | b'\n' | b'\r' => output.push(byte),
+            0x00...0x1F => {},
+            _ => output.push(byte),
+        }
+        i += 1;
+    }
+    output
+}
+
+/// Closes the innermost open element among 'names', and everything opened
+/// after it, unless a 'scope' element is opened after it.
+fn close_open(open: &mut Vec<String>, names: &[&str], scope: &[&str], output: &mut Vec<u8>) {
+    for i in (0..open.len()).rev() {
+        if names.contains(&&open[i][..]) {
+            while open.len() > i {
+                let name = open.pop().unwrap();
+                output.extend_from_slice(format!("</{}>", name).as_bytes());
+            }
+            return;
+        }
+        if scope.contains(&&open[i][..]) {
+            return;
+        }
+    }
+}
+
+/// Closes the elements that the start of 'name' ends implicitly in HTML.
+fn close_implied(open: &mut Vec<String>, name: &str, output: &mut Vec<u8>) {
+    const P_SCOPE: &'static [&'static str] = &["blockquote", "dd", "div", "li",
+        "table", "td", "th"];
+    if BLOCK_ELEMENTS.contains(&name) {
+        close_open(open, &["p"], P_SCOPE, output);
+    }
+    match name {
+        "li" => close_open(open, &["li"], &["ol", "ul"], output),
+        "dt" | "dd" => close_open(open, &["dt", "dd"], &["dl"], output),
+        "tr" => close_open(open, &["tr"], &["table"], output),
+        "td" | "th" => close_open(open, &["td", "th"], &["tr", "table"], output),
+        _ => {},
+    }
+}
+
+/// Converts (Mobipocket) HTML into well-formed XHTML: attributes are quoted,
+/// empty elements closed, unclosed elements ended, entities made valid XML
+/// and the Mobipocket tags replaced. Page breaks become page-break divs.
+pub fn to_xhtml(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + data.len() / 8);
+    let mut open: Vec<String> = Vec::new();
+    for (_, token) in Tokenizer::new(data) {
+        match token {
+            Token::Text(text) => output.extend(escape_xml(text)),
+            Token::Comment(_) => {},
+            Token::Declaration(raw) => {
+                if raw.starts_with(b"<![CDATA[") {
+                    output.extend_from_slice(raw);
+                }
+            },
+            Token::StartTag(mut tag) => {
+                if tag.name == "mbp:pagebreak" {
+                    close_open(&mut open, &["p"], &["div"], &mut output);
+                    output.extend_from_slice(
+                        b"<div style=\"page-break-before: always\"></div>");
+                    continue;
+                }
+                if tag.name.contains(':') || tag.name == "guide" || tag.name == "reference" {
+                    continue;
+                }
+                if tag.name == "font" {
+                    tag.name = String::from("span");
+                    tag.attributes.clear();
+                }
+
+                let mut attributes: Vec<(String, String)> = Vec::new();
+                for (name, value) in tag.attributes.drain(..) {
+                    let valid = name == "xml:lang" || (!name.contains(':')
+                        && name.chars().next().map_or(false, |c| c.is_alphabetic())
+                        && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_'));
+                    if valid && !attributes.iter().any(|&(ref other, _)| *other == name) {
+                        let value = escape_xml(value.as_bytes());
+                        attributes.push((name, String::from_utf8_lossy(&value).into_owned()));
+                    }
+                }
+                tag.attributes = attributes;
+
+                close_implied(&mut open, &tag.name, &mut output);
+                if VOID_ELEMENTS.contains(&&tag.name[..]) {
+                    tag.self_closing = true;
+                }
+                output.extend_from_slice(tag.to_html().as_bytes());
+                if !tag.self_closing {
+                    open.push(tag.name);
+                }
+            },
+            Token::EndTag(name) => {
+                let name = if name == "font" { String::from("span") } else { name };
+                if let Some(i) = open.iter().rposition(|other| *other == name) {
+                    while open.len() > i {
+                        let name = open.pop().unwrap();
+                        output.extend_from_slice(format!("</{}>", name).as_bytes());
+                    }
+                }
+            },
+        }
+    }
+    while let Some(name) = open.pop() {
+        output.extend_from_slice(format!("</{}>", name).as_bytes());
+    }
+    output
+}
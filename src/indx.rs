@@ -0,0 +1,532 @@
+//! INDX records: the indices of MOBI files (NCX, KF8 skeletons and
+//! fragments, guide, dictionaries).
+//!
+//! An index is a header record holding the TAGX table, followed by records
+//! with the entries, followed by CNCX records with the strings the entries
+//! refer to.
+
+use std::collections::HashMap;
+use std::io;
+use common::*;
+
+/// The length of the INDX record headers.
+pub const HEADER_LENGTH: usize = 192;
+
+// Kindlegen leaves some room at the end of each record
+const RECORD_LIMIT: usize = 0x10000 - HEADER_LENGTH - 1048;
+const CNCX_RECORD_LIMIT: usize = 0x10000 - 1024;
+
+/// An entry of the TAGX table, describing one tag of the index entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagDefinition {
+    pub tag: u8,
+    pub values_per_entry: u8,
+    /// The bits of the control byte that hold the number of values.
+    pub mask: u8,
+    /// Set on the pseudo-tag that ends a control byte.
+    pub end_flag: u8,
+}
+
+impl TagDefinition {
+    pub fn new(tag: u8, values_per_entry: u8, mask: u8) -> TagDefinition {
+        TagDefinition { tag: tag, values_per_entry: values_per_entry,
+            mask: mask, end_flag: 0 }
+    }
+
+    /// The pseudo-tag ending the tags of a control byte.
+    pub fn end() -> TagDefinition {
+        TagDefinition { tag: 0, values_per_entry: 0, mask: 0, end_flag: 1 }
+    }
+}
+
+/// An index entry: a label (the key) and the values of its tags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub label: Vec<u8>,
+    pub tags: Vec<(u8, Vec<u32>)>,
+}
+
+impl IndexEntry {
+    pub fn new(label: &str) -> IndexEntry {
+        IndexEntry { label: label.as_bytes().to_vec(), tags: Vec::new() }
+    }
+
+    /// Adds a tag with the given values.
+    pub fn tag(mut self, tag: u8, values: Vec<u32>) -> IndexEntry {
+        self.tags.push((tag, values));
+        self
+    }
+
+    /// Returns the values of the given tag.
+    pub fn values(&self, tag: u8) -> Option<&[u32]> {
+        self.tags.iter()
+            .find(|&&(number, _)| number == tag)
+            .map(|&(_, ref values)| &values[..])
+    }
+
+    /// Returns the first value of the given tag.
+    pub fn value(&self, tag: u8) -> Option<u32> {
+        self.values(tag).and_then(|values| values.first().cloned())
+    }
+}
+
+/// The strings referenced by an index, stored as length-prefixed UTF-8.
+/// Offsets encode the record number in the upper 16 bits.
+#[derive(Debug, Clone, Default)]
+pub struct Cncx {
+    records: Vec<Vec<u8>>,
+    offsets: HashMap<String, u32>,
+}
+
+impl Cncx {
+    pub fn new() -> Cncx {
+        Cncx { records: vec![Vec::new()], offsets: HashMap::new() }
+    }
+
+    /// Adds a string (once) and returns its offset.
+    pub fn add(&mut self, text: &str) -> u32 {
+        if let Some(&offset) = self.offsets.get(text) {
+            return offset;
+        }
+        let mut raw = encode_vwi(text.len() as u32);
+        raw.extend_from_slice(text.as_bytes());
+        if self.records.last().unwrap().len() + raw.len() > CNCX_RECORD_LIMIT {
+            self.records.push(Vec::new());
+        }
+        let record = self.records.len() - 1;
+        let offset = ((record as u32) << 16) | self.records[record].len() as u32;
+        self.records[record].extend_from_slice(&raw);
+        self.offsets.insert(text.to_string(), offset);
+        offset
+    }
+
+    /// Returns the CNCX records, which follow the index records.
+    pub fn to_records(&self) -> Vec<Vec<u8>> {
+        self.records.iter()
+            .filter(|record| !record.is_empty())
+            .map(|record| {
+                let mut record = record.clone();
+                pad_to_multiple(&mut record, 4);
+                record
+            })
+            .collect()
+    }
+}
+
+/// Returns the number of value groups of a tag of an entry.
+fn tag_count(definition: &TagDefinition, entry: &IndexEntry) -> usize {
+    entry.values(definition.tag).map_or(0, |values| {
+        values.len() / ::std::cmp::max(1, definition.values_per_entry as usize)
+    })
+}
+
+/// Returns whether a tag has too many values for its mask. All the bits of
+/// the mask are then set, and the values are preceded by their byte count.
+fn is_counted_in_bytes(definition: &TagDefinition, entry: &IndexEntry) -> bool {
+    let max = definition.mask >> definition.mask.trailing_zeros();
+    definition.mask.count_ones() > 1 && tag_count(definition, entry) >= max as usize
+}
+
+/// Returns the control bytes describing which tags an entry has.
+fn control_bytes(definitions: &[TagDefinition], entry: &IndexEntry) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut current = 0u8;
+    for definition in definitions.iter() {
+        if definition.end_flag == 1 {
+            bytes.push(current);
+            current = 0;
+            continue;
+        }
+        if is_counted_in_bytes(definition, entry) {
+            current |= definition.mask;
+        } else {
+            let shift = definition.mask.trailing_zeros();
+            current |= definition.mask & ((tag_count(definition, entry) as u8) << shift);
+        }
+    }
+    bytes
+}
+
+fn encode_entry(definitions: &[TagDefinition], entry: &IndexEntry) -> Vec<u8> {
+    let mut raw = vec![entry.label.len() as u8];
+    raw.extend_from_slice(&entry.label);
+    raw.extend(control_bytes(definitions, entry));
+    for definition in definitions.iter().filter(|d| d.end_flag == 0) {
+        if let Some(values) = entry.values(definition.tag) {
+            let mut encoded = Vec::new();
+            for &value in values.iter() {
+                encoded.extend(encode_vwi(value));
+            }
+            if is_counted_in_bytes(definition, entry) {
+                raw.extend(encode_vwi(encoded.len() as u32));
+            }
+            raw.extend(encoded);
+        }
+    }
+    raw
+}
+
+fn index_record_header(idxt_offset: usize, entry_count: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    header.extend_from_slice(b"INDX");
+    for &value in &[HEADER_LENGTH as u32, 0, 1, 0, idxt_offset as u32,
+            entry_count as u32, 0xFFFFFFFF, 0xFFFFFFFF] {
+        write_u32_be(&mut header, value).unwrap();
+    }
+    header.resize(HEADER_LENGTH, 0);
+    header
+}
+
+/// Builds the records of an index: the header record, the entry records and
+/// the CNCX records. 'index_type' is 0 for normal indices and 2 for
+/// inflection indices.
+pub fn write_index(definitions: &[TagDefinition], entries: &[IndexEntry],
+        cncx: &Cncx, index_type: u32) -> Vec<Vec<u8>> {
+    // Split the entries into records
+    let mut blocks: Vec<(Vec<u8>, Vec<u16>, Vec<u8>)> = vec![(Vec::new(), Vec::new(), Vec::new())];
+    for entry in entries.iter() {
+        let raw = encode_entry(definitions, entry);
+        let full = {
+            let &(ref block, ref positions, _) = blocks.last().unwrap();
+            block.len() + 2 * positions.len() + raw.len() + 2 > RECORD_LIMIT
+        };
+        if full {
+            blocks.push((Vec::new(), Vec::new(), Vec::new()));
+        }
+        let &mut (ref mut block, ref mut positions, ref mut last) = blocks.last_mut().unwrap();
+        positions.push((HEADER_LENGTH + block.len()) as u16);
+        block.extend_from_slice(&raw);
+        *last = entry.label.clone();
+    }
+
+    let mut records = vec![Vec::new()];
+    for &(ref block, ref positions, _) in blocks.iter() {
+        let mut block = block.clone();
+        pad_to_multiple(&mut block, 4);
+        let mut record = index_record_header(HEADER_LENGTH + block.len(), positions.len());
+        record.extend_from_slice(&block);
+        record.extend_from_slice(b"IDXT");
+        for &position in positions.iter() {
+            write_u16_be(&mut record, position).unwrap();
+        }
+        pad_to_multiple(&mut record, 4);
+        records.push(record);
+    }
+
+    // The TAGX table
+    let control_byte_count = definitions.iter().filter(|d| d.end_flag == 1).count();
+    let mut tagx = Vec::new();
+    tagx.extend_from_slice(b"TAGX");
+    write_u32_be(&mut tagx, 12 + 4 * definitions.len() as u32).unwrap();
+    write_u32_be(&mut tagx, control_byte_count as u32).unwrap();
+    for definition in definitions.iter() {
+        tagx.extend_from_slice(&[definition.tag, definition.values_per_entry,
+            definition.mask, definition.end_flag]);
+    }
+
+    // The last label and entry count of every record, pointed to by an IDXT
+    let mut geometry = Vec::new();
+    let mut idxt = Vec::new();
+    idxt.extend_from_slice(b"IDXT");
+    for &(_, ref positions, ref last) in blocks.iter() {
+        write_u16_be(&mut idxt, (HEADER_LENGTH + tagx.len() + geometry.len()) as u16).unwrap();
+        geometry.push(last.len() as u8);
+        geometry.extend_from_slice(last);
+        write_u16_be(&mut geometry, positions.len() as u16).unwrap();
+    }
+    pad_to_multiple(&mut geometry, 4);
+    pad_to_multiple(&mut idxt, 4);
+
+    let cncx_records = cncx.to_records();
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    header.extend_from_slice(b"INDX");
+    for &value in &[HEADER_LENGTH as u32, 0, 0, index_type,
+            (HEADER_LENGTH + tagx.len() + geometry.len()) as u32,
+            blocks.len() as u32, 65001, 0xFFFFFFFF, entries.len() as u32,
+            0, 0, 0, cncx_records.len() as u32] {
+        write_u32_be(&mut header, value).unwrap();
+    }
+    header.resize(180, 0);
+    write_u32_be(&mut header, HEADER_LENGTH as u32).unwrap(); // TAGX offset
+    header.resize(HEADER_LENGTH, 0);
+    header.extend_from_slice(&tagx);
+    header.extend_from_slice(&geometry);
+    header.extend_from_slice(&idxt);
+    records[0] = header;
+
+    records.extend(cncx_records);
+    records
+}
+
+/// A decoded index, with the strings of its CNCX records.
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    pub index_type: u32,
+    /// The encoding of the labels: 65001 for UTF-8, 1252 for Windows-1252.
+    pub encoding: u32,
+    /// The ORDT table mapping the bytes of the labels of dictionaries to
+    /// UTF-16 code units, when the index has one.
+    pub ordt: Vec<u16>,
+    pub definitions: Vec<TagDefinition>,
+    pub entries: Vec<IndexEntry>,
+    pub cncx: HashMap<u32, String>,
+}
+
+impl Index {
+    /// Returns the label of an entry as text.
+    pub fn label(&self, entry: &IndexEntry) -> String {
+        self.decode(&entry.label)
+    }
+
+    /// Decodes the bytes of a label, with the ORDT table or the encoding of
+    /// the index.
+    pub fn decode(&self, label: &[u8]) -> String {
+        if !self.ordt.is_empty() {
+            let units = label.iter()
+                .map(|&byte| self.ordt.get(byte as usize).cloned().unwrap_or(byte as u16))
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        } else if self.encoding == 1252 {
+            decode_cp1252(label)
+        } else {
+            String::from_utf8_lossy(label).into_owned()
+        }
+    }
+
+    /// Returns the CNCX string that the given tag of an entry points to.
+    pub fn cncx_string(&self, entry: &IndexEntry, tag: u8) -> Option<&str> {
+        entry.value(tag)
+            .and_then(|offset| self.cncx.get(&offset))
+            .map(|text| &text[..])
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, io::Error> {
+    if offset + 4 > data.len() {
+        return Err(invalid("INDX record is truncated"));
+    }
+    Ok(try!(read_u32_be(&mut &data[offset..])))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, io::Error> {
+    if offset + 2 > data.len() {
+        return Err(invalid("INDX record is truncated"));
+    }
+    Ok(try!(read_u16_be(&mut &data[offset..])))
+}
+
+/// Reads the TAGX table of an index header record.
+fn read_tagx(header: &[u8]) -> Result<(Vec<TagDefinition>, usize), io::Error> {
+    let start = match find_tagx(header) {
+        Some(start) => start,
+        None => return Ok((Vec::new(), 0)),
+    };
+    let length = try!(u32_at(header, start + 4)) as usize;
+    let control_byte_count = try!(u32_at(header, start + 8)) as usize;
+    let end = ::std::cmp::min(start + length, header.len());
+    let definitions = header[::std::cmp::min(start + 12, end)..end].chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| TagDefinition { tag: chunk[0], values_per_entry: chunk[1],
+            mask: chunk[2], end_flag: chunk[3] })
+        .collect();
+    Ok((definitions, control_byte_count))
+}
+
+/// Reads the ORDT table of an index header record, which maps the bytes of
+/// the labels to UTF-16 code units.
+fn read_ordt(header: &[u8]) -> Result<Vec<u16>, io::Error> {
+    // Short headers have no ORDT fields
+    let count = u32_at(header, 0xA4).unwrap_or(0);
+    let entries = u32_at(header, 0xA8).unwrap_or(0) as usize;
+    if count == 0 || entries == 0 {
+        return Ok(Vec::new());
+    }
+    let start = try!(u32_at(header, 0xB0)) as usize;
+    if header.get(start..start + 4) != Some(b"ORDT") {
+        return Err(invalid("The ORDT table of the index is missing"));
+    }
+    if entries > header.len().saturating_sub(start + 4) / 2 {
+        return Err(invalid("The ORDT table of the index is truncated"));
+    }
+    let mut ordt = Vec::with_capacity(entries);
+    for i in 0..entries {
+        ordt.push(try!(u16_at(header, start + 4 + 2 * i)));
+    }
+    Ok(ordt)
+}
+
+fn find_tagx(header: &[u8]) -> Option<usize> {
+    let offset = u32_at(header, 180).unwrap_or(0) as usize;
+    if offset != 0 && header.get(offset..offset + 4) == Some(b"TAGX") {
+        return Some(offset);
+    }
+    header.windows(4).position(|window| window == b"TAGX")
+}
+
+/// Decodes the tags of an entry from its control bytes and values.
+fn decode_tags(definitions: &[TagDefinition], control_bytes: &[u8], data: &[u8])
+        -> Vec<(u8, Vec<u32>)> {
+    // The number of values (or of bytes holding them) of every tag
+    let mut counts = Vec::new();
+    let mut control_byte = 0;
+    for definition in definitions.iter() {
+        if definition.end_flag == 1 {
+            control_byte += 1;
+            continue;
+        }
+        let masked = control_bytes.get(control_byte).cloned().unwrap_or(0) & definition.mask;
+        if masked == 0 {
+            continue;
+        }
+        if masked == definition.mask && definition.mask.count_ones() > 1 {
+            counts.push((definition, None));
+        } else {
+            let count = masked >> definition.mask.trailing_zeros();
+            counts.push((definition, Some(count as usize)));
+        }
+    }
+
+    let mut pos = 0;
+    let mut tags = Vec::new();
+    for (definition, count) in counts {
+        let mut values = Vec::new();
+        match count {
+            Some(count) => {
+                for _ in 0..count * definition.values_per_entry as usize {
+                    let (value, consumed) = decode_vwi(&data[::std::cmp::min(pos, data.len())..]);
+                    values.push(value);
+                    pos += consumed;
+                }
+            },
+            None => {
+                // A value holding the number of bytes of the values follows
+                let (byte_count, consumed) = decode_vwi(&data[::std::cmp::min(pos, data.len())..]);
+                pos += consumed;
+                let end = pos + byte_count as usize;
+                while pos < end && pos < data.len() {
+                    let (value, consumed) = decode_vwi(&data[pos..]);
+                    values.push(value);
+                    pos += consumed;
+                }
+            },
+        }
+        tags.push((definition.tag, values));
+    }
+    tags
+}
+
+/// Reads the strings of CNCX records, keyed by their offsets.
+fn read_cncx(records: &[Vec<u8>]) -> HashMap<u32, String> {
+    let mut strings = HashMap::new();
+    for (number, record) in records.iter().enumerate() {
+        let mut pos = 0;
+        while pos < record.len() {
+            let (length, consumed) = decode_vwi(&record[pos..]);
+            if length == 0 {
+                pos += consumed;
+                continue;
+            }
+            let end = ::std::cmp::min(pos + consumed + length as usize, record.len());
+            let text = String::from_utf8_lossy(&record[pos + consumed..end]).into_owned();
+            strings.insert(((number as u32) << 16) | pos as u32, text);
+            pos = end;
+        }
+    }
+    strings
+}
+
+/// Reads the index starting at the given record of the book.
+pub fn read_index(records: &[Vec<u8>], first: usize) -> Result<Index, io::Error> {
+    let header = match records.get(first) {
+        Some(header) if header.starts_with(b"INDX") => header,
+        _ => return Err(invalid("The index does not start with an INDX record")),
+    };
+    let index_type = try!(u32_at(header, 16));
+    let encoding = try!(u32_at(header, 28));
+    let ordt = try!(read_ordt(header));
+    let record_count = try!(u32_at(header, 24)) as usize;
+    let cncx_count = try!(u32_at(header, 52)) as usize;
+    let (definitions, control_byte_count) = try!(read_tagx(header));
+
+    let mut entries = Vec::new();
+    for number in first + 1..first + 1 + record_count {
+        let record = match records.get(number) {
+            Some(record) => record,
+            None => return Err(invalid("An index record is missing")),
+        };
+        let idxt = try!(u32_at(record, 20)) as usize;
+        let count = try!(u32_at(record, 24)) as usize;
+        if count > record.len().saturating_sub(idxt + 4) / 2 {
+            return Err(invalid("The IDXT table of an index record is truncated"));
+        }
+        let mut positions = Vec::with_capacity(count + 1);
+        for i in 0..count {
+            positions.push(try!(u16_at(record, idxt + 4 + 2 * i)) as usize);
+        }
+        positions.push(idxt);
+
+        for window in positions.windows(2) {
+            let (start, end) = (window[0], ::std::cmp::min(window[1], record.len()));
+            if start >= end {
+                continue;
+            }
+            let entry = &record[start..end];
+            let label_end = ::std::cmp::min(1 + entry[0] as usize, entry.len());
+            let control_end = ::std::cmp::min(label_end + control_byte_count, entry.len());
+            entries.push(IndexEntry {
+                label: entry[1..label_end].to_vec(),
+                tags: decode_tags(&definitions, &entry[label_end..control_end],
+                    &entry[control_end..]),
+            });
+        }
+    }
+
+    let cncx_start = ::std::cmp::min(first + 1 + record_count, records.len());
+    let cncx_end = ::std::cmp::min(cncx_start + cncx_count, records.len());
+    Ok(Index {
+        index_type: index_type,
+        encoding: encoding,
+        ordt: ordt,
+        definitions: definitions,
+        entries: entries,
+        cncx: read_cncx(&records[cncx_start..cncx_end]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_entries() -> Vec<Vec<u8>> {
+        let entries = [IndexEntry::new("a"), IndexEntry::new("b")];
+        write_index(&[], &entries, &Cncx::new(), 0)
+    }
+
+    #[test]
+    fn sound_indices() {
+        let index = read_index(&two_entries(), 0).unwrap();
+        let labels = index.entries.iter().map(|entry| &entry.label[..]).collect::<Vec<_>>();
+        assert_eq!(labels, vec![&b"a"[..], &b"b"[..]]);
+    }
+
+    #[test]
+    fn huge_counts() {
+        let mut records = two_entries();
+        records[1][24..28].copy_from_slice(&[0xFF; 4]);
+        let error = read_index(&records, 0).err().unwrap();
+        assert_eq!(error.to_string(), "The IDXT table of an index record is truncated");
+
+        let mut records = two_entries();
+        let start = records[0].len();
+        records[0].extend_from_slice(b"ORDT");
+        for &(offset, value) in &[(0xA4, 1), (0xA8, 0x7FFFFFFF), (0xB0, start as u32)] {
+            write_u32_be(&mut &mut records[0][offset..offset + 4], value).unwrap();
+        }
+        let error = read_index(&records, 0).err().unwrap();
+        assert_eq!(error.to_string(), "The ORDT table of the index is truncated");
+    }
+}
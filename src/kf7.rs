@@ -0,0 +1,239 @@
+//! KF7 (MOBI) markup: resolving the `filepos` links and `recindex` images,
+//! which point at byte offsets of the text and at resource records, into
+//! anchors, `#id` links and file names.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use book::{MobiBook, ResourceKind, Section};
+use common::*;
+use html;
+use html::{Token, Tokenizer};
+use mobi::TextEncoding;
+
+/// KF7 markup with its references resolved.
+#[derive(Debug, Clone)]
+pub struct ResolvedHtml {
+    /// The content of the body, in UTF-8.
+    pub body: Vec<u8>,
+    /// The id of the anchor inserted for every link target, by its byte
+    /// offset in the text.
+    pub anchors: BTreeMap<usize, String>,
+    /// The file name of every image, by its recindex (from 1).
+    pub resources: BTreeMap<usize, String>,
+    /// The references of the guide: their type, title and target offset.
+    pub guide: Vec<(String, String, usize)>,
+}
+
+/// Returns the file extension of an image type.
+pub fn image_extension(mime: &str) -> &'static str {
+    match mime {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/bmp" => "bmp",
+        _ => "bin",
+    }
+}
+
+/// Returns the file name of an image, by its resource index (from 0), such
+/// as "images/image00001.jpg" for recindex 1.
+pub fn image_name(index: usize, mime: &str) -> String {
+    format!("images/image{:05}.{}", index + 1, image_extension(mime))
+}
+
+/// Returns the file names of the images of a book, by resource index.
+pub fn image_names(book: &MobiBook) -> Result<HashMap<usize, String>, io::Error> {
+    Ok(try!(book.resources()).into_iter().filter_map(|(index, kind, _)| match kind {
+        ResourceKind::Image(mime) => Some((index, image_name(index, mime))),
+        _ => None,
+    }).collect())
+}
+
+/// Returns the id of the anchor for a byte offset of the text.
+pub fn anchor_id(position: usize) -> String {
+    format!("filepos{}", position)
+}
+
+/// Resolves the references of KF7 text. Anchors are inserted where filepos
+/// links and the given extra targets (such as the NCX offsets) point to,
+/// and filepos links become `#id` links. Recindex attributes become image
+/// sources relative to 'base', the name of the resulting document, from the
+/// names of the resources by index (from 0 at the first image record).
+pub fn resolve(text: &[u8], encoding: TextEncoding, targets: &[usize],
+        resources: &HashMap<usize, String>, base: &str) -> ResolvedHtml {
+    // Find the body and the link targets
+    let mut body_start = 0;
+    let mut body_end = text.len();
+    let mut targets = targets.to_vec();
+    let mut guide = Vec::new();
+    for (range, token) in Tokenizer::new(text) {
+        match token {
+            Token::StartTag(ref tag) => {
+                if tag.name == "body" && body_start == 0 {
+                    body_start = range.end;
+                }
+                let position = tag.attribute("filepos").and_then(|pos| pos.trim().parse().ok());
+                if let Some(position) = position {
+                    targets.push(position);
+                }
+                if tag.name == "reference" {
+                    if let (Some(kind), Some(position)) = (tag.attribute("type"), position) {
+                        let title = tag.attribute("title").unwrap_or(kind).to_string();
+                        guide.push((kind.to_string(), title, position));
+                    }
+                }
+            },
+            Token::EndTag(ref name) if name == "body" => body_end = range.start,
+            _ => {},
+        }
+    }
+    if body_end < body_start {
+        body_end = text.len();
+    }
+
+    // Anchors outside of the body are moved to its edges
+    let mut anchors = targets.iter()
+        .map(|&target| (::std::cmp::min(::std::cmp::max(target, body_start), body_end), target))
+        .collect::<Vec<_>>();
+    anchors.sort();
+    anchors.dedup();
+    let anchor_html = |target: usize| format!("<a id=\"{}\"></a>", anchor_id(target)).into_bytes();
+
+    let mut body = Vec::with_capacity(body_end - body_start);
+    let mut names = BTreeMap::new();
+    let mut next = 0;
+    for (range, token) in Tokenizer::new(text) {
+        if range.start < body_start || range.start >= body_end {
+            continue;
+        }
+        let mut tag = match token {
+            Token::StartTag(tag) => tag,
+            Token::Text(_) => {
+                // Anchors inside text go where they point to
+                let mut start = range.start;
+                while next < anchors.len() && anchors[next].0 < range.end {
+                    let position = ::std::cmp::max(anchors[next].0, start);
+                    body.extend_from_slice(&text[start..position]);
+                    body.extend(anchor_html(anchors[next].1));
+                    start = position;
+                    next += 1;
+                }
+                body.extend_from_slice(&text[start..range.end]);
+                continue;
+            },
+            _ => {
+                body.extend_from_slice(&text[range]);
+                continue;
+            }
+        };
+        while next < anchors.len() && anchors[next].0 < range.end {
+            body.extend(anchor_html(anchors[next].1));
+            next += 1;
+        }
+
+        // Positions are usually padded with zeros, unlike the anchor ids
+        if let Some(position) = tag.remove_attribute("filepos") {
+            let id = match position.trim().parse() {
+                Ok(position) => anchor_id(position),
+                Err(_) => format!("filepos{}", position.trim()),
+            };
+            tag.set_attribute("href", &format!("#{}", id));
+        }
+        if let Some(recindex) = tag.remove_attribute("recindex") {
+            let index = recindex.trim().parse::<usize>().ok();
+            let name = index.and_then(|index| resources.get(&index.saturating_sub(1)));
+            if let (Some(index), Some(name)) = (index, name) {
+                tag.set_attribute("src", &html::relative_href(base, name));
+                names.insert(index, name.clone());
+            }
+        }
+        tag.remove_attribute("hirecindex");
+        tag.remove_attribute("lorecindex");
+        body.extend_from_slice(tag.to_html().as_bytes());
+    }
+    while next < anchors.len() {
+        body.extend(anchor_html(anchors[next].1));
+        next += 1;
+    }
+
+    let body = match encoding {
+        TextEncoding::Latin1 => decode_cp1252(&body).into_bytes(),
+        _ => body,
+    };
+    ResolvedHtml {
+        body: body,
+        anchors: anchors.iter().map(|&(_, target)| (target, anchor_id(target))).collect(),
+        resources: names,
+        guide: guide,
+    }
+}
+
+/// Resolves the references of the text of a KF7 section, with anchors for
+/// its NCX entries and the images named by `image_names`. Image sources are
+/// relative to 'base', the name of the resulting document.
+pub fn resolve_section(book: &MobiBook, section: &Section, base: &str)
+        -> Result<ResolvedHtml, io::Error> {
+    let text = try!(book.text(section));
+    let targets = try!(book.ncx(section)).iter()
+        .map(|entry| entry.offset as usize)
+        .collect::<Vec<_>>();
+    Ok(resolve(&text, section.header.text_encoding, &targets, &try!(image_names(book)), base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_utf8(text: &str, targets: &[usize]) -> (String, ResolvedHtml) {
+        let mut resources = HashMap::new();
+        resources.insert(0, "images/image00001.jpg".to_string());
+        resources.insert(2, "images/image00003.png".to_string());
+        let resolved = resolve(text.as_bytes(), TextEncoding::UTF8, targets, &resources,
+            "text/part0000.xhtml");
+        (String::from_utf8(resolved.body.clone()).unwrap(), resolved)
+    }
+
+    #[test]
+    fn anchors_inside_text() {
+        let text = "<html><body><p>Hello world</p></body></html>";
+        let world = text.find("world").unwrap();
+        let (body, resolved) = resolve_utf8(text, &[world]);
+        assert_eq!(body, format!("<p>Hello <a id=\"filepos{}\"></a>world</p>", world));
+        assert_eq!(resolved.anchors.get(&world), Some(&anchor_id(world)));
+    }
+
+    #[test]
+    fn filepos_links() {
+        let html = |position: usize| format!("<html><head><guide><reference type=\"toc\" \
+            title=\"Contents\" filepos=\"0000000006\"/></guide></head><body><p>\
+            <a filepos=\"{:010}\">Go</a></p><p>Target</p></body></html>", position);
+        let target = html(0).find("<p>Target").unwrap();
+        let (body, resolved) = resolve_utf8(&html(target), &[]);
+        // The guide points into the head, so its anchor starts the body
+        assert_eq!(body, format!("<a id=\"filepos6\"></a><p><a href=\"#filepos{0}\">Go</a></p>\
+            <a id=\"filepos{0}\"></a><p>Target</p>", target));
+        assert_eq!(resolved.guide, vec![("toc".to_string(), "Contents".to_string(), 6)]);
+    }
+
+    #[test]
+    fn targets_outside_the_body() {
+        let text = "<html><head><title>T</title></head><body><p>Text</p></body></html>";
+        let (body, resolved) = resolve_utf8(text, &[3, 1000]);
+        assert_eq!(body, "<a id=\"filepos3\"></a><p>Text</p><a id=\"filepos1000\"></a>");
+        assert_eq!(resolved.anchors.keys().cloned().collect::<Vec<_>>(), vec![3, 1000]);
+    }
+
+    #[test]
+    fn image_sources() {
+        let text = "<html><body><img recindex=\"00001\" alt=\"a\"/><img hirecindex=\"2\" \
+            recindex=\"3\"/><img recindex=\"9\"/></body></html>";
+        let (body, resolved) = resolve_utf8(text, &[]);
+        assert_eq!(body.matches("src=\"../images/image00001.jpg\"").count(), 1);
+        assert_eq!(body.matches("src=\"../images/image00003.png\"").count(), 1);
+        assert!(!body.contains("recindex"));
+        let names = resolved.resources.values().cloned().collect::<Vec<_>>();
+        assert_eq!(resolved.resources.keys().cloned().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(names, vec!["images/image00001.jpg", "images/image00003.png"]);
+        assert_eq!(image_name(2, "image/png"), "images/image00003.png");
+    }
+}
@@ -0,0 +1,301 @@
+//! KF8 (AZW3) building blocks: base-32 numbers, `kindle:` URIs, the FDST
+//! flow table and FONT records.
+
+use std::io;
+use std::io::Read;
+use flate2::read::ZlibDecoder;
+use common::*;
+use html;
+
+const BASE32_DIGITS: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Formats a number in the base 32 used by KF8, padded with zeros.
+pub fn to_base32(value: u32, min_digits: usize) -> String {
+    let mut digits = Vec::new();
+    let mut rest = value;
+    loop {
+        digits.push(BASE32_DIGITS[(rest % 32) as usize]);
+        rest /= 32;
+        if rest == 0 {
+            break;
+        }
+    }
+    while digits.len() < min_digits {
+        digits.push(b'0');
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// Parses a base-32 number, as used in `kindle:` URIs.
+pub fn from_base32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 32).ok()
+}
+
+/// The URI of a position: a fragment (by its index in the FRAG index) and
+/// an offset within it. It always has the same length.
+pub fn pos_uri(fragment: u32, offset: u32) -> String {
+    format!("kindle:pos:fid:{}:off:{}", to_base32(fragment, 4), to_base32(offset, 10))
+}
+
+/// The URI of a resource, numbered from 1 from the first resource record.
+pub fn embed_uri(resource: usize, mime: Option<&str>) -> String {
+    match mime {
+        Some(mime) => format!("kindle:embed:{}?mime={}", to_base32(resource as u32 + 1, 4), mime),
+        None => format!("kindle:embed:{}", to_base32(resource as u32 + 1, 4)),
+    }
+}
+
+/// The URI of a flow (by its index in the FDST table).
+pub fn flow_uri(flow: usize, mime: &str) -> String {
+    format!("kindle:flow:{}?mime={}", to_base32(flow as u32, 4), mime)
+}
+
+/// Returns the MIME type belonging to a file name.
+pub fn mime_type(name: &str) -> &'static str {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match &extension[..] {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "ttf" => "application/x-font-truetype",
+        "otf" => "application/vnd.ms-opentype",
+        "woff" => "application/font-woff",
+        "css" => "text/css",
+        "html" | "htm" | "xhtml" => "application/xhtml+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Builds the FDST record from the (start, end) offsets of the flows.
+pub fn fdst_record(flows: &[(u32, u32)]) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(b"FDST");
+    write_u32_be(&mut record, 12).unwrap();
+    write_u32_be(&mut record, flows.len() as u32).unwrap();
+    for &(start, end) in flows.iter() {
+        write_u32_be(&mut record, start).unwrap();
+        write_u32_be(&mut record, end).unwrap();
+    }
+    record
+}
+
+/// Builds a FONT record holding the font as it is (neither compressed nor
+/// obfuscated).
+pub fn font_record(font: &[u8]) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(b"FONT");
+    // Decoded size, flags, data offset, XOR key length and XOR key offset
+    for &value in &[font.len() as u32, 0, 24, 0, 24] {
+        write_u32_be(&mut record, value).unwrap();
+    }
+    record.extend_from_slice(font);
+    record
+}
+
+/// Reads the (start, end) offsets of the flows from the FDST record.
+pub fn read_fdst(record: &[u8]) -> Result<Vec<(u32, u32)>, io::Error> {
+    if !record.starts_with(b"FDST") || record.len() < 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid FDST record"));
+    }
+    let start = try!(read_u32_be(&mut &record[4..])) as usize;
+    let count = try!(read_u32_be(&mut &record[8..])) as usize;
+    if count > record.len().saturating_sub(start) / 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The FDST record is truncated"));
+    }
+    let mut flows = Vec::with_capacity(count);
+    for i in 0..count {
+        let offset = start + 8 * i;
+        let flow_start = try!(read_u32_be(&mut &record[offset..]));
+        let flow_end = try!(read_u32_be(&mut &record[offset + 4..]));
+        flows.push((flow_start, flow_end));
+    }
+    Ok(flows)
+}
+
+/// The FONT record flag of fonts compressed with zlib.
+pub const FONT_COMPRESSED: u32 = 0x01;
+/// The FONT record flag of fonts obfuscated with a XOR key.
+pub const FONT_OBFUSCATED: u32 = 0x02;
+/// The number of bytes at the start of an obfuscated font that are XORed.
+const OBFUSCATED_LENGTH: usize = 1040;
+
+/// The header of a FONT record.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FontHeader {
+    /// The size of the font once decoded.
+    pub decoded_size: u32,
+    pub flags: u32,
+    pub data_offset: u32,
+    pub xor_key_length: u32,
+    pub xor_key_offset: u32,
+}
+
+/// Reads the header of a FONT record.
+pub fn read_font_header(record: &[u8]) -> Result<FontHeader, io::Error> {
+    if !record.starts_with(b"FONT") || record.len() < 24 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid FONT record"));
+    }
+    let mut fields = &record[4..];
+    Ok(FontHeader {
+        decoded_size: try!(read_u32_be(&mut fields)),
+        flags: try!(read_u32_be(&mut fields)),
+        data_offset: try!(read_u32_be(&mut fields)),
+        xor_key_length: try!(read_u32_be(&mut fields)),
+        xor_key_offset: try!(read_u32_be(&mut fields)),
+    })
+}
+
+/// Returns the font held by a FONT record, as a TTF or OTF file. The start
+/// of obfuscated fonts is XORed with the key, and then compressed fonts are
+/// inflated.
+pub fn decode_font_record(record: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let header = try!(read_font_header(record));
+    let mut font = match record.get(header.data_offset as usize..) {
+        Some(data) => data.to_vec(),
+        None => return Err(invalid("The font data is outside of the FONT record")),
+    };
+
+    if header.flags & FONT_OBFUSCATED != 0 {
+        let key_start = header.xor_key_offset as usize;
+        let key = match record.get(key_start..key_start + header.xor_key_length as usize) {
+            Some(key) if !key.is_empty() => key,
+            _ => return Err(invalid("The XOR key is outside of the FONT record")),
+        };
+        for (i, byte) in font.iter_mut().take(OBFUSCATED_LENGTH).enumerate() {
+            *byte ^= key[i % key.len()];
+        }
+    }
+
+    if header.flags & FONT_COMPRESSED != 0 {
+        let mut inflated = Vec::with_capacity(header.decoded_size as usize);
+        try!(ZlibDecoder::new(&font[..]).read_to_end(&mut inflated));
+        if inflated.len() != header.decoded_size as usize {
+            return Err(invalid("The inflated font does not have the size of the header"));
+        }
+        font = inflated;
+    }
+    Ok(font)
+}
+
+/// Returns the font held by a FONT record, if it can be decoded.
+pub fn read_font_record(record: &[u8]) -> Option<Vec<u8>> {
+    decode_font_record(record).ok()
+}
+
+/// Returns the file extension of a font: "otf", "ttf", "woff", or "dat"
+/// when the format is unknown.
+pub fn font_extension(font: &[u8]) -> &'static str {
+    if font.starts_with(b"OTTO") {
+        "otf"
+    } else if font.starts_with(b"wOFF") {
+        "woff"
+    } else if [&b"\0\x01\0\0"[..], b"true", b"ttcf"].iter().any(|magic| font.starts_with(magic)) {
+        "ttf"
+    } else {
+        "dat"
+    }
+}
+
+/// Parses a `kindle:pos:fid:XXXX:off:YYYYYYYYYY` URI into the fragment and
+/// the offset.
+pub fn parse_pos_uri(uri: &str) -> Option<(u32, u32)> {
+    let rest = match uri.find("kindle:pos:fid:") {
+        Some(start) => &uri[start + 15..],
+        None => return None,
+    };
+    let mut parts = rest.splitn(3, ':');
+    let fid = parts.next().and_then(from_base32);
+    let off = match (parts.next(), parts.next()) {
+        (Some("off"), Some(offset)) => {
+            let digits = offset.chars().take_while(|c| c.is_ascii_alphanumeric()).count();
+            from_base32(&offset[..digits])
+        },
+        _ => None,
+    };
+    match (fid, off) {
+        (Some(fid), Some(off)) => Some((fid, off)),
+        _ => None,
+    }
+}
+
+/// Parses the number of a `kindle:embed:XXXX` or `kindle:flow:XXXX` URI.
+/// For resources it is the resource index plus one.
+pub fn parse_uri_number(uri: &str, kind: &str) -> Option<u32> {
+    let prefix = format!("kindle:{}:", kind);
+    if !uri.starts_with(&prefix) {
+        return None;
+    }
+    let rest = &uri[prefix.len()..];
+    let digits = rest.chars().take_while(|c| c.is_ascii_alphanumeric()).count();
+    from_base32(&rest[..digits])
+}
+
+/// Replaces the `kindle:` URIs in the data with what 'replace' returns for
+/// them. URIs it returns None for are kept.
+pub fn replace_uris(data: &[u8], replace: &mut FnMut(&str) -> Option<String>) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while let Some(start) = html::find_bytes(&data[pos..], b"kindle:").map(|i| i + pos) {
+        let end = data[start..].iter()
+            .position(|&byte| b"\"'()<> \t\r\n".contains(&byte))
+            .map_or(data.len(), |i| i + start);
+        output.extend_from_slice(&data[pos..start]);
+        let uri = String::from_utf8_lossy(&data[start..end]).into_owned();
+        match replace(&uri) {
+            Some(replacement) => output.extend_from_slice(replacement.as_bytes()),
+            None => output.extend_from_slice(&data[start..end]),
+        }
+        pos = end;
+    }
+    output.extend_from_slice(&data[pos..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FDST record of three flows, laid out like calibre's.
+    const FDST: &'static [u8] = include_bytes!("../tests/fixtures/fdst.dat");
+
+    #[test]
+    fn fdst_records() {
+        let flows = read_fdst(FDST).unwrap();
+        assert_eq!(flows, vec![(0, 8211), (8211, 8380), (8380, 9012)]);
+        assert_eq!(fdst_record(&flows), FDST);
+        assert!(read_fdst(&FDST[..FDST.len() - 4]).is_err());
+        assert_eq!(read_fdst(&fdst_record(&[])).unwrap(), Vec::new());
+        let mut huge = FDST.to_vec();
+        huge[8..12].copy_from_slice(&[0xFF; 4]);
+        assert!(read_fdst(&huge).is_err());
+    }
+
+    /// An obfuscated and compressed FONT record, laid out like kindlegen's.
+    const FONT: &'static [u8] = include_bytes!("../tests/fixtures/font.dat");
+
+    #[test]
+    fn font_records() {
+        let header = read_font_header(FONT).unwrap();
+        assert_eq!(header.flags, FONT_COMPRESSED | FONT_OBFUSCATED);
+        assert_eq!((header.xor_key_offset, header.xor_key_length), (24, 16));
+        let mut expected = b"\0\x01\0\0".to_vec();
+        expected.extend((0..5000).map(|i| (i * 7 % 251) as u8));
+        let font = decode_font_record(FONT).unwrap();
+        assert_eq!(font, expected);
+        assert_eq!(font_extension(&font), "ttf");
+
+        // A wrong decoded size, or key, is an error
+        let mut record = FONT.to_vec();
+        record[7] ^= 1;
+        assert!(decode_font_record(&record).is_err());
+        record = FONT.to_vec();
+        record[24] ^= 1;
+        assert!(decode_font_record(&record).is_err());
+
+        assert_eq!(decode_font_record(&font_record(&font)).unwrap(), font);
+    }
+}
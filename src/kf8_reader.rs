@@ -0,0 +1,318 @@
+//! Reading the text of KF8 books: the flows of the FDST table, and the
+//! documents rebuilt from the skeleton and fragment indices.
+
+use std::collections::HashMap;
+use std::io;
+use book::{MobiBook, Section};
+use html;
+use kf8;
+
+/// An entry of the fragment (FRAG) index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentEntry {
+    /// The position in the text where the fragment goes.
+    pub insert_pos: u32,
+    pub selector: String,
+    pub file_number: u32,
+    pub sequence_number: u32,
+    /// The position of the fragment data after the end of the skeleton.
+    pub start_pos: u32,
+    pub length: u32,
+}
+
+/// An entry of the skeleton (SKEL) index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkeletonEntry {
+    pub name: String,
+    pub fragment_count: u32,
+    pub start_pos: u32,
+    pub length: u32,
+}
+
+/// A rebuilt document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub file_number: u32,
+    /// The position of the document in the text, and its length once rebuilt.
+    /// Positions inside the document are counted from 'start'.
+    pub start: u32,
+    pub length: u32,
+    pub data: Vec<u8>,
+}
+
+/// What a flow holds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FlowKind {
+    Html,
+    Css,
+    Svg,
+}
+
+impl FlowKind {
+    /// Identifies a flow by its number and content. The first flow is the
+    /// HTML, and the others are stylesheets unless they hold an SVG image.
+    pub fn of(number: usize, data: &[u8]) -> FlowKind {
+        if number == 0 {
+            FlowKind::Html
+        } else if html::find_bytes(&data[..data.len().min(1024)], b"<svg").is_some() {
+            FlowKind::Svg
+        } else {
+            FlowKind::Css
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match *self {
+            FlowKind::Html => "text/html",
+            FlowKind::Css => "text/css",
+            FlowKind::Svg => "image/svg+xml",
+        }
+    }
+}
+
+/// A flow of the text of a KF8 section, as listed by the FDST record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flow {
+    pub number: usize,
+    pub kind: FlowKind,
+    pub data: Vec<u8>,
+}
+
+impl Flow {
+    /// Returns the file name of the flow, such as "styles/style0001.css" or
+    /// "images/flow0002.svg".
+    pub fn name(&self) -> String {
+        match self.kind {
+            FlowKind::Html => format!("text/flow{:04}.html", self.number),
+            FlowKind::Css => format!("styles/style{:04}.css", self.number),
+            FlowKind::Svg => format!("images/flow{:04}.svg", self.number),
+        }
+    }
+}
+
+/// Returns the file names of the flows after the HTML, by number.
+pub fn flow_names(flows: &[Flow]) -> HashMap<usize, String> {
+    flows.iter().skip(1).map(|flow| (flow.number, flow.name())).collect()
+}
+
+/// Returns the relative link, from the file named 'base', to the file of
+/// the flow that a `kindle:flow:XXXX?mime=...` URI points to, from the
+/// names of the flows by number.
+pub fn resolve_flow_uri(uri: &str, base: &str, names: &HashMap<usize, String>) -> Option<String> {
+    kf8::parse_uri_number(uri, "flow")
+        .and_then(|number| names.get(&(number as usize)))
+        .map(|name| html::relative_href(base, name))
+}
+
+/// Replaces the `kindle:flow` URIs of a document or flow named 'base' with
+/// links to the files of the flows. Other URIs are kept.
+pub fn link_flows(data: &[u8], base: &str, names: &HashMap<usize, String>) -> Vec<u8> {
+    kf8::replace_uris(data, &mut |uri| resolve_flow_uri(uri, base, names))
+}
+
+/// The text of a KF8 section.
+#[derive(Debug, Clone, Default)]
+pub struct Kf8Text {
+    /// The flows of the text: the HTML first, then the stylesheets and SVG
+    /// images.
+    pub flows: Vec<Vec<u8>>,
+    pub skeletons: Vec<SkeletonEntry>,
+    pub fragments: Vec<FragmentEntry>,
+    pub parts: Vec<Part>,
+}
+
+impl Kf8Text {
+    /// Returns the flows of the text, with their kinds.
+    pub fn flow_list(&self) -> Vec<Flow> {
+        self.flows.iter().enumerate().map(|(number, data)| Flow {
+            number: number,
+            kind: FlowKind::of(number, data),
+            data: data.clone(),
+        }).collect()
+    }
+
+    /// Returns the file names of the flows after the HTML, by number.
+    pub fn flow_names(&self) -> HashMap<usize, String> {
+        flow_names(&self.flow_list())
+    }
+
+    /// Returns the text position of a fragment and an offset in it.
+    pub fn position(&self, fid: u32, offset: u32) -> Option<u32> {
+        self.fragments.get(fid as usize).map(|fragment| fragment.insert_pos + offset)
+    }
+
+    /// Returns the document containing a text position, and the offset of
+    /// the position in it.
+    pub fn locate(&self, position: u32) -> Option<(usize, usize)> {
+        self.parts.iter()
+            .position(|part| part.start <= position && position < part.start + part.length)
+            .or_else(|| if self.parts.is_empty() { None } else { Some(self.parts.len() - 1) })
+            .map(|index| {
+                let part = &self.parts[index];
+                let offset = position.saturating_sub(part.start);
+                (index, ::std::cmp::min(offset, part.length) as usize)
+            })
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the skeleton entries of a section.
+fn read_skeletons(book: &MobiBook, section: &Section, record: u32)
+        -> Result<Vec<SkeletonEntry>, io::Error> {
+    let index = try!(book.index(section, record));
+    Ok(index.entries.iter().map(|entry| {
+        let geometry = entry.values(6).unwrap_or(&[]);
+        SkeletonEntry {
+            name: String::from_utf8_lossy(&entry.label).into_owned(),
+            fragment_count: entry.value(1).unwrap_or(0),
+            start_pos: geometry.get(0).cloned().unwrap_or(0),
+            length: geometry.get(1).cloned().unwrap_or(0),
+        }
+    }).collect())
+}
+
+/// Reads the fragment entries of a section.
+fn read_fragments(book: &MobiBook, section: &Section, record: u32)
+        -> Result<Vec<FragmentEntry>, io::Error> {
+    let index = try!(book.index(section, record));
+    Ok(index.entries.iter().map(|entry| {
+        let geometry = entry.values(6).unwrap_or(&[]);
+        let label = String::from_utf8_lossy(&entry.label);
+        FragmentEntry {
+            insert_pos: label.trim().parse().unwrap_or(0),
+            selector: index.cncx_string(entry, 2).unwrap_or("").to_string(),
+            file_number: entry.value(3).unwrap_or(0),
+            sequence_number: entry.value(4).unwrap_or(0),
+            start_pos: geometry.get(0).cloned().unwrap_or(0),
+            length: geometry.get(1).cloned().unwrap_or(0),
+        }
+    }).collect())
+}
+
+/// Reads the text of a KF8 section, and rebuilds its documents by inserting
+/// the fragments into the skeletons.
+pub fn read_text(book: &MobiBook, section: &Section) -> Result<Kf8Text, io::Error> {
+    let kf8 = match section.header.kf8 {
+        Some(ref kf8) => kf8,
+        None => return Err(invalid("The section is not a KF8 section")),
+    };
+    let text = try!(book.text(section));
+
+    let fdst = kf8.fdst_record.and_then(|record| book.section_record(section, record));
+    let mut flow_table = match fdst {
+        Some(record) if kf8.fdst_count > 1 => try!(kf8::read_fdst(record)),
+        _ => Vec::new(),
+    };
+    // A FDST record that lists no flows is taken as a missing one
+    if flow_table.is_empty() {
+        flow_table.push((0, text.len() as u32));
+    }
+    let flows = flow_table.iter().map(|&(start, end)| {
+        let end = ::std::cmp::min(end as usize, text.len());
+        text[::std::cmp::min(start as usize, end)..end].to_vec()
+    }).collect::<Vec<_>>();
+
+    let skeletons = match kf8.skeleton_index {
+        Some(record) => try!(read_skeletons(book, section, record)),
+        None => Vec::new(),
+    };
+    let fragments = match kf8.fragment_index {
+        Some(record) => try!(read_fragments(book, section, record)),
+        None => Vec::new(),
+    };
+
+    let html = &flows[0];
+    let mut parts = Vec::with_capacity(skeletons.len());
+    let mut fragment_index = 0;
+    for (file_number, skeleton) in skeletons.iter().enumerate() {
+        let start = skeleton.start_pos as usize;
+        let mut base = start + skeleton.length as usize;
+        if base > html.len() {
+            return Err(invalid("A skeleton is outside of the text"));
+        }
+        let mut data = html[start..base].to_vec();
+        for _ in 0..skeleton.fragment_count {
+            let fragment = match fragments.get(fragment_index) {
+                Some(fragment) => fragment,
+                None => return Err(invalid("A fragment is missing")),
+            };
+            let end = ::std::cmp::min(base + fragment.length as usize, html.len());
+            let insert = (fragment.insert_pos as usize).saturating_sub(start);
+            let insert = ::std::cmp::min(insert, data.len());
+            let tail = data.split_off(insert);
+            data.extend_from_slice(&html[base..end]);
+            data.extend(tail);
+            base = end;
+            fragment_index += 1;
+        }
+        parts.push(Part {
+            file_number: file_number as u32,
+            start: skeleton.start_pos,
+            length: data.len() as u32,
+            data: data,
+        });
+    }
+    if parts.is_empty() {
+        parts.push(Part { file_number: 0, start: 0, length: html.len() as u32,
+            data: html.clone() });
+    }
+
+    Ok(Kf8Text {
+        flows: flows,
+        skeletons: skeletons,
+        fragments: fragments,
+        parts: parts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+    use writer::Resource;
+
+    #[test]
+    fn empty_flow_table() {
+        let mut writer = testing::kf8_writer(&["<html><body><p>Text</p></body></html>"]);
+        writer.stylesheets.push(Resource {
+            name: "style.css".into(),
+            data: b"p { margin: 0 }".to_vec(),
+        });
+        let mut book = testing::kf8_book(&writer);
+        let fdst = {
+            let section = book.kf8().unwrap();
+            section.record(section.header.kf8.as_ref().unwrap().fdst_record.unwrap())
+        };
+        book.replace_record(fdst, kf8::fdst_record(&[])).unwrap();
+
+        let text = read_text(&book, book.kf8().unwrap()).unwrap();
+        assert_eq!(text.flows.len(), 1);
+        assert_eq!(text.parts.len(), 1);
+    }
+
+    #[test]
+    fn stylesheet_flows() {
+        let mut writer = testing::kf8_writer(&["<html><head><link rel=\"stylesheet\" \
+            href=\"../style.css\"/></head><body><p>Text</p></body></html>"]);
+        writer.stylesheets.push(Resource {
+            name: "style.css".into(),
+            data: b"p { margin: 0 }".to_vec(),
+        });
+        let book = testing::kf8_book(&writer);
+
+        let flows = book.flows().unwrap();
+        assert_eq!(flows.iter().map(|flow| flow.kind).collect::<Vec<_>>(),
+            vec![FlowKind::Html, FlowKind::Css]);
+        assert_eq!(flows[1].data, b"p { margin: 0 }");
+        let names = flow_names(&flows);
+        assert_eq!(names[&1], "styles/style0001.css");
+
+        let text = read_text(&book, book.kf8().unwrap()).unwrap();
+        assert_eq!(text.flow_names(), names);
+        let part = link_flows(&text.parts[0].data, "text/part0000.xhtml", &names);
+        assert!(String::from_utf8(part).unwrap().contains("href=\"../styles/style0001.css\""));
+    }
+}
@@ -0,0 +1,743 @@
+//! Creation of KF8 (AZW3) books from XHTML documents, optionally joined with
+//! a KF7 version of the book for older readers.
+//!
+//! The text of each document is split into a skeleton (everything but the
+//! body content) and fragments of the body content, which the reader inserts
+//! back into the skeleton. Links point at positions inside the fragments.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use common::*;
+use exth_tags::ExthTag;
+use html;
+use html::{Token, Tokenizer};
+use indx;
+use indx::{Cncx, IndexEntry, TagDefinition};
+use kf8;
+use metadata::Metadata;
+use mobi::MobiHeader;
+use palmdb::PalmdbHeader;
+use writer;
+use writer::{MobiWriter, Resource};
+
+/// The preferred size of the fragments.
+const CHUNK_SIZE: usize = 8192;
+
+/// The record separating the KF7 and KF8 parts of a joint file.
+pub const BOUNDARY_RECORD: &'static [u8] = b"BOUNDARY";
+
+/// An entry of the table of contents.
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub title: String,
+    /// The target, relative to the root of the book.
+    pub href: String,
+    pub children: Vec<TocEntry>,
+}
+
+/// A reference of the guide, such as the table of contents ("toc") or the
+/// start of the text ("text").
+#[derive(Debug, Clone)]
+pub struct GuideEntry {
+    pub kind: String,
+    pub title: String,
+    /// The target, relative to the root of the book.
+    pub href: String,
+}
+
+/// Builds a KF8 book.
+/// Every file is named by its path relative to the root of the book, and the
+/// references between them are relative to the referring document.
+#[derive(Debug, Clone)]
+pub struct Kf8Writer {
+    pub metadata: Metadata,
+    /// The XHTML documents, in reading order.
+    pub parts: Vec<Resource>,
+    pub stylesheets: Vec<Resource>,
+    pub images: Vec<Resource>,
+    pub fonts: Vec<Resource>,
+    /// The index of the cover in 'images'.
+    pub cover: Option<usize>,
+    pub toc: Vec<TocEntry>,
+    pub guide: Vec<GuideEntry>,
+    /// Whether to write a joint file, with a KF7 version before the KF8 one.
+    pub joint: bool,
+}
+
+/// A document after the KF8 rewriting, with the positions needed to chunk it.
+struct RewrittenPart {
+    data: Vec<u8>,
+    anchors: HashMap<String, usize>,
+    /// (position of a link placeholder, target part, target id)
+    links: Vec<(usize, usize, Option<String>)>,
+    body_start: usize,
+    body_end: usize,
+}
+
+#[derive(Debug)]
+struct Fragment {
+    insert_pos: u32,
+    selector: String,
+    file_number: u32,
+    sequence_number: u32,
+    start_pos: u32,
+    length: u32,
+}
+
+#[derive(Debug)]
+struct Skeleton {
+    file_number: u32,
+    fragment_count: u32,
+    start_pos: u32,
+    length: u32,
+}
+
+/// The chunked text of every document.
+struct ChunkedText {
+    text: Vec<u8>,
+    skeletons: Vec<Skeleton>,
+    fragments: Vec<Fragment>,
+    parts: Vec<RewrittenPart>,
+}
+
+impl ChunkedText {
+    /// Returns the position in the text of a position in a rewritten part.
+    fn text_position(&self, part: usize, pos: usize) -> usize {
+        let info = &self.parts[part];
+        let skeleton = &self.skeletons[part];
+        let start = skeleton.start_pos as usize;
+        if pos < info.body_start {
+            start + pos
+        } else if pos < info.body_end {
+            start + skeleton.length as usize + pos - info.body_start
+        } else {
+            start + info.body_start + pos - info.body_end
+        }
+    }
+
+    /// Returns the fragment and the offset in it of a position in a part.
+    fn pos_fid(&self, part: usize, pos: usize) -> (u32, u32) {
+        let skeleton_start = self.skeletons[part].start_pos;
+        let absolute = skeleton_start + pos as u32;
+        let mut found = None;
+        for fragment in self.fragments.iter().filter(|f| f.file_number == part as u32) {
+            if found.is_none() || fragment.insert_pos <= absolute {
+                found = Some(fragment);
+            }
+        }
+        match found {
+            Some(fragment) if fragment.insert_pos <= absolute => {
+                (fragment.sequence_number, absolute - fragment.insert_pos)
+            },
+            Some(fragment) => (fragment.sequence_number, 0),
+            None => (0, 0),
+        }
+    }
+
+    /// Returns the part, and the position in it, that a link points to.
+    fn find_target(&self, part: usize, id: Option<&str>) -> usize {
+        let info = &self.parts[part];
+        id.and_then(|id| info.anchors.get(id).cloned()).unwrap_or(info.body_start)
+    }
+}
+
+impl Kf8Writer {
+    pub fn new(metadata: Metadata) -> Kf8Writer {
+        Kf8Writer {
+            metadata: metadata,
+            parts: Vec::new(),
+            stylesheets: Vec::new(),
+            images: Vec::new(),
+            fonts: Vec::new(),
+            cover: None,
+            toc: Vec::new(),
+            guide: Vec::new(),
+            joint: false,
+        }
+    }
+
+    fn find_part(&self, name: &str) -> Option<usize> {
+        self.parts.iter().position(|part| part.name == name)
+    }
+
+    /// Returns the resource records: the images, then the fonts.
+    fn resource_records(&self) -> Vec<Vec<u8>> {
+        let mut records = self.images.iter()
+            .map(|image| image.data.clone())
+            .collect::<Vec<_>>();
+        for font in self.fonts.iter() {
+            records.push(kf8::font_record(&font.data));
+        }
+        records
+    }
+
+    /// Returns the `kindle:embed` URI of the image or font at 'path'.
+    fn embed_uri(&self, path: &str) -> Option<String> {
+        if let Some(index) = self.images.iter().position(|image| image.name == path) {
+            return Some(kf8::embed_uri(index, Some(kf8::mime_type(path))));
+        }
+        self.fonts.iter().position(|font| font.name == path).map(|index| {
+            kf8::embed_uri(self.images.len() + index, None)
+        })
+    }
+
+    /// Rewrites the references of a stylesheet to resources.
+    fn rewrite_css(&self, name: &str, css: &[u8]) -> Vec<u8> {
+        let css = String::from_utf8_lossy(css);
+        let mut output = String::with_capacity(css.len());
+        let mut rest = &css[..];
+        while let Some(start) = rest.find("url(") {
+            output.push_str(&rest[..start + 4]);
+            rest = &rest[start + 4..];
+            let end = match rest.find(')') {
+                Some(end) => end,
+                None => break,
+            };
+            let target = rest[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+            match self.embed_uri(&html::resolve_href(name, target)) {
+                Some(uri) => output.push_str(&uri),
+                None => output.push_str(&rest[..end]),
+            }
+            rest = &rest[end..];
+        }
+        output.push_str(rest);
+        output.into_bytes()
+    }
+
+    /// Rewrites the references of a document to their KF8 forms, leaving
+    /// placeholders for the internal links.
+    fn rewrite_part(&self, index: usize) -> RewrittenPart {
+        let part = &self.parts[index];
+        let source = &part.data[..];
+        let mut result = RewrittenPart {
+            data: Vec::with_capacity(source.len()),
+            anchors: HashMap::new(),
+            links: Vec::new(),
+            body_start: 0,
+            body_end: source.len(),
+        };
+        let mut found_body = false;
+        let mut found_body_end = false;
+
+        for (range, token) in Tokenizer::new(source) {
+            let mut tag = match token {
+                Token::StartTag(tag) => tag,
+                Token::EndTag(ref name) if name == "body" && !found_body_end => {
+                    result.body_end = result.data.len();
+                    found_body_end = true;
+                    result.data.extend_from_slice(&source[range]);
+                    continue;
+                },
+                _ => {
+                    result.data.extend_from_slice(&source[range]);
+                    continue;
+                }
+            };
+
+            let mut changed = false;
+            if let Some(id) = tag.attribute("id").map(String::from) {
+                result.anchors.insert(id, result.data.len());
+            }
+            if tag.name == "body" && !found_body {
+                tag.set_attribute("aid", &kf8::to_base32(index as u32, 1));
+                changed = true;
+            }
+
+            for attribute in &["src", "xlink:href"] {
+                let uri = tag.attribute(attribute)
+                    .and_then(|src| self.embed_uri(&html::resolve_href(&part.name, src)));
+                if let Some(uri) = uri {
+                    tag.set_attribute(attribute, &uri);
+                    changed = true;
+                }
+            }
+
+            let href = tag.attribute("href").map(String::from);
+            if let Some(href) = href.filter(|href| !html::is_external(href)) {
+                let (path, fragment) = html::split_fragment(&href);
+                let target = if path.is_empty() {
+                    part.name.clone()
+                } else {
+                    html::resolve_href(&part.name, path)
+                };
+                if tag.name == "link" {
+                    let stylesheet = self.stylesheets.iter().position(|s| s.name == target);
+                    if let Some(stylesheet) = stylesheet {
+                        tag.set_attribute("href", &kf8::flow_uri(stylesheet + 1, "text/css"));
+                        changed = true;
+                    }
+                } else if let Some(target_part) = self.find_part(&target) {
+                    tag.set_attribute("href", &kf8::pos_uri(0, 0));
+                    changed = true;
+                    let html = tag.to_html();
+                    let offset = html.find("kindle:pos:").unwrap();
+                    result.links.push((result.data.len() + offset, target_part,
+                        fragment.map(String::from)));
+                }
+            }
+
+            if changed {
+                result.data.extend_from_slice(tag.to_html().as_bytes());
+            } else {
+                result.data.extend_from_slice(&source[range]);
+            }
+            if tag.name == "body" && !found_body {
+                result.body_start = result.data.len();
+                found_body = true;
+            }
+        }
+
+        if !found_body_end || result.body_end < result.body_start {
+            result.body_end = result.data.len();
+        }
+        result
+    }
+
+    /// Returns where the body content of a rewritten part can be split,
+    /// as offsets into the body content. Splits happen between the top-level
+    /// elements of the body, once a fragment reaches CHUNK_SIZE.
+    fn split_points(body: &[u8]) -> Vec<usize> {
+        let mut points = Vec::new();
+        let mut depth = 0usize;
+        let mut chunk_start = 0;
+        for (range, token) in Tokenizer::new(body) {
+            match token {
+                Token::StartTag(ref tag) => {
+                    if !tag.self_closing && !html::VOID_ELEMENTS.contains(&&tag.name[..]) {
+                        depth += 1;
+                    }
+                },
+                Token::EndTag(_) => {
+                    depth = depth.saturating_sub(1);
+                },
+                _ => {},
+            }
+            if depth == 0 && range.end - chunk_start >= CHUNK_SIZE && range.end < body.len() {
+                points.push(range.end);
+                chunk_start = range.end;
+            }
+        }
+        points
+    }
+
+    /// Rewrites and chunks every document, and resolves the internal links.
+    fn chunk_text(&self) -> ChunkedText {
+        let mut chunked = ChunkedText {
+            text: Vec::new(),
+            skeletons: Vec::new(),
+            fragments: Vec::new(),
+            parts: Vec::new(),
+        };
+
+        for index in 0..self.parts.len() {
+            let part = self.rewrite_part(index);
+            let body = &part.data[part.body_start..part.body_end];
+            let skeleton_start = chunked.text.len() as u32;
+            chunked.text.extend_from_slice(&part.data[..part.body_start]);
+            chunked.text.extend_from_slice(&part.data[part.body_end..]);
+            let skeleton_length = chunked.text.len() as u32 - skeleton_start;
+            chunked.text.extend_from_slice(body);
+
+            let mut bounds = vec![0];
+            bounds.extend(Kf8Writer::split_points(body));
+            bounds.push(body.len());
+            let selector = format!("P-//*[@aid='{}']", kf8::to_base32(index as u32, 1));
+            for window in bounds.windows(2) {
+                let sequence_number = chunked.fragments.len() as u32;
+                chunked.fragments.push(Fragment {
+                    insert_pos: skeleton_start + (part.body_start + window[0]) as u32,
+                    selector: selector.clone(),
+                    file_number: index as u32,
+                    sequence_number: sequence_number,
+                    start_pos: window[0] as u32,
+                    length: (window[1] - window[0]) as u32,
+                });
+            }
+
+            chunked.skeletons.push(Skeleton {
+                file_number: index as u32,
+                fragment_count: bounds.len() as u32 - 1,
+                start_pos: skeleton_start,
+                length: skeleton_length,
+            });
+            chunked.parts.push(part);
+        }
+
+        // The link placeholders have a fixed length, so they can be replaced
+        let mut replacements = Vec::new();
+        for (part_index, part) in chunked.parts.iter().enumerate() {
+            for &(pos, target, ref id) in part.links.iter() {
+                let target_pos = chunked.find_target(target, id.as_ref().map(|s| &s[..]));
+                let (fid, offset) = chunked.pos_fid(target, target_pos);
+                let text_pos = chunked.text_position(part_index, pos);
+                replacements.push((text_pos, kf8::pos_uri(fid, offset)));
+            }
+        }
+        for (pos, uri) in replacements {
+            chunked.text[pos..pos + uri.len()].copy_from_slice(uri.as_bytes());
+        }
+        chunked
+    }
+
+    /// Returns the position in the text and the (fragment, offset) of a
+    /// link target relative to the root of the book.
+    fn resolve_target(&self, chunked: &ChunkedText, href: &str) -> Option<(u32, (u32, u32))> {
+        let (path, fragment) = html::split_fragment(href);
+        self.find_part(path).map(|part| {
+            let pos = chunked.find_target(part, fragment);
+            let absolute = chunked.skeletons[part].start_pos + pos as u32;
+            (absolute, chunked.pos_fid(part, pos))
+        })
+    }
+
+    fn skeleton_index(chunked: &ChunkedText) -> Vec<Vec<u8>> {
+        let definitions = [
+            TagDefinition::new(1, 1, 0x03), // Fragment count
+            TagDefinition::new(6, 2, 0x0C), // Geometry
+            TagDefinition::end(),
+        ];
+        let entries = chunked.skeletons.iter().map(|skeleton| {
+            // These values are repeated, as kindlegen does
+            IndexEntry::new(&format!("SKEL{:010}", skeleton.file_number))
+                .tag(1, vec![skeleton.fragment_count, skeleton.fragment_count])
+                .tag(6, vec![skeleton.start_pos, skeleton.length,
+                    skeleton.start_pos, skeleton.length])
+        }).collect::<Vec<_>>();
+        indx::write_index(&definitions, &entries, &Cncx::new(), 0)
+    }
+
+    fn fragment_index(chunked: &ChunkedText) -> Vec<Vec<u8>> {
+        let definitions = [
+            TagDefinition::new(2, 1, 0x01), // Selector (CNCX offset)
+            TagDefinition::new(3, 1, 0x02), // File number
+            TagDefinition::new(4, 1, 0x04), // Sequence number
+            TagDefinition::new(6, 2, 0x08), // Geometry
+            TagDefinition::end(),
+        ];
+        let mut cncx = Cncx::new();
+        let entries = chunked.fragments.iter().map(|fragment| {
+            IndexEntry::new(&format!("{:010}", fragment.insert_pos))
+                .tag(2, vec![cncx.add(&fragment.selector)])
+                .tag(3, vec![fragment.file_number])
+                .tag(4, vec![fragment.sequence_number])
+                .tag(6, vec![fragment.start_pos, fragment.length])
+        }).collect::<Vec<_>>();
+        indx::write_index(&definitions, &entries, &cncx, 0)
+    }
+
+    fn ncx_index(&self, chunked: &ChunkedText, flow_length: u32) -> Vec<Vec<u8>> {
+        // Entries are ordered by depth, then by their order in the book
+        struct Entry<'a> {
+            toc: &'a TocEntry,
+            depth: u32,
+            parent: Option<usize>,
+            children: Vec<usize>,
+        }
+        let mut entries: Vec<Entry> = self.toc.iter()
+            .map(|toc| Entry { toc: toc, depth: 0, parent: None, children: Vec::new() })
+            .collect();
+        let mut i = 0;
+        while i < entries.len() {
+            let (toc, depth) = (entries[i].toc, entries[i].depth);
+            for child in toc.children.iter() {
+                let child_index = entries.len();
+                entries[i].children.push(child_index);
+                entries.push(Entry { toc: child, depth: depth + 1, parent: Some(i),
+                    children: Vec::new() });
+            }
+            i += 1;
+        }
+
+        let targets = entries.iter()
+            .map(|entry| self.resolve_target(chunked, &entry.toc.href).unwrap_or((0, (0, 0))))
+            .collect::<Vec<_>>();
+        let mut offsets = targets.iter().map(|&(offset, _)| offset).collect::<Vec<_>>();
+        offsets.sort();
+
+        let definitions = [
+            TagDefinition::new(1, 1, 0x01), // Offset
+            TagDefinition::new(2, 1, 0x02), // Length
+            TagDefinition::new(3, 1, 0x04), // Label (CNCX offset)
+            TagDefinition::new(4, 1, 0x08), // Depth
+            TagDefinition::new(21, 1, 0x10), // Parent
+            TagDefinition::new(22, 1, 0x20), // First child
+            TagDefinition::new(23, 1, 0x40), // Last child
+            TagDefinition::new(6, 2, 0x80), // Position (fragment, offset)
+            TagDefinition::end(),
+        ];
+        let width = ::std::cmp::max(2, format!("{:X}", entries.len()).len());
+        let mut cncx = Cncx::new();
+        let index_entries = entries.iter().zip(targets.iter()).enumerate()
+            .map(|(i, (entry, &(offset, (fid, fid_offset))))| {
+                let end = offsets.iter().cloned().find(|&o| o > offset).unwrap_or(flow_length);
+                let mut index_entry = IndexEntry::new(&format!("{:0width$X}", i, width = width))
+                    .tag(1, vec![offset])
+                    .tag(2, vec![end - offset])
+                    .tag(3, vec![cncx.add(&entry.toc.title)])
+                    .tag(4, vec![entry.depth]);
+                if let Some(parent) = entry.parent {
+                    index_entry = index_entry.tag(21, vec![parent as u32]);
+                }
+                if let (Some(&first), Some(&last)) = (entry.children.first(), entry.children.last()) {
+                    index_entry = index_entry
+                        .tag(22, vec![first as u32])
+                        .tag(23, vec![last as u32]);
+                }
+                index_entry.tag(6, vec![fid, fid_offset])
+            })
+            .collect::<Vec<_>>();
+        indx::write_index(&definitions, &index_entries, &cncx, 0)
+    }
+
+    fn guide_index(&self, chunked: &ChunkedText) -> Vec<Vec<u8>> {
+        let definitions = [
+            TagDefinition::new(1, 1, 0x01), // Title (CNCX offset)
+            TagDefinition::new(6, 2, 0x02), // Position (fragment, offset)
+            TagDefinition::end(),
+        ];
+        let mut cncx = Cncx::new();
+        let entries = self.guide.iter()
+            .filter_map(|entry| {
+                self.resolve_target(chunked, &entry.href).map(|(_, (fid, offset))| {
+                    IndexEntry::new(&entry.kind)
+                        .tag(1, vec![cncx.add(&entry.title)])
+                        .tag(6, vec![fid, offset])
+                })
+            })
+            .collect::<Vec<_>>();
+        indx::write_index(&definitions, &entries, &cncx, 0)
+    }
+
+    /// Returns the EXTH tags of the KF8 record 0.
+    fn exth_tags(&self) -> Vec<ExthTag> {
+        let mut tags = self.metadata.to_exth_tags();
+        tags.push(ExthTag::CDEType(String::from("EBOK")));
+        tags.extend(writer::creator_tags());
+        if let Some(cover) = self.cover {
+            tags.push(ExthTag::CoverOffset(cover as u32));
+            tags.push(ExthTag::ThumbnailOffset(cover as u32));
+            tags.push(ExthTag::HasFakeCover(false));
+            tags.push(ExthTag::KF8CoverURI(kf8::embed_uri(cover, None)));
+        }
+        tags.push(ExthTag::ResourceCount((self.images.len() + self.fonts.len()) as u32));
+        tags
+    }
+
+    /// Builds the records of the KF8 part, up to and including FCIS.
+    /// Record numbers in the header are relative to its record 0. In joint
+    /// files the resources are stored in the KF7 part instead.
+    pub fn section_records(&self, with_resources: bool) -> Vec<Vec<u8>> {
+        let chunked = self.chunk_text();
+
+        // Flow 0 is the text, the stylesheets follow as flows of their own
+        let mut text = chunked.text.clone();
+        let mut flows = vec![(0, text.len() as u32)];
+        for stylesheet in self.stylesheets.iter() {
+            let start = text.len() as u32;
+            text.extend(self.rewrite_css(&stylesheet.name, &stylesheet.data));
+            flows.push((start, text.len() as u32));
+        }
+        let text_records = writer::text_records(&text);
+
+        let mut header = MobiHeader::new_kf8();
+        header.mobi_id = writer::unique_id(&self.metadata.title);
+        header.uncompressed_text_length = text.len() as u32;
+        header.text_record_count = text_records.len() as u16;
+        header.extra_record_data_flags = 0x01; // Multibyte trailing entries
+        header.locale = Language::from(
+            self.metadata.language.as_ref().map_or(0, |code| locale_from_code(code)));
+
+        let mut records = vec![Vec::new()];
+        records.extend(text_records);
+        header.first_non_book_record = records.len() as u32;
+
+        let fragment_index = records.len() as u32;
+        records.extend(Kf8Writer::fragment_index(&chunked));
+        let skeleton_index = records.len() as u32;
+        records.extend(Kf8Writer::skeleton_index(&chunked));
+        if !self.toc.is_empty() {
+            header.indx_record_offset = Some(records.len() as u32);
+            records.extend(self.ncx_index(&chunked, flows[0].1));
+        }
+        let guide_index = if self.guide.is_empty() {
+            None
+        } else {
+            let index = records.len() as u32;
+            records.extend(self.guide_index(&chunked));
+            Some(index)
+        };
+
+        let resources = self.resource_records();
+        if !resources.is_empty() {
+            header.first_image_record = records.len() as u32;
+            if with_resources {
+                records.extend(resources);
+            }
+        }
+
+        let fdst = records.len() as u32;
+        records.push(kf8::fdst_record(&flows));
+        header.fcis_flis.flis_record_number = records.len() as u32;
+        records.push(writer::flis_record());
+        header.fcis_flis.fcis_record_number = records.len() as u32;
+        records.push(writer::fcis_record(text.len() as u32));
+
+        if let Some(ref mut kf8) = header.kf8 {
+            kf8.fdst_record = Some(fdst);
+            kf8.fdst_count = flows.len() as u32;
+            kf8.fragment_index = Some(fragment_index);
+            kf8.skeleton_index = Some(skeleton_index);
+            kf8.guide_index = guide_index;
+        }
+        records[0] = writer::record0(&mut header, &self.exth_tags(), &self.metadata.title);
+        records
+    }
+
+    /// Returns a KF7 version of the book: the document bodies joined by page
+    /// breaks, with the links and images rewritten to match.
+    pub fn to_kf7(&self) -> MobiWriter {
+        let mut body = Vec::new();
+        for (index, part) in self.parts.iter().enumerate() {
+            if index > 0 {
+                body.extend_from_slice(b"<mbp:pagebreak />");
+            }
+            body.extend_from_slice(format!("<a id=\"part{}\"></a>", index).as_bytes());
+
+            let mut in_body = false;
+            for (range, token) in Tokenizer::new(&part.data) {
+                match token {
+                    Token::StartTag(ref tag) if tag.name == "body" => {
+                        in_body = true;
+                        continue;
+                    },
+                    Token::EndTag(ref name) if name == "body" => {
+                        in_body = false;
+                    },
+                    _ => {},
+                }
+                if !in_body {
+                    continue;
+                }
+                let mut tag = match token {
+                    Token::StartTag(tag) => tag,
+                    _ => {
+                        body.extend_from_slice(&part.data[range]);
+                        continue;
+                    }
+                };
+                if let Some(id) = tag.attribute("id").map(String::from) {
+                    tag.set_attribute("id", &format!("part{}-{}", index, id));
+                }
+                if let Some(src) = tag.attribute("src").map(String::from) {
+                    tag.set_attribute("src", &html::resolve_href(&part.name, &src));
+                }
+                let href = tag.attribute("href").map(String::from);
+                if let Some(href) = href.filter(|href| !html::is_external(href)) {
+                    let (path, fragment) = html::split_fragment(&href);
+                    let target = if path.is_empty() {
+                        Some(index)
+                    } else {
+                        self.find_part(&html::resolve_href(&part.name, path))
+                    };
+                    if let Some(target) = target {
+                        let anchor = match fragment {
+                            Some(id) => format!("#part{}-{}", target, id),
+                            None => format!("#part{}", target),
+                        };
+                        tag.set_attribute("href", &anchor);
+                    }
+                }
+                body.extend_from_slice(tag.to_html().as_bytes());
+            }
+        }
+
+        let html = format!("<html><head></head><body>{}</body></html>",
+            String::from_utf8_lossy(&body));
+        let mut kf7 = MobiWriter::new(self.metadata.clone(), &html);
+        kf7.images = self.images.clone();
+        kf7.cover = self.cover;
+        kf7
+    }
+
+    /// Builds every record of the book, starting with record 0.
+    pub fn to_records(&self) -> Vec<Vec<u8>> {
+        let mut records = Vec::new();
+        if self.joint {
+            records.extend(self.to_kf7().section_records(&self.resource_records(), true));
+            records.push(BOUNDARY_RECORD.to_vec());
+            records.extend(self.section_records(false));
+        } else {
+            records.extend(self.section_records(true));
+        }
+        records.push(writer::EOF_RECORD.to_vec());
+        records
+    }
+
+    /// Writes the book as an AZW3 file, or as a joint MOBI file.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        let records = self.to_records();
+        let mut palmdb = PalmdbHeader::new(&self.metadata.title);
+        palmdb.write_with_records(&records, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use book::MobiBook;
+    use kf8_reader;
+    use testing;
+    use validate;
+
+    #[test]
+    fn kf8_round_trip() {
+        for &joint in [false, true].iter() {
+            let mut writer = testing::linked_kf8_writer();
+            writer.joint = joint;
+            let mut data = Vec::new();
+            writer.write_to(&mut data).unwrap();
+            assert_eq!(validate::validate(&data), Vec::new());
+
+            let book = MobiBook::from_bytes(&data).unwrap();
+            assert_eq!(book.kf7().is_some(), joint);
+            assert_eq!(book.metadata().title, "Test");
+            assert_eq!(book.resource(0), Some(&b"\x89PNGimage"[..]));
+            let section = book.kf8().unwrap();
+            let text = kf8_reader::read_text(&book, section).unwrap();
+            assert_eq!(text.parts.len(), 2);
+            assert!(text.fragments.len() > 2);
+            assert!(::html::find_bytes(&text.flows[1], b"url(kindle:embed:0001").is_some());
+
+            // The link back points at the paragraph, in a later fragment
+            let second = String::from_utf8(text.parts[1].data.clone()).unwrap();
+            assert!(second.contains("src=\"kindle:embed:0001?mime=image/png\""));
+            let (fid, offset) = kf8::parse_pos_uri(&second).unwrap();
+            let position = text.position(fid, offset).unwrap();
+            let (part, offset) = text.locate(position).unwrap();
+            assert_eq!(part, 0);
+            assert!(text.parts[0].data[offset..].starts_with(b"<p id=\"p300\""));
+
+            let ncx = book.ncx(section).unwrap();
+            let titles = ncx.iter()
+                .map(|entry| (&entry.title[..], entry.depth))
+                .collect::<Vec<_>>();
+            assert_eq!(titles, vec![("First", 0), ("Second", 1)]);
+            let (fid, offset) = ncx[1].pos_fid.unwrap();
+            let (part, offset) = text.locate(text.position(fid, offset).unwrap()).unwrap();
+            assert_eq!(part, 1);
+            assert!(text.parts[1].data[offset..].starts_with(b"<h1"));
+        }
+    }
+
+    #[test]
+    fn kf7_version_of_joint_files() {
+        let mut data = Vec::new();
+        testing::linked_kf8_writer().to_kf7().write_to(&mut data).unwrap();
+        let book = MobiBook::from_bytes(&data).unwrap();
+        let html = String::from_utf8(book.text(&book.main).unwrap()).unwrap();
+        assert!(html.contains("<mbp:pagebreak />"));
+        let target = html.find("<p id=\"part0-p300\"").unwrap();
+        assert!(html.contains(&format!("filepos=\"{:010}\"", target)));
+    }
+}
@@ -2,20 +2,52 @@
 
 extern crate byteorder;
 extern crate chrono;
+extern crate flate2;
 extern crate argonaut;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate zip;
 
 #[macro_use]
 mod common;
+mod container;
+mod drm;
 mod palmdb;
 mod mobi;
 mod exth_tags;
+mod palmdoc;
+mod html;
+mod metadata;
+mod writer;
+mod indx;
+mod kf7;
+mod kf8;
+mod kf8_writer;
+mod kf8_reader;
+mod book;
+mod epub;
+mod validate;
+mod repair;
+mod diff;
+mod dictionary;
+mod dictionary_writer;
+mod render;
+mod chapters;
+mod position;
+mod apnx;
+mod annotations;
+#[cfg(test)]
+mod testing;
 
 use std::env;
 use std::fmt;
 use std::io;
 use std::io::{BufReader, Read, Write, Seek, SeekFrom};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use argonaut::{ArgDef, parse, ParseError, help_arg, version_arg};
 use byteorder::{ReadBytesExt, BigEndian};
@@ -23,6 +55,13 @@ use common::*;
 use palmdb::PalmdbHeader;
 use mobi::MobiHeader;
 use exth_tags::ExthTag;
+use book::{MobiBook, Section};
+use dictionary::Dictionary;
+use drm::DrmStatus;
+use epub::Epub;
+use apnx::{Apnx, Pagination};
+use container::Container;
+use render::TextFormat;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(i32)]
@@ -42,18 +81,509 @@ fn main() {
 
 fn print_mobi_info(filename: &str) {
     let path = Path::new(filename);
-    let file = match File::open(&path) {
-        Ok(f) => f,
+    let mut data = Vec::new();
+    if let Err(reason) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+        println!("Could not open file '{}'", filename);
+        unsafe {
+            ERROR_CODE = Some(ErrorCode::Unspecified);
+        }
+        return ();
+    }
+    let container = container::sniff(&data);
+    println!("Container: {}", container.name());
+    match container {
+        Container::Mobi | Container::PrintReplica => {
+            if let Err(reason) = read_mobi(&mut io::Cursor::new(data)) {
+                fail(&format!("Could not read '{}': {}", filename, reason));
+            }
+        },
+        other => fail(&other.unsupported().to_string()),
+    }
+}
+
+/// The information that 'info' prints in the structured formats.
+#[derive(Serialize)]
+struct BookInfo<'a> {
+    /// The name of the container, such as "Print Replica (.azw4)".
+    container: String,
+    palmdb: &'a PalmdbHeader,
+    /// The KF7 and KF8 parts of the book.
+    sections: Vec<&'a Section>,
+    /// The DRM status of every section.
+    drm: Vec<DrmStatus>,
+}
+
+fn print_structured_info(filename: &str, format: &str) {
+    let book = match MobiBook::open(filename) {
+        Ok(book) => book,
         Err(reason) => {
-            println!("Could not open file '{}'", filename);
-            unsafe {
-                ERROR_CODE = Some(ErrorCode::Unspecified);
+            fail(&format!("Could not read '{}': {}", filename, reason));
+            return;
+        },
+    };
+    let container = if book.is_print_replica() {
+        Container::PrintReplica
+    } else {
+        Container::Mobi
+    };
+    let info = BookInfo {
+        container: container.name(),
+        palmdb: &book.palmdb,
+        sections: book.sections(),
+        drm: book.sections().iter()
+            .map(|section| DrmStatus::new(&section.header, &section.exth))
+            .collect(),
+    };
+    let output = match format {
+        "json" => serde_json::to_string_pretty(&info).map_err(|error| error.to_string()),
+        _ => serde_yaml::to_string(&info).map_err(|error| error.to_string()),
+    };
+    match output {
+        Ok(output) => println!("{}", output),
+        Err(reason) => fail(&format!("Could not serialize the info: {}", reason)),
+    }
+}
+
+fn print_record_table(filename: &str) {
+    let book = match MobiBook::open(filename) {
+        Ok(book) => book,
+        Err(reason) => {
+            fail(&format!("Could not read '{}': {}", filename, reason));
+            return;
+        },
+    };
+    println!("{:>6} {:>10} {:>8}  {:<8} {}", "Record", "Offset", "Size", "Role", "Ratio");
+    for (i, info) in book.record_table().iter().enumerate() {
+        let ratio = match info.text_length {
+            Some(length) if info.size > 0 => format!("{:.2}", length as f64 / info.size as f64),
+            _ => String::new(),
+        };
+        println!("{:>6} {:>10} {:>8}  {:<8} {}", i, info.offset, info.size, info.role.name(), ratio);
+    }
+}
+
+/// Parses a record number given on the command line.
+fn parse_record_number(number: &str) -> Result<usize, io::Error> {
+    number.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a record number", number))
+    })
+}
+
+fn dump_record(filename: &str, number: &str, output: Option<String>, decompress: bool) {
+    let result = MobiBook::open(filename).and_then(|book| {
+        let index = try!(parse_record_number(number));
+        let data = if decompress {
+            match book.find_text_record(index) {
+                Some((section, number)) => try!(book.text_record(section, number)),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Record {} is not a text record", index)));
+                },
+            }
+        } else {
+            // Resources of encrypted books are refused like their text
+            if book.resource_index(index).is_some() {
+                try!(drm::check(&book.main.header));
+            }
+            match book.records.get(index) {
+                Some(record) => record.clone(),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("The book has no record {}", index)));
+                },
+            }
+        };
+        match output {
+            Some(path) => try!(File::create(path)).write_all(&data),
+            None => io::stdout().write_all(&data),
+        }
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not dump the record: {}", reason));
+    }
+}
+
+fn replace_record(filename: &str, number: &str, replacement: &str, output: Option<String>) {
+    let result = MobiBook::open(filename).and_then(|mut book| {
+        let index = try!(parse_record_number(number));
+        let mut data = Vec::new();
+        try!(try!(File::open(replacement)).read_to_end(&mut data));
+        try!(book.replace_record(index, data));
+        book.save(output.as_ref().map_or(filename, |output| &output[..]))
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not replace the record: {}", reason));
+    }
+}
+
+fn validate_book(filename: &str) {
+    let mut data = Vec::new();
+    if let Err(reason) = File::open(filename).and_then(|mut file| file.read_to_end(&mut data)) {
+        fail(&format!("Could not read '{}': {}", filename, reason));
+        return;
+    }
+    let diagnostics = validate::validate(&data);
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics.iter()
+        .filter(|diagnostic| diagnostic.severity == validate::Severity::Error).count();
+    if errors > 0 {
+        fail(&format!("'{}' has {} errors", filename, errors));
+    } else if diagnostics.is_empty() {
+        println!("No problems found");
+    }
+}
+
+fn repair_book(input: &str, output: &str) {
+    let mut data = Vec::new();
+    let result = File::open(input).and_then(|mut file| file.read_to_end(&mut data))
+        .and_then(|_| repair::repair(&data))
+        .and_then(|(mut book, fixes)| {
+            for fix in fixes.iter() {
+                println!("{}", fix);
+            }
+            if fixes.is_empty() {
+                println!("Nothing to repair");
+            }
+            book.save(output)
+        });
+    if let Err(reason) = result {
+        fail(&format!("Could not repair '{}': {}", input, reason));
+    }
+}
+
+fn diff_books(old: &str, new: &str) {
+    let books = MobiBook::open(old).and_then(|old| MobiBook::open(new).map(|new| (old, new)));
+    match books {
+        Ok((old_book, new_book)) => {
+            let diff = diff::diff(&old_book, &new_book);
+            if diff.is_empty() {
+                println!("'{}' and '{}' are the same", old, new);
+            } else {
+                diff.print();
             }
-            return ();
         },
+        Err(reason) => fail(&format!("Could not read the books: {}", reason)),
+    }
+}
+
+fn extract_pdf(filename: &str, output: &str) {
+    let result = MobiBook::open(filename).and_then(|book| {
+        let pdfs = try!(book.print_replica_pdfs());
+        for (i, pdf) in pdfs.iter().enumerate() {
+            // Books with several tables get one numbered file per table
+            let path = if i == 0 {
+                output.to_string()
+            } else {
+                let path = Path::new(output);
+                let stem = path.file_stem().map_or(String::new(), |stem| {
+                    stem.to_string_lossy().into_owned()
+                });
+                let name = format!("{}.{}.pdf", stem, i + 1);
+                path.with_file_name(name).to_string_lossy().into_owned()
+            };
+            try!(try!(File::create(&path)).write_all(pdf));
+            println!("Wrote {}", path);
+        }
+        Ok(())
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not extract the PDF: {}", reason));
+    }
+}
+
+fn extract_fonts(filename: &str, output: &str) {
+    let book = match MobiBook::open(filename) {
+        Ok(book) => book,
+        Err(reason) => return fail(&format!("Could not read '{}': {}", filename, reason)),
     };
-    let mut reader = BufReader::new(file);
-    read_mobi(&mut reader).expect("Something went wrong:");
+    let fonts = match book.fonts() {
+        Ok(fonts) => fonts,
+        Err(reason) => return fail(&format!("Could not read the fonts: {}", reason)),
+    };
+    if fonts.is_empty() {
+        println!("The book has no fonts");
+        return;
+    }
+    if let Err(reason) = std::fs::create_dir_all(output) {
+        return fail(&format!("Could not create '{}': {}", output, reason));
+    }
+    for (index, font) in fonts {
+        let result = font.and_then(|font| {
+            let name = format!("font{:05}.{}", index + 1, kf8::font_extension(&font));
+            let path = Path::new(output).join(name);
+            try!(try!(File::create(&path)).write_all(&font));
+            Ok(path)
+        });
+        match result {
+            Ok(path) => println!("Wrote {}", path.display()),
+            Err(reason) => fail(&format!("Could not extract font {}: {}", index + 1, reason)),
+        }
+    }
+}
+
+fn print_flows(filename: &str, output: Option<&str>) {
+    let flows = match MobiBook::open(filename).and_then(|book| book.flows()) {
+        Ok(flows) => flows,
+        Err(reason) => return fail(&format!("Could not read the flows: {}", reason)),
+    };
+    let names = kf8_reader::flow_names(&flows);
+    let output = match output {
+        Some(output) => output,
+        None => {
+            for flow in flows.iter() {
+                println!("{:4}  {:14} {:8} bytes  {}", flow.number, flow.kind.mime(),
+                    flow.data.len(), names.get(&flow.number).map_or("", |name| &name[..]));
+            }
+            return;
+        },
+    };
+
+    // The stylesheets and images are written with their links to each other
+    for flow in flows.iter().skip(1) {
+        let name = &names[&flow.number];
+        let path = Path::new(output).join(name);
+        let data = kf8_reader::link_flows(&flow.data, name, &names);
+        let result = path.parent().map_or(Ok(()), |parent| std::fs::create_dir_all(parent))
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| file.write_all(&data));
+        match result {
+            Ok(_) => println!("Wrote {}", path.display()),
+            Err(reason) => fail(&format!("Could not write '{}': {}", path.display(), reason)),
+        }
+    }
+}
+
+fn define_word(filename: &str, word: &str, inflections: bool) {
+    let result = MobiBook::open(filename).and_then(|book| Dictionary::from_book(&book));
+    match result {
+        Ok(ref dictionary) if inflections => {
+            let forms = dictionary.inflections(word);
+            if forms.is_empty() {
+                fail(&format!("'{}' has no inflected forms in the dictionary", word));
+            }
+            for inflection in forms {
+                if inflection.name.is_empty() {
+                    println!("{}", inflection.form);
+                } else {
+                    println!("{} ({})", inflection.form, inflection.name);
+                }
+            }
+        },
+        Ok(dictionary) => match dictionary.lookup(word) {
+            Some(definition) => println!("{}", definition),
+            None => fail(&format!("'{}' is not in the dictionary", word)),
+        },
+        Err(reason) => fail(&format!("Could not read the dictionary: {}", reason)),
+    }
+}
+
+fn print_text(filename: &str, format: &str) {
+    let format = match TextFormat::from_name(format) {
+        Some(format) => format,
+        None => return fail(&format!("Unknown text format '{}', use 'txt' or 'md'", format)),
+    };
+    let result = MobiBook::open(filename)
+        .and_then(|book| render::render_book(&book, format))
+        .and_then(|text| io::stdout().write_all(text.as_bytes()));
+    if let Err(reason) = result {
+        fail(&format!("Could not render the text: {}", reason));
+    }
+}
+
+fn split_book(filename: &str, output: &str, format: &str) {
+    let text_format = match format {
+        "html" | "htm" => None,
+        _ => match TextFormat::from_name(format) {
+            Some(text_format) => Some(text_format),
+            None => return fail(&format!("Unknown format '{}', use 'html', 'txt' or 'md'", format)),
+        },
+    };
+    let extension = match text_format {
+        None => "html",
+        Some(TextFormat::PlainText) => "txt",
+        Some(TextFormat::Markdown) => "md",
+    };
+    let result = MobiBook::open(filename).and_then(|book| {
+        let chapters = try!(book.chapters());
+        try!(std::fs::create_dir_all(output));
+        for (i, chapter) in chapters.iter().enumerate() {
+            let content = match text_format {
+                Some(text_format) => chapter.text(text_format),
+                None => format!("<html><head><meta charset=\"utf-8\"/><title>{}</title></head>\
+                    <body>{}</body></html>", html::escape(&chapter.title), chapter.html),
+            };
+            let path = Path::new(output).join(format!("chapter{:03}.{}", i + 1, extension));
+            try!(try!(File::create(&path)).write_all(content.as_bytes()));
+            println!("Wrote {} ({})", path.display(), chapter.title);
+        }
+        Ok(())
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not split the book: {}", reason));
+    }
+}
+
+fn write_apnx(filename: &str, output: Option<&str>, pagination: &str) {
+    let pagination = match Pagination::from_name(pagination) {
+        Some(pagination) => pagination,
+        None => return fail(&format!("Unknown pagination '{}', use 'fast' or 'accurate'",
+            pagination)),
+    };
+    let path = output.map_or_else(|| apnx::sidecar_path(filename), PathBuf::from);
+    let result = MobiBook::open(filename)
+        .and_then(|book| Apnx::generate(&book, pagination))
+        .and_then(|apnx| {
+            try!(apnx.write_to(&mut try!(File::create(&path))));
+            Ok(apnx.pages.len())
+        });
+    match result {
+        Ok(pages) => println!("Wrote {} ({} pages)", path.display(), pages),
+        Err(reason) => fail(&format!("Could not write the page numbers: {}", reason)),
+    }
+}
+
+fn print_apnx(filename: &str, apnx_path: Option<&str>) {
+    let path = apnx_path.map_or_else(|| apnx::sidecar_path(filename), PathBuf::from);
+    let result = Apnx::open(&path).and_then(|apnx| {
+        let map = try!(MobiBook::open(filename).and_then(|book| book.positions()));
+        Ok((apnx, map))
+    });
+    let (apnx, map) = match result {
+        Ok(result) => result,
+        Err(reason) => return fail(&format!("Could not read the page numbers: {}", reason)),
+    };
+    println!("{} pages ({}, ASIN '{}')", apnx.pages.len(), apnx.format, apnx.asin);
+    for ((label, &offset), position) in apnx.labels().iter().zip(apnx.pages.iter())
+            .zip(apnx.positions(&map)) {
+        match position {
+            Some(position) => println!("{:>6}  offset {:>8}  location {:>6}", label, offset,
+                map.location(position)),
+            None => println!("{:>6}  offset {:>8}  (outside of the text)", label, offset),
+        }
+    }
+}
+
+fn print_annotations(filename: &str, annotation_file: &str, format: &str) {
+    if format != "md" && format != "json" {
+        return fail(&format!("Unknown format '{}', use 'md' or 'json'", format));
+    }
+    let result = MobiBook::open(filename).and_then(|book| {
+        let found = try!(annotations::open(annotation_file)
+            .and_then(|annotations| annotations::for_book(&book, &annotations)));
+        Ok((book.metadata(), found))
+    });
+    let (metadata, found) = match result {
+        Ok(result) => result,
+        Err(reason) => return fail(&format!("Could not read the annotations: {}", reason)),
+    };
+    if format == "json" {
+        match serde_json::to_string_pretty(&found) {
+            Ok(output) => println!("{}", output),
+            Err(reason) => fail(&format!("Could not serialize the annotations: {}", reason)),
+        }
+    } else {
+        let author = metadata.authors.first().map(|author| &author[..]);
+        print!("{}", annotations::to_markdown(&metadata.title, author, &found));
+    }
+}
+
+/// Reports an error and sets the exit code.
+fn fail(message: &str) {
+    println!("{}", message);
+    unsafe {
+        ERROR_CODE = Some(ErrorCode::Unspecified);
+    }
+}
+
+/// Returns the lowercased extension of a path.
+fn extension(filename: &str) -> String {
+    Path::new(filename).extension()
+        .map_or(String::new(), |extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// Reads a book to convert: an EPUB book, or a MOBI book converted to EPUB.
+fn read_book(filename: &str) -> Result<Epub, io::Error> {
+    match &extension(filename)[..] {
+        "epub" => Epub::open(filename),
+        _ => MobiBook::open(filename).and_then(|book| Epub::from_book(&book)),
+    }
+}
+
+fn convert_book(input: &str, output: &str) {
+    let format = extension(output);
+    match &format[..] {
+        "epub" | "azw3" | "mobi" | "azw" => {},
+        other => {
+            fail(&format!("Unsupported output format '{}'", other));
+            return;
+        },
+    }
+    let result = read_book(input).and_then(|epub| {
+        let mut file = try!(File::create(output));
+        match &format[..] {
+            "epub" => epub.write_to(file),
+            "azw3" => epub.to_kf8().write_to(&mut file),
+            _ => {
+                // MOBI files hold both versions, for older readers
+                let mut writer = epub.to_kf8();
+                writer.joint = true;
+                writer.write_to(&mut file)
+            },
+        }
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not convert '{}': {}", input, reason));
+    }
+}
+
+/// The metadata fields that 'meta set' can change.
+#[derive(Debug, Default)]
+struct MetadataChanges {
+    title: Option<String>,
+    author: Option<String>,
+    publisher: Option<String>,
+    isbn: Option<String>,
+    language: Option<String>,
+}
+
+fn set_book_metadata(filename: &str, changes: MetadataChanges) {
+    let result = MobiBook::open(filename).and_then(|mut book| {
+        let mut metadata = book.metadata();
+        if let Some(title) = changes.title {
+            metadata.title = title;
+        }
+        if let Some(author) = changes.author {
+            metadata.authors = vec![author];
+        }
+        if changes.publisher.is_some() {
+            metadata.publisher = changes.publisher;
+        }
+        if changes.isbn.is_some() {
+            metadata.isbn = changes.isbn;
+        }
+        if changes.language.is_some() {
+            metadata.language = changes.language;
+        }
+        try!(book.edit_metadata(&metadata));
+        book.save(filename)
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not update '{}': {}", filename, reason));
+    }
+}
+
+fn set_book_cover(filename: &str, image: &str) {
+    let result = MobiBook::open(filename).and_then(|mut book| {
+        let mut data = Vec::new();
+        try!(try!(File::open(image)).read_to_end(&mut data));
+        try!(book.set_cover(data));
+        book.save(filename)
+    });
+    if let Err(reason) = result {
+        fail(&format!("Could not set the cover of '{}': {}", filename, reason));
+    }
 }
 
 fn mobi_main() {
@@ -66,22 +596,470 @@ fn mobi_main() {
     match parse("mobi", &args, vec![
         ArgDef::cmd("info", |program, args| {
             let mut filename = String::new();
+            let mut format: Option<String> = None;
             
             parse(program, args, vec![
                 ArgDef::pos("filename", &mut filename)
                     .help("The file to print info about."),
+                ArgDef::option("format", &mut format)
+                    .help("The output format: 'text' (default), 'json' or 'yaml'."),
                 
                 help_arg("
                     Prints all metadata of a MOBI file.
                 "),
             ])?;
             
-            print_mobi_info(&filename);
+            match format.as_ref().map_or("text", |format| &format[..]) {
+                "text" => print_mobi_info(&filename),
+                format @ "json" | format @ "yaml" => print_structured_info(&filename, format),
+                other => fail(&format!("Unknown format '{}'", other)),
+            }
             
             Ok(())
         })
         .help("Prints all metadata of a MOBI file."),
         
+        ArgDef::cmd("records", |program, args| {
+            let mut filename = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The file to list the records of."),
+                
+                help_arg("
+                    Lists every record of a MOBI file with its offset, size and
+                    role, and the compression ratio of the text records.
+                "),
+            ])?;
+            
+            print_record_table(&filename);
+            
+            Ok(())
+        })
+        .help("Lists the records of a MOBI file."),
+        
+        ArgDef::cmd("validate", |program, args| {
+            let mut filename = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The file to check."),
+                
+                help_arg("
+                    Checks the structure of a MOBI file: the record list, the
+                    headers, the EXTH header, the text length and the links in
+                    the text. Every problem is printed with its severity, its
+                    code and its offset in the file.
+                "),
+            ])?;
+            
+            validate_book(&filename);
+            
+            Ok(())
+        })
+        .help("Checks the structure of a MOBI file."),
+        
+        ArgDef::cmd("repair", |program, args| {
+            let mut input = String::new();
+            let mut output = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("input", &mut input)
+                    .help("The file to repair."),
+                ArgDef::pos("output", &mut output)
+                    .help("The file to write the repaired book to."),
+                
+                help_arg("
+                    Fixes common inconsistencies between the headers and the
+                    records of a MOBI file: the text record count and length,
+                    the last content record, the first image record, the FLIS
+                    and FCIS records, the EXTH length and padding, dangling
+                    cover and thumbnail offsets and a missing EOF record.
+                    Every fix is printed.
+                "),
+            ])?;
+            
+            repair_book(&input, &output);
+            
+            Ok(())
+        })
+        .help("Fixes inconsistent headers of a MOBI file."),
+        
+        ArgDef::cmd("diff", |program, args| {
+            let mut old = String::new();
+            let mut new = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("old", &mut old)
+                    .help("The first file to compare."),
+                ArgDef::pos("new", &mut new)
+                    .help("The second file to compare."),
+                
+                help_arg("
+                    Compares two MOBI files: the fields of their PalmDB and
+                    MOBI headers, their EXTH tags, their records by content,
+                    and their text as a unified diff.
+                "),
+            ])?;
+            
+            diff_books(&old, &new);
+            
+            Ok(())
+        })
+        .help("Compares two MOBI files."),
+        
+        ArgDef::cmd("dump-record", |program, args| {
+            let mut filename = String::new();
+            let mut number = String::new();
+            let mut output: Option<String> = None;
+            let mut decompress = false;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The file to read."),
+                ArgDef::pos("record", &mut number)
+                    .help("The number of the record, from 0."),
+                ArgDef::option("output", &mut output).short("o")
+                    .help("The file to write the record to, instead of stdout."),
+                ArgDef::flag("decompress", &mut decompress).short("d")
+                    .help("Writes the decompressed text of a text record."),
+                
+                help_arg("
+                    Writes the bytes of a record of a MOBI file.
+                "),
+            ])?;
+            
+            dump_record(&filename, &number, output, decompress);
+            
+            Ok(())
+        })
+        .help("Writes the bytes of a record."),
+        
+        ArgDef::cmd("replace-record", |program, args| {
+            let mut filename = String::new();
+            let mut number = String::new();
+            let mut replacement = String::new();
+            let mut output: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The file to change."),
+                ArgDef::pos("record", &mut number)
+                    .help("The number of the record, from 0."),
+                ArgDef::pos("replacement", &mut replacement)
+                    .help("The file holding the new bytes of the record."),
+                ArgDef::option("output", &mut output).short("o")
+                    .help("The file to write the book to, instead of changing it in place."),
+                
+                help_arg("
+                    Replaces a record of a MOBI file, and moves the records
+                    after it.
+                "),
+            ])?;
+            
+            replace_record(&filename, &number, &replacement, output);
+            
+            Ok(())
+        })
+        .help("Replaces a record."),
+        
+        ArgDef::cmd("extract-pdf", |program, args| {
+            let mut filename = String::new();
+            let mut output = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The Print Replica (.azw4) book to read."),
+                ArgDef::pos("output", &mut output)
+                    .help("The PDF file to write."),
+                
+                help_arg("
+                    Extracts the PDF of a Print Replica (.azw4) book. Books
+                    with several PDF files get numbered output files.
+                "),
+            ])?;
+            
+            extract_pdf(&filename, &output);
+            
+            Ok(())
+        })
+        .help("Extracts the PDF of a Print Replica book."),
+        
+        ArgDef::cmd("extract-fonts", |program, args| {
+            let mut filename = String::new();
+            let mut output: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book to extract the fonts of."),
+                ArgDef::option("out", &mut output).short("o")
+                    .help("The directory to write the fonts to (default: '.')."),
+                
+                help_arg("
+                    Extracts the embedded fonts of a KF8 book as TTF or OTF
+                    files, undoing their obfuscation and compression.
+                "),
+            ])?;
+            
+            extract_fonts(&filename, output.as_ref().map_or(".", |output| &output[..]));
+            
+            Ok(())
+        })
+        .help("Extracts the embedded fonts of a book."),
+        
+        ArgDef::cmd("flows", |program, args| {
+            let mut filename = String::new();
+            let mut output: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book to list the flows of."),
+                ArgDef::option("out", &mut output).short("o")
+                    .help("Write the stylesheets and SVG images to this directory."),
+                
+                help_arg("
+                    Lists the flows of the text of a KF8 book: the HTML,
+                    followed by the stylesheets and SVG images that it
+                    references with 'kindle:flow' URIs. With '--out', the
+                    stylesheets and images are written with their links to
+                    each other resolved.
+                "),
+            ])?;
+            
+            print_flows(&filename, output.as_ref().map(|output| &output[..]));
+            
+            Ok(())
+        })
+        .help("Lists or extracts the flows (stylesheets, SVG) of a book."),
+        
+        ArgDef::cmd("define", |program, args| {
+            let mut filename = String::new();
+            let mut word = String::new();
+            let mut inflections = false;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The dictionary to look in."),
+                ArgDef::pos("word", &mut word)
+                    .help("The word to look up."),
+                ArgDef::flag("inflections", &mut inflections).short("i")
+                    .help("List the inflected forms of the word instead."),
+                
+                help_arg("
+                    Prints the HTML definition of a word from a Kindle
+                    dictionary. Words are matched regardless of case, and
+                    inflected forms find their headwords.
+                "),
+            ])?;
+            
+            define_word(&filename, &word, inflections);
+            
+            Ok(())
+        })
+        .help("Looks up a word in a Kindle dictionary."),
+        
+        ArgDef::cmd("text", |program, args| {
+            let mut filename = String::new();
+            let mut format: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book to read."),
+                ArgDef::option("format", &mut format).short("f")
+                    .help("The format to write: 'txt' (default) or 'md'."),
+                
+                help_arg("
+                    Writes the text of a book without its markup, as plain
+                    text or as Markdown. Chapters are separated by a form
+                    feed in plain text, and by a rule in Markdown.
+                "),
+            ])?;
+            
+            print_text(&filename, format.as_ref().map_or("txt", |format| &format[..]));
+            
+            Ok(())
+        })
+        .help("Writes the text of a book as plain text or Markdown."),
+        
+        ArgDef::cmd("split", |program, args| {
+            let mut filename = String::new();
+            let mut output: Option<String> = None;
+            let mut format: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book to split."),
+                ArgDef::option("out", &mut output).short("o")
+                    .help("The directory to write the chapters to (default: '.')."),
+                ArgDef::option("format", &mut format).short("f")
+                    .help("The format of the chapters: 'html' (default), 'txt' or 'md'."),
+                
+                help_arg("
+                    Writes every chapter of a book to a numbered file. KF7
+                    books are split at the entries of their table of
+                    contents, or else at their page breaks or headings.
+                "),
+            ])?;
+            
+            split_book(&filename, output.as_ref().map_or(".", |output| &output[..]),
+                format.as_ref().map_or("html", |format| &format[..]));
+            
+            Ok(())
+        })
+        .help("Writes the chapters of a book to separate files."),
+        
+        ArgDef::cmd("apnx", |program, args| {
+            let mut filename = String::new();
+            let mut output: Option<String> = None;
+            let mut pagination: Option<String> = None;
+            let mut print = false;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book to paginate."),
+                ArgDef::option("out", &mut output).short("o")
+                    .help("The APNX file (default: next to the book)."),
+                ArgDef::option("method", &mut pagination).short("m")
+                    .help("The pagination: 'fast' (default) or 'accurate'."),
+                ArgDef::flag("print", &mut print).short("p")
+                    .help("Print the pages of the existing APNX file instead."),
+                
+                help_arg("
+                    Writes the page numbers of a book to an APNX file, with
+                    calibre's fast or accurate pagination. The fast one puts
+                    a page every 2300 bytes of text, and the accurate one
+                    every 32 lines of paragraph text.
+                "),
+            ])?;
+            
+            if print {
+                print_apnx(&filename, output.as_ref().map(|output| &output[..]));
+            } else {
+                write_apnx(&filename, output.as_ref().map(|output| &output[..]),
+                    pagination.as_ref().map_or("fast", |pagination| &pagination[..]));
+            }
+            
+            Ok(())
+        })
+        .help("Writes or prints the page numbers (APNX) of a book."),
+        
+        ArgDef::cmd("annotations", |program, args| {
+            let mut filename = String::new();
+            let mut annotation_file = String::new();
+            let mut format: Option<String> = None;
+            
+            parse(program, args, vec![
+                ArgDef::pos("filename", &mut filename)
+                    .help("The book the annotations were made in."),
+                ArgDef::pos("annotations", &mut annotation_file)
+                    .help("A 'My Clippings.txt' file, or a '.mbp' or '.mbs' file."),
+                ArgDef::option("format", &mut format).short("f")
+                    .help("The format to write: 'md' (default) or 'json'."),
+                
+                help_arg("
+                    Writes the highlights, notes and bookmarks made in a book
+                    on a Kindle. Clippings are matched to the book by ASIN or
+                    title, and highlights are found in its text.
+                "),
+            ])?;
+            
+            print_annotations(&filename, &annotation_file,
+                format.as_ref().map_or("md", |format| &format[..]));
+            
+            Ok(())
+        })
+        .help("Exports the annotations made in a book."),
+        
+        ArgDef::cmd("convert", |program, args| {
+            let mut input = String::new();
+            let mut output = String::new();
+            
+            parse(program, args, vec![
+                ArgDef::pos("input", &mut input)
+                    .help("The book to convert (MOBI, AZW, AZW3 or EPUB)."),
+                ArgDef::pos("output", &mut output)
+                    .help("The file to write. Its extension selects the format: \
+                        'epub', 'azw3', or 'mobi' for a joint KF7/KF8 file."),
+                
+                help_arg("
+                    Converts a book between the MOBI, AZW3 and EPUB formats.
+                "),
+            ])?;
+            
+            convert_book(&input, &output);
+            
+            Ok(())
+        })
+        .help("Converts a book to another format."),
+        
+        ArgDef::cmd("meta", |program, args| {
+            parse(program, args, vec![
+                ArgDef::cmd("set", |program, args| {
+                    let mut filename = String::new();
+                    let mut changes = MetadataChanges::default();
+                    
+                    parse(program, args, vec![
+                        ArgDef::pos("filename", &mut filename)
+                            .help("The book to change."),
+                        ArgDef::option("title", &mut changes.title)
+                            .help("The new title."),
+                        ArgDef::option("author", &mut changes.author)
+                            .help("The new author, replacing the current ones."),
+                        ArgDef::option("publisher", &mut changes.publisher)
+                            .help("The new publisher."),
+                        ArgDef::option("isbn", &mut changes.isbn)
+                            .help("The new ISBN."),
+                        ArgDef::option("language", &mut changes.language)
+                            .help("The new language, as a code such as 'en' or 'de-DE'."),
+                        
+                        help_arg("
+                            Changes the metadata of a MOBI/AZW3 book in place.
+                        "),
+                    ])?;
+                    
+                    set_book_metadata(&filename, changes);
+                    
+                    Ok(())
+                })
+                .help("Changes the metadata of a book."),
+                
+                help_arg("
+                    Works with the metadata of a MOBI/AZW3 book.
+                "),
+            ])
+        })
+        .help("Works with the metadata of a book."),
+        
+        ArgDef::cmd("cover", |program, args| {
+            parse(program, args, vec![
+                ArgDef::cmd("set", |program, args| {
+                    let mut filename = String::new();
+                    let mut image = String::new();
+                    
+                    parse(program, args, vec![
+                        ArgDef::pos("filename", &mut filename)
+                            .help("The book to change."),
+                        ArgDef::pos("image", &mut image)
+                            .help("The new cover (JPEG, PNG, GIF or BMP)."),
+                        
+                        help_arg("
+                            Replaces the cover of a MOBI/AZW3 book, or adds one.
+                        "),
+                    ])?;
+                    
+                    set_book_cover(&filename, &image);
+                    
+                    Ok(())
+                })
+                .help("Replaces or adds the cover of a book."),
+                
+                help_arg("
+                    Works with the cover of a MOBI/AZW3 book.
+                "),
+            ])
+        })
+        .help("Works with the cover of a book."),
+        
         help_arg(description),
         version_arg(),
     ]) {
@@ -102,7 +1080,10 @@ fn read_mobi<R>(source: &mut R) -> Result<(), io::Error> where R: Read + Seek {
     let palm_db_header = try!(PalmdbHeader::read_from(source));
     palm_db_header.print_info();
     
-    let first = palm_db_header.records[0];
+    let first = match palm_db_header.records.first() {
+        Some(&first) => first,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "The book has no records")),
+    };
     try!(source.seek(SeekFrom::Start(first.data_offset as u64)));
     
     let mobi_header = try!(MobiHeader::read_from(source));
@@ -117,9 +1098,14 @@ fn read_mobi<R>(source: &mut R) -> Result<(), io::Error> where R: Read + Seek {
     for tag in tags.iter() {
         println!("- {:?}", tag);
     }
+    DrmStatus::new(&mobi_header, &tags).print_info();
     
-    read_compressed_record(source, palm_db_header.records[1].data_offset);
-    read_compressed_record(source, palm_db_header.records[2].data_offset);
+    // Encrypted text is not decompressed, as it would only show garbage
+    if !drm::is_encrypted(&mobi_header) {
+        for record in palm_db_header.records.iter().skip(1).take(2) {
+            read_compressed_record(source, record.data_offset);
+        }
+    }
     
     Ok(())
 }
@@ -161,6 +1147,10 @@ fn read_compressed_record<R>(source: &mut R, data_offset: u32)
                 let length = ((second & 0b00000111) + 3) as usize;
                 
                 //println!("Distance: {}, Length: {}", distance, length);
+                if distance == 0 || distance as usize > output.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        "The distance points before the start of the text"));
+                }
                 let pos = output.len() - distance as usize;
                 let mut end = pos + length;
                 // This is taken from the Calibre implementation
@@ -242,3 +1232,26 @@ fn test_headers() {
     
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metadata::Metadata;
+    use writer::MobiWriter;
+
+    #[test]
+    fn info_of_malformed_books() {
+        let mut data = Vec::new();
+        MobiWriter::new(Metadata::new("Test"), "<p>Text</p>").write_to(&mut data).unwrap();
+        assert!(read_mobi(&mut io::Cursor::new(&data)).is_ok());
+        let record0 = PalmdbHeader::read_from(&mut io::Cursor::new(&data)).unwrap().records[0];
+        let start = record0.data_offset as usize;
+        for &length in [0, 40, 78, start + 20, start + 100].iter() {
+            assert!(read_mobi(&mut io::Cursor::new(&data[..length])).is_err(), "{}", length);
+        }
+
+        let mut empty = Vec::new();
+        PalmdbHeader::new("Empty").write_with_records(&[], &mut empty).unwrap();
+        assert!(read_mobi(&mut io::Cursor::new(&empty)).is_err());
+    }
+}
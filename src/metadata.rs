@@ -0,0 +1,106 @@
+//! Book metadata, and its mapping onto EXTH tags.
+
+use exth_tags::ExthTag;
+
+/// The descriptive metadata of a book.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub contributors: Vec<String>,
+    pub publisher: Option<String>,
+    pub description: Option<String>,
+    pub isbn: Option<String>,
+    pub asin: Option<String>,
+    pub subjects: Vec<String>,
+    pub published: Option<String>,
+    pub rights: Option<String>,
+    /// An IANA language code, such as "en" or "de-DE".
+    pub language: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(title: &str) -> Metadata {
+        Metadata {
+            title: title.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the EXTH tags describing this metadata.
+    pub fn to_exth_tags(&self) -> Vec<ExthTag> {
+        let mut tags = Vec::new();
+        for author in self.authors.iter() {
+            tags.push(ExthTag::Author(author.clone()));
+        }
+        for contributor in self.contributors.iter() {
+            tags.push(ExthTag::Contributor(contributor.clone()));
+        }
+        if let Some(ref publisher) = self.publisher {
+            tags.push(ExthTag::Publisher(publisher.clone()));
+        }
+        if let Some(ref description) = self.description {
+            tags.push(ExthTag::Description(description.clone()));
+        }
+        if let Some(ref isbn) = self.isbn {
+            tags.push(ExthTag::ISBN(isbn.clone()));
+        }
+        if let Some(ref asin) = self.asin {
+            tags.push(ExthTag::ASIN(asin.clone()));
+        }
+        for subject in self.subjects.iter() {
+            tags.push(ExthTag::Subject(subject.clone()));
+        }
+        if let Some(ref published) = self.published {
+            tags.push(ExthTag::PublishingDate(published.clone()));
+        }
+        if let Some(ref rights) = self.rights {
+            tags.push(ExthTag::Rights(rights.clone()));
+        }
+        if let Some(ref language) = self.language {
+            tags.push(ExthTag::Language(language.clone()));
+        }
+        tags.push(ExthTag::UpdatedTitle(self.title.clone()));
+        tags
+    }
+
+    /// Collects the metadata from EXTH tags. The title is the full name of
+    /// the book, unless an updated title overrides it.
+    pub fn from_exth_tags(title: &str, tags: &[ExthTag]) -> Metadata {
+        let mut metadata = Metadata::new(title);
+        for tag in tags.iter() {
+            match *tag {
+                ExthTag::Author(ref author) => metadata.authors.push(author.clone()),
+                ExthTag::Contributor(ref contributor) => {
+                    metadata.contributors.push(contributor.clone());
+                },
+                ExthTag::Publisher(ref publisher) => metadata.publisher = Some(publisher.clone()),
+                ExthTag::Description(ref description) => {
+                    metadata.description = Some(description.clone());
+                },
+                ExthTag::ISBN(ref isbn) => metadata.isbn = Some(isbn.clone()),
+                ExthTag::ASIN(ref asin) => metadata.asin = Some(asin.clone()),
+                ExthTag::Subject(ref subject) => metadata.subjects.push(subject.clone()),
+                ExthTag::PublishingDate(ref date) => metadata.published = Some(date.clone()),
+                ExthTag::Rights(ref rights) => metadata.rights = Some(rights.clone()),
+                ExthTag::Language(ref language) => metadata.language = Some(language.clone()),
+                ExthTag::UpdatedTitle(ref title) if !title.is_empty() => {
+                    metadata.title = title.clone();
+                },
+                _ => {},
+            }
+        }
+        metadata
+    }
+}
+
+/// Returns whether an EXTH tag holds metadata, as written by 'to_exth_tags'.
+pub fn is_metadata_tag(tag: &ExthTag) -> bool {
+    match *tag {
+        ExthTag::Author(_) | ExthTag::Contributor(_) | ExthTag::Publisher(_) |
+        ExthTag::Description(_) | ExthTag::ISBN(_) | ExthTag::ASIN(_) |
+        ExthTag::Subject(_) | ExthTag::PublishingDate(_) | ExthTag::Rights(_) |
+        ExthTag::Language(_) | ExthTag::UpdatedTitle(_) => true,
+        _ => false,
+    }
+}
@@ -3,7 +3,7 @@ extern crate byteorder;
 
 use std::fmt;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 use byteorder::{ReadBytesExt};
 use common::*;
 
@@ -51,7 +51,7 @@ valued_enum! {
 }
 
 /// Not quite sure what these are for
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Indices {
     pub orthographic: Option<u32>,
     pub inflection: Option<u32>,
@@ -61,7 +61,7 @@ pub struct Indices {
 }
 
 /// Uh, not sure about this either.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct HuffmanEncodingInfo {
     pub record_offset: u32,
     pub record_count: u32,
@@ -70,14 +70,14 @@ pub struct HuffmanEncodingInfo {
 }
 
 /// Info for dictionary e-books, I guess.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
 pub struct DictionaryInfo {
     pub input: Language,
     pub output: Language,
 }
 
 /// Info about the DRM of the content.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DrmInfo {
     pub offset: Option<u32>,
     pub count: u32,
@@ -86,13 +86,13 @@ pub struct DrmInfo {
 }
 
 /// What even is this?
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct CompilationInfo {
     pub data_section_count: u32,
     pub data_sections: Option<u32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct FcisFlis {
     pub fcis_record_number: u32,
     pub fcis_record_count: u32,
@@ -100,18 +100,36 @@ pub struct FcisFlis {
     pub flis_record_count: u32,
 }
 
+/// The fields that only KF8 headers (MOBI version 8) have.
+/// The FDST fields take the place of the first and last content records.
+#[derive(Debug, Serialize)]
+pub struct Kf8Info {
+    pub fdst_record: Option<u32>,
+    pub fdst_count: u32,
+    pub fragment_index: Option<u32>,
+    pub skeleton_index: Option<u32>,
+    pub datp_record: Option<u32>,
+    pub guide_index: Option<u32>,
+}
+
 /// The header 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MobiHeader {
     pub compression: CompressionType,
     pub uncompressed_text_length: u32,
+    pub text_record_count: u16,
+    pub text_record_size: u16,
     pub encryption: EncryptionType,
+    pub header_length: u32,
     pub content_type: MobiType,
     pub text_encoding: TextEncoding,
     pub mobi_id: u32,
     pub mobi_version: u32,
     pub min_mobi_version: u32,
     pub indices: Indices,
+    pub first_non_book_record: u32,
+    pub full_name_offset: u32,
+    pub full_name_length: u32,
     pub locale: Language,
     pub dictionary: DictionaryInfo,
     pub first_image_record: u32,
@@ -124,9 +142,102 @@ pub struct MobiHeader {
     pub compilation: CompilationInfo,
     pub extra_record_data_flags: u32,
     pub indx_record_offset: Option<u32>,
+    pub kf8: Option<Kf8Info>,
+    /// The bytes of the header beyond the known fields, kept as they are.
+    pub header_tail: Vec<u8>,
 }
 
 impl MobiHeader {
+    /// Creates a header for a new UTF-8 book without any text, images or
+    /// indices. Writers fill in the record numbers.
+    pub fn new() -> MobiHeader {
+        MobiHeader {
+            compression: CompressionType::PalmDOC,
+            uncompressed_text_length: 0,
+            text_record_count: 0,
+            text_record_size: 4096,
+            encryption: EncryptionType::None,
+            header_length: 232,
+            content_type: MobiType::MobiPocketBook,
+            text_encoding: TextEncoding::UTF8,
+            mobi_id: 0,
+            mobi_version: 6,
+            min_mobi_version: 6,
+            indices: Indices {
+                orthographic: None,
+                inflection: None,
+                names: None,
+                keys: None,
+                extra: [None; 6],
+            },
+            first_non_book_record: 0,
+            full_name_offset: 0,
+            full_name_length: 0,
+            locale: Language::from(0),
+            dictionary: DictionaryInfo {
+                input: Language::from(0),
+                output: Language::from(0),
+            },
+            first_image_record: 0xFFFFFFFF,
+            huffman_encoding: HuffmanEncodingInfo {
+                record_offset: 0,
+                record_count: 0,
+                table_offset: 0,
+                table_length: 0,
+            },
+            exth_flags: 0x50,
+            drm: DrmInfo {
+                offset: None,
+                count: 0,
+                size: 0,
+                flags: 0,
+            },
+            text_record: 1,
+            last_record: 0,
+            fcis_flis: FcisFlis {
+                fcis_record_number: 0,
+                fcis_record_count: 1,
+                flis_record_number: 0,
+                flis_record_count: 1,
+            },
+            compilation: CompilationInfo {
+                data_section_count: 0,
+                data_sections: None,
+            },
+            extra_record_data_flags: 0,
+            indx_record_offset: None,
+            kf8: None,
+            header_tail: Vec::new(),
+        }
+    }
+    
+    /// Creates a header for a new KF8 book, see 'new'.
+    pub fn new_kf8() -> MobiHeader {
+        let mut header = MobiHeader::new();
+        header.mobi_version = 8;
+        header.min_mobi_version = 8;
+        header.kf8 = Some(Kf8Info {
+            fdst_record: None,
+            fdst_count: 0,
+            fragment_index: None,
+            skeleton_index: None,
+            datp_record: None,
+            guide_index: None,
+        });
+        // Unknown fields, as written by kindlegen
+        for &value in &[0xFFFFFFFFu32, 0, 0xFFFFFFFF, 0] {
+            write_u32_be(&mut header.header_tail, value).unwrap();
+        }
+        header.header_length = header.length();
+        header
+    }
+    
+    /// Returns the length of the header as written (from 'MOBI' on).
+    pub fn length(&self) -> u32 {
+        let kf8_len = if self.kf8.is_some() { 16 } else { 0 };
+        232 + kf8_len + self.header_tail.len() as u32
+    }
+    
     /// Attempts to read a MOBI header from the given source
     pub fn read_from(source: &mut Read) -> Result<MobiHeader, io::Error> {
         let compression = CompressionType::from(try!(read_u16_be(source)));
@@ -134,13 +245,15 @@ impl MobiHeader {
         let uncompressed_text_length = try!(read_u32_be(source));
         let record_count = try!(read_u16_be(source));
         let record_size = try!(read_u16_be(source));
-        assert_eq!(record_size, 4096);
         let encryption = EncryptionType::from(try!(read_u16_be(source)));
         let unknown = try!(read_u16_be(source));
         
     
         let magic = try!(read_string(source, 4));
-        assert_eq!(magic, String::from("MOBI"));
+        if magic != "MOBI" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "Record 0 has no MOBI header"));
+        }
     
         let header_len = try!(read_u32_be(source));
         let content_type = MobiType::from(try!(read_u32_be(source)));
@@ -198,7 +311,7 @@ impl MobiHeader {
         let last_record = try!(read_u16_be(source));
         
     
-        try!(discard(source, 4)); // Unknown (0x00000001)
+        let fdst_count = try!(read_u32_be(source)); // (0x00000001) in KF7
     
         let fcis_record_number = try!(read_u32_be(source));
         let fcis_record_count = try!(read_u32_be(source)); // (0x00000001)
@@ -219,10 +332,27 @@ impl MobiHeader {
         let extra_record_data_flags = try!(read_u32_be(source));
         let indx_record_offset = try!(read_unmaxed_u32(source));
     
-        if header_len > 232 {
+        let mut kf8 = None;
+        let mut known_len = 232;
+        if mobi_version >= 8 && header_len >= 248 {
+            let fdst_record = ((text_record as u32) << 16) | last_record as u32;
+            kf8 = Some(Kf8Info {
+                fdst_record: if fdst_record == 0xFFFFFFFF { None } else { Some(fdst_record) },
+                fdst_count: fdst_count,
+                fragment_index: try!(read_unmaxed_u32(source)),
+                skeleton_index: try!(read_unmaxed_u32(source)),
+                datp_record: try!(read_unmaxed_u32(source)),
+                guide_index: try!(read_unmaxed_u32(source)),
+            });
+            known_len = 248;
+        }
+    
+        let mut header_tail = Vec::new();
+        if header_len > known_len {
             //try!(discard(source, 20)); // 5x (0xFFFFFFFF)
             //try!(discard(source, 4)); // (0)
-            try!(discard(source, (header_len - 232) as u64));
+            try!(source.take((header_len - known_len) as u64)
+                .read_to_end(&mut header_tail));
         }
     
         let indices = Indices {
@@ -267,12 +397,18 @@ impl MobiHeader {
         Ok(MobiHeader {
             compression: compression,
             uncompressed_text_length: uncompressed_text_length,
+            text_record_count: record_count,
+            text_record_size: record_size,
             encryption: encryption,
+            header_length: header_len,
             content_type: content_type,
             text_encoding: text_encoding,
             mobi_id: mobi_id,
             mobi_version: mobi_version,
             indices: indices,
+            first_non_book_record: first_record,
+            full_name_offset: full_name_offset,
+            full_name_length: full_name_length,
             locale: locale,
             dictionary: dictionary,
             min_mobi_version: min_version,
@@ -286,9 +422,153 @@ impl MobiHeader {
             compilation: compilation,
             extra_record_data_flags: extra_record_data_flags,
             indx_record_offset: indx_record_offset,
+            kf8: kf8,
+            header_tail: header_tail,
         })
     }
     
+    /// Writes the PalmDOC and MOBI headers, the inverse of 'read_from'.
+    /// The written header length is that of 'length'.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        try!(write_u16_be(output, self.compression.value()));
+        try!(write_u16_be(output, 0));
+        try!(write_u32_be(output, self.uncompressed_text_length));
+        try!(write_u16_be(output, self.text_record_count));
+        try!(write_u16_be(output, self.text_record_size));
+        try!(write_u16_be(output, self.encryption.value()));
+        try!(write_u16_be(output, 0));
+        
+        try!(output.write_all(b"MOBI"));
+        try!(write_u32_be(output, self.length()));
+        try!(write_u32_be(output, self.content_type.value()));
+        try!(write_u32_be(output, self.text_encoding.value()));
+        try!(write_u32_be(output, self.mobi_id));
+        try!(write_u32_be(output, self.mobi_version));
+        
+        try!(write_unmaxed_u32(output, self.indices.orthographic));
+        try!(write_unmaxed_u32(output, self.indices.inflection));
+        try!(write_unmaxed_u32(output, self.indices.names));
+        try!(write_unmaxed_u32(output, self.indices.keys));
+        for index in self.indices.extra.iter() {
+            try!(write_unmaxed_u32(output, *index));
+        }
+        
+        try!(write_u32_be(output, self.first_non_book_record));
+        try!(write_u32_be(output, self.full_name_offset));
+        try!(write_u32_be(output, self.full_name_length));
+        try!(write_u32_be(output, self.locale.value()));
+        try!(write_u32_be(output, self.dictionary.input.value()));
+        try!(write_u32_be(output, self.dictionary.output.value()));
+        try!(write_u32_be(output, self.min_mobi_version));
+        try!(write_u32_be(output, self.first_image_record));
+        
+        try!(write_u32_be(output, self.huffman_encoding.record_offset));
+        try!(write_u32_be(output, self.huffman_encoding.record_count));
+        try!(write_u32_be(output, self.huffman_encoding.table_offset));
+        try!(write_u32_be(output, self.huffman_encoding.table_length));
+        
+        try!(write_u32_be(output, self.exth_flags));
+        try!(output.write_all(&[0; 32]));
+        try!(write_u32_be(output, 0xFFFFFFFF));
+        
+        try!(write_unmaxed_u32(output, self.drm.offset));
+        match self.drm.offset {
+            Some(_) => try!(write_u32_be(output, self.drm.count)),
+            None => try!(write_u32_be(output, 0xFFFFFFFF)),
+        }
+        try!(write_u32_be(output, self.drm.size));
+        try!(write_u32_be(output, self.drm.flags));
+        
+        try!(output.write_all(&[0; 8]));
+        match self.kf8 {
+            Some(ref kf8) => {
+                try!(write_unmaxed_u32(output, kf8.fdst_record));
+                try!(write_u32_be(output, kf8.fdst_count));
+            },
+            None => {
+                try!(write_u16_be(output, self.text_record));
+                try!(write_u16_be(output, self.last_record));
+                try!(write_u32_be(output, 1));
+            }
+        }
+        
+        try!(write_u32_be(output, self.fcis_flis.fcis_record_number));
+        try!(write_u32_be(output, self.fcis_flis.fcis_record_count));
+        try!(write_u32_be(output, self.fcis_flis.flis_record_number));
+        try!(write_u32_be(output, self.fcis_flis.flis_record_count));
+        
+        try!(output.write_all(&[0; 8]));
+        try!(write_u32_be(output, 0xFFFFFFFF));
+        try!(write_u32_be(output, self.compilation.data_section_count));
+        try!(write_unmaxed_u32(output, self.compilation.data_sections));
+        try!(write_u32_be(output, 0xFFFFFFFF));
+        
+        try!(write_u32_be(output, self.extra_record_data_flags));
+        try!(write_unmaxed_u32(output, self.indx_record_offset));
+        if let Some(ref kf8) = self.kf8 {
+            try!(write_unmaxed_u32(output, kf8.fragment_index));
+            try!(write_unmaxed_u32(output, kf8.skeleton_index));
+            try!(write_unmaxed_u32(output, kf8.datp_record));
+            try!(write_unmaxed_u32(output, kf8.guide_index));
+        }
+        try!(output.write_all(&self.header_tail));
+        Ok(())
+    }
+    
+    /// Shifts the record numbers of the header by one, after a record was
+    /// inserted at 'position' (relative to the record 0 of the header).
+    pub fn shift_records(&mut self, position: u32) {
+        fn shift(record: &mut u32, position: u32) {
+            if *record != 0xFFFFFFFF && *record >= position {
+                *record += 1;
+            }
+        }
+        fn shift_option(record: &mut Option<u32>, position: u32) {
+            if let Some(ref mut record) = *record {
+                shift(record, position);
+            }
+        }
+        
+        shift_option(&mut self.indices.orthographic, position);
+        shift_option(&mut self.indices.inflection, position);
+        shift_option(&mut self.indices.names, position);
+        shift_option(&mut self.indices.keys, position);
+        for index in self.indices.extra.iter_mut() {
+            shift_option(index, position);
+        }
+        // The first non-book record stays where the text ends
+        if self.first_non_book_record > position {
+            self.first_non_book_record += 1;
+        }
+        shift(&mut self.first_image_record, position);
+        if self.huffman_encoding.record_count > 0 {
+            shift(&mut self.huffman_encoding.record_offset, position);
+        }
+        shift(&mut self.fcis_flis.fcis_record_number, position);
+        shift(&mut self.fcis_flis.flis_record_number, position);
+        shift_option(&mut self.compilation.data_sections, position);
+        shift_option(&mut self.indx_record_offset, position);
+        match self.kf8 {
+            Some(ref mut kf8) => {
+                shift_option(&mut kf8.fdst_record, position);
+                shift_option(&mut kf8.fragment_index, position);
+                shift_option(&mut kf8.skeleton_index, position);
+                shift_option(&mut kf8.datp_record, position);
+                shift_option(&mut kf8.guide_index, position);
+            },
+            None => {
+                if self.last_record as u32 >= position {
+                    self.last_record += 1;
+                }
+            },
+        }
+    }
+    
+    /// Returns whether the header is followed by an EXTH header.
+    pub fn has_exth(&self) -> bool {
+        (self.exth_flags & 0x40) != 0
+    }
+    
     pub fn print_info(&self) {
         println!("===== MOBI header =====");
         println!("Id: {}, Version: {}", self.mobi_id, self.mobi_version);
@@ -345,5 +625,15 @@ impl MobiHeader {
         
         println!("Extra record data flags: {:b}", self.extra_record_data_flags);
         println!("INDX record offset: {:?}", self.indx_record_offset);
+        
+        if let Some(ref kf8) = self.kf8 {
+            println!("KF8:");
+            println!("- FDST record: {:?}, Count: {}", kf8.fdst_record, 
+                kf8.fdst_count);
+            println!("- Fragment index: {:?}", kf8.fragment_index);
+            println!("- Skeleton index: {:?}", kf8.skeleton_index);
+            println!("- DATP record:    {:?}", kf8.datp_record);
+            println!("- Guide index:    {:?}", kf8.guide_index);
+        }
     }
 }
\ No newline at end of file
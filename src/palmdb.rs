@@ -6,11 +6,14 @@ extern crate byteorder;
 use std::fmt;
 use std::io;
 use std::io::{Read, Write};
-use chrono::{NaiveDateTime};
+use chrono::{NaiveDateTime, UTC};
 use byteorder::{ReadBytesExt, BigEndian, WriteBytesExt};
+use serde::Serializer;
 use common::*;
+use container;
+use container::Container;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Record {
     pub id: u32,
     pub data_offset: u32,
@@ -26,19 +29,33 @@ impl fmt::Display for Record {
 
 valued_enum! {
     PalmDbType : &'static str {
-        Mobi = "BOOKMOBI"
+        Mobi = "BOOKMOBI",
+        Annotations = "BPARMOBI" // The annotations of a book (.mbp, .mbs)
     }
 }
 
+fn serialize_name<S: Serializer>(name: &[u8; 31], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(read_until_zero(name)))
+}
+
+fn serialize_date<S: Serializer>(date: &NaiveDateTime, serializer: S)
+        -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&date.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
 /// The Palm Database format header. 
 /// The struct only supports MOBI.
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct PalmdbHeader {
+    #[serde(serialize_with = "serialize_name")]
     pub name: [u8; 31], // Null-terminated string * by the program *
     pub attributes: u16,
     pub version: u16,
+    #[serde(serialize_with = "serialize_date")]
     pub creation_date: NaiveDateTime,
+    #[serde(serialize_with = "serialize_date")]
     pub modification_date: NaiveDateTime,
+    #[serde(serialize_with = "serialize_date")]
     pub backup_date: NaiveDateTime,
     pub modification_number: u32,
     pub app_info_offset: Option<u32>,
@@ -50,10 +67,61 @@ pub struct PalmdbHeader {
 }
 impl PalmdbHeader {
     
+    /// Creates a header for a new MOBI database with the given name.
+    /// The name is reduced to 31 ASCII characters, like Calibre does.
+    pub fn new(name: &str) -> PalmdbHeader {
+        let now = UTC::now().naive_utc();
+        let mut header = PalmdbHeader {
+            name: [0; 31],
+            attributes: 0,
+            version: 0,
+            creation_date: now,
+            modification_date: now,
+            backup_date: NaiveDateTime::from_timestamp(0, 0),
+            modification_number: 0,
+            app_info_offset: None,
+            sort_info_offset: None,
+            content_type: PalmDbType::Mobi,
+            unique_id_seed: 0,
+            next_record_list_id: 0,
+            records: Vec::new(),
+        };
+        header.set_name(name);
+        header
+    }
+    
+    /// Sets the name of the database.
+    /// The name is reduced to 31 ASCII characters, like Calibre does.
+    pub fn set_name(&mut self, name: &str) {
+        let cleaned = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect::<String>();
+        self.name = [0; 31];
+        for (i, byte) in cleaned.bytes().take(31).enumerate() {
+            self.name[i] = byte;
+        }
+    }
+    
     /// Reads a Palm database header from the given source
     pub fn read_from(source: &mut Read) -> Result<PalmdbHeader, io::Error> {
+        let header = try!(PalmdbHeader::read_database(source));
+        if header.content_type != PalmDbType::Mobi {
+            return Err(Container::PalmDb(header.content_type.value().to_string()).unsupported());
+        }
+        Ok(header)
+    }
+
+    /// Reads the header of a book or annotation database from the given
+    /// source.
+    pub fn read_database(source: &mut Read) -> Result<PalmdbHeader, io::Error> {
         let mut name_buf = [0; 32];
-        try!(source.read(&mut name_buf));
+        try!(source.read_exact(&mut name_buf));
+        // Topaz and KFX books start with their own magic instead of a name
+        match container::sniff(&name_buf) {
+            container @ Container::Topaz | container @ Container::Kfx
+                | container @ Container::KfxDrm => return Err(container.unsupported()),
+            _ => {},
+        }
         let mut name: [u8; 31] = [0; 31];
         for i in 0..31 {
             name[i] = name_buf[i];
@@ -87,9 +155,12 @@ impl PalmdbHeader {
     
         let file_type = try!(read_string(source, 4));
         let creator_program = try!(read_string(source, 4));
-        assert_eq!(file_type, String::from("BOOK"));
-        assert_eq!(creator_program, String::from("MOBI"));
-        let content_type = PalmDbType::Mobi;
+        let kind = format!("{}{}", file_type, creator_program);
+        let content_type = match &kind[..] {
+            "BOOKMOBI" => PalmDbType::Mobi,
+            "BPARMOBI" => PalmDbType::Annotations,
+            _ => return Err(Container::PalmDb(kind).unsupported()),
+        };
     
         let unique_id_seed = try!(read_u32_be(source));
         let next_record_list_id = try!(read_u32_be(source));
@@ -165,4 +236,49 @@ impl PalmdbHeader {
         
         Ok(())
     }
-}
\ No newline at end of file
+    
+    /// Returns the size of the header as written, including the record list.
+    pub fn size(&self) -> u32 {
+        78 + 8 * self.records.len() as u32 + 2
+    }
+    
+    /// Writes the header followed by the given record data.
+    /// The record list is rebuilt so that the ids and offsets match the data.
+    pub fn write_with_records(&mut self, data: &[Vec<u8>], output: &mut Write)
+            -> Result<(), io::Error> {
+        self.set_records(data);
+        try!(self.write_to(output));
+        for record in data.iter() {
+            try!(output.write_all(record));
+        }
+        Ok(())
+    }
+    
+    /// Rebuilds the record list for the given record data, keeping the ids
+    /// and attributes of the existing records and shifting their offsets.
+    pub fn set_records(&mut self, data: &[Vec<u8>]) {
+        let previous = self.records.clone();
+        let mut next_id = previous.iter().map(|r| r.id + 2).max().unwrap_or(0);
+        self.records.clear();
+        let mut offset = 78 + 8 * data.len() as u32 + 2;
+        for (i, record) in data.iter().enumerate() {
+            let (id, attributes) = match previous.get(i) {
+                Some(old) => (old.id, old.attributes),
+                None => {
+                    next_id += 2;
+                    (next_id - 2, 0)
+                },
+            };
+            self.records.push(Record { id: id, data_offset: offset, 
+                attributes: attributes });
+            offset += record.len() as u32;
+        }
+        if let Some(last) = self.records.iter().map(|r| r.id).max() {
+            self.unique_id_seed = ::std::cmp::max(self.unique_id_seed, last + 1);
+            // Some creators keep the next free record id here
+            if self.next_record_list_id != 0 {
+                self.next_record_list_id = ::std::cmp::max(self.next_record_list_id, last + 2);
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! PalmDOC (LZ77) compression of text records.
+
+/// The maximum number of uncompressed bytes in a text record.
+pub const RECORD_SIZE: usize = 4096;
+
+// Number of buckets in the hash chains used to find back-references
+const HASH_SIZE: usize = 1 << 12;
+
+/// Compresses a chunk of text using the PalmDOC scheme.
+/// This follows the Calibre implementation: back-references of 3 to 10 bytes
+/// within 2047 bytes, 'space + char' pairs, and literal runs.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let len = data.len();
+    let mut chains = Chains::new(len);
+    let mut i = 0;
+
+    while i < len {
+        chains.insert_until(data, i);
+        if i > 10 && len - i > 10 {
+            if let Some((distance, length)) = chains.find_match(data, i) {
+                let code = 0x8000 | ((distance << 3) & 0x3FF8) | (length - 3);
+                output.push((code >> 8) as u8);
+                output.push((code & 0xFF) as u8);
+                i += length;
+                continue;
+            }
+        }
+
+        let byte = data[i];
+        i += 1;
+        if byte == b' ' && i + 1 < len && data[i] >= 0x40 && data[i] < 0x80 {
+            output.push(data[i] ^ 0x80);
+            i += 1;
+            continue;
+        }
+
+        if byte == 0 || (byte > 8 && byte < 0x80) {
+            output.push(byte);
+        } else {
+            // Binary bytes are stored as a counted run of up to 8 bytes
+            let start = i - 1;
+            let mut end = i;
+            while end < len && end - start < 8 {
+                let next = data[end];
+                if next == 0 || (next > 8 && next < 0x80) {
+                    break;
+                }
+                end += 1;
+            }
+            output.push((end - start) as u8);
+            output.extend_from_slice(&data[start..end]);
+            i = end;
+        }
+    }
+
+    output
+}
+
+/// Hash chains over the 3-byte prefixes of the positions seen so far.
+struct Chains {
+    head: Vec<Option<usize>>,
+    previous: Vec<Option<usize>>,
+    inserted: usize,
+}
+
+impl Chains {
+    fn new(len: usize) -> Chains {
+        Chains {
+            head: vec![None; HASH_SIZE],
+            previous: vec![None; len],
+            inserted: 0,
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        ((data[pos] as usize) << 6 ^ (data[pos + 1] as usize) << 3
+            ^ data[pos + 2] as usize) & (HASH_SIZE - 1)
+    }
+
+    /// Adds every position before 'pos' to the chains.
+    fn insert_until(&mut self, data: &[u8], pos: usize) {
+        while self.inserted < pos && self.inserted + 3 <= data.len() {
+            let hash = Chains::hash(data, self.inserted);
+            self.previous[self.inserted] = self.head[hash];
+            self.head[hash] = Some(self.inserted);
+            self.inserted += 1;
+        }
+    }
+
+    /// Finds the longest earlier occurrence (3 to 10 bytes, within 2047
+    /// bytes and not overlapping 'pos') of the bytes at 'pos'.
+    /// Returns (distance, length).
+    fn find_match(&self, data: &[u8], pos: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[Chains::hash(data, pos)];
+        while let Some(start) = candidate {
+            let distance = pos - start;
+            if distance > 2047 {
+                break;
+            }
+            let max_len = *[10, distance, data.len() - pos].iter().min().unwrap();
+            let mut length = 0;
+            while length < max_len && data[start + length] == data[pos + length] {
+                length += 1;
+            }
+            if length >= 3 && best.map_or(true, |(_, len)| length > len) {
+                best = Some((distance, length));
+                if length == 10 {
+                    break;
+                }
+            }
+            candidate = self.previous[start];
+        }
+        best
+    }
+}
+
+/// Decompresses a PalmDOC-compressed record.
+/// Trailing entries must already have been stripped from the record.
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::with_capacity(RECORD_SIZE);
+    let mut i = 0;
+
+    while i < data.len() {
+        let byte = data[i];
+        i += 1;
+        match byte {
+            0x01 ... 0x08 => {
+                let end = ::std::cmp::min(i + byte as usize, data.len());
+                output.extend_from_slice(&data[i..end]);
+                i = end;
+            },
+            0x00 | 0x09 ... 0x7F => {
+                output.push(byte);
+            },
+            0x80 ... 0xBF => {
+                if i >= data.len() {
+                    break;
+                }
+                let pair = ((byte as usize) << 8 | data[i] as usize) & 0x3FFF;
+                i += 1;
+                let distance = pair >> 3;
+                let length = (pair & 0x07) + 3;
+                if distance == 0 || distance > output.len() {
+                    continue; // Corrupt back-reference
+                }
+                // The source may overlap the bytes being written
+                let start = output.len() - distance;
+                for j in 0..length {
+                    let copied = output[start + j];
+                    output.push(copied);
+                }
+            },
+            _ => {
+                output.push(b' ');
+                output.push(byte ^ 0x80);
+            }
+        }
+    }
+
+    output
+}
@@ -0,0 +1,295 @@
+//! Positions in the text of a book, given as byte offsets, Kindle locations,
+//! percentages, text records with offsets, or KF8 `kindle:pos` fragments.
+//!
+//! Positions are byte offsets of the uncompressed text. In KF8 sections
+//! they are offsets of the rebuilt documents, as used by the NCX and the
+//! `kindle:pos` links, which differ from the offsets of the stored text
+//! where fragments were moved out of their skeletons. A Kindle location is
+//! a block of 150 bytes, numbered from 1.
+
+use std::io;
+use book::{MobiBook, Section};
+use kf8;
+use kf8_reader;
+use kf8_reader::FragmentEntry;
+
+/// The number of bytes of text in a Kindle location.
+pub const LOCATION_SIZE: u32 = 150;
+
+/// A position given in every form.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Position {
+    pub offset: u32,
+    pub location: u32,
+    pub percentage: f64,
+    /// The text record (from 1) and the offset in its uncompressed text.
+    pub record: Option<(u32, u32)>,
+    /// The fragment and the offset in it, for KF8 sections.
+    pub pos_fid: Option<(u32, u32)>,
+}
+
+/// Converts between the forms of the positions of a section.
+#[derive(Debug, Clone)]
+pub struct PositionMap {
+    /// The length of the text: the rebuilt documents of KF8 sections.
+    pub length: u32,
+    record_size: u32,
+    /// The length of the stored text, for all the records.
+    stored_length: u32,
+    /// The pieces of the rebuilt text of a KF8 section, in order: their
+    /// position, their offset in the stored text and their length.
+    pieces: Vec<(u32, u32, u32)>,
+    fragments: Vec<FragmentEntry>,
+    /// The start and length of the rebuilt documents.
+    parts: Vec<(u32, u32)>,
+}
+
+impl PositionMap {
+    /// Builds the map of a section. KF8 sections need their indices, and
+    /// the text to find the flows.
+    pub fn new(book: &MobiBook, section: &Section) -> Result<PositionMap, io::Error> {
+        try!(book.check_html());
+        let header = &section.header;
+        let mut map = PositionMap {
+            length: header.uncompressed_text_length,
+            record_size: ::std::cmp::max(header.text_record_size as u32, 1),
+            stored_length: header.uncompressed_text_length,
+            pieces: Vec::new(),
+            fragments: Vec::new(),
+            parts: Vec::new(),
+        };
+        if !section.is_kf8() {
+            return Ok(map);
+        }
+
+        let text = try!(kf8_reader::read_text(book, section));
+        let flow_start = match header.kf8.as_ref().and_then(|kf8| kf8.fdst_record) {
+            Some(record) if text.flows.len() > 1 => {
+                match book.section_record(section, record) {
+                    Some(record) => try!(kf8::read_fdst(record)).get(0).map_or(0, |flow| flow.0),
+                    None => 0,
+                }
+            },
+            _ => 0,
+        };
+
+        // Follow how the documents are rebuilt, with pieces instead of bytes
+        let mut fragment_index = 0;
+        for (skeleton, part) in text.skeletons.iter().zip(text.parts.iter()) {
+            let mut pieces = vec![(skeleton.start_pos, skeleton.length)];
+            let mut base = skeleton.start_pos + skeleton.length;
+            for fragment in text.fragments.iter().skip(fragment_index)
+                    .take(skeleton.fragment_count as usize) {
+                let insert = fragment.insert_pos.saturating_sub(skeleton.start_pos);
+                split_pieces(&mut pieces, insert);
+                let mut at = 0;
+                let index = pieces.iter()
+                    .position(|&(_, length)| { at += length; at > insert })
+                    .unwrap_or(pieces.len());
+                pieces.insert(index, (base, fragment.length));
+                base += fragment.length;
+            }
+            fragment_index += skeleton.fragment_count as usize;
+
+            let mut position = part.start;
+            for (offset, length) in pieces.into_iter().filter(|&(_, length)| length > 0) {
+                map.pieces.push((position, flow_start + offset, length));
+                position += length;
+            }
+        }
+        map.length = text.parts.iter().map(|part| part.length).sum();
+        map.parts = text.parts.iter().map(|part| (part.start, part.length)).collect();
+        map.fragments = text.fragments;
+        Ok(map)
+    }
+
+    /// Returns the Kindle location of a position.
+    pub fn location(&self, position: u32) -> u32 {
+        position / LOCATION_SIZE + 1
+    }
+
+    /// Returns the position of the start of a Kindle location.
+    pub fn location_position(&self, location: u32) -> u32 {
+        ::std::cmp::min(location.saturating_sub(1).saturating_mul(LOCATION_SIZE), self.length)
+    }
+
+    /// Returns the number of Kindle locations of the text.
+    pub fn location_count(&self) -> u32 {
+        self.length / LOCATION_SIZE + if self.length % LOCATION_SIZE > 0 { 1 } else { 0 }
+    }
+
+    /// Returns how far into the text a position is, in percent.
+    pub fn percentage(&self, position: u32) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        ::std::cmp::min(position, self.length) as f64 * 100.0 / self.length as f64
+    }
+
+    /// Returns the position at some percentage of the text.
+    pub fn percentage_position(&self, percentage: f64) -> u32 {
+        let percentage = percentage.max(0.0).min(100.0);
+        (percentage * self.length as f64 / 100.0) as u32
+    }
+
+    /// Returns the offset of a position in the stored text, which differs
+    /// from the position in KF8 sections.
+    pub fn stored_offset(&self, position: u32) -> Option<u32> {
+        let offset = if self.pieces.is_empty() {
+            Some(position)
+        } else {
+            self.pieces.iter()
+                .find(|&&(start, _, length)| start <= position && position < start + length)
+                .map(|&(start, offset, _)| offset + position - start)
+        };
+        offset.and_then(|offset| if offset < self.stored_length { Some(offset) } else { None })
+    }
+
+    /// Returns the position of an offset in the stored text.
+    pub fn stored_position(&self, offset: u32) -> Option<u32> {
+        if offset >= self.stored_length {
+            return None;
+        }
+        if self.pieces.is_empty() {
+            return Some(offset);
+        }
+        self.pieces.iter()
+            .find(|&&(_, start, length)| start <= offset && offset < start + length)
+            .map(|&(position, start, _)| position + offset - start)
+    }
+
+    /// Returns the text record (from 1) holding a position, and the offset
+    /// of the position in its uncompressed text.
+    pub fn record(&self, position: u32) -> Option<(u32, u32)> {
+        self.stored_offset(position)
+            .map(|offset| (offset / self.record_size + 1, offset % self.record_size))
+    }
+
+    /// Returns the position of an offset in the uncompressed text of a text
+    /// record (from 1).
+    pub fn record_position(&self, record: u32, offset: u32) -> Option<u32> {
+        if record == 0 || offset >= self.record_size {
+            return None;
+        }
+        (record - 1).checked_mul(self.record_size)
+            .and_then(|start| start.checked_add(offset))
+            .and_then(|offset| self.stored_position(offset))
+    }
+
+    /// Returns the position of a fragment (by its index in the FRAG index)
+    /// and an offset in it.
+    pub fn pos_fid_position(&self, fid: u32, offset: u32) -> Option<u32> {
+        self.fragments.get(fid as usize)
+            .and_then(|fragment| fragment.insert_pos.checked_add(offset))
+            .and_then(|position| if position <= self.length { Some(position) } else { None })
+    }
+
+    /// Returns the position a `kindle:pos:fid:XXXX:off:YYYYYYYYYY` URI
+    /// points to.
+    pub fn kindle_pos_position(&self, uri: &str) -> Option<u32> {
+        kf8::parse_pos_uri(uri).and_then(|(fid, offset)| self.pos_fid_position(fid, offset))
+    }
+
+    /// Returns the fragment and the offset in it of a position: the last
+    /// fragment of its document starting at or before the position.
+    pub fn pos_fid(&self, position: u32) -> Option<(u32, u32)> {
+        let file_number = match self.parts.iter()
+                .position(|&(start, length)| start <= position && position < start + length) {
+            Some(index) => index as u32,
+            None => return None,
+        };
+        self.fragments.iter().enumerate()
+            .filter(|&(_, fragment)| fragment.file_number == file_number)
+            .take_while(|&(_, fragment)| fragment.insert_pos <= position)
+            .last()
+            .map(|(index, fragment)| (index as u32, position - fragment.insert_pos))
+    }
+
+    /// Returns a position in every form.
+    pub fn describe(&self, position: u32) -> Position {
+        Position {
+            offset: position,
+            location: self.location(position),
+            percentage: self.percentage(position),
+            record: self.record(position),
+            pos_fid: self.pos_fid(position),
+        }
+    }
+}
+
+/// Splits the piece of a document holding an offset, so that a piece
+/// starts there.
+fn split_pieces(pieces: &mut Vec<(u32, u32)>, at: u32) {
+    let mut start = 0;
+    for index in 0..pieces.len() {
+        let (offset, length) = pieces[index];
+        if at > start && at < start + length {
+            pieces[index] = (offset, at - start);
+            pieces.insert(index + 1, (offset + at - start, start + length - at));
+            return;
+        }
+        start += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn kf7_positions() {
+        let paragraphs = (0..1000).map(|i| format!("<p>Paragraph {}</p>", i)).collect::<String>();
+        let book = testing::kf7_book(&format!("<html><body>{}</body></html>", paragraphs));
+        let map = PositionMap::new(&book, &book.main).unwrap();
+        assert_eq!(map.length as usize, book.text(&book.main).unwrap().len());
+        assert!(map.record(map.length - 1).unwrap().0 > 1);
+        assert_eq!(map.location_count(), map.location(map.length - 1));
+
+        for position in (0..map.length).filter(|position| position % 997 == 0) {
+            let (record, offset) = map.record(position).unwrap();
+            assert_eq!(map.record_position(record, offset), Some(position));
+            let location = map.location(position);
+            assert_eq!(location, position / LOCATION_SIZE + 1);
+            assert_eq!(map.location_position(location), position - position % LOCATION_SIZE);
+            let percentage = map.percentage(position);
+            assert!(map.percentage_position(percentage) + 1 >= position);
+            assert_eq!(map.describe(position).pos_fid, None);
+        }
+        assert_eq!(map.record(map.length), None);
+        assert_eq!(map.record_position(0, 0), None);
+        assert_eq!(map.record_position(::std::u32::MAX, 4095), None);
+        assert_eq!(map.location_position(::std::u32::MAX), map.length);
+        assert_eq!(map.percentage_position(100.0), map.length);
+    }
+
+    #[test]
+    fn kf8_positions() {
+        let book = testing::kf8_book(&testing::linked_kf8_writer());
+        let section = book.kf8().unwrap();
+        let map = PositionMap::new(&book, section).unwrap();
+        let text = kf8_reader::read_text(&book, section).unwrap();
+        let rebuilt = text.parts.iter().flat_map(|part| part.data.clone()).collect::<Vec<_>>();
+        let stored = book.text(section).unwrap();
+        assert_eq!(map.length as usize, rebuilt.len());
+
+        for position in 0..map.length {
+            let offset = map.stored_offset(position).unwrap();
+            assert_eq!(stored[offset as usize], rebuilt[position as usize]);
+            assert_eq!(map.stored_position(offset), Some(position));
+            let (record, offset) = map.record(position).unwrap();
+            assert_eq!(map.record_position(record, offset), Some(position));
+            // The heads of the documents come before their first fragment
+            if let Some((fid, offset)) = map.pos_fid(position) {
+                assert_eq!(map.kindle_pos_position(&kf8::pos_uri(fid, offset)), Some(position));
+            }
+        }
+
+        // The target of the link back to the first document
+        let target = ::html::find_bytes(&rebuilt, b"<p id=\"p300\"").unwrap() as u32;
+        let (fid, offset) = map.pos_fid(target).unwrap();
+        assert_eq!(map.pos_fid_position(fid, offset), Some(target));
+        assert_eq!(map.pos_fid_position(fid, ::std::u32::MAX), None);
+        assert_eq!(map.pos_fid_position(text.fragments.len() as u32, 0), None);
+    }
+}
@@ -0,0 +1,613 @@
+//! Rendering of book content as plain text or Markdown, for reading the
+//! text without its markup.
+//!
+//! Block elements become paragraphs separated by blank lines, lists get
+//! bullets or numbers, and table rows become lines of cells. Page breaks
+//! (`<mbp:pagebreak/>`) and the boundaries of KF8 documents separate the
+//! chapters: with a form feed in plain text, and with a `---` rule in
+//! Markdown. Other Mobipocket tags and `<font>` tags only keep their text.
+
+use std::collections::HashMap;
+use std::io;
+use book::MobiBook;
+use common::*;
+use html;
+use html::{Tag, Token, Tokenizer};
+use kf7;
+use kf8;
+use kf8_reader;
+use mobi::TextEncoding;
+
+/// The formats text can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextFormat {
+    PlainText,
+    Markdown,
+}
+
+impl TextFormat {
+    /// Returns the format with the given name: "txt" or "md".
+    pub fn from_name(name: &str) -> Option<TextFormat> {
+        match &name.to_lowercase()[..] {
+            "txt" | "text" | "plain" => Some(TextFormat::PlainText),
+            "md" | "markdown" => Some(TextFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    /// Returns the line separating chapters.
+    pub fn chapter_separator(&self) -> &'static str {
+        match *self {
+            TextFormat::PlainText => "\x0C",
+            TextFormat::Markdown => "---",
+        }
+    }
+}
+
+/// Elements whose content is not part of the text.
+const HIDDEN_ELEMENTS: &'static [&'static str] = &["head", "script", "style", "title",
+    "guide", "svg"];
+
+/// Elements that start and end a paragraph.
+const BLOCK_ELEMENTS: &'static [&'static str] = &["p", "div", "body", "section", "article",
+    "header", "footer", "aside", "nav", "figure", "figcaption", "address", "center",
+    "dl", "dt", "dd", "caption", "mbp:section", "mbp:frameset", "mbp:nu"];
+
+/// The kinds of blocks. List items and table rows follow blocks of the same
+/// kind without blank lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockKind {
+    Paragraph,
+    ListItem,
+    TableRow,
+}
+
+/// A paragraph of the output, with its prefix.
+struct Block {
+    text: String,
+    kind: BlockKind,
+}
+
+struct List {
+    ordered: bool,
+    count: usize,
+}
+
+/// Turns markup into text, one token at a time.
+struct Renderer<'a> {
+    format: TextFormat,
+    /// The names of the images by resource index.
+    images: &'a HashMap<usize, String>,
+    blocks: Vec<Block>,
+    /// The text of the current paragraph.
+    inline: String,
+    /// The heading level of the current paragraph.
+    heading: usize,
+    hidden: usize,
+    pre: usize,
+    quotes: usize,
+    lists: Vec<List>,
+    /// The marker of the list item that the current paragraph starts.
+    item_marker: Option<String>,
+    /// The start of the text of the open links, with their targets.
+    links: Vec<(usize, Option<String>)>,
+    /// The emphasis markers of the open elements, by element name.
+    emphasis: Vec<(String, &'static str)>,
+    /// The emphasis markers that open before the next visible character.
+    pending: String,
+    /// The cells of the current table row, and the number of rows so far.
+    cells: Option<Vec<String>>,
+    cell_open: bool,
+    rows: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(format: TextFormat, images: &'a HashMap<usize, String>) -> Renderer<'a> {
+        Renderer {
+            format: format,
+            images: images,
+            blocks: Vec::new(),
+            inline: String::new(),
+            heading: 0,
+            hidden: 0,
+            pre: 0,
+            quotes: 0,
+            lists: Vec::new(),
+            item_marker: None,
+            links: Vec::new(),
+            emphasis: Vec::new(),
+            pending: String::new(),
+            cells: None,
+            cell_open: false,
+            rows: 0,
+        }
+    }
+
+    fn is_markdown(&self) -> bool {
+        self.format == TextFormat::Markdown
+    }
+
+    /// Returns the indentation of the content of the open lists.
+    fn list_indent(&self) -> String {
+        let depth = self.lists.len().saturating_sub(1);
+        ::std::iter::repeat("  ").take(depth).collect()
+    }
+
+    /// Adds a block of text, with the quote prefix and list indentation.
+    /// The lines of list items after the first are indented past the marker.
+    fn push_block(&mut self, text: &str, kind: BlockKind) {
+        let quote = if self.is_markdown() {
+            ::std::iter::repeat("> ").take(self.quotes).collect::<String>()
+        } else {
+            String::new()
+        };
+        let indent = if self.lists.is_empty() {
+            String::new()
+        } else if kind == BlockKind::ListItem {
+            self.list_indent()
+        } else {
+            self.list_indent() + "  "
+        };
+        let mut lines = Vec::new();
+        for (i, line) in text.split('\n').enumerate() {
+            let hanging = if i > 0 && kind == BlockKind::ListItem { "  " } else { "" };
+            lines.push(format!("{}{}{}{}", quote, indent, hanging, line).trim_right().to_string());
+        }
+        self.blocks.push(Block { text: lines.join("\n"), kind: kind });
+    }
+
+    /// Ends the current paragraph.
+    fn end_paragraph(&mut self) {
+        if self.cells.is_some() {
+            // Paragraphs inside table cells stay on the line of the row
+            self.space();
+            return;
+        }
+        let text = self.inline.trim().to_string();
+        self.inline.clear();
+        self.links.clear();
+        self.pending.clear();
+        let marker = self.item_marker.take();
+        if text.is_empty() {
+            self.heading = 0;
+            if let Some(marker) = marker {
+                // Keep the marker for the content of the item
+                self.item_marker = Some(marker);
+            }
+            return;
+        }
+        let text = match (self.heading, self.is_markdown()) {
+            (0, _) | (_, false) => text,
+            (level, true) => format!("{} {}", ::std::iter::repeat("#").take(level).collect::<String>(), text),
+        };
+        self.heading = 0;
+        match marker {
+            Some(marker) => {
+                let text = format!("{}{}", marker, text);
+                self.push_block(&text, BlockKind::ListItem);
+            },
+            None => self.push_block(&text, BlockKind::Paragraph),
+        }
+    }
+
+    /// Adds a line of its own, such as a rule or a chapter separator.
+    fn push_separator(&mut self, separator: &str) {
+        self.end_paragraph();
+        let repeated = self.blocks.last().map_or(true, |block| block.text == separator);
+        if !repeated || separator != self.format.chapter_separator() {
+            self.blocks.push(Block { text: separator.to_string(), kind: BlockKind::Paragraph });
+        }
+    }
+
+    /// Separates chapters, unless nothing came before.
+    fn chapter_break(&mut self) {
+        let separator = self.format.chapter_separator();
+        self.push_separator(separator);
+    }
+
+    /// Adds a collapsible space.
+    fn space(&mut self) {
+        if !self.inline.is_empty() && !self.inline.ends_with(' ') && !self.inline.ends_with('\n') {
+            self.inline.push(' ');
+        }
+    }
+
+    fn text(&mut self, raw: &[u8]) {
+        let text = html::unescape(raw);
+        if self.pre > 0 {
+            self.inline.push_str(&text);
+            return;
+        }
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.space();
+                continue;
+            }
+            self.open_emphasis();
+            if self.is_markdown() && "\\`*_[]<>".contains(c) {
+                self.inline.push('\\');
+                self.inline.push(c);
+            } else {
+                self.inline.push(c);
+            }
+        }
+    }
+
+    /// Cuts the text of the current paragraph, moving the start of the open
+    /// links that were past the cut to it.
+    fn truncate_inline(&mut self, end: usize) {
+        self.inline.truncate(end);
+        for link in self.links.iter_mut() {
+            link.0 = ::std::cmp::min(link.0, end);
+        }
+    }
+
+    /// Opens the pending emphasis.
+    fn open_emphasis(&mut self) {
+        if !self.pending.is_empty() {
+            self.inline.push_str(&self.pending);
+            self.pending.clear();
+        }
+    }
+
+    /// Returns the name of the image a tag shows, from its recindex (KF7) or
+    /// its `kindle:embed` source (KF8).
+    fn image_name(&self, tag: &Tag) -> Option<String> {
+        let index = match tag.attribute("recindex") {
+            Some(recindex) => recindex.trim().parse::<usize>().ok(),
+            None => tag.attribute("src")
+                .and_then(|src| kf8::parse_uri_number(src, "embed"))
+                .map(|number| number as usize),
+        };
+        index.and_then(|index| self.images.get(&index.saturating_sub(1)).cloned())
+            .or_else(|| tag.attribute("src").map(String::from))
+    }
+
+    fn image(&mut self, tag: &Tag) {
+        let alt = html::unescape(tag.attribute("alt").unwrap_or("").as_bytes());
+        let alt = alt.trim();
+        let source = self.image_name(tag);
+        self.space();
+        self.open_emphasis();
+        match (self.is_markdown(), source) {
+            (true, Some(source)) => {
+                self.inline.push_str(&format!("![{}]({})", alt, source.replace(' ', "%20")));
+            },
+            _ if alt.is_empty() => self.inline.push_str("[Image]"),
+            _ => self.inline.push_str(&format!("[Image: {}]", alt)),
+        }
+    }
+
+    fn start_tag(&mut self, tag: &Tag) {
+        let name = &tag.name[..];
+        if HIDDEN_ELEMENTS.contains(&name) {
+            if !tag.self_closing {
+                self.hidden += 1;
+            }
+            return;
+        }
+        if self.hidden > 0 {
+            return;
+        }
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.end_paragraph();
+                self.heading = (name.as_bytes()[1] - b'0') as usize;
+            },
+            "br" => {
+                if self.cells.is_some() {
+                    self.space();
+                } else {
+                    let end = self.inline.trim_right().len();
+                    self.truncate_inline(end);
+                    self.inline.push_str(if self.is_markdown() { "\\\n" } else { "\n" });
+                }
+            },
+            "hr" => self.push_separator("* * *"),
+            "mbp:pagebreak" => self.chapter_break(),
+            "pre" => {
+                self.end_paragraph();
+                self.pre += 1;
+            },
+            "blockquote" => {
+                self.end_paragraph();
+                self.quotes += 1;
+            },
+            "ul" | "ol" => {
+                self.end_paragraph();
+                let start = tag.attribute("start").and_then(|start| start.trim().parse().ok());
+                self.lists.push(List {
+                    ordered: name == "ol",
+                    count: start.map_or(0, |start: usize| start.saturating_sub(1)),
+                });
+            },
+            "li" => {
+                self.end_paragraph();
+                let marker = match self.lists.last_mut() {
+                    Some(ref mut list) if list.ordered => {
+                        list.count += 1;
+                        format!("{}. ", list.count)
+                    },
+                    _ => "- ".to_string(),
+                };
+                self.item_marker = Some(marker);
+            },
+            "table" => {
+                self.end_paragraph();
+                self.rows = 0;
+            },
+            "tr" => {
+                self.end_row();
+                self.end_paragraph();
+                self.cells = Some(Vec::new());
+            },
+            "td" | "th" => {
+                self.end_cell();
+                self.cell_open = self.cells.is_some();
+            },
+            "img" => self.image(tag),
+            "a" => {
+                let href = tag.attribute("href")
+                    .and_then(|href| if html::is_external(href) { Some(href.to_string()) } else { None });
+                self.links.push((self.inline.len(), href));
+            },
+            "i" | "em" | "cite" | "b" | "strong" if self.is_markdown() && !tag.self_closing => {
+                let marker = if name == "b" || name == "strong" { "**" } else { "*" };
+                self.pending.push_str(marker);
+                self.emphasis.push((name.to_string(), marker));
+            },
+            _ if BLOCK_ELEMENTS.contains(&name) => self.end_paragraph(),
+            _ => {},
+        }
+    }
+
+    /// Ends the current table cell, if there is one.
+    fn end_cell(&mut self) {
+        if !self.cell_open {
+            return;
+        }
+        self.cell_open = false;
+        let text = self.inline.trim().to_string();
+        self.inline.clear();
+        self.links.clear();
+        self.pending.clear();
+        if let Some(ref mut cells) = self.cells {
+            cells.push(text);
+        }
+    }
+
+    fn end_row(&mut self) {
+        self.end_cell();
+        let cells = match self.cells.take() {
+            Some(cells) => cells,
+            None => return,
+        };
+        if cells.iter().all(|cell| cell.is_empty()) {
+            return;
+        }
+        if self.is_markdown() {
+            let row = format!("| {} |", cells.iter()
+                .map(|cell| cell.replace('|', "\\|"))
+                .collect::<Vec<_>>()
+                .join(" | "));
+            let mut text = row;
+            if self.rows == 0 {
+                let rule = vec!["---"; cells.len()].join(" | ");
+                text = format!("{}\n| {} |", text, rule);
+            }
+            self.push_block(&text, BlockKind::TableRow);
+        } else {
+            let text = cells.join("\t");
+            self.push_block(&text, BlockKind::TableRow);
+        }
+        self.rows += 1;
+    }
+
+    fn end_tag(&mut self, name: &str) {
+        if HIDDEN_ELEMENTS.contains(&name) {
+            self.hidden = self.hidden.saturating_sub(1);
+            return;
+        }
+        if self.hidden > 0 {
+            return;
+        }
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.end_paragraph(),
+            "pre" => {
+                let text = self.inline.trim_matches('\n').to_string();
+                self.inline.clear();
+                self.pre = self.pre.saturating_sub(1);
+                if !text.trim().is_empty() {
+                    let text = if self.is_markdown() {
+                        format!("```\n{}\n```", text)
+                    } else {
+                        text
+                    };
+                    self.push_block(&text, BlockKind::Paragraph);
+                }
+            },
+            "blockquote" => {
+                self.end_paragraph();
+                self.quotes = self.quotes.saturating_sub(1);
+            },
+            "ul" | "ol" => {
+                self.end_paragraph();
+                self.lists.pop();
+                self.item_marker = None;
+            },
+            "li" => self.end_paragraph(),
+            "td" | "th" => self.end_cell(),
+            "tr" => self.end_row(),
+            "table" => {
+                self.end_row();
+                self.end_paragraph();
+            },
+            "a" => {
+                if let Some((start, Some(href))) = self.links.pop() {
+                    let start = ::std::cmp::min(start, self.inline.len());
+                    let text = self.inline[start..].trim().to_string();
+                    if !text.is_empty() {
+                        let lead = if self.inline[start..].starts_with(' ') { " " } else { "" };
+                        self.inline.truncate(start);
+                        self.inline.push_str(&format!("{}[{}]({})", lead, text, href));
+                    }
+                }
+            },
+            "i" | "em" | "cite" | "b" | "strong" if self.is_markdown() => {
+                let position = self.emphasis.iter().rposition(|&(ref open, _)| open == name);
+                if let Some(position) = position {
+                    let (_, marker) = self.emphasis.remove(position);
+                    self.close_emphasis(marker);
+                }
+            },
+            _ if BLOCK_ELEMENTS.contains(&name) => self.end_paragraph(),
+            _ => {},
+        }
+    }
+
+    /// Closes emphasis, keeping the spaces before its end outside of it.
+    /// Emphasis that never opened is dropped.
+    fn close_emphasis(&mut self, marker: &str) {
+        if self.pending.ends_with(marker) {
+            let end = self.pending.len() - marker.len();
+            self.pending.truncate(end);
+            return;
+        }
+        let trailing = self.inline.ends_with(' ');
+        let end = self.inline.trim_right().len();
+        self.truncate_inline(end);
+        self.inline.push_str(marker);
+        if trailing {
+            self.inline.push(' ');
+        }
+    }
+
+    /// Renders a document.
+    fn document(&mut self, data: &[u8]) {
+        for (_, token) in Tokenizer::new(data) {
+            match token {
+                Token::Text(text) => {
+                    if self.hidden == 0 {
+                        self.text(text);
+                    }
+                },
+                Token::StartTag(ref tag) => self.start_tag(tag),
+                Token::EndTag(ref name) => self.end_tag(&name.to_lowercase()),
+                _ => {},
+            }
+        }
+        self.end_row();
+        self.end_paragraph();
+    }
+
+    fn finish(mut self) -> String {
+        self.end_paragraph();
+        let separator = self.format.chapter_separator();
+        while self.blocks.last().map_or(false, |block| block.text == separator) {
+            self.blocks.pop();
+        }
+        let mut output = String::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                let previous = self.blocks[i - 1].kind;
+                let tight = block.kind != BlockKind::Paragraph && block.kind == previous;
+                output.push_str(if tight { "\n" } else { "\n\n" });
+            }
+            output.push_str(&block.text);
+        }
+        output.push('\n');
+        output
+    }
+}
+
+/// Renders documents as text, separating them as chapters. Images are named
+/// by their resource index (from 0).
+pub fn render(documents: &[&[u8]], format: TextFormat, images: &HashMap<usize, String>)
+        -> String {
+    let mut renderer = Renderer::new(format, images);
+    for document in documents.iter() {
+        if !renderer.blocks.is_empty() {
+            renderer.chapter_break();
+        }
+        renderer.document(document);
+    }
+    renderer.finish()
+}
+
+/// Renders the text of a book, from its KF8 documents when it has them.
+/// Images are referenced by the names they get in EPUB conversions.
+pub fn render_book(book: &MobiBook, format: TextFormat) -> Result<String, io::Error> {
+    try!(book.check_html());
+    let images = try!(kf7::image_names(book));
+    match book.kf8() {
+        Some(section) => {
+            let text = try!(kf8_reader::read_text(book, section));
+            let parts = text.parts.iter().map(|part| &part.data[..]).collect::<Vec<_>>();
+            Ok(render(&parts, format, &images))
+        },
+        None => {
+            let text = try!(book.text(&book.main));
+            let text = match book.main.header.text_encoding {
+                TextEncoding::Latin1 => decode_cp1252(&text).into_bytes(),
+                _ => text,
+            };
+            Ok(render(&[&text[..]], format, &images))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &'static str = "<html><head><title>Hidden</title></head><body>\
+        <h1>Chapter 1</h1><p>Some <i>emphasised </i>and <b>bold</b> text &amp; a_b.</p>\
+        <p><a href=\"http://example.com\">A link</a>, <a filepos=\"0000000010\">inside</a>\
+        <br/>Next line</p>\
+        <ul><li>One</li><li>Two<ol><li>a</li><li>b</li></ol></li></ul>\
+        <table><tr><th>H1</th><th>H2</th></tr><tr><td>x|y</td><td><p>z</p></td></tr></table>\
+        <p><img recindex=\"00002\" alt=\"A cat\"/></p><mbp:pagebreak/><mbp:pagebreak/>\
+        <h2>Chapter 2</h2><p>End</p><mbp:pagebreak/></body></html>";
+
+    fn images() -> HashMap<usize, String> {
+        let mut images = HashMap::new();
+        images.insert(1, "images/image00002.jpg".to_string());
+        images
+    }
+
+    #[test]
+    fn to_plain_text() {
+        let text = render(&[HTML.as_bytes()], TextFormat::PlainText, &images());
+        assert_eq!(text, "Chapter 1\n\n\
+            Some emphasised and bold text & a_b.\n\n\
+            [A link](http://example.com), inside\nNext line\n\n\
+            - One\n- Two\n  1. a\n  2. b\n\n\
+            H1\tH2\nx|y\tz\n\n\
+            [Image: A cat]\n\n\x0C\n\n\
+            Chapter 2\n\nEnd\n");
+
+        let documents = [&b"<p>One</p>"[..], b"<p>Two</p>"];
+        assert_eq!(render(&documents, TextFormat::PlainText, &images()), "One\n\n\x0C\n\nTwo\n");
+    }
+
+    #[test]
+    fn to_markdown() {
+        let text = render(&[HTML.as_bytes()], TextFormat::Markdown, &images());
+        assert_eq!(text, "# Chapter 1\n\n\
+            Some *emphasised* and **bold** text & a\\_b.\n\n\
+            [A link](http://example.com), inside\\\nNext line\n\n\
+            - One\n- Two\n  1. a\n  2. b\n\n\
+            | H1 | H2 |\n| --- | --- |\n| x\\|y | z |\n\n\
+            ![A cat](images/image00002.jpg)\n\n---\n\n\
+            ## Chapter 2\n\nEnd\n");
+    }
+
+    #[test]
+    fn links_around_cut_spaces() {
+        let html = "<pre>a&nbsp;<a href=\"http://x\"><br>é</a></pre>".as_bytes();
+        assert_eq!(render(&[html], TextFormat::PlainText, &images()), "a[é](http://x)\n");
+        let html = "<p><i>a&nbsp;</i><a href=\"http://x\"><b>é&nbsp;</b>b</a></p>".as_bytes();
+        assert_eq!(render(&[html], TextFormat::Markdown, &images()),
+            "*a* [**é** b](http://x)\n");
+    }
+}
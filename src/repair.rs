@@ -0,0 +1,478 @@
+//! Repairs of common inconsistencies between the headers and the records of
+//! MOBI books.
+
+use std::io;
+use common::*;
+use book::{MobiBook, RecordRole, ResourceKind, Section};
+use exth_tags::ExthTag;
+use mobi::CompressionType;
+use palmdb::PalmdbHeader;
+use writer::EOF_RECORD;
+
+/// The corrected header fields of a section.
+#[derive(Default)]
+struct SectionFixes {
+    text_record_count: Option<u16>,
+    uncompressed_text_length: Option<u32>,
+    last_record: Option<u16>,
+    first_image_record: Option<u32>,
+    flis_record: Option<u32>,
+    fcis_record: Option<u32>,
+    dangling_tags: Vec<ExthTag>,
+    rebuild: bool,
+}
+
+fn u32_at(record: &[u8], position: usize) -> u32 {
+    record.get(position..).and_then(|mut bytes| read_u32_be(&mut bytes).ok()).unwrap_or(0)
+}
+
+fn set_u32_at(record: &mut [u8], position: usize, value: u32) {
+    write_u32_be(&mut &mut record[position..position + 4], value).unwrap();
+}
+
+/// Describes a record number of a header, which may be unset.
+fn record_name(number: u32) -> String {
+    if number == 0xFFFFFFFF {
+        "none".to_string()
+    } else {
+        number.to_string()
+    }
+}
+
+/// Fixes the record count and length of the EXTH header of a record 0, so
+/// that the headers can be read. Returns whether the record must be rebuilt
+/// to pad the EXTH header.
+fn fix_exth(record: &mut [u8], index: usize, fixes: &mut Vec<String>) -> bool {
+    if record.len() < 0x84 || &record[16..20] != b"MOBI" || u32_at(record, 0x80) & 0x40 == 0 {
+        return false;
+    }
+    let start = 16 + u32_at(record, 20) as usize;
+    if record.get(start..start + 4) != Some(&b"EXTH"[..]) || start + 12 > record.len() {
+        let flags = u32_at(record, 0x80) & !0x40;
+        set_u32_at(record, 0x80, flags);
+        fixes.push(format!("Record {}: cleared the EXTH flag, as there is no EXTH header",
+            index));
+        return false;
+    }
+
+    let count = u32_at(record, start + 8);
+    let mut position = start + 12;
+    let mut valid = 0;
+    while valid < count && position + 8 <= record.len() {
+        let length = u32_at(record, position + 4) as usize;
+        if length < 8 || position + length > record.len() {
+            break;
+        }
+        position += length;
+        valid += 1;
+    }
+    if valid != count {
+        set_u32_at(record, start + 8, valid);
+        fixes.push(format!("Record {}: dropped {} EXTH records that run past the end of the \
+            record", index, count - valid));
+    }
+    let length = (position - start) as u32;
+    if u32_at(record, start + 4) != length {
+        fixes.push(format!("Record {}: set the EXTH length to {} instead of {}", index, length,
+            u32_at(record, start + 4)));
+        set_u32_at(record, start + 4, length);
+    }
+
+    let padding = (4 - position % 4) % 4;
+    let padded = record.get(position..position + padding)
+        .map_or(false, |bytes| bytes.iter().all(|&byte| byte == 0));
+    if !padded || (u32_at(record, 0x54) as usize) < position + padding {
+        fixes.push(format!("Record {}: padded the EXTH header to a multiple of four bytes",
+            index));
+        return true;
+    }
+    false
+}
+
+/// Returns the number of records of a section, from its record 0.
+fn section_length(book: &MobiBook, section: &Section) -> usize {
+    match book.kf8_section {
+        // The BOUNDARY record ends the KF7 section of joint files
+        Some(ref kf8) if kf8.start > section.start => kf8.start - 1 - section.start,
+        _ => book.records.len() - section.start,
+    }
+}
+
+/// Returns whether a record only pads the text records.
+fn is_padding(record: &[u8]) -> bool {
+    record.len() <= 4 && record.iter().all(|&byte| byte == 0)
+}
+
+/// Counts the text records of a section: the records after record 0 with no
+/// magic, up to the first record that a header field points at.
+fn count_text_records(book: &MobiBook, section: &Section, length: usize) -> u32 {
+    let header = &section.header;
+    let mut pointers = vec![header.indices.orthographic, header.indices.inflection,
+        header.indx_record_offset, Some(header.first_image_record)];
+    if header.compression == CompressionType::HUFFCDIC {
+        pointers.push(Some(header.huffman_encoding.record_offset));
+    }
+    if let Some(ref kf8) = header.kf8 {
+        pointers.extend_from_slice(&[kf8.fragment_index, kf8.skeleton_index, kf8.guide_index,
+            kf8.datp_record]);
+        if kf8.fdst_count > 1 {
+            pointers.push(kf8.fdst_record);
+        }
+    }
+    // Only pointers at records with a known role bound the text
+    let bound = pointers.into_iter()
+        .filter_map(|pointer| pointer)
+        .filter(|&pointer| {
+            pointer > 0 && (pointer as usize) < length && book.section_record(section, pointer)
+                .map_or(false, |record| RecordRole::of(record) != RecordRole::Unknown)
+        })
+        .min()
+        .unwrap_or(length as u32);
+
+    let mut number = 1;
+    while number < bound {
+        let record = book.section_record(section, number).unwrap_or(&[]);
+        if RecordRole::of(record) != RecordRole::Unknown || is_padding(record) {
+            break;
+        }
+        number += 1;
+    }
+    number - 1
+}
+
+/// Finds the first record of a section with the given role, after the text.
+fn find_record(book: &MobiBook, section: &Section, length: usize, text_count: u32,
+        roles: &[RecordRole]) -> Option<u32> {
+    (text_count + 1..length as u32).find(|&number| {
+        book.section_record(section, number)
+            .map_or(false, |record| roles.contains(&RecordRole::of(record)))
+    })
+}
+
+/// Works out the header fields of a section that need fixing.
+fn check_section(book: &MobiBook, section: &Section, fixes: &mut Vec<String>)
+        -> SectionFixes {
+    let header = &section.header;
+    let length = section_length(book, section);
+    let mut result = SectionFixes::default();
+    let start = section.start;
+
+    let text_count = count_text_records(book, section, length);
+    if text_count != header.text_record_count as u32 {
+        fixes.push(format!("Record {}: set the text record count to {} instead of {}", start,
+            text_count, header.text_record_count));
+        result.text_record_count = Some(text_count as u16);
+    }
+    let text_length = (1..text_count + 1)
+        .map(|number| book.text_record(section, number).map(|text| text.len() as u32))
+        .fold(Ok(0), |total: Result<u32, io::Error>, length| Ok(try!(total) + try!(length)));
+    if let Ok(text_length) = text_length {
+        if text_length != header.uncompressed_text_length {
+            fixes.push(format!("Record {}: set the uncompressed text length to {} instead of \
+                {}", start, text_length, header.uncompressed_text_length));
+            result.uncompressed_text_length = Some(text_length);
+        }
+    }
+
+    let mut first_image = header.first_image_record;
+    if book.has_resources(section) {
+        let valid = first_image == 0xFFFFFFFF || (first_image > text_count
+            && book.section_record(section, first_image)
+                .map_or(false, |record| ResourceKind::of(record) != ResourceKind::End));
+        if !valid {
+            first_image = find_record(book, section, length, text_count,
+                &[RecordRole::Image, RecordRole::Font, RecordRole::Resc])
+                .unwrap_or(0xFFFFFFFF);
+            fixes.push(format!("Record {}: set the first image record to {} instead of {}",
+                start, record_name(first_image), record_name(header.first_image_record)));
+            result.first_image_record = Some(first_image);
+        }
+    }
+
+    let structure = [RecordRole::Flis, RecordRole::Fcis, RecordRole::Fdst, RecordRole::Datp,
+        RecordRole::Srcs, RecordRole::Boundary, RecordRole::Eof];
+    if !section.is_kf8() {
+        let last = header.last_record as usize;
+        let valid = last < length && last as u32 >= text_count
+            && !structure.contains(&RecordRole::of(&book.records[start + last]));
+        if !valid {
+            let content_end = find_record(book, section, length, text_count, &structure)
+                .unwrap_or(length as u32);
+            let last_record = (content_end - 1) as u16;
+            fixes.push(format!("Record {}: set the last content record to {} instead of {}",
+                start, last_record, header.last_record));
+            result.last_record = Some(last_record);
+        }
+    }
+
+    let records = [(header.fcis_flis.flis_record_number, RecordRole::Flis, "FLIS"),
+        (header.fcis_flis.fcis_record_number, RecordRole::Fcis, "FCIS")];
+    for (i, &(number, role, name)) in records.iter().enumerate() {
+        let valid = number == 0xFFFFFFFF || book.section_record(section, number)
+            .map_or(false, |record| RecordRole::of(record) == role);
+        if !valid {
+            let found = find_record(book, section, length, text_count, &[role])
+                .unwrap_or(0xFFFFFFFF);
+            fixes.push(format!("Record {}: set the {} record to {} instead of {}", start, name,
+                record_name(found), record_name(number)));
+            if i == 0 {
+                result.flis_record = Some(found);
+            } else {
+                result.fcis_record = Some(found);
+            }
+        }
+    }
+
+    // Resource indices are relative to the first image record of the main
+    // section
+    let resource_start = if section.start == book.main.start {
+        first_image
+    } else {
+        book.main.header.first_image_record
+    };
+    for tag in section.exth.iter() {
+        let (offset, name) = match *tag {
+            ExthTag::CoverOffset(offset) => (offset, "cover"),
+            ExthTag::ThumbnailOffset(offset) => (offset, "thumbnail"),
+            _ => continue,
+        };
+        if offset == 0xFFFFFFFF {
+            continue;
+        }
+        let record = if resource_start == 0xFFFFFFFF {
+            None
+        } else {
+            book.section_record(&book.main, resource_start.saturating_add(offset))
+        };
+        match record.map(ResourceKind::of) {
+            Some(ResourceKind::Image(_)) => {},
+            _ => {
+                fixes.push(format!("Record {}: dropped the {} offset {}, which is not an image",
+                    start, name, offset));
+                result.dangling_tags.push(tag.clone());
+            },
+        }
+    }
+    result
+}
+
+/// Repairs the headers of a book given the bytes of the file, and returns
+/// the repaired book along with a description of every fix.
+pub fn repair(data: &[u8]) -> Result<(MobiBook, Vec<String>), io::Error> {
+    let mut fixes = Vec::new();
+    let mut data = data.to_vec();
+    let mut rebuild = Vec::new();
+
+    // Records 0 follow the start of the file and the BOUNDARY record
+    let palmdb = try!(PalmdbHeader::read_from(&mut &data[..]));
+    let mut previous: Option<usize> = None;
+    for (i, record) in palmdb.records.iter().enumerate() {
+        let start = record.data_offset as usize;
+        let end = palmdb.records.get(i + 1).map_or(data.len(), |next| next.data_offset as usize);
+        if start > end || end > data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "The record offsets are out of order"));
+        }
+        let is_start = i == 0 || previous.map_or(false, |previous| {
+            data[previous..start].starts_with(b"BOUNDARY")
+        });
+        if is_start && fix_exth(&mut data[start..end], i, &mut fixes) {
+            rebuild.push(i);
+        }
+        previous = Some(start);
+    }
+
+    let mut book = try!(MobiBook::from_bytes(&data));
+    let mut section_fixes = Vec::new();
+    for section in book.sections() {
+        let mut result = check_section(&book, section, &mut fixes);
+        result.rebuild = rebuild.contains(&section.start);
+        section_fixes.push((section.start, result));
+    }
+
+    if !book.records.last().map_or(false, |record| record.starts_with(EOF_RECORD)) {
+        // A truncated file may keep an empty record where the EOF record was
+        if book.records.last().map_or(false, |record| record.is_empty()) {
+            book.records.pop();
+        }
+        book.records.push(EOF_RECORD.to_vec());
+        fixes.push(format!("Record {}: added the missing EOF record", book.records.len() - 1));
+    }
+
+    let needs_rebuild = section_fixes.iter().any(|&(_, ref result)| {
+        result.rebuild || result.text_record_count.is_some()
+            || result.uncompressed_text_length.is_some() || result.last_record.is_some()
+            || result.first_image_record.is_some() || result.flis_record.is_some()
+            || result.fcis_record.is_some() || !result.dangling_tags.is_empty()
+    });
+    if needs_rebuild {
+        book.edit_sections(|section| {
+            let result = match section_fixes.iter().find(|&&(start, _)| start == section.start) {
+                Some(&(_, ref result)) => result,
+                None => return,
+            };
+            let header = &mut section.header;
+            if let Some(count) = result.text_record_count {
+                header.text_record_count = count;
+            }
+            if let Some(length) = result.uncompressed_text_length {
+                header.uncompressed_text_length = length;
+            }
+            if let Some(last) = result.last_record {
+                header.last_record = last;
+            }
+            if let Some(first) = result.first_image_record {
+                header.first_image_record = first;
+            }
+            if let Some(flis) = result.flis_record {
+                header.fcis_flis.flis_record_number = flis;
+                header.fcis_flis.flis_record_count = if flis == 0xFFFFFFFF { 0 } else { 1 };
+            }
+            if let Some(fcis) = result.fcis_record {
+                header.fcis_flis.fcis_record_number = fcis;
+                header.fcis_flis.fcis_record_count = if fcis == 0xFFFFFFFF { 0 } else { 1 };
+            }
+            section.exth.retain(|tag| !result.dangling_tags.contains(tag));
+        });
+    } else {
+        book.palmdb.set_records(&book.records);
+    }
+    Ok((book, fixes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exth_tags;
+    use metadata::Metadata;
+    use validate::validate;
+    use writer::MobiWriter;
+
+    /// Returns a KF7 book with an image as the cover.
+    fn book() -> MobiBook {
+        let mut writer = MobiWriter::new(Metadata::new("Test"),
+            "<html><body><p>Text</p><img src=\"a.jpg\"/></body></html>");
+        writer.set_cover("a.jpg", b"\xFF\xD8\xFF\xE0image".to_vec());
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        MobiBook::from_bytes(&data).unwrap()
+    }
+
+    fn write(book: &mut MobiBook) -> Vec<u8> {
+        let mut data = Vec::new();
+        book.write_to(&mut data).unwrap();
+        data
+    }
+
+    /// Repairs a book, and checks that some fix is described and that the
+    /// repaired book validates.
+    fn check_repair(mut broken: MobiBook, expected: &[&str]) {
+        let (mut book, fixes) = repair(&write(&mut broken)).unwrap();
+        for fix in expected {
+            assert!(fixes.iter().any(|other| other.contains(fix)), "{} not in {:?}", fix, fixes);
+        }
+        let codes = validate(&write(&mut book)).iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect::<Vec<_>>();
+        assert_eq!(codes, Vec::<&str>::new());
+    }
+
+    /// Returns the start and length of the EXTH header of a record 0.
+    fn exth(record: &[u8]) -> (usize, usize) {
+        let start = 16 + u32_at(record, 20) as usize;
+        (start, u32_at(record, start + 4) as usize)
+    }
+
+    #[test]
+    fn exth_lengths() {
+        let mut broken = book();
+        {
+            let record = &mut broken.records[0];
+            let (start, length) = exth(record);
+            let count = u32_at(record, start + 8);
+            set_u32_at(record, start + 4, length as u32 + 4);
+            set_u32_at(record, start + 8, count + 1);
+        }
+        broken.palmdb.set_records(&broken.records);
+        check_repair(broken, &["dropped 1 EXTH records", "set the EXTH length to"]);
+    }
+
+    #[test]
+    fn exth_padding() {
+        // Ends the EXTH header at an odd position, right before the title
+        let mut broken = book();
+        {
+            let record = &mut broken.records[0];
+            let (start, length) = exth(record);
+            let end = start + length;
+            let extra = if (end + 9) % 4 == 0 { 2 } else { 1 };
+            let mut exth_record = vec![0, 0, 0, 100, 0, 0, 0, 8 + extra as u8];
+            exth_record.extend(vec![b'A'; extra]);
+            let name = u32_at(record, 0x54) as usize;
+            let tail = record.split_off(name);
+            record.truncate(end);
+            record.extend(exth_record);
+            record.extend(tail);
+            let count = u32_at(record, start + 8);
+            set_u32_at(record, start + 4, (length + 8 + extra) as u32);
+            set_u32_at(record, start + 8, count + 1);
+            set_u32_at(record, 0x54, (end + 8 + extra) as u32);
+        }
+        broken.palmdb.set_records(&broken.records);
+        check_repair(broken, &["padded the EXTH header"]);
+    }
+
+    #[test]
+    fn text_records() {
+        let mut broken = book();
+        broken.edit_sections(|section| {
+            section.header.text_record_count += 1;
+            section.header.uncompressed_text_length += 100;
+        });
+        check_repair(broken, &["set the text record count to 1 instead of 2",
+            "set the uncompressed text length to"]);
+    }
+
+    #[test]
+    fn last_records() {
+        let mut broken = book();
+        broken.edit_sections(|section| section.header.last_record = 0);
+        check_repair(broken, &["set the last content record to"]);
+    }
+
+    #[test]
+    fn flis_and_fcis() {
+        let mut broken = book();
+        broken.edit_sections(|section| {
+            section.header.fcis_flis.flis_record_number = 1;
+            section.header.fcis_flis.fcis_record_number = 1;
+        });
+        check_repair(broken, &["set the FLIS record to", "set the FCIS record to"]);
+    }
+
+    #[test]
+    fn dangling_cover_offsets() {
+        let mut data = Vec::new();
+        MobiWriter::new(Metadata::new("Test"), "<html><body><p>Text</p></body></html>")
+            .write_to(&mut data).unwrap();
+        let mut broken = MobiBook::from_bytes(&data).unwrap();
+        broken.edit_sections(|section| {
+            exth_tags::set_tag(&mut section.exth, ExthTag::CoverOffset(5));
+        });
+        check_repair(broken, &["dropped the cover offset 5, which is not an image"]);
+    }
+
+    #[test]
+    fn missing_eof_records() {
+        let mut broken = book();
+        broken.records.pop();
+        broken.palmdb.set_records(&broken.records);
+        check_repair(broken, &["added the missing EOF record"]);
+    }
+
+    #[test]
+    fn sound_books() {
+        let (_, fixes) = repair(&write(&mut book())).unwrap();
+        assert_eq!(fixes, Vec::<String>::new());
+    }
+}
@@ -0,0 +1,71 @@
+//! Helpers shared by the tests: small books written and read back.
+
+use book::MobiBook;
+use kf8_writer::{GuideEntry, Kf8Writer, TocEntry};
+use metadata::Metadata;
+use writer::{MobiWriter, Resource};
+
+/// Writes a KF7 book of some HTML, and reads it back.
+pub fn kf7_book(html: &str) -> MobiBook {
+    let mut data = Vec::new();
+    MobiWriter::new(Metadata::new("Test"), html).write_to(&mut data).unwrap();
+    MobiBook::from_bytes(&data).unwrap()
+}
+
+/// Returns a KF8 writer of some XHTML documents, named 'text/partN.xhtml'.
+pub fn kf8_writer(parts: &[&str]) -> Kf8Writer {
+    let mut writer = Kf8Writer::new(Metadata::new("Test"));
+    for (i, part) in parts.iter().enumerate() {
+        writer.parts.push(Resource {
+            name: format!("text/part{}.xhtml", i),
+            data: part.as_bytes().to_vec(),
+        });
+    }
+    writer
+}
+
+/// Returns a KF8 writer of two documents linking to each other, the first
+/// one long enough for several fragments, with a stylesheet, an image as
+/// the cover, a table of contents and a guide.
+pub fn linked_kf8_writer() -> Kf8Writer {
+    let paragraphs = (0..400)
+        .map(|i| format!("<p id=\"p{}\">Paragraph {}</p>", i, i))
+        .collect::<String>();
+    let mut writer = kf8_writer(&[
+        &format!("<html><head><link rel=\"stylesheet\" href=\"../styles/style.css\"/>\
+            </head><body><a href=\"part1.xhtml#x\">Next</a>{}</body></html>", paragraphs),
+        "<html><head></head><body><h1 id=\"x\">Second</h1><img src=\"../images/a.png\"/>\
+            <a href=\"part0.xhtml#p300\">Back</a></body></html>",
+    ]);
+    writer.stylesheets.push(Resource {
+        name: "styles/style.css".into(),
+        data: b"h1 { background: url(../images/a.png) }".to_vec(),
+    });
+    writer.images.push(Resource {
+        name: "images/a.png".into(),
+        data: b"\x89PNGimage".to_vec(),
+    });
+    writer.cover = Some(0);
+    writer.toc.push(TocEntry {
+        title: "First".into(),
+        href: "text/part0.xhtml".into(),
+        children: vec![TocEntry {
+            title: "Second".into(),
+            href: "text/part1.xhtml#x".into(),
+            children: Vec::new(),
+        }],
+    });
+    writer.guide.push(GuideEntry {
+        kind: "text".into(),
+        title: "Start".into(),
+        href: "text/part0.xhtml".into(),
+    });
+    writer
+}
+
+/// Writes a KF8 book, and reads it back.
+pub fn kf8_book(writer: &Kf8Writer) -> MobiBook {
+    let mut data = Vec::new();
+    writer.write_to(&mut data).unwrap();
+    MobiBook::from_bytes(&data).unwrap()
+}
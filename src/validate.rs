@@ -0,0 +1,512 @@
+//! Structural checks of MOBI books, reported as lint-style diagnostics.
+//!
+//! Every diagnostic has a stable code:
+//!
+//! - R001: the record offsets are not in increasing order
+//! - R002: a record starts outside of the file, or inside the PalmDB header
+//! - H001: record 0 has no valid MOBI header
+//! - H002: the text records are not where the header says
+//! - H003: the last content record is outside of the book
+//! - H004: the first image record does not point at a resource after the text
+//! - H005: the FLIS record number does not point at a FLIS record
+//! - H006: the FCIS record number does not point at a FCIS record
+//! - H007: an index record number does not point at an INDX record
+//! - H008: the FDST record number does not point at a FDST record
+//! - H009: the HUFF record number does not point at a HUFF record
+//! - H010: the DATP record number does not point at a DATP record
+//! - H011: the full name is outside of record 0
+//! - H012: the text record size is not 4096
+//! - T001: the uncompressed text length differs from the decompressed text
+//! - T002: the text could not be decompressed
+//! - D001: the text is encrypted, so it was not checked
+//! - D002: the EXTH header has DRM tags
+//! - X001: the EXTH header is missing although the header announces it
+//! - X002: the EXTH length does not match its records
+//! - X003: the EXTH header is not padded to a multiple of four bytes
+//! - L001: a filepos link points outside of the text, or a link to an anchor
+//!   has no filepos
+//! - L002: a recindex reference does not point at an image
+//! - L003: a kindle:embed reference does not point at a resource
+//! - L004: a kindle:pos reference does not point at a fragment
+
+use std::fmt;
+use common::*;
+use book::{MobiBook, ResourceKind, Section};
+use container;
+use container::Container;
+use drm;
+use drm::DrmStatus;
+use html::{Token, Tokenizer};
+use kf8;
+use kf8_reader;
+use mobi::CompressionType;
+use palmdb::PalmdbHeader;
+
+/// How serious a problem is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The book is broken: readers will likely fail to open it.
+    Error,
+    /// Readers may cope with it, but parts of the book may be lost.
+    Warning,
+    /// Something could not be checked.
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A problem found in a book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The stable code of the problem, such as "H005".
+    pub code: &'static str,
+    /// The offset in the file where the problem is, when it is known.
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} {} at 0x{:08X}: {}", self.severity, self.code,
+                offset, self.message),
+            None => write!(f, "{} {}: {}", self.severity, self.code, self.message),
+        }
+    }
+}
+
+/// Collects the diagnostics of a book.
+struct Validator<'a> {
+    data: &'a [u8],
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, severity: Severity, code: &'static str, offset: Option<u64>,
+            message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: severity,
+            code: code,
+            offset: offset,
+            message: message,
+        });
+    }
+
+    fn error(&mut self, code: &'static str, offset: Option<u64>, message: String) {
+        self.report(Severity::Error, code, offset, message);
+    }
+
+    fn warning(&mut self, code: &'static str, offset: Option<u64>, message: String) {
+        self.report(Severity::Warning, code, offset, message);
+    }
+
+    /// Checks the record list. Returns false if the records cannot be read.
+    fn check_records(&mut self, palmdb: &PalmdbHeader) -> bool {
+        let mut valid = true;
+        let header_size = palmdb.size() as u64;
+        let mut previous = 0;
+        for (i, record) in palmdb.records.iter().enumerate() {
+            let entry = Some(78 + 8 * i as u64);
+            let offset = record.data_offset as u64;
+            if offset > self.data.len() as u64 {
+                self.error("R002", entry, format!("Record {} starts at {}, after the end of \
+                    the file ({} bytes)", i, offset, self.data.len()));
+                valid = false;
+            } else if offset < header_size {
+                self.error("R002", entry, format!("Record {} starts at {}, inside the PalmDB \
+                    header ({} bytes)", i, offset, header_size));
+                valid = false;
+            }
+            if offset < previous {
+                self.error("R001", entry, format!("Record {} starts at {}, before record {} \
+                    at {}", i, offset, i - 1, previous));
+                valid = false;
+            }
+            previous = offset;
+        }
+        valid
+    }
+
+    /// Checks that a record number of a header points at a record starting
+    /// with 'magic'.
+    fn check_magic(&mut self, book: &MobiBook, section: &Section, field: u64, number: u32,
+            magic: &[u8], code: &'static str, name: &str) {
+        let offset = Some(self.field_offset(book, section, field));
+        match book.section_record(section, number) {
+            Some(record) if record.starts_with(magic) => {},
+            Some(_) => self.error(code, offset, format!("The {} record {} does not start \
+                with '{}'", name, section.record(number), String::from_utf8_lossy(magic))),
+            None => self.error(code, offset, format!("The {} record {} is outside of the book",
+                name, section.record(number))),
+        }
+    }
+
+    /// Returns the offset in the file of a field of record 0 of a section.
+    fn field_offset(&self, book: &MobiBook, section: &Section, field: u64) -> u64 {
+        book.palmdb.records[section.start].data_offset as u64 + field
+    }
+
+    fn check_header(&mut self, book: &MobiBook, section: &Section) {
+        let header = &section.header;
+        let record_count = book.records.len() - section.start;
+
+        if header.text_record_size != 4096 {
+            let offset = Some(self.field_offset(book, section, 10));
+            self.warning("H012", offset, format!("The text record size is {}, not 4096",
+                header.text_record_size));
+        }
+        let text_end = 1 + header.text_record_count as usize;
+        if text_end > record_count {
+            let offset = Some(self.field_offset(book, section, 8));
+            self.error("H002", offset, format!("The {} text records go past the end of the \
+                book", header.text_record_count));
+        } else {
+            for number in 1..text_end as u32 {
+                let record = book.section_record(section, number).unwrap_or(&[]);
+                if ResourceKind::of(record) == ResourceKind::End {
+                    let offset = Some(book.palmdb.records[section.record(number)].data_offset as u64);
+                    self.error("H002", offset, format!("Text record {} holds a {} record",
+                        section.record(number), String::from_utf8_lossy(&record[..4])));
+                    break;
+                }
+            }
+        }
+
+        match header.kf8 {
+            Some(ref kf8) => {
+                if let Some(fdst) = kf8.fdst_record {
+                    if kf8.fdst_count > 1 {
+                        self.check_magic(book, section, 0xC0, fdst, b"FDST", "H008", "FDST");
+                    }
+                }
+                let indices = [(0xF8, kf8.fragment_index, "fragment index"),
+                    (0xFC, kf8.skeleton_index, "skeleton index"),
+                    (0x104, kf8.guide_index, "guide index")];
+                for &(field, index, name) in indices.iter() {
+                    if let Some(index) = index {
+                        self.check_magic(book, section, field, index, b"INDX", "H007", name);
+                    }
+                }
+                if let Some(datp) = kf8.datp_record {
+                    self.check_magic(book, section, 0x100, datp, b"DATP", "H010", "DATP");
+                }
+            },
+            None => {
+                if header.text_record != 1 {
+                    let offset = Some(self.field_offset(book, section, 0xC0));
+                    self.warning("H002", offset, format!("The first content record is {}, \
+                        not 1", header.text_record));
+                }
+                if header.last_record as usize >= record_count {
+                    let offset = Some(self.field_offset(book, section, 0xC2));
+                    self.error("H003", offset, format!("The last content record {} is outside \
+                        of the book", section.record(header.last_record as u32)));
+                }
+            },
+        }
+
+        if book.has_resources(section) && header.first_image_record != 0xFFFFFFFF {
+            let offset = Some(self.field_offset(book, section, 0x6C));
+            match book.section_record(section, header.first_image_record) {
+                Some(_) if header.first_image_record <= header.text_record_count as u32 => {
+                    self.error("H004", offset, format!("The first image record {} is record 0 \
+                        or a text record", section.record(header.first_image_record)));
+                },
+                Some(record) if ResourceKind::of(record) != ResourceKind::End => {},
+                Some(_) => self.error("H004", offset, format!("The first image record {} \
+                    does not hold a resource", section.record(header.first_image_record))),
+                None => self.error("H004", offset, format!("The first image record {} is \
+                    outside of the book", section.record(header.first_image_record))),
+            }
+        }
+
+        let fcis_flis = &header.fcis_flis;
+        if fcis_flis.flis_record_number != 0xFFFFFFFF && fcis_flis.flis_record_count > 0 {
+            self.check_magic(book, section, 0xD0, fcis_flis.flis_record_number, b"FLIS",
+                "H005", "FLIS");
+        }
+        if fcis_flis.fcis_record_number != 0xFFFFFFFF && fcis_flis.fcis_record_count > 0 {
+            self.check_magic(book, section, 0xC8, fcis_flis.fcis_record_number, b"FCIS",
+                "H006", "FCIS");
+        }
+
+        let indices = [(0x28, header.indices.orthographic, "orthographic index"),
+            (0x2C, header.indices.inflection, "inflection index"),
+            (0xF4, header.indx_record_offset, "NCX index")];
+        for &(field, index, name) in indices.iter() {
+            if let Some(index) = index {
+                self.check_magic(book, section, field, index, b"INDX", "H007", name);
+            }
+        }
+        if header.compression == CompressionType::HUFFCDIC {
+            let record = header.huffman_encoding.record_offset;
+            self.check_magic(book, section, 0x70, record, b"HUFF", "H009", "HUFF");
+        }
+
+        let record0 = &book.records[section.start];
+        let name_end = header.full_name_offset as u64 + header.full_name_length as u64;
+        if name_end > record0.len() as u64 {
+            let offset = Some(self.field_offset(book, section, 0x54));
+            self.error("H011", offset, format!("The full name ends at {}, after the end of \
+                record 0 ({} bytes)", name_end, record0.len()));
+        }
+    }
+
+    fn check_exth(&mut self, book: &MobiBook, section: &Section) {
+        let header = &section.header;
+        if !header.has_exth() {
+            return;
+        }
+        let record0 = &book.records[section.start];
+        let start = 16 + header.header_length as usize;
+        let offset = Some(self.field_offset(book, section, start as u64));
+        if record0.get(start..start + 4) != Some(&b"EXTH"[..]) || start + 12 > record0.len() {
+            self.error("X001", offset, "The header announces an EXTH header, but there is \
+                none".to_string());
+            return;
+        }
+        let u32_at = |position: usize| read_u32_be(&mut &record0[position..]).unwrap_or(0);
+        let length = u32_at(start + 4) as usize;
+        let count = u32_at(start + 8) as usize;
+
+        // Add up the lengths of the records
+        let mut position = start + 12;
+        for i in 0..count {
+            if position + 8 > record0.len() {
+                self.error("X002", offset, format!("EXTH record {} of {} is outside of \
+                    record 0", i, count));
+                return;
+            }
+            let record_length = u32_at(position + 4) as usize;
+            if record_length < 8 {
+                self.error("X002", Some(self.field_offset(book, section, position as u64)),
+                    format!("EXTH record {} has an invalid length of {}", i, record_length));
+                return;
+            }
+            position += record_length;
+        }
+        if position - start != length {
+            self.error("X002", offset, format!("The EXTH length is {}, but its records end \
+                after {} bytes", length, position - start));
+            return;
+        }
+
+        let padding = (4 - length % 4) % 4;
+        let padded = record0.get(position..position + padding)
+            .map_or(false, |bytes| bytes.iter().all(|&byte| byte == 0));
+        if !padded || (header.full_name_offset as usize) < position + padding {
+            self.warning("X003", offset, format!("The EXTH header of {} bytes is not \
+                followed by {} bytes of padding", length, padding));
+        }
+    }
+
+    /// Checks the text length, and returns the text if it can be read.
+    fn check_text(&mut self, book: &MobiBook, section: &Section) -> Option<Vec<u8>> {
+        let header = &section.header;
+        let text = match book.text(section) {
+            Ok(text) => text,
+            Err(error) => {
+                let severity = if header.compression == CompressionType::HUFFCDIC {
+                    Severity::Info
+                } else {
+                    Severity::Error
+                };
+                self.report(severity, "T002", None, format!("The text of the section at \
+                    record {} could not be read: {}", section.start, error));
+                return None;
+            },
+        };
+        if text.len() != header.uncompressed_text_length as usize {
+            let offset = Some(self.field_offset(book, section, 4));
+            self.warning("T001", offset, format!("The uncompressed text length is {}, but \
+                the text is {} bytes long", header.uncompressed_text_length, text.len()));
+        }
+        Some(text)
+    }
+
+    /// Checks the filepos and recindex references of a KF7 section.
+    fn check_kf7_links(&mut self, book: &MobiBook, text: &[u8]) {
+        for (range, token) in Tokenizer::new(text) {
+            let tag = match token {
+                Token::StartTag(tag) => tag,
+                _ => continue,
+            };
+            if let Some(filepos) = tag.attribute("filepos") {
+                match filepos.trim().parse::<usize>() {
+                    Ok(position) if position <= text.len() => {},
+                    _ => self.warning("L001", None, format!("The filepos link '{}' at text \
+                        position {} points outside of the text", filepos, range.start)),
+                }
+            } else if let Some(href) = tag.attribute("href") {
+                // KF7 readers only follow filepos links inside the book
+                if href.starts_with('#') {
+                    self.warning("L001", None, format!("The link '{}' at text position {} has \
+                        no filepos", href, range.start));
+                }
+            }
+            if let Some(recindex) = tag.attribute("recindex") {
+                let resource = recindex.trim().parse::<usize>().ok()
+                    .and_then(|index| if index > 0 { book.resource(index - 1) } else { None });
+                match resource.map(ResourceKind::of) {
+                    Some(ResourceKind::Image(_)) => {},
+                    _ => self.warning("L002", None, format!("The recindex '{}' at text \
+                        position {} does not point at an image", recindex, range.start)),
+                }
+            }
+        }
+    }
+
+    /// Checks the kindle:embed and kindle:pos references of a KF8 section.
+    fn check_kf8_links(&mut self, book: &MobiBook, section: &Section) {
+        let text = match kf8_reader::read_text(book, section) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+        let resource_count = book.resource_count();
+        for flow in text.flows.iter() {
+            let mut references = Vec::new();
+            kf8::replace_uris(flow, &mut |uri| {
+                references.push(uri.to_string());
+                None
+            });
+            for uri in references {
+                if uri.starts_with("kindle:embed:") {
+                    let found = kf8::parse_uri_number(&uri, "embed")
+                        .map_or(false, |number| number > 0 && number as usize <= resource_count);
+                    if !found {
+                        self.warning("L003", None, format!("The reference '{}' does not point \
+                            at a resource", uri));
+                    }
+                } else if uri.starts_with("kindle:pos:") {
+                    let found = kf8::parse_pos_uri(&uri)
+                        .map_or(false, |(fid, _)| (fid as usize) < text.fragments.len());
+                    if !found {
+                        self.warning("L004", None, format!("The reference '{}' does not point \
+                            at a fragment", uri));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks the structure of a book, given the bytes of the file.
+/// The diagnostics are sorted by severity.
+pub fn validate(data: &[u8]) -> Vec<Diagnostic> {
+    let mut validator = Validator { data: data, diagnostics: Vec::new() };
+    match container::sniff(data) {
+        Container::Mobi | Container::PrintReplica => {},
+        container => {
+            validator.error("H001", Some(0), container.unsupported().to_string());
+            return validator.diagnostics;
+        },
+    }
+    let palmdb = match PalmdbHeader::read_from(&mut &data[..]) {
+        Ok(palmdb) => palmdb,
+        Err(error) => {
+            validator.error("R002", None, format!("The record list could not be read: {}", error));
+            return validator.diagnostics;
+        },
+    };
+    if !validator.check_records(&palmdb) {
+        return validator.diagnostics;
+    }
+
+    let book = match MobiBook::from_bytes(data) {
+        Ok(book) => book,
+        Err(error) => {
+            let offset = palmdb.records.first().map(|record| record.data_offset as u64);
+            validator.error("H001", offset, format!("The headers could not be read: {}", error));
+            return validator.diagnostics;
+        },
+    };
+    for section in book.sections() {
+        validator.check_header(&book, section);
+        validator.check_exth(&book, section);
+        let drm = DrmStatus::new(&section.header, &section.exth);
+        for &(name, ref value) in drm.tags.iter() {
+            validator.report(Severity::Info, "D002", None, format!("The section at record {} \
+                has the DRM tag {}: {}", section.start, name, value));
+        }
+        if drm.encrypted {
+            let offset = Some(validator.field_offset(&book, section, 12));
+            validator.report(Severity::Info, "D001", offset, format!("The text of the section \
+                at record {} is encrypted with {} DRM, so it was not checked", section.start,
+                drm::scheme_name(drm.scheme)));
+            continue;
+        }
+        if let Some(text) = validator.check_text(&book, section) {
+            if section.is_kf8() {
+                validator.check_kf8_links(&book, section);
+            } else {
+                validator.check_kf7_links(&book, &text);
+            }
+        }
+    }
+
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.severity);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metadata::Metadata;
+    use repair;
+    use writer::MobiWriter;
+
+    fn codes(data: &[u8]) -> Vec<&'static str> {
+        validate(data).iter().map(|diagnostic| diagnostic.code).collect()
+    }
+
+    #[test]
+    fn first_image_record_agrees_with_repair() {
+        let mut writer = MobiWriter::new(Metadata::new("Test"),
+            "<html><body><p>Text</p><img src=\"a.jpg\"/></body></html>");
+        writer.add_image("a.jpg", b"\xFF\xD8\xFF\xE0image".to_vec());
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        assert_eq!(codes(&data), Vec::<&str>::new());
+
+        for &first_image in [0, 1].iter() {
+            let mut book = MobiBook::from_bytes(&data).unwrap();
+            book.edit_sections(|section| section.header.first_image_record = first_image);
+            let mut broken = Vec::new();
+            book.write_to(&mut broken).unwrap();
+            assert!(codes(&broken).contains(&"H004"));
+
+            let (mut repaired, fixes) = repair::repair(&broken).unwrap();
+            assert_eq!(fixes.len(), 1);
+            let mut fixed = Vec::new();
+            repaired.write_to(&mut fixed).unwrap();
+            assert_eq!(codes(&fixed), Vec::<&str>::new());
+        }
+    }
+
+    #[test]
+    fn links_to_missing_anchors() {
+        let writer = MobiWriter::new(Metadata::new("Test"), "<html><body>\
+            <p><a href=\"#end\">End</a> <a href=\"#missing\">Missing</a></p>\
+            <p id=\"end\">The end</p></body></html>");
+        let html = String::from_utf8(writer.kf7_html()).unwrap();
+        assert!(!html.contains("filepos=\"0000000000\""));
+        assert!(html.contains("href=\"#missing\""));
+
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        let diagnostics = validate(&data);
+        assert_eq!(codes(&data), vec!["L001"]);
+        assert!(diagnostics[0].message.contains("'#missing'"));
+    }
+}
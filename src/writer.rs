@@ -0,0 +1,353 @@
+//! Creation of KF7 MOBI files from HTML, images and metadata.
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+use chrono::UTC;
+use common::*;
+use exth_tags;
+use exth_tags::{ExthTag, CreatorSoftware};
+use html;
+use metadata::Metadata;
+use mobi::MobiHeader;
+use palmdb::PalmdbHeader;
+use palmdoc;
+use palmdoc::RECORD_SIZE;
+
+/// The record that ends the file.
+pub const EOF_RECORD: &'static [u8] = b"\xE9\x8E\r\n";
+
+/// A file embedded in the book (an image or a font), referenced by its name.
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Builds a KF7 book.
+/// Images are referenced with `<img src="name">`, and internal links with
+/// `<a href="#id">`; both are rewritten to recindex and filepos attributes.
+#[derive(Debug, Clone)]
+pub struct MobiWriter {
+    pub metadata: Metadata,
+    pub html: String,
+    pub images: Vec<Resource>,
+    /// The index of the cover in 'images'.
+    pub cover: Option<usize>,
+}
+
+impl MobiWriter {
+    pub fn new(metadata: Metadata, html: &str) -> MobiWriter {
+        MobiWriter {
+            metadata: metadata,
+            html: html.to_string(),
+            images: Vec::new(),
+            cover: None,
+        }
+    }
+
+    /// Adds an image, returning its index among the images.
+    pub fn add_image(&mut self, name: &str, data: Vec<u8>) -> usize {
+        self.images.push(Resource { name: name.to_string(), data: data });
+        self.images.len() - 1
+    }
+
+    /// Adds the cover image.
+    pub fn set_cover(&mut self, name: &str, data: Vec<u8>) {
+        let index = self.add_image(name, data);
+        self.cover = Some(index);
+    }
+
+    /// Returns the index of the image referenced by 'src'.
+    pub fn find_image(&self, src: &str) -> Option<usize> {
+        find_image(&self.images, src)
+    }
+
+    /// Returns the book markup with the image and link references rewritten
+    /// to their KF7 forms. Links to anchors that do not exist keep their
+    /// 'href', rather than pointing at the start of the book.
+    pub fn kf7_html(&self) -> Vec<u8> {
+        let mut anchors: HashMap<String, usize> = HashMap::new();
+        let mut links: Vec<(usize, String)> = Vec::new();
+        let mut output = Vec::with_capacity(self.html.len());
+        let source = self.html.as_bytes();
+        let ids = html::Tokenizer::new(source).filter_map(|(_, token)| match token {
+            html::Token::StartTag(tag) => tag.attribute("id").or(tag.attribute("name"))
+                .map(String::from),
+            _ => None,
+        }).collect::<HashSet<_>>();
+
+        for (range, token) in html::Tokenizer::new(source) {
+            let mut tag = match token {
+                html::Token::StartTag(tag) => tag,
+                _ => {
+                    output.extend_from_slice(&source[range]);
+                    continue;
+                }
+            };
+
+            let id = tag.attribute("id").or(tag.attribute("name")).map(String::from);
+            if let Some(id) = id {
+                anchors.insert(id, output.len());
+            }
+
+            let mut changed = false;
+            if tag.name == "img" {
+                let image = tag.attribute("src").and_then(|src| self.find_image(src));
+                if let Some(index) = image {
+                    tag.remove_attribute("src");
+                    tag.set_attribute("recindex", &format!("{:05}", index + 1));
+                    changed = true;
+                }
+            }
+            let target = match tag.attribute("href") {
+                Some(href) if href.starts_with('#') && ids.contains(&href[1..]) => {
+                    Some(href[1..].to_string())
+                },
+                _ => None,
+            };
+            if let Some(target) = target {
+                tag.remove_attribute("href");
+                tag.set_attribute("filepos", "0000000000");
+                changed = true;
+                let serialized = tag.to_html();
+                let attr = serialized.find("filepos=\"").unwrap() + 9;
+                links.push((output.len() + attr, target));
+            }
+
+            if changed {
+                output.extend_from_slice(tag.to_html().as_bytes());
+            } else {
+                output.extend_from_slice(&source[range]);
+            }
+        }
+
+        // The placeholders have a fixed width, so the offsets are stable
+        for (pos, target) in links {
+            if let Some(&offset) = anchors.get(&target) {
+                let digits = format!("{:010}", offset);
+                output[pos..pos + 10].copy_from_slice(digits.as_bytes());
+            }
+        }
+        output
+    }
+
+    /// Returns the EXTH tags of the book, including the cover references.
+    pub fn exth_tags(&self) -> Vec<ExthTag> {
+        let mut tags = self.metadata.to_exth_tags();
+        tags.push(ExthTag::CDEType(String::from("EBOK")));
+        tags.extend(creator_tags());
+        if let Some(cover) = self.cover {
+            tags.push(ExthTag::CoverOffset(cover as u32));
+            tags.push(ExthTag::ThumbnailOffset(cover as u32));
+            tags.push(ExthTag::HasFakeCover(false));
+        }
+        tags
+    }
+
+    /// Builds every record of the book, starting with record 0.
+    pub fn to_records(&self) -> Vec<Vec<u8>> {
+        let resources = self.images.iter()
+            .map(|image| image.data.clone())
+            .collect::<Vec<_>>();
+        let mut records = self.section_records(&resources, false);
+        records.push(EOF_RECORD.to_vec());
+        records
+    }
+
+    /// Builds the records of the KF7 part of a file: record 0, the text, the
+    /// resource records (the images first), FLIS and FCIS.
+    /// In joint files a BOUNDARY record and the KF8 part follow, which is
+    /// noted in the EXTH header.
+    pub fn section_records(&self, resources: &[Vec<u8>], joint: bool) -> Vec<Vec<u8>> {
+        let text = self.kf7_html();
+        let text_records = text_records(&text);
+
+        let mut header = MobiHeader::new();
+        header.mobi_id = unique_id(&self.metadata.title);
+        header.uncompressed_text_length = text.len() as u32;
+        header.text_record_count = text_records.len() as u16;
+        header.extra_record_data_flags = 0x01; // Multibyte trailing entries
+        header.locale = Language::from(
+            self.metadata.language.as_ref().map_or(0, |code| locale_from_code(code)));
+
+        let first_image = 1 + text_records.len() as u32;
+        let flis = first_image + resources.len() as u32;
+        header.first_non_book_record = first_image;
+        if !resources.is_empty() {
+            header.first_image_record = first_image;
+        }
+        header.last_record = (flis - 1) as u16;
+        header.fcis_flis.flis_record_number = flis;
+        header.fcis_flis.fcis_record_number = flis + 1;
+
+        let mut tags = self.exth_tags();
+        if joint {
+            // The KF8 record 0 follows FCIS and the BOUNDARY record
+            tags.push(ExthTag::KF8BoundaryOffset(flis + 3));
+        }
+
+        let mut records = Vec::new();
+        records.push(record0(&mut header, &tags, &self.metadata.title));
+        records.extend(text_records);
+        records.extend(resources.iter().cloned());
+        records.push(flis_record());
+        records.push(fcis_record(text.len() as u32));
+        records
+    }
+
+    /// Writes the book as a MOBI file.
+    pub fn write_to(&self, output: &mut Write) -> Result<(), io::Error> {
+        let records = self.to_records();
+        let mut palmdb = PalmdbHeader::new(&self.metadata.title);
+        palmdb.write_with_records(&records, output)
+    }
+}
+
+/// Returns the index of the image referenced by 'src', matching either the
+/// full name or the file name.
+pub fn find_image(images: &[Resource], src: &str) -> Option<usize> {
+    let file_name = |path: &str| path.rsplit('/').next().unwrap_or("").to_string();
+    images.iter().position(|image| image.name == src)
+        .or_else(|| images.iter().position(|image| file_name(&image.name) == file_name(src)))
+}
+
+/// The EXTH tags identifying the creator software. The values are those of
+/// KindleGen 2.9 (Linux), as some devices check them.
+pub fn creator_tags() -> Vec<ExthTag> {
+    vec![
+        ExthTag::CreatorSoftware(CreatorSoftware::KindleGenLinux),
+        ExthTag::CreatorMajorVersion(2),
+        ExthTag::CreatorMinorVersion(9),
+        ExthTag::CreatorBuildNumber(0),
+    ]
+}
+
+/// Creates a MOBI id from the title and the current time.
+pub fn unique_id(title: &str) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    title.hash(&mut hasher);
+    UTC::now().timestamp().hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Splits the text into compressed records of 4096 bytes.
+/// A character split between two records is completed by a multibyte
+/// trailing entry (extra record data flag 0x01).
+pub fn text_records(text: &[u8]) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    for (i, chunk) in text.chunks(RECORD_SIZE).enumerate() {
+        let end = i * RECORD_SIZE + chunk.len();
+        let overlap = text[end..].iter()
+            .take(3)
+            .take_while(|&&byte| byte & 0xC0 == 0x80)
+            .count();
+
+        let mut record = palmdoc::compress(chunk);
+        record.extend_from_slice(&text[end..end + overlap]);
+        record.push(overlap as u8);
+        records.push(record);
+    }
+    records
+}
+
+/// Builds record 0: the PalmDOC and MOBI headers, the EXTH header and the
+/// full name. The full name fields of the header are filled in.
+pub fn record0(header: &mut MobiHeader, tags: &[ExthTag], title: &str) -> Vec<u8> {
+    let mut exth = Vec::new();
+    exth_tags::write_to(tags, &mut exth).unwrap();
+
+    header.exth_flags |= 0x40;
+    header.header_length = header.length();
+    header.full_name_offset = 16 + header.header_length + exth.len() as u32;
+    header.full_name_length = title.len() as u32;
+
+    let mut record = Vec::new();
+    header.write_to(&mut record).unwrap();
+    record.extend_from_slice(&exth);
+    record.extend_from_slice(title.as_bytes());
+    record.extend_from_slice(&[0, 0]);
+    pad_to_multiple(&mut record, 4);
+    record
+}
+
+/// The FLIS record, which is the same in every file.
+pub fn flis_record() -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(b"FLIS");
+    for &value in &[8u32, 0x00410000, 0, 0xFFFFFFFF, 0x00010003, 3, 1, 0xFFFFFFFF] {
+        write_u32_be(&mut record, value).unwrap();
+    }
+    record
+}
+
+/// The FCIS record, which holds the text length.
+pub fn fcis_record(text_length: u32) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(b"FCIS");
+    for &value in &[0x14u32, 0x10, 1, 0, text_length, 0, 0x20, 8, 0x00010001, 0] {
+        write_u32_be(&mut record, value).unwrap();
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use book::{MobiBook, RecordRole};
+    use testing;
+    use validate;
+
+    #[test]
+    fn characters_split_between_records() {
+        // The 'ü' at 4095 is completed by a trailing entry of the first record
+        let html = format!("<p>{}</p>", "ü".repeat(3000));
+        let records = text_records(html.as_bytes());
+        assert_eq!(records[0][records[0].len() - 2..], [0xBC, 1]);
+        let book = testing::kf7_book(&html);
+        assert_eq!(book.text(&book.main).unwrap(), html.as_bytes());
+    }
+
+    #[test]
+    fn kf7_round_trip() {
+        let mut metadata = Metadata::new("Grüße & Co");
+        metadata.authors.push("Jane Doe".to_string());
+        metadata.language = Some("de".to_string());
+        // Enough text for several records
+        let paragraphs = (0..400)
+            .map(|i| format!("<p id=\"p{}\">Absatz {} über Straßen</p>", i, i))
+            .collect::<String>();
+        let mut writer = MobiWriter::new(metadata.clone(), &format!("<html><body>\
+            <a href=\"#p300\">Weiter</a><img src=\"images/figure.gif\"/>{}</body></html>",
+            paragraphs));
+        writer.set_cover("cover.jpg", b"\xFF\xD8\xFF\xE0cover".to_vec());
+        writer.add_image("images/figure.gif", b"GIF89afigure".to_vec());
+        let mut data = Vec::new();
+        writer.write_to(&mut data).unwrap();
+        assert_eq!(validate::validate(&data), Vec::new());
+
+        let book = MobiBook::from_bytes(&data).unwrap();
+        let html = writer.kf7_html();
+        assert!(html.len() > 3 * RECORD_SIZE);
+        assert_eq!(book.main.header.text_record_count as usize,
+            (html.len() + RECORD_SIZE - 1) / RECORD_SIZE);
+        assert_eq!(book.text(&book.main).unwrap(), html);
+
+        let read = book.metadata();
+        assert_eq!((read.title, read.authors, read.language),
+            (metadata.title, metadata.authors, metadata.language));
+        assert_eq!(book.cover(), Some(0));
+        assert_eq!(book.resource(0), Some(&b"\xFF\xD8\xFF\xE0cover"[..]));
+        let roles = book.record_table().iter().map(|info| info.role).collect::<Vec<_>>();
+        assert_eq!(&roles[roles.len() - 3..],
+            &[RecordRole::Flis, RecordRole::Fcis, RecordRole::Eof]);
+
+        // The link and the image point at the paragraph and the second image
+        let html = String::from_utf8(html).unwrap();
+        let target = html.find("<p id=\"p300\"").unwrap();
+        assert!(html.contains(&format!("filepos=\"{:010}\"", target)));
+        assert!(html.contains("recindex=\"00002\""));
+    }
+}